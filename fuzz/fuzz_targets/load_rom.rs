@@ -0,0 +1,15 @@
+/* Feeds arbitrary files to the rom loader: plain roms, zip and gzip
+archives and Octo cartridges. Bad files must come back as errors.
+
+    cargo +nightly fuzz run load_rom
+*/
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use chip8::rom;
+
+fuzz_target!(|bytes: &[u8]| {
+    let _ = rom::from_bytes("fuzz.ch8", bytes.to_vec(), None);
+});
@@ -0,0 +1,40 @@
+/* Runs arbitrary programs for a bounded number of cycles, on every platform
+and with keys held. Whatever the program does, the machine must either
+carry on or stop with a fault.
+
+    cargo +nightly fuzz run run_rom
+*/
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+use chip8::arch::Arch;
+use chip8::cpu::Platform;
+
+const CYCLES : usize = 10_000;
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    platform: u8,
+    /// A bit a key, held the whole run.
+    keys: u16,
+    program: Vec<u8>,
+}
+
+fuzz_target!(|input: Input| {
+    let platform = [Platform::Chip8, Platform::Schip, Platform::XoChip][input.platform as usize % 3];
+    let mut arch = Arch::new();
+    arch.cpu.quirks = platform.quirks();
+    arch.cpu.load_program(&input.program);
+    for (key, pressed) in arch.keypad.iter_mut().enumerate() {
+        *pressed = input.keys & (1 << key) != 0;
+    }
+
+    for _ in 0..CYCLES {
+        if arch.step().is_err() {
+            break;
+        }
+    }
+});
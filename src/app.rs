@@ -8,21 +8,183 @@ use winit::{
 };
 use std::sync::Arc;
 use crate::arch::Arch;
+use crate::cli::{self, Args};
+use crate::dap::DapServer;
+use crate::debugger::Repl;
+use crate::gdb::GdbStub;
+use crate::display::Display;
+use crate::gpu::Gpu;
+use crate::palette::Palette;
+use crate::recording::Recorder;
+use crate::screenshot;
+use crate::software::Software;
+
+/// Whatever draws the screen in the window.
+pub enum Renderer {
+    Gpu(Box<Gpu>),
+    Software(Box<Software>),
+}
+
+impl Renderer {
+    /// Draws with the gpu, or with the CPU when asked to or when there is
+    /// no gpu to draw with.
+    pub fn new(window : Arc<Window>, software : bool) -> anyhow::Result<Self> {
+        if !software {
+            match pollster::block_on(Gpu::new(Arc::clone(&window))) {
+                Ok(gpu) => return Ok(Renderer::Gpu(Box::new(gpu))),
+                Err(error) => log::warn!("{error:#}, drawing without the gpu"),
+            }
+        }
+        Ok(Renderer::Software(Box::new(Software::new(window)?)))
+    }
+
+    pub fn set_palette(&mut self, palette : Palette) {
+        match self {
+            Renderer::Gpu(gpu) => gpu.set_palette(palette),
+            Renderer::Software(software) => software.set_palette(palette),
+        }
+    }
+
+    pub fn resize(&mut self, size : PhysicalSize<u32>) {
+        match self {
+            Renderer::Gpu(gpu) => gpu.resize(size),
+            Renderer::Software(software) => software.window.request_redraw(),
+        }
+    }
+
+    pub fn render(&mut self) {
+        let result = match self {
+            Renderer::Gpu(gpu) => gpu.render().map_err(anyhow::Error::from),
+            Renderer::Software(software) => software.render(),
+        };
+        if let Err(error) = result {
+            log::warn!("could not draw the screen: {error:#}");
+        }
+    }
+
+    pub fn draw(&mut self, display : &Display) {
+        match self {
+            Renderer::Gpu(gpu) => gpu.draw(display),
+            Renderer::Software(software) => software.draw(display),
+        }
+    }
+}
+
 pub struct App {
     pub arch: Option<Arch>,
+    pub renderer: Option<Renderer>,
+    pub repl: Option<Repl>,
+    pub gdb: Option<GdbStub>,
+    pub dap: Option<DapServer>,
+    args: Args,
+    /// The colours the screen is drawn in, for screenshots.
+    palette: Palette,
+    /// Frames run so far, for --frames.
+    frames: u64,
 }
 
 impl App {
-    pub fn new() -> Self {
+    pub fn new(args : Args) -> Self {
         Self {
             arch: None,
+            renderer: None,
+            repl: None,
+            gdb: None,
+            dap: None,
+            args,
+            palette: Palette::default(),
+            frames: 0,
         }
     }
 
+    /// Writes out what the machine recorded, when it is done.
+    pub fn finish(&mut self) -> anyhow::Result<()> {
+        match &mut self.arch {
+            Some(arch) => {
+                cli::save_screenshot(&self.args, &arch.display, &self.palette)?;
+                arch.finish()
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Runs a frame of the machine, or of the debugger when there is one,
+    /// and hands the display to the renderer if it changed. Returns false
+    /// once the debugger was told to quit, the editor went away, the frames
+    /// asked for have run or the rom faulted.
+    pub fn frame(&mut self) -> bool {
+        let Some(arch) = &mut self.arch else {
+            return true;
+        };
+        self.frames += 1;
+        if self.args.frames.is_some_and(|frames| self.frames > frames) {
+            return false;
+        }
+
+        if let Some(repl) = &mut self.repl {
+            if !repl.frame(arch, false) {
+                return false;
+            }
+        } else if let Some(gdb) = &mut self.gdb {
+            // Once gdb leaves the machine runs on its own
+            match gdb.frame(arch, false) {
+                Ok(true) => {}
+                Ok(false) => self.gdb = None,
+                Err(error) => {
+                    log::error!("gdb: {error}");
+                    self.gdb = None;
+                }
+            }
+        } else if let Some(dap) = &mut self.dap {
+            match dap.frame(arch, false) {
+                Ok(true) => {}
+                Ok(false) => return false,
+                Err(error) => {
+                    log::error!("dap: {error}");
+                    return false;
+                }
+            }
+        } else if let Err(fault) = arch.emulate() {
+            log::error!("the rom faulted at 0x{:03X}: {fault}", arch.cpu.pc());
+            return false;
+        }
+
+        if let Some(renderer) = &mut self.renderer
+            && arch.display.dirty {
+            renderer.draw(&arch.display);
+            arch.display.dirty = false;
+        }
+        true
+    }
+
     fn handle_key(&mut self, event_loop: &ActiveEventLoop, code: KeyCode, is_pressed: bool) {
         if let Some(arch) = &mut self.arch {
             match (code, is_pressed) {
                 (KeyCode::Escape, true) => event_loop.exit(),
+                (KeyCode::F12, true) => {
+                    let path = self.args.screenshot.clone().map_or_else(
+                        || screenshot::next_path(&self.args.rom_path, "png"), Into::into);
+                    match screenshot::save(&arch.display, &self.palette, self.args.screenshot_scale, &path) {
+                        Ok(()) => log::info!("saved a screenshot to {}", path.display()),
+                        Err(error) => log::error!("{error:#}"),
+                    }
+                }
+                (KeyCode::F10, true) => match arch.recorder.take() {
+                    Some(recorder) => match recorder.finish() {
+                        Ok(()) => log::info!("stopped recording"),
+                        Err(error) => log::error!("{error:#}"),
+                    },
+                    None => {
+                        let path = screenshot::next_path(&self.args.rom_path, "gif");
+                        match Recorder::create(&path, &self.palette, self.args.gif_scale) {
+                            Ok(recorder) => {
+                                log::info!("recording to {}", path.display());
+                                arch.recorder = Some(recorder);
+                            }
+                            Err(error) => log::error!("{error:#}"),
+                        }
+                    }
+                },
                 (KeyCode::Digit1, true) => arch.keypad[1] = true,
                 (KeyCode::Digit2, true) => arch.keypad[2] = true,
                 (KeyCode::Digit3, true) => arch.keypad[3] = true,
@@ -66,9 +228,75 @@ impl ApplicationHandler for App {
         #[allow(unused_mut)]
         let mut window_attributes = Window::default_attributes();
         let window = Arc::new(event_loop.create_window(window_attributes).unwrap());
-        let mut arch = Arch::new(window);
-        arch.cpu.put_rom("Pong (1 player).ch8");
+        let mut renderer = match Renderer::new(Arc::clone(&window), self.args.software) {
+            Ok(renderer) => renderer,
+            Err(error) => {
+                log::error!("{error:#}");
+                event_loop.exit();
+                return;
+            }
+        };
+
+        // The editor names the rom once it launches
+        if cli::uses_dap(&self.args) {
+            match cli::start_dap(&self.args) {
+                Ok(server) => self.dap = Some(server),
+                Err(error) => {
+                    log::error!("{error:#}");
+                    event_loop.exit();
+                    return;
+                }
+            }
+            self.arch = Some(Arch::new());
+            self.renderer = Some(renderer);
+            return;
+        }
+
+        let (arch, rom) = match cli::load_arch(&self.args) {
+            Ok(loaded) => loaded,
+            Err(error) => {
+                log::error!("{error:#}");
+                event_loop.exit();
+                return;
+            }
+        };
+        match cli::choose_palette(&self.args, &rom) {
+            Ok(palette) => {
+                renderer.set_palette(palette);
+                self.palette = palette;
+            }
+            Err(error) => {
+                log::error!("{error:#}");
+                event_loop.exit();
+                return;
+            }
+        }
+
+        let symbols = match cli::load_symbols(&self.args, &rom) {
+            Ok(symbols) => symbols,
+            Err(error) => {
+                log::error!("{error:#}");
+                event_loop.exit();
+                return;
+            }
+        };
+        if self.args.debug {
+            self.repl = Some(Repl::new(&arch, symbols));
+        } else if let Some(port) = self.args.gdb {
+            match GdbStub::bind(("127.0.0.1", port)) {
+                Ok(mut stub) => {
+                    stub.debugger.symbols = symbols;
+                    self.gdb = Some(stub);
+                }
+                Err(error) => {
+                    log::error!("could not listen for gdb on port {port}: {error}");
+                    event_loop.exit();
+                    return;
+                }
+            }
+        }
         self.arch = Some(arch);
+        self.renderer = Some(renderer);
     }
 
     fn window_event(
@@ -78,17 +306,17 @@ impl ApplicationHandler for App {
             event: WindowEvent,
         ) {
 
-        let arch = match &mut self.arch {
-            Some(canvas) => canvas,
+        let renderer = match &mut self.renderer {
+            Some(renderer) => renderer,
             None => return,
         };
         
         match event {
             WindowEvent::CloseRequested => event_loop.exit(),
-            WindowEvent::Resized(size) => arch.gpu.resize(PhysicalSize { 
+            WindowEvent::Resized(size) => renderer.resize(PhysicalSize { 
                 width: size.width, 
                 height: size.height }),
-            WindowEvent::RedrawRequested => {let _ = arch.gpu.render();}
+            WindowEvent::RedrawRequested => renderer.render(),
             WindowEvent::KeyboardInput {
                 event:
                     KeyEvent {
@@ -1,40 +1,153 @@
 use std::sync::Arc;
-use winit::window::Window;
 
-use crate::cpu::{Cpu, GpuInstruction};
-use crate::gpu::Gpu;
+use crate::coverage::Coverage;
+use crate::cpu::{Cpu, Fault, GpuInstruction};
+use crate::display::Display;
+use crate::profiler::Profiler;
+use crate::recording::Recorder;
+use crate::rom::Rom;
+use crate::trace::Tracer;
 
+/// The whole machine, without anything to show it on. Frontends draw the
+/// display and fill the keypad.
 pub struct Arch {
     pub cpu: Cpu,
-    pub gpu: Gpu,
-    pub keypad: [bool; 16]
+    pub display: Display,
+    pub keypad: [bool; 16],
+    /// Instructions executed on each 60Hz frame.
+    pub cycles_per_frame: usize,
+    /// Instructions executed since the machine started.
+    pub cycles: u64,
+    /// Whether the beeper sounded over the last frame.
+    pub beeping: bool,
+    /// Logs each instruction executed, with the machine as it was before.
+    pub tracer: Option<Tracer>,
+    /// Counts where the cycles go.
+    pub profiler: Option<Profiler>,
+    /// Marks the memory executed, read and written.
+    pub coverage: Option<Coverage>,
+    /// Records the screen at the end of each frame.
+    pub recorder: Option<Recorder>,
+}
+
+impl Default for Arch {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Arch {
-    pub fn new(window : Arc<Window>) -> Self {
+    pub const DEFAULT_CYCLES_PER_FRAME : usize = 10;
+
+    pub fn new() -> Self {
         let keypad = [false; 16];
         let cpu = Cpu::new(Arc::new(keypad));
-        let gpu = pollster::block_on(Gpu::new(Arc::clone(&window))).unwrap();
-        let keypad = [false; 16];
 
         Self {
             cpu,
-            gpu,
-            keypad
+            display: Display::new(),
+            keypad,
+            cycles_per_frame: Self::DEFAULT_CYCLES_PER_FRAME,
+            cycles: 0,
+            beeping: false,
+            tracer: None,
+            profiler: None,
+            coverage: None,
+            recorder: None,
         }
     }
 
-    pub fn emulate(&mut self) {
-        match self.cpu.process() {
-            GpuInstruction::Clear => {self.gpu.clear_screen();}
+    /// Loads the rom, picking `entry` out of archives, and applies the
+    /// speed it was shipped with. The rom is returned so the frontend can
+    /// use the rest of its options.
+    pub fn load_rom(&mut self, rom_path : &str, entry : Option<&str>) -> anyhow::Result<Rom> {
+        let rom = self.cpu.put_rom_entry(rom_path, entry)?;
+        if let Some(tickrate) = rom.options.as_ref().and_then(|options| options.tickrate) {
+            self.cycles_per_frame = tickrate;
+        }
+
+        Ok(rom)
+    }
+
+    /// Runs a single 60Hz frame, stopping short at a fault.
+    pub fn emulate(&mut self) -> Result<(), Fault> {
+        for _ in 0..self.cycles_per_frame {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    /// Executes a single instruction, ticking the timers when it is the
+    /// last one of a frame. An instruction that faults does not count as
+    /// executed.
+    pub fn step(&mut self) -> Result<(), Fault> {
+        self.cpu.set_keypad(self.keypad);
+
+        // What the instruction is and where it runs from, for recording it
+        // once it ran
+        let (pc, opcode, calls) = (self.cpu.pc(), self.cpu.current_instruction(), self.cpu.stack().len());
+        if let Some(tracer) = &mut self.tracer {
+            tracer.record(&self.cpu, self.cycles);
+        }
+        if self.coverage.is_some() {
+            self.cpu.record_accesses(true);
+        }
+
+        match self.cpu.process()? {
+            GpuInstruction::Clear => self.display.clear(),
             GpuInstruction::XorSprite(pos_x, pos_y, sprite_data) => {
-                if self.gpu.xor_sprite(pos_x, pos_y, sprite_data) {
-                    self.cpu.reg[15] = 1;
-                } else {
-                    self.cpu.reg[15] = 0;
-                }
+                let collision = self.display.xor_sprite(pos_x, pos_y, &sprite_data);
+                self.cpu.reg[15] = collision as u8;
+            }
+            GpuInstruction::Nothing => {}
+        }
+        if let Some(tracer) = &mut self.tracer
+            && let Err(error) = tracer.commit() {
+            log::error!("could not write the trace, stopping it: {error}");
+            self.tracer = None;
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(pc, opcode, calls);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record_fetch(pc);
+            coverage.record_accesses(self.cpu.accesses());
+        }
+
+        self.cycles += 1;
+        if self.cycles.is_multiple_of(self.cycles_per_frame.max(1) as u64) {
+            self.beeping = self.cpu.sound_timer() > 0;
+            self.cpu.tick_timers();
+            // So the trace is whole up to the last frame if we get killed
+            if let Some(tracer) = &mut self.tracer
+                && let Err(error) = tracer.flush() {
+                log::error!("could not write the trace, stopping it: {error}");
+                self.tracer = None;
+            }
+            if let Some(recorder) = &mut self.recorder
+                && let Err(error) = recorder.record(&self.display) {
+                log::error!("could not write the recording, stopping it: {error:#}");
+                self.recorder = None;
             }
-            _ => {}
         }
+        Ok(())
     }
-}
\ No newline at end of file
+
+    /// Writes out whatever was recorded while running, once the machine
+    /// is done.
+    pub fn finish(&mut self) -> anyhow::Result<()> {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.flush()?;
+        }
+        if let Some(profiler) = &self.profiler {
+            profiler.save(self.cpu.memory())?;
+        }
+        if let Some(coverage) = &self.coverage {
+            coverage.save(self.cpu.memory())?;
+        }
+        if let Some(recorder) = self.recorder.take() {
+            recorder.finish()?;
+        }
+        Ok(())
+    }
+}
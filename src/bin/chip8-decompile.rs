@@ -0,0 +1,60 @@
+use std::process::ExitCode;
+
+use chip8::coverage;
+use chip8::decompile;
+use chip8::rom;
+use chip8::symbols::Symbols;
+
+const USAGE : &str = "\
+Usage: chip8-decompile [OPTIONS] ROM
+
+Prints ROM as Octo source, with loops, ifs and subroutines recovered,
+which Octo assembles back into ROM.
+
+Options:
+    --entry <NAME>     Rom to decompile out of an archive
+    --symbols <FILE>   Name addresses after the symbols in FILE
+    --coverage <FILE>  Tell code from data with the coverage FILE of a run
+    -h, --help         Print this message";
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let mut rom_path = None;
+    let mut entry = None;
+    let mut symbols = None;
+    let mut regions = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return ExitCode::SUCCESS;
+            }
+            "--entry" => entry = args.next(),
+            "--symbols" => symbols = args.next(),
+            "--coverage" => regions = args.next(),
+            _ if rom_path.is_none() && !arg.starts_with('-') => rom_path = Some(arg),
+            _ => {
+                eprintln!("unexpected argument {arg}\n\n{USAGE}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let Some(rom_path) = rom_path else {
+        eprintln!("{USAGE}");
+        return ExitCode::FAILURE;
+    };
+
+    let loaded = rom::load_entry(&rom_path, entry.as_deref())
+        .and_then(|rom| Ok((rom, symbols.as_deref().map(Symbols::load).transpose()?.unwrap_or_default())))
+        .and_then(|(rom, symbols)| Ok((rom, symbols, regions.as_deref().map(coverage::load_regions).transpose()?.unwrap_or_default())));
+    match loaded {
+        Ok((rom, symbols, regions)) => {
+            print!("{}", decompile::decompile_with_coverage(&rom.program, &symbols, &regions));
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            eprintln!("{error:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
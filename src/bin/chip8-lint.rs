@@ -0,0 +1,83 @@
+use std::process::ExitCode;
+
+use chip8::cfg::Cfg;
+use chip8::coverage;
+use chip8::lint::Analysis;
+use chip8::rom;
+use chip8::symbols::Symbols;
+
+const USAGE : &str = "\
+Usage: chip8-lint [OPTIONS] ROM
+
+Looks for problems in ROM without running it and suggests the platform to
+run it with. Exits with 1 when there are warnings.
+
+Options:
+    --entry <NAME>     Rom to check out of an archive
+    --dot <FILE>       Write the control-flow graph to FILE for Graphviz
+    --symbols <FILE>   Name the blocks of the graph after the symbols in FILE
+    --coverage <FILE>  Find the graph's code with the coverage FILE of a run
+    -h, --help         Print this message";
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let mut rom_path = None;
+    let mut entry = None;
+    let mut dot = None;
+    let mut symbols = None;
+    let mut regions = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return ExitCode::SUCCESS;
+            }
+            "--entry" => entry = args.next(),
+            "--dot" => dot = args.next(),
+            "--symbols" => symbols = args.next(),
+            "--coverage" => regions = args.next(),
+            _ if rom_path.is_none() && !arg.starts_with('-') => rom_path = Some(arg),
+            _ => {
+                eprintln!("unexpected argument {arg}\n\n{USAGE}");
+                return ExitCode::from(2);
+            }
+        }
+    }
+    let Some(rom_path) = rom_path else {
+        eprintln!("{USAGE}");
+        return ExitCode::from(2);
+    };
+
+    let rom = match rom::load_entry(&rom_path, entry.as_deref()) {
+        Ok(rom) => rom,
+        Err(error) => {
+            eprintln!("{error:#}");
+            return ExitCode::from(2);
+        }
+    };
+
+    if let Some(path) = dot {
+        let symbols = match symbols.as_deref().map(Symbols::load).transpose() {
+            Ok(symbols) => symbols.unwrap_or_default(),
+            Err(error) => {
+                eprintln!("{error:#}");
+                return ExitCode::from(2);
+            }
+        };
+        let regions = match regions.as_deref().map(coverage::load_regions).transpose() {
+            Ok(regions) => regions.unwrap_or_default(),
+            Err(error) => {
+                eprintln!("{error:#}");
+                return ExitCode::from(2);
+            }
+        };
+        if let Err(error) = std::fs::write(&path, Cfg::with_coverage(&rom.program, &regions).dot(&symbols)) {
+            eprintln!("could not write the graph to {path}: {error}");
+            return ExitCode::from(2);
+        }
+    }
+
+    let analysis = Analysis::new(&rom.program);
+    print!("{}", analysis.report());
+    if analysis.has_warnings() {ExitCode::FAILURE} else {ExitCode::SUCCESS}
+}
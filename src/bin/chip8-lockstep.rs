@@ -0,0 +1,117 @@
+use std::process::ExitCode;
+
+use chip8::arch::Arch;
+use chip8::lockstep::{self, Lockstep};
+use chip8::symbols::Symbols;
+use chip8::testing;
+
+const USAGE : &str = "\
+Usage: chip8-lockstep [OPTIONS] ROM A B
+
+Runs ROM on two machines side by side, instruction by instruction, and
+reports the first one after which they differ. A and B are a platform
+followed by quirks to change, like chip8 or schip,shift=off,jump=off.
+Exits with 1 when the machines diverge.
+
+Options:
+    --entry <NAME>    Rom to run out of an archive
+    --inputs <FILE>   Press keys as FILE says, lines of <frame> press|release <key>
+    --frames <N>      Give up after N frames, 600 by default
+    --seed <N>        Seed the random numbers both machines get
+    --symbols <FILE>  Name addresses after the symbols in FILE
+    -h, --help        Print this message";
+
+struct Options {
+    rom_path: String,
+    entry: Option<String>,
+    configs: (String, String),
+    inputs: Option<String>,
+    frames: u64,
+    seed: u64,
+    symbols: Option<String>,
+}
+
+fn parse_args() -> anyhow::Result<Option<Options>> {
+    let mut args = std::env::args().skip(1);
+    let mut positional = Vec::new();
+    let mut entry = None;
+    let mut inputs = None;
+    let mut frames = 600;
+    let mut seed = 0;
+    let mut symbols = None;
+    while let Some(arg) = args.next() {
+        let mut value = |name : &str| args.next().ok_or_else(|| anyhow::anyhow!("{name} expects a value"));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--entry" => entry = Some(value("--entry")?),
+            "--inputs" => inputs = Some(value("--inputs")?),
+            "--frames" => frames = value("--frames")?.parse()?,
+            "--seed" => seed = value("--seed")?.parse()?,
+            "--symbols" => symbols = Some(value("--symbols")?),
+            _ if !arg.starts_with('-') => positional.push(arg),
+            _ => anyhow::bail!("unexpected argument {arg}"),
+        }
+    }
+
+    let [rom_path, a, b] = <[String; 3]>::try_from(positional)
+        .map_err(|_| anyhow::anyhow!("expected a rom and two configurations"))?;
+    Ok(Some(Options { rom_path, entry, configs: (a, b), inputs, frames, seed, symbols }))
+}
+
+fn machine(options : &Options, config : &str) -> anyhow::Result<Arch> {
+    let mut arch = Arch::new();
+    arch.load_rom(&options.rom_path, options.entry.as_deref())?;
+    arch.cpu.quirks = lockstep::parse_config(config)?;
+    Ok(arch)
+}
+
+fn run(options : &Options) -> anyhow::Result<bool> {
+    let (a, b) = (&options.configs.0, &options.configs.1);
+    let mut lockstep = Lockstep::new(machine(options, a)?, machine(options, b)?, options.seed);
+    if let Some(path) = &options.inputs {
+        lockstep.inputs = testing::parse_inputs(&std::fs::read_to_string(path)?)?;
+    }
+    let symbols = options.symbols.as_deref().map(Symbols::load).transpose()?.unwrap_or_default();
+
+    let differing = lockstep::differing_quirks(lockstep.a.cpu.quirks, lockstep.b.cpu.quirks);
+    if !differing.is_empty() {
+        println!("{a} and {b} differ in: {}", differing.join(", "));
+    }
+    match lockstep.run(options.frames) {
+        Ok(Some(divergence)) => {
+            print!("{}", divergence.report((a, b), &symbols));
+            Ok(false)
+        }
+        Ok(None) => {
+            println!("{a} and {b} agree for {} frames", options.frames);
+            Ok(true)
+        }
+        Err(fault) => {
+            println!("{a} and {b} agree up to a fault at {}: {fault}", symbols.describe(lockstep.a.cpu.pc()));
+            Ok(true)
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args() {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(error) => {
+            eprintln!("{error:#}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(&options) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(error) => {
+            eprintln!("{error:#}");
+            ExitCode::from(2)
+        }
+    }
+}
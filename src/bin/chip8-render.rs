@@ -0,0 +1,155 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::process::ExitCode;
+
+use chip8::arch::Arch;
+use chip8::cpu::Platform;
+use chip8::export::{Audio, Video};
+use chip8::palette;
+use chip8::testing;
+
+const USAGE : &str = "\
+Usage: chip8-render [OPTIONS] ROM
+
+Runs ROM without a window for a number of frames, pressing keys as a movie
+says, and writes the screen as Y4M video and the beeper as WAV audio for an
+encoder. The random numbers are seeded, so the same movie always renders
+the same video.
+
+Options:
+    --video <FILE>                   Write the screen to FILE as Y4M
+    --audio <FILE>                   Write the beeper to FILE as WAV
+    --scale <N>                      Scale the video up N times
+    --theme <NAME>                   Draw in the colours of a theme instead of
+                                     those the rom came with
+    --entry <NAME>                   Rom to run out of an archive
+    --platform <chip8|schip|xochip>  Use the quirks of this platform
+    --inputs <FILE>                  Press keys as FILE says, lines of
+                                     <frame> press|release <key>
+    --frames <N>                     Render N frames, 600 by default
+    --seed <N>                       Seed the random numbers
+    -h, --help                       Print this message";
+
+struct Options {
+    rom_path: String,
+    video: Option<String>,
+    audio: Option<String>,
+    scale: usize,
+    theme: Option<String>,
+    entry: Option<String>,
+    platform: Option<Platform>,
+    inputs: Option<String>,
+    frames: u64,
+    seed: u64,
+}
+
+fn parse_args() -> anyhow::Result<Option<Options>> {
+    let mut args = std::env::args().skip(1);
+    let mut rom_path = None;
+    let mut options = Options {
+        rom_path: String::new(),
+        video: None,
+        audio: None,
+        scale: 1,
+        theme: None,
+        entry: None,
+        platform: None,
+        inputs: None,
+        frames: 600,
+        seed: 0,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = |name : &str| args.next().ok_or_else(|| anyhow::anyhow!("{name} expects a value"));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--video" => options.video = Some(value("--video")?),
+            "--audio" => options.audio = Some(value("--audio")?),
+            "--scale" => options.scale = value("--scale")?.parse()?,
+            "--theme" => options.theme = Some(value("--theme")?),
+            "--entry" => options.entry = Some(value("--entry")?),
+            "--platform" => options.platform = Some(value("--platform")?.parse()?),
+            "--inputs" => options.inputs = Some(value("--inputs")?),
+            "--frames" => options.frames = value("--frames")?.parse()?,
+            "--seed" => options.seed = value("--seed")?.parse()?,
+            _ if !arg.starts_with('-') && rom_path.is_none() => rom_path = Some(arg),
+            _ => anyhow::bail!("unexpected argument {arg}"),
+        }
+    }
+
+    options.rom_path = rom_path.ok_or_else(|| anyhow::anyhow!("expected a rom"))?;
+    if options.video.is_none() && options.audio.is_none() {
+        anyhow::bail!("nothing to render, give --video or --audio");
+    }
+    Ok(Some(options))
+}
+
+fn run(options : &Options) -> anyhow::Result<()> {
+    let mut arch = Arch::new();
+    let rom = arch.load_rom(&options.rom_path, options.entry.as_deref())?;
+    if let Some(platform) = options.platform {
+        arch.cpu.quirks = platform.quirks();
+    }
+    arch.cpu.seed_rng(options.seed);
+    let inputs = match &options.inputs {
+        Some(path) => testing::parse_inputs(&std::fs::read_to_string(path)?)?,
+        None => Vec::new(),
+    };
+
+    let palette = match &options.theme {
+        Some(name) => palette::load_theme(name)?,
+        None => rom.options.map(|options| options.palette).unwrap_or_default(),
+    };
+    let create = |path : &str| File::create(path).map(BufWriter::new).map_err(|error| anyhow::anyhow!("could not create {path}: {error}"));
+    let mut video = options.video.as_deref().map(create).transpose()?
+        .map(|file| Video::new(file, &palette, options.scale)).transpose()?;
+    let mut audio = options.audio.as_deref().map(create).transpose()?
+        .map(Audio::new).transpose()?;
+
+    // What was rendered up to a fault is kept
+    let mut fault = None;
+    for frame in 0..options.frames {
+        for input in inputs.iter().filter(|input| input.frame == frame) {
+            arch.keypad[input.key as usize & 0xF] = input.pressed;
+        }
+        if let Err(error) = arch.emulate() {
+            fault = Some(anyhow::anyhow!("the rom faulted at 0x{:03X} on frame {frame}: {error}", arch.cpu.pc()));
+            break;
+        }
+        if let Some(video) = &mut video {
+            video.frame(&arch.display)?;
+        }
+        if let Some(audio) = &mut audio {
+            audio.frame(arch.beeping)?;
+        }
+    }
+
+    if let Some(video) = video {
+        video.finish()?;
+    }
+    if let Some(audio) = audio {
+        audio.finish()?;
+    }
+    fault.map_or(Ok(()), Err)
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args() {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(error) => {
+            eprintln!("{error:#}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(&options) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("{error:#}");
+            ExitCode::from(2)
+        }
+    }
+}
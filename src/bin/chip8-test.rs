@@ -0,0 +1,68 @@
+use std::path::Path;
+use std::process::ExitCode;
+
+use chip8::romtest::Suite;
+
+const USAGE : &str = "\
+Usage: chip8-test [OPTIONS] FILE...
+
+Runs the subroutine tests in each FILE, see the romtest module for how to
+write them. Exits with 1 when a test fails.
+
+Options:
+    --rom <ROM>  Test ROM instead of the rom the files name
+    -h, --help   Print this message";
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let mut files = Vec::new();
+    let mut rom = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return ExitCode::SUCCESS;
+            }
+            "--rom" => rom = args.next(),
+            _ if !arg.starts_with('-') => files.push(arg),
+            _ => {
+                eprintln!("unexpected argument {arg}\n\n{USAGE}");
+                return ExitCode::from(2);
+            }
+        }
+    }
+    if files.is_empty() {
+        eprintln!("{USAGE}");
+        return ExitCode::from(2);
+    }
+
+    let (mut passed, mut failed) = (0, 0);
+    for file in &files {
+        let results = match Suite::load(Path::new(file)).and_then(|suite| {
+            let results = suite.run(rom.as_deref())?;
+            Ok(results.into_iter().map(|(test, result)| (test.name.clone(), test.line, result)).collect::<Vec<_>>())
+        }) {
+            Ok(results) => results,
+            Err(error) => {
+                eprintln!("{error:#}");
+                return ExitCode::from(2);
+            }
+        };
+
+        for (name, line, result) in results {
+            match result {
+                Ok(()) => {
+                    println!("ok      {file}:{line} {name}");
+                    passed += 1;
+                }
+                Err(error) => {
+                    println!("FAILED  {file}:{line} {name}\n    {}", format!("{error:#}").replace('\n', "\n    "));
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    println!("\n{passed} passed, {failed} failed");
+    if failed == 0 {ExitCode::SUCCESS} else {ExitCode::FAILURE}
+}
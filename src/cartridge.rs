@@ -0,0 +1,222 @@
+/* Octo shares games as "cartridges", GIF images that carry the program
+and the options it should be run with next to a label picture. The payload
+is hidden in the two lowest bits of each pixel's palette index, four pixels
+to a byte with the most significant bits first, continuing from frame to
+frame. It starts with a 32 bit big endian length followed by that many
+bytes of JSON:
+
+    {"program": ..., "options": {"tickrate": 20, "shiftQuirks": true, ...}}
+*/
+
+use anyhow::{anyhow, bail, Context};
+use serde::Deserialize;
+
+use crate::cpu::Quirks;
+use crate::octo;
+use crate::palette::{self, Palette};
+use crate::symbols::Symbols;
+
+/// The options a cartridge asks to be run with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    /// Instructions executed per 60Hz frame.
+    pub tickrate: Option<usize>,
+    pub quirks: Quirks,
+    pub palette: Palette,
+}
+
+#[derive(Debug)]
+pub struct Cartridge {
+    pub program: Vec<u8>,
+    pub options: Options,
+    /// The labels of the source the program was assembled from.
+    pub symbols: Symbols,
+}
+
+#[derive(Deserialize)]
+struct Payload {
+    program: serde_json::Value,
+    #[serde(default)]
+    options: RawOptions,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct RawOptions {
+    tickrate: Option<usize>,
+    fill_color: Option<String>,
+    fill_color2: Option<String>,
+    blend_color: Option<String>,
+    background_color: Option<String>,
+    shift_quirks: Option<bool>,
+    load_store_quirks: Option<bool>,
+    jump_quirks: Option<bool>,
+    logic_quirks: Option<bool>,
+    clip_quirks: Option<bool>,
+    v_blank_quirks: Option<bool>,
+}
+
+impl RawOptions {
+    /// Octo leaves the quirks a cartridge does not mention off.
+    fn into_options(self) -> Options {
+        let quirks = Quirks {
+            shift: self.shift_quirks.unwrap_or(false),
+            load_store: self.load_store_quirks.unwrap_or(false),
+            jump: self.jump_quirks.unwrap_or(false),
+            logic: self.logic_quirks.unwrap_or(false),
+        };
+        // The display always clips sprites at the edges and drawing never
+        // waits for the next frame, so these can not be followed
+        if self.clip_quirks == Some(false) {
+            log::warn!("the cartridge wants sprites wrapped at the screen edges, they will be clipped");
+        }
+        if self.v_blank_quirks == Some(true) {
+            log::warn!("the cartridge wants drawing to wait for the next frame, it will not");
+        }
+
+        let mut palette = Palette::default();
+        if let Some(color) = self.fill_color.as_deref().and_then(palette::parse_color) {
+            palette.foreground = color;
+        }
+        if let Some(color) = self.fill_color2.as_deref().and_then(palette::parse_color) {
+            palette.foreground2 = color;
+        }
+        if let Some(color) = self.blend_color.as_deref().and_then(palette::parse_color) {
+            palette.blend = color;
+        }
+        if let Some(color) = self.background_color.as_deref().and_then(palette::parse_color) {
+            palette.background = color;
+        }
+
+        Options {
+            tickrate: self.tickrate,
+            quirks,
+            palette,
+        }
+    }
+}
+
+pub fn is_cartridge(bytes : &[u8]) -> bool {
+    bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a")
+}
+
+pub fn decode(bytes : &[u8]) -> anyhow::Result<Cartridge> {
+    let mut decode_options = gif::DecodeOptions::new();
+    decode_options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = decode_options.read_info(bytes)?;
+
+    let mut data: Vec<u8> = Vec::new();
+    let mut byte = 0u8;
+    let mut pairs = 0;
+    while let Some(frame) = decoder.read_next_frame()? {
+        for index in frame.buffer.iter() {
+            byte = (byte << 2) | (index & 0x3);
+            pairs += 1;
+            if pairs == 4 {
+                data.push(byte);
+                byte = 0;
+                pairs = 0;
+            }
+        }
+    }
+
+    if data.len() < 4 {
+        bail!("image is too small to hold a cartridge");
+    }
+    let length = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    let json = data.get(4..(4 + length))
+        .ok_or_else(|| anyhow!("cartridge says it holds {length} bytes but the image is smaller"))?;
+
+    let payload: Payload = serde_json::from_slice(json)
+        .context("cartridge payload is not valid JSON")?;
+
+    let (program, symbols) = program_bytes(&payload.program)?;
+    Ok(Cartridge {
+        program,
+        options: payload.options.into_options(),
+        symbols,
+    })
+}
+
+/// The program is either a list of bytes or Octo source, whose labels
+/// come with it.
+fn program_bytes(program : &serde_json::Value) -> anyhow::Result<(Vec<u8>, Symbols)> {
+    match program {
+        serde_json::Value::Array(values) => values
+            .iter()
+            .map(|value| {
+                value.as_u64()
+                    .and_then(|byte| u8::try_from(byte).ok())
+                    .ok_or_else(|| anyhow!("program holds {value}, which is not a byte"))
+            })
+            .collect::<anyhow::Result<_>>()
+            .map(|program| (program, Symbols::new())),
+        serde_json::Value::String(source) => octo::assemble(source)
+            .map(|assembly| {
+                let symbols = Symbols::from_assembly(&assembly);
+                (assembly.program, symbols)
+            })
+            .context("could not assemble the cartridge's Octo source"),
+        _ => bail!("cartridge has no program"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_cartridge(json : &str) -> Vec<u8> {
+        let mut data = (json.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(json.as_bytes());
+
+        let mut pixels: Vec<u8> = Vec::new();
+        for byte in data {
+            for shift in [6, 4, 2, 0] {
+                // The high bits stand in for the label picture
+                pixels.push(0xF0 | ((byte >> shift) & 0x3));
+            }
+        }
+        let width = 64;
+        pixels.resize(pixels.len().div_ceil(width) * width, 0);
+        let height = (pixels.len() / width) as u16;
+
+        let palette: Vec<u8> = (0..=255).flat_map(|i| [i, i, i]).collect();
+        let mut image = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut image, width as u16, height, &palette).unwrap();
+            let frame = gif::Frame::from_indexed_pixels(width as u16, height, pixels, None);
+            encoder.write_frame(&frame).unwrap();
+        }
+        image
+    }
+
+    #[test]
+    fn test_decode_cartridge() {
+        let image = make_cartridge(r##"{
+            "program": [0, 224, 18, 0],
+            "options": {"tickrate": 7, "shiftQuirks": false, "jumpQuirks": true, "fillColor": "#FFCC00"}
+        }"##);
+        assert!(is_cartridge(&image));
+
+        let cartridge = decode(&image).unwrap();
+        assert_eq!(cartridge.program, vec![0x00, 0xE0, 0x12, 0x00]);
+        assert_eq!(cartridge.options.tickrate, Some(7));
+        assert!(!cartridge.options.quirks.shift);
+        assert!(cartridge.options.quirks.jump);
+        assert!(!cartridge.options.quirks.load_store);
+        assert_eq!(cartridge.options.palette.foreground, [0xFF, 0xCC, 0x00]);
+        assert_eq!(cartridge.options.palette.background, Palette::default().background);
+    }
+
+    #[test]
+    fn test_decode_source() {
+        let image = make_cartridge(r#"{"program": ": main\n  clear\n  loop again # forever\n", "options": {}}"#);
+        let cartridge = decode(&image).unwrap();
+        assert_eq!(cartridge.program, vec![0x00, 0xE0, 0x12, 0x02]);
+        assert_eq!(cartridge.symbols.name_of(0x200), Some("main"));
+        assert_eq!(cartridge.options.quirks, Quirks { shift: false, load_store: false, jump: false, logic: false });
+
+        let image = make_cartridge(r#"{"program": ": main jump nowhere", "options": {}}"#);
+        assert!(decode(&image).is_err());
+    }
+}
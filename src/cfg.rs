@@ -0,0 +1,418 @@
+/* The control-flow graph of the code reachable from 0x200: basic blocks of
+instructions that run one after the other, joined by the ways control
+leaves them.
+
+- next: falling through, including the return site after a call and a
+  skip that does not skip
+- skip: a skip instruction skipping
+- jump: 1nnn
+- call: 2nnn, to the subroutine's first block
+- return: from the blocks ending in RET to the return sites of the calls
+  into the subroutine they belong to
+
+Bnnn jumps somewhere computed and is left unresolved. A coverage report
+of a run fills in what walking the code can not: the instructions it saw
+executed are code even when only a Bnnn leads there, and what it saw only
+read or written is data, never decoded. `dot` writes the graph for
+Graphviz, `dot -Tsvg cfg.dot > cfg.svg`.
+*/
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::ops::RangeInclusive;
+
+use crate::coverage::Usage;
+use crate::instruction::Instruction;
+use crate::lint;
+use crate::symbols::Symbols;
+
+/// Where programs are loaded and start.
+pub const START : u16 = 0x200;
+
+/// Where execution goes after an instruction.
+#[derive(Debug, Default)]
+pub struct Flow {
+    /// The next instruction, and the one after it for skips.
+    pub next: Vec<u16>,
+    pub jump: Option<u16>,
+    pub call: Option<u16>,
+}
+
+impl Flow {
+    /// Carries on to the next instruction and nowhere else.
+    pub fn is_straight(&self) -> bool {
+        self.next.len() == 1 && self.jump.is_none() && self.call.is_none()
+    }
+}
+
+/// A program loaded at 0x200, read an instruction at a time.
+pub struct Program<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Program<'a> {
+    pub fn new(bytes : &'a [u8]) -> Self {
+        Self { bytes: &bytes[..bytes.len().min(4096 - START as usize)] }
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn contains(&self, addr : u16) -> bool {
+        (START..START + self.bytes.len() as u16).contains(&addr)
+    }
+
+    pub fn byte(&self, addr : u16) -> u8 {
+        addr.checked_sub(START).and_then(|offset| self.bytes.get(offset as usize)).copied().unwrap_or_default()
+    }
+
+    pub fn opcode(&self, pc : u16) -> Option<u16> {
+        let offset = pc.checked_sub(START)? as usize;
+        let bytes = self.bytes.get(offset..offset + 2)?;
+        Some(((bytes[0] as u16) << 8) | bytes[1] as u16)
+    }
+
+    /// XO-CHIP's long I is followed by its address.
+    pub fn size(&self, pc : u16) -> u16 {
+        if self.opcode(pc) == Some(0xF000) {4} else {2}
+    }
+
+    pub fn flow(&self, pc : u16, opcode : u16) -> Flow {
+        let next = pc.wrapping_add(self.size(pc));
+        match Instruction::decode(opcode) {
+            Instruction::Jp(target) => Flow { jump: Some(target), ..Flow::default() },
+            Instruction::Call(target) => Flow { next: vec![next], call: Some(target), ..Flow::default() },
+            Instruction::Ret | Instruction::JpV0(_) => Flow::default(),
+            Instruction::SeByte(..) | Instruction::SneByte(..) | Instruction::SeReg(..)
+                | Instruction::SneReg(..) | Instruction::Skp(_) | Instruction::Sknp(_) =>
+                Flow { next: vec![next, next.wrapping_add(self.size(next))], ..Flow::default() },
+            _ if opcode == 0x00FD => Flow::default(),
+            Instruction::Unknown(_) if lint::extension(opcode).is_none() => Flow::default(),
+            _ => Flow { next: vec![next], ..Flow::default() },
+        }
+    }
+
+    /// The subroutines called from the one at `entry`.
+    pub fn callees(&self, entry : u16) -> BTreeSet<u16> {
+        let mut callees = BTreeSet::new();
+        let mut seen = BTreeSet::new();
+        let mut work = vec![entry];
+        while let Some(pc) = work.pop() {
+            if !seen.insert(pc) {
+                continue;
+            }
+            let Some(opcode) = self.opcode(pc) else {
+                continue;
+            };
+            let flow = self.flow(pc, opcode);
+            work.extend(flow.next.iter().chain(&flow.jump));
+            if let Some(call) = flow.call
+                && self.contains(call) {
+                callees.insert(call);
+            }
+        }
+        callees
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EdgeKind {
+    Next,
+    Skip,
+    Jump,
+    Call,
+    Return,
+}
+
+/// An edge between the blocks starting at `from` and `to`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Edge {
+    pub from: u16,
+    pub to: u16,
+    pub kind: EdgeKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    /// The address and opcode of each instruction.
+    pub instructions: Vec<(u16, u16)>,
+    /// Ends in a Bnnn, whose targets are not known.
+    pub unresolved: bool,
+}
+
+impl Block {
+    pub fn start(&self) -> u16 {
+        self.instructions[0].0
+    }
+
+    /// The address and opcode of the instruction control leaves from.
+    pub fn last(&self) -> (u16, u16) {
+        self.instructions[self.instructions.len() - 1]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cfg {
+    pub blocks: BTreeMap<u16, Block>,
+    pub edges: Vec<Edge>,
+    /// The blocks of each subroutine by its entry, 0x200 standing for the
+    /// main program. Code shared between subroutines is in all of them.
+    pub functions: BTreeMap<u16, BTreeSet<u16>>,
+}
+
+impl Cfg {
+    pub fn new(bytes : &[u8]) -> Self {
+        Self::with_coverage(bytes, &[])
+    }
+
+    /// The graph of the code reachable from 0x200 and from the code
+    /// regions of a coverage report, leaving out its data regions.
+    pub fn with_coverage(bytes : &[u8], regions : &[(RangeInclusive<u16>, Usage)]) -> Self {
+        let program = Program::new(bytes);
+        let is_data = |pc : u16| regions.iter()
+            .any(|(range, usage)| matches!(usage, Usage::Data { .. }) && range.contains(&pc));
+
+        // Executed instructions follow one another through a code region
+        let mut seeds = BTreeSet::from([START]);
+        for (range, _) in regions.iter().filter(|(_, usage)| *usage == Usage::Code) {
+            let mut pc = *range.start();
+            while pc <= *range.end() && program.contains(pc) {
+                seeds.insert(pc);
+                pc += program.size(pc);
+            }
+        }
+
+        let mut code = BTreeMap::new();
+        let mut work: Vec<u16> = seeds.iter().copied().collect();
+        while let Some(pc) = work.pop() {
+            if code.contains_key(&pc) || is_data(pc) {
+                continue;
+            }
+            let Some(opcode) = program.opcode(pc) else {
+                continue;
+            };
+            code.insert(pc, opcode);
+            let flow = program.flow(pc, opcode);
+            work.extend(flow.next.iter().chain(&flow.jump).chain(&flow.call));
+        }
+
+        // Blocks start wherever control arrives other than by falling
+        // through
+        let mut leaders = BTreeSet::from([START]);
+        let mut fallen_into = BTreeSet::new();
+        for (pc, opcode) in &code {
+            let flow = program.flow(*pc, *opcode);
+            if flow.is_straight() {
+                fallen_into.extend(&flow.next);
+            } else {
+                leaders.extend(flow.next.iter().chain(&flow.jump).chain(&flow.call));
+            }
+        }
+        // Seeds nothing falls into were reached some way the walk can not see
+        leaders.extend(seeds.difference(&fallen_into));
+
+        let mut blocks = BTreeMap::new();
+        let mut edges = Vec::new();
+        for leader in leaders.iter().filter(|leader| code.contains_key(leader)) {
+            let mut instructions = Vec::new();
+            let mut pc = *leader;
+            let flow = loop {
+                let opcode = code[&pc];
+                instructions.push((pc, opcode));
+                let flow = program.flow(pc, opcode);
+                let next = pc.wrapping_add(program.size(pc));
+                if !flow.is_straight() || leaders.contains(&next) || !code.contains_key(&next) {
+                    break flow;
+                }
+                pc = next;
+            };
+
+            let mut edge = |to : u16, kind : EdgeKind| if code.contains_key(&to) {
+                edges.push(Edge { from: *leader, to, kind });
+            };
+            match flow.next[..] {
+                [next, skipped] => {
+                    edge(next, EdgeKind::Next);
+                    edge(skipped, EdgeKind::Skip);
+                }
+                [next] => edge(next, EdgeKind::Next),
+                _ => {}
+            }
+            if let Some(target) = flow.jump {
+                edge(target, EdgeKind::Jump);
+            }
+            if let Some(target) = flow.call {
+                edge(target, EdgeKind::Call);
+            }
+
+            let unresolved = matches!(Instruction::decode(code[&pc]), Instruction::JpV0(_));
+            blocks.insert(*leader, Block { instructions, unresolved });
+        }
+
+        let mut entries = BTreeSet::from([START]);
+        entries.extend(edges.iter().filter(|edge| edge.kind == EdgeKind::Call).map(|edge| edge.to));
+        let functions: BTreeMap<u16, BTreeSet<u16>> = entries.iter()
+            .filter(|entry| blocks.contains_key(entry))
+            .map(|entry| (*entry, Self::body(&edges, *entry)))
+            .collect();
+
+        let mut returns = Vec::new();
+        for call in edges.iter().filter(|edge| edge.kind == EdgeKind::Call) {
+            let Some(site) = edges.iter().find(|edge| edge.from == call.from && edge.kind == EdgeKind::Next) else {
+                continue;
+            };
+            for block in functions.get(&call.to).into_iter().flatten() {
+                if Instruction::decode(blocks[block].last().1) == Instruction::Ret {
+                    returns.push(Edge { from: *block, to: site.to, kind: EdgeKind::Return });
+                }
+            }
+        }
+        edges.extend(returns);
+        edges.sort();
+        edges.dedup();
+
+        Self { blocks, edges, functions }
+    }
+
+    /// The blocks reachable from `entry` without following calls or
+    /// returns.
+    fn body(edges : &[Edge], entry : u16) -> BTreeSet<u16> {
+        let mut body = BTreeSet::new();
+        let mut work = vec![entry];
+        while let Some(block) = work.pop() {
+            if body.insert(block) {
+                work.extend(edges.iter()
+                    .filter(|edge| edge.from == block && matches!(edge.kind, EdgeKind::Next | EdgeKind::Skip | EdgeKind::Jump))
+                    .map(|edge| edge.to));
+            }
+        }
+        body
+    }
+
+    /// The edges leaving the block starting at `start`.
+    pub fn successors(&self, start : u16) -> impl Iterator<Item = &Edge> {
+        self.edges.iter().filter(move |edge| edge.from == start)
+    }
+
+    /// The name of the block starting at `start`, if it has one: its
+    /// symbol, or `sub_XXX` for subroutines without one.
+    pub fn name(&self, start : u16, symbols : &Symbols) -> Option<String> {
+        match symbols.name_of(start) {
+            Some(name) => Some(name.to_string()),
+            None if start != START && self.functions.contains_key(&start) => Some(format!("sub_{start:03X}")),
+            None => None,
+        }
+    }
+
+    /// The graph in Graphviz's DOT language.
+    pub fn dot(&self, symbols : &Symbols) -> String {
+        let escape = |text : &str| text.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut out = String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+        for (start, block) in &self.blocks {
+            let mut label = String::new();
+            if let Some(name) = self.name(*start, symbols) {
+                let _ = write!(label, "{}:\\l", escape(&name));
+            }
+            for (addr, opcode) in &block.instructions {
+                let _ = write!(label, "0x{addr:03X}: {}\\l", escape(&symbols.instruction(Instruction::decode(*opcode))));
+            }
+            let entry = if self.functions.contains_key(start) {", peripheries=2"} else {""};
+            let _ = writeln!(out, "    b{start:03X} [label=\"{label}\"{entry}];");
+            if block.unresolved {
+                let _ = writeln!(out, "    u{start:03X} [label=\"?\", shape=circle, style=dashed];");
+                let _ = writeln!(out, "    b{start:03X} -> u{start:03X} [style=dashed, color=red, label=\"unresolved\"];");
+            }
+        }
+
+        for edge in &self.edges {
+            let style = match edge.kind {
+                EdgeKind::Next => "",
+                EdgeKind::Skip => " [color=blue, label=\"skip\"]",
+                EdgeKind::Jump => " [style=bold]",
+                EdgeKind::Call => " [style=dashed, color=darkgreen, label=\"call\"]",
+                EdgeKind::Return => " [style=dotted, color=gray, label=\"return\"]",
+            };
+            let _ = writeln!(out, "    b{:03X} -> b{:03X}{style};", edge.from, edge.to);
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocks_and_edges() {
+        let program = [
+            0x60, 0x00, // 0x200: LD V0, 0x00
+            0x22, 0x0C, // 0x202: CALL 0x20C
+            0x30, 0x05, // 0x204: SE V0, 0x05
+            0x12, 0x02, // 0x206: JP 0x202
+            0xB3, 0x00, // 0x208: JP V0, 0x300
+            0x00, 0x00, // 0x20A: unreachable
+            0x70, 0x01, // 0x20C: ADD V0, 0x01
+            0x00, 0xEE, // 0x20E: RET
+        ];
+        let cfg = Cfg::new(&program);
+
+        assert_eq!(cfg.blocks.keys().copied().collect::<Vec<_>>(), [0x200, 0x202, 0x204, 0x206, 0x208, 0x20C]);
+        assert_eq!(cfg.blocks[&0x20C].instructions, [(0x20C, 0x7001), (0x20E, 0x00EE)]);
+        assert!(cfg.blocks[&0x208].unresolved);
+
+        let edge = |from, to, kind| Edge { from, to, kind };
+        assert_eq!(cfg.edges, [
+            edge(0x200, 0x202, EdgeKind::Next),
+            edge(0x202, 0x204, EdgeKind::Next),
+            edge(0x202, 0x20C, EdgeKind::Call),
+            edge(0x204, 0x206, EdgeKind::Next),
+            edge(0x204, 0x208, EdgeKind::Skip),
+            edge(0x206, 0x202, EdgeKind::Jump),
+            edge(0x20C, 0x204, EdgeKind::Return),
+        ]);
+        assert_eq!(cfg.functions[&0x20C], BTreeSet::from([0x20C]));
+
+        let symbols = Symbols::parse("0x20C step").unwrap();
+        let dot = cfg.dot(&symbols);
+        assert!(dot.contains("b202 [label=\"0x202: CALL step\\l\"];"));
+        assert!(dot.contains("b20C [label=\"step:\\l0x20C: ADD V0, 0x01\\l0x20E: RET\\l\", peripheries=2];"));
+        assert!(dot.contains("b208 -> u208 [style=dashed, color=red, label=\"unresolved\"];"));
+        assert!(dot.contains("b20C -> b204 [style=dotted, color=gray, label=\"return\"];"));
+    }
+
+    #[test]
+    fn test_with_coverage() {
+        let program = [
+            0x22, 0x06, // 0x200: CALL 0x206
+            0xF0, 0x90, // 0x202: sprite, as the call never returns
+            0x00, 0x00, // 0x204
+            0xA2, 0x02, // 0x206: LD I, 0x202
+            0xB2, 0x0E, // 0x208: JP V0, 0x20E
+            0x00, 0x00, // 0x20A
+            0x00, 0x00, // 0x20C
+            0xD0, 0x12, // 0x20E: DRW V0, V1, 2
+            0x12, 0x10, // 0x210: JP 0x210
+        ];
+        let cfg = Cfg::new(&program);
+        assert_eq!(cfg.blocks.keys().copied().collect::<Vec<_>>(), [0x200, 0x202, 0x206]);
+
+        let regions = crate::coverage::parse_regions("\
+0x200-0x201 code
+0x202-0x203 data read
+0x204-0x205 unused
+0x206-0x209 code
+0x20A-0x20D unused
+0x20E-0x211 code
+").unwrap();
+        let cfg = Cfg::with_coverage(&program, &regions);
+        assert_eq!(cfg.blocks.keys().copied().collect::<Vec<_>>(), [0x200, 0x206, 0x20E, 0x210]);
+        assert_eq!(cfg.blocks[&0x20E].instructions, [(0x20E, 0xD012)]);
+        assert!(!cfg.edges.iter().any(|edge| edge.to == 0x202));
+    }
+}
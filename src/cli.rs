@@ -0,0 +1,294 @@
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context};
+
+use crate::arch::Arch;
+use crate::coverage::Coverage;
+use crate::cpu::Platform;
+use crate::dap::DapServer;
+use crate::display::Display;
+use crate::palette::{self, Palette};
+use crate::profiler::Profiler;
+use crate::recording::Recorder;
+use crate::rom::{self, Rom};
+use crate::screenshot;
+use crate::symbols::Symbols;
+use crate::trace::{self, TraceFilter, TraceFormat, Tracer};
+
+pub const USAGE : &str = "\
+Usage: chip8_emu [OPTIONS] [ROM]
+
+Runs ROM, which may be a raw program, an Octo cartridge or a zip/gzip
+archive of roms. Defaults to \"Pong (1 player).ch8\".
+
+Options:
+    --platform <chip8|schip|xochip>  Use the quirks of this platform
+    --entry <NAME>                   Rom to run out of an archive
+    --debug                          Start paused in the debugger
+    --gdb <PORT>                     Wait for gdb on localhost:PORT
+    --dap                            Serve the Debug Adapter Protocol on stdio
+    --dap-port <PORT>                Wait for an editor on localhost:PORT
+    --symbols <FILE>                 Name addresses after the symbols in FILE
+    --trace <FILE>                   Log every executed instruction to FILE
+    --trace-range <START-END>        Only trace instructions at these addresses
+    --trace-cycles <START-END>       Only trace these cycles, END may be left out
+    --trace-plain                    Leave the mnemonics out of the trace
+    --trace-format <TEMPLATE>        Lay the trace out like TEMPLATE, for example
+                                     \"{pc} {opcode} {regs} ; {asm}\"
+    --profile <FILE>                 Write where the cycles went to FILE on exit
+    --profile-folded <FILE>          Write the call stacks for flame graphs
+    --coverage <FILE>                Write which bytes ran as code or data on exit
+    --coverage-disassembly <FILE>    Write the disassembly marked with the coverage
+    --theme <NAME>                   Draw in the colours of a theme: default, octo,
+                                     vip, lcd or one from ~/.config/chip8/themes
+    --software                       Draw with the CPU instead of the GPU, which
+                                     happens anyway when there is no GPU
+    --tui                            Play in the terminal instead of a window
+    --braille                        Draw with braille in the terminal, for
+                                     terminals too small for half blocks
+    --screenshot <FILE>              Save the screen to FILE as PNG on exit
+    --screenshot-scale <N>           Scale screenshots up N times, F12 takes
+                                     them in the window
+    --gif <FILE>                     Record the screen to FILE as an animated GIF
+    --gif-scale <N>                  Scale recordings up N times, F10 starts and
+                                     stops them in the window
+    --headless                       Run without a window
+    --inputs <FILE>                  Press keys as FILE says when headless, lines
+                                     of <frame> press|release <key>
+    --frames <N>                     Stop after N frames, which run as fast as
+                                     they can when headless
+    -h, --help                       Print this message";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Args {
+    pub rom_path: String,
+    pub entry: Option<String>,
+    pub platform: Option<Platform>,
+    pub debug: bool,
+    pub gdb: Option<u16>,
+    pub dap: bool,
+    pub dap_port: Option<u16>,
+    pub symbols: Option<String>,
+    pub trace: Option<String>,
+    pub trace_filter: TraceFilter,
+    pub trace_format: Option<TraceFormat>,
+    pub profile: Option<String>,
+    pub profile_folded: Option<String>,
+    pub coverage: Option<String>,
+    pub coverage_disassembly: Option<String>,
+    pub theme: Option<String>,
+    pub software: bool,
+    pub screenshot: Option<String>,
+    pub screenshot_scale: usize,
+    pub gif: Option<String>,
+    pub gif_scale: usize,
+    pub tui: bool,
+    pub braille: bool,
+    pub headless: bool,
+    pub inputs: Option<String>,
+    pub frames: Option<u64>,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            rom_path: String::from("Pong (1 player).ch8"),
+            entry: None,
+            platform: None,
+            debug: false,
+            gdb: None,
+            dap: false,
+            dap_port: None,
+            symbols: None,
+            trace: None,
+            trace_filter: TraceFilter::default(),
+            trace_format: None,
+            profile: None,
+            profile_folded: None,
+            coverage: None,
+            coverage_disassembly: None,
+            theme: None,
+            software: false,
+            screenshot: None,
+            screenshot_scale: 1,
+            gif: None,
+            gif_scale: 1,
+            tui: false,
+            braille: false,
+            headless: false,
+            inputs: None,
+            frames: None,
+        }
+    }
+}
+
+/// Parses the arguments, without the program name. Returns `None` when
+/// help was asked for.
+pub fn parse(mut args : impl Iterator<Item = String>) -> anyhow::Result<Option<Args>> {
+    let mut parsed = Args::default();
+    let mut rom_path = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = |flag : &str| args.next().with_context(|| format!("{flag} expects a value"));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--platform" => parsed.platform = Some(value("--platform")?.parse()?),
+            "--entry" => parsed.entry = Some(value("--entry")?),
+            "--debug" => parsed.debug = true,
+            "--gdb" => parsed.gdb = Some(value("--gdb")?.parse().context("--gdb expects a port")?),
+            "--dap" => parsed.dap = true,
+            "--dap-port" => parsed.dap_port = Some(value("--dap-port")?.parse().context("--dap-port expects a port")?),
+            "--symbols" => parsed.symbols = Some(value("--symbols")?),
+            "--trace" => parsed.trace = Some(value("--trace")?),
+            "--trace-range" => parsed.trace_filter.addresses = Some(trace::parse_address_range(&value("--trace-range")?)?),
+            "--trace-cycles" => parsed.trace_filter.cycles = Some(trace::parse_cycle_range(&value("--trace-cycles")?)?),
+            "--trace-plain" => parsed.trace_format = Some(TraceFormat::parse(TraceFormat::PLAIN)?),
+            "--trace-format" => parsed.trace_format = Some(TraceFormat::parse(&value("--trace-format")?)?),
+            "--profile" => parsed.profile = Some(value("--profile")?),
+            "--profile-folded" => parsed.profile_folded = Some(value("--profile-folded")?),
+            "--coverage" => parsed.coverage = Some(value("--coverage")?),
+            "--coverage-disassembly" => parsed.coverage_disassembly = Some(value("--coverage-disassembly")?),
+            "--theme" => parsed.theme = Some(value("--theme")?),
+            "--software" => parsed.software = true,
+            "--screenshot" => parsed.screenshot = Some(value("--screenshot")?),
+            "--screenshot-scale" => parsed.screenshot_scale = value("--screenshot-scale")?.parse().context("--screenshot-scale expects a number")?,
+            "--gif" => parsed.gif = Some(value("--gif")?),
+            "--gif-scale" => parsed.gif_scale = value("--gif-scale")?.parse().context("--gif-scale expects a number")?,
+            "--tui" => parsed.tui = true,
+            "--braille" => parsed.braille = true,
+            "--headless" => parsed.headless = true,
+            "--inputs" => parsed.inputs = Some(value("--inputs")?),
+            "--frames" => parsed.frames = Some(value("--frames")?.parse().context("--frames expects a number")?),
+            flag if flag.starts_with('-') => bail!("unknown option {flag}"),
+            _ if rom_path.is_some() => bail!("only one rom can be given"),
+            _ => rom_path = Some(arg),
+        }
+    }
+
+    if let Some(rom_path) = rom_path {
+        parsed.rom_path = rom_path;
+    }
+    let debuggers = [parsed.debug, parsed.gdb.is_some(), uses_dap(&parsed)];
+    if debuggers.iter().filter(|used| **used).count() > 1 {
+        bail!("only one of --debug, --gdb and --dap can be used");
+    }
+    Ok(Some(parsed))
+}
+
+/// Builds the machine with the rom loaded and the platform asked for
+/// applied. The rom is returned for the options it may carry.
+pub fn load_arch(args : &Args) -> anyhow::Result<(Arch, Rom)> {
+    let mut arch = Arch::new();
+    let rom = arch.load_rom(&args.rom_path, args.entry.as_deref())?;
+    if let Some(platform) = args.platform {
+        arch.cpu.quirks = platform.quirks();
+    }
+    if let Some(path) = &args.trace {
+        let mut tracer = Tracer::create(path, args.trace_filter.clone())?;
+        if let Some(format) = &args.trace_format {
+            tracer.format = format.clone();
+        }
+        tracer.symbols = load_symbols(args, &rom)?;
+        arch.tracer = Some(tracer);
+    }
+    if args.profile.is_some() || args.profile_folded.is_some() {
+        let mut profiler = Profiler::new();
+        profiler.symbols = load_symbols(args, &rom)?;
+        profiler.report_path = args.profile.clone();
+        profiler.folded_path = args.profile_folded.clone();
+        arch.profiler = Some(profiler);
+    }
+    if args.coverage.is_some() || args.coverage_disassembly.is_some() {
+        let mut coverage = Coverage::new(0x200..0x200 + rom.program.len());
+        coverage.symbols = load_symbols(args, &rom)?;
+        coverage.report_path = args.coverage.clone();
+        coverage.disassembly_path = args.coverage_disassembly.clone();
+        arch.coverage = Some(coverage);
+    }
+    if let Some(path) = &args.gif {
+        let palette = choose_palette(args, &rom)?;
+        arch.recorder = Some(Recorder::create(Path::new(path), &palette, args.gif_scale)?);
+    }
+
+    Ok((arch, rom))
+}
+
+/// The symbols asked for, along with the labels the rom came with. The
+/// file's win where both name something.
+pub fn load_symbols(args : &Args, rom : &Rom) -> anyhow::Result<Symbols> {
+    let mut symbols = match &args.symbols {
+        Some(path) => Symbols::load(path)?,
+        None => Symbols::new(),
+    };
+    symbols.extend(&rom.symbols);
+    Ok(symbols)
+}
+
+/// The theme asked for, or else the colours the rom came with.
+pub fn choose_palette(args : &Args, rom : &Rom) -> anyhow::Result<Palette> {
+    match &args.theme {
+        Some(name) => palette::load_theme(name),
+        None => Ok(rom.options.as_ref().map(|options| options.palette).unwrap_or_default()),
+    }
+}
+
+/// Saves the screen where --screenshot asked for, if it did.
+pub fn save_screenshot(args : &Args, display : &Display, palette : &Palette) -> anyhow::Result<()> {
+    match &args.screenshot {
+        Some(path) => screenshot::save(display, palette, args.screenshot_scale, Path::new(path)),
+        None => Ok(()),
+    }
+}
+
+/// Whether an editor drives the emulator over the Debug Adapter Protocol.
+/// The rom then comes from its launch request.
+pub fn uses_dap(args : &Args) -> bool {
+    args.dap || args.dap_port.is_some()
+}
+
+/// Starts the Debug Adapter Protocol server the arguments ask for.
+pub fn start_dap(args : &Args) -> anyhow::Result<DapServer> {
+    match args.dap_port {
+        Some(port) => DapServer::accept(port, args.clone())
+            .with_context(|| format!("could not listen for an editor on port {port}")),
+        None => Ok(DapServer::stdio(args.clone())),
+    }
+}
+
+/// When the rom is an archive with several roms and no entry was given,
+/// lists them and asks which one to run.
+pub fn choose_entry(args : &mut Args) -> anyhow::Result<()> {
+    if args.entry.is_some() {
+        return Ok(());
+    }
+
+    let entries = rom::list_entries(&args.rom_path)?;
+    if entries.len() < 2 {
+        return Ok(());
+    }
+
+    let stdin = std::io::stdin();
+    let mut stderr = std::io::stderr();
+    for (number, entry) in entries.iter().enumerate() {
+        writeln!(stderr, "{:>3}: {entry}", number + 1)?;
+    }
+
+    loop {
+        write!(stderr, "Rom to run [1-{}]: ", entries.len())?;
+        stderr.flush()?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            bail!("no rom was chosen");
+        }
+
+        match line.trim().parse::<usize>() {
+            Ok(number) if (1..=entries.len()).contains(&number) => {
+                args.entry = Some(entries[number - 1].clone());
+                return Ok(());
+            }
+            _ => writeln!(stderr, "{} is not one of the roms", line.trim())?,
+        }
+    }
+}
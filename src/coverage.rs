@@ -0,0 +1,269 @@
+/* Tracks what every byte of memory was used for while running: executed
+as an instruction, read as data (sprites for Dxyn, Fx65) or written
+(Fx33, Fx55). Instruction fetches are not data reads.
+
+The report lists the program as regions of bytes used the same way, one
+per line, which `load_regions` reads back for `Cfg::with_coverage` and
+`decompile_with_coverage` to tell code from data:
+
+    0x200-0x2A5 code
+    0x2A6-0x2E9 unused
+    0x2EA-0x2EF data read
+
+The annotated disassembly prints the program with the executed parts as
+instructions and the rest as bytes, marking how each was used.
+*/
+
+use std::fmt::Write as _;
+use std::ops::{Range, RangeInclusive};
+
+use anyhow::{bail, Context};
+
+use crate::cpu::MemoryAccess;
+use crate::debugger;
+use crate::instruction::Instruction;
+use crate::symbols::Symbols;
+
+/// An instruction started at the byte.
+pub const EXECUTED : u8 = 1;
+/// The byte was the second half of an executed instruction.
+pub const OPERAND : u8 = 2;
+pub const READ : u8 = 4;
+pub const WRITTEN : u8 = 8;
+
+/// What a region of memory was used for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Usage {
+    Code,
+    Data { read: bool, written: bool },
+    /// Executed and also read or written, which is self-modifying code or
+    /// a program reading its own instructions.
+    Mixed,
+    Unused,
+}
+
+impl Usage {
+    fn of(flags : u8) -> Self {
+        let code = flags & (EXECUTED | OPERAND) != 0;
+        let data = flags & (READ | WRITTEN) != 0;
+        match (code, data) {
+            (true, true) => Usage::Mixed,
+            (true, false) => Usage::Code,
+            (false, true) => Usage::Data { read: flags & READ != 0, written: flags & WRITTEN != 0 },
+            (false, false) => Usage::Unused,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Usage::Code => "code",
+            Usage::Data { read: true, written: true } => "data read written",
+            Usage::Data { read: true, .. } => "data read",
+            Usage::Data { .. } => "data written",
+            Usage::Mixed => "mixed",
+            Usage::Unused => "unused",
+        }
+    }
+
+    fn from_name(name : &str) -> Option<Self> {
+        [
+            Usage::Code,
+            Usage::Data { read: true, written: true },
+            Usage::Data { read: true, written: false },
+            Usage::Data { read: false, written: true },
+            Usage::Mixed,
+            Usage::Unused,
+        ].into_iter().find(|usage| usage.name() == name)
+    }
+}
+
+#[derive(Debug)]
+pub struct Coverage {
+    flags: Box<[u8; 4096]>,
+    /// Where the program was loaded, which the exports cover.
+    pub program: Range<usize>,
+    pub symbols: Symbols,
+    /// Where `save` writes the report.
+    pub report_path: Option<String>,
+    /// Where `save` writes the annotated disassembly.
+    pub disassembly_path: Option<String>,
+}
+
+impl Coverage {
+    pub fn new(program : Range<usize>) -> Self {
+        Self {
+            flags: Box::new([0; 4096]),
+            program,
+            symbols: Symbols::new(),
+            report_path: None,
+            disassembly_path: None,
+        }
+    }
+
+    /// The `EXECUTED`, `OPERAND`, `READ` and `WRITTEN` flags of a byte.
+    pub fn flags(&self, addr : u16) -> u8 {
+        self.flags[addr as usize % 4096]
+    }
+
+    pub fn record_fetch(&mut self, pc : u16) {
+        self.flags[pc as usize % 4096] |= EXECUTED;
+        self.flags[(pc as usize + 1) % 4096] |= OPERAND;
+    }
+
+    pub fn record_accesses(&mut self, accesses : &[MemoryAccess]) {
+        for access in accesses {
+            self.flags[access.addr as usize % 4096] |= if access.write {WRITTEN} else {READ};
+        }
+    }
+
+    /// The program split into runs of bytes used the same way.
+    pub fn regions(&self) -> Vec<(RangeInclusive<u16>, Usage)> {
+        let mut regions: Vec<(RangeInclusive<u16>, Usage)> = Vec::new();
+        for addr in self.program.clone() {
+            let usage = Usage::of(self.flags[addr]);
+            match regions.last_mut() {
+                Some((range, last)) if *last == usage => *range = *range.start()..=addr as u16,
+                _ => regions.push((addr as u16..=addr as u16, usage)),
+            }
+        }
+        regions
+    }
+
+    pub fn report(&self) -> String {
+        let count = |mask : u8| self.program.clone().filter(|addr| self.flags[*addr] & mask != 0).count();
+        let total = self.program.len().max(1);
+        let mut out = String::new();
+        let _ = writeln!(out, "# {} program bytes", self.program.len());
+        for (name, mask) in [("executed", EXECUTED | OPERAND), ("read", READ), ("written", WRITTEN)] {
+            let used = count(mask);
+            let _ = writeln!(out, "# {used} {name} ({:.1}%)", used as f64 * 100.0 / total as f64);
+        }
+        let unused = self.program.clone().filter(|addr| self.flags[*addr] == 0).count();
+        let _ = writeln!(out, "# {unused} unused ({:.1}%)", unused as f64 * 100.0 / total as f64);
+
+        for (range, usage) in self.regions() {
+            let _ = write!(out, "0x{:03X}-0x{:03X} {}", range.start(), range.end(), usage.name());
+            if let Some(label) = self.symbols.label(*range.start()) {
+                let _ = write!(out, " ; {label}");
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// The program disassembled where it was executed and dumped as bytes
+    /// elsewhere, each line marked with how it was used.
+    pub fn disassembly(&self, memory : &[u8; 4096]) -> String {
+        let mut out = String::new();
+        let mut addr = self.program.start;
+        while addr < self.program.end {
+            if let Some(name) = self.symbols.name_of(addr as u16) {
+                let _ = writeln!(out, "{name}:");
+            }
+
+            let flags = self.flags[addr];
+            let marks = format!(
+                "{}{}{}",
+                if flags & (EXECUTED | OPERAND) != 0 {'x'} else {'-'},
+                if flags & READ != 0 {'r'} else {'-'},
+                if flags & WRITTEN != 0 {'w'} else {'-'},
+            );
+            if flags & EXECUTED != 0 && addr + 1 < memory.len() {
+                let instr = ((memory[addr] as u16) << 8) | memory[addr + 1] as u16;
+                let _ = writeln!(out, "{marks} 0x{addr:03X}: {:02X} {:02X}  {}",
+                    memory[addr], memory[addr + 1], self.symbols.instruction(Instruction::decode(instr)));
+                addr += 2;
+            } else {
+                // Sprites read well as pixels
+                let pixels: String = (0..8).rev().map(|bit| if memory[addr] >> bit & 1 == 1 {'#'} else {'.'}).collect();
+                let _ = writeln!(out, "{marks} 0x{addr:03X}: {:02X}     DB 0x{:02X}  ; {pixels}", memory[addr], memory[addr]);
+                addr += 1;
+            }
+        }
+        out
+    }
+
+    /// Writes the report and the annotated disassembly where they were
+    /// asked for.
+    pub fn save(&self, memory : &[u8; 4096]) -> anyhow::Result<()> {
+        if let Some(path) = &self.report_path {
+            std::fs::write(path, self.report()).with_context(|| format!("could not write the coverage to {path}"))?;
+        }
+        if let Some(path) = &self.disassembly_path {
+            std::fs::write(path, self.disassembly(memory)).with_context(|| format!("could not write the disassembly to {path}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the regions of the coverage report at `path`.
+pub fn load_regions(path : &str) -> anyhow::Result<Vec<(RangeInclusive<u16>, Usage)>> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("could not read coverage {path}"))?;
+    parse_regions(&text).with_context(|| format!("could not parse coverage {path}"))
+}
+
+/// Reads the regions back out of a coverage report.
+pub fn parse_regions(text : &str) -> anyhow::Result<Vec<(RangeInclusive<u16>, Usage)>> {
+    let mut regions = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split(['#', ';']).next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let Some((range, usage)) = line.split_once(char::is_whitespace) else {
+            bail!("line {} of the coverage should be <start>-<end> <usage>", number + 1);
+        };
+        let (start, end) = range.split_once('-').with_context(|| format!("{range} is not a range"))?;
+        let usage = Usage::from_name(usage.trim()).with_context(|| format!("{} is not a usage", usage.trim()))?;
+        regions.push((debugger::parse_number(start)?..=debugger::parse_number(end)?, usage));
+    }
+    Ok(regions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::Arch;
+
+    #[test]
+    fn test_coverage() {
+        let mut arch = Arch::new();
+        // 0x200: LD I, 0x20A, 0x202: DRW V0, V0, 2, 0x204: LD [I], V0, 0x206: JP 0x206,
+        // 0x208: unused, 0x20A: sprite
+        let program = [0xA2, 0x0A, 0xD0, 0x02, 0xF0, 0x55, 0x12, 0x06, 0x00, 0x00, 0xF0, 0x90];
+        arch.cpu.load_program(&program);
+        arch.coverage = Some(Coverage::new(0x200..0x200 + program.len()));
+        for _ in 0..5 {
+            arch.step().unwrap();
+        }
+
+        let coverage = arch.coverage.as_ref().unwrap();
+        let regions = coverage.regions();
+        assert_eq!(regions, [
+            (0x200..=0x207, Usage::Code),
+            (0x208..=0x209, Usage::Unused),
+            (0x20A..=0x20A, Usage::Data { read: true, written: true }),
+            (0x20B..=0x20B, Usage::Data { read: true, written: false }),
+        ]);
+        assert_eq!(parse_regions(&coverage.report()).unwrap(), regions);
+
+        let disassembly = coverage.disassembly(arch.cpu.memory());
+        assert!(disassembly.contains("x-- 0x202: D0 02  DRW V0, V0, 2"));
+        assert!(disassembly.contains("-rw 0x20A: 00     DB 0x00"));
+        assert!(disassembly.contains("-r- 0x20B: 90     DB 0x90  ; #..#...."));
+    }
+
+    #[test]
+    fn test_fault_is_not_covered() {
+        let mut arch = Arch::new();
+        // LD V0, 1; RET with nothing to return to
+        arch.cpu.load_program(&[0x60, 0x01, 0x00, 0xEE]);
+        arch.coverage = Some(Coverage::new(0x200..0x204));
+        arch.step().unwrap();
+        assert!(arch.step().is_err());
+
+        let coverage = arch.coverage.as_ref().unwrap();
+        assert_eq!(coverage.regions(), [(0x200..=0x201, Usage::Code), (0x202..=0x203, Usage::Unused)]);
+    }
+}
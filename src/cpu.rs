@@ -1,33 +1,176 @@
 use std::iter::zip;
 use std::sync::Arc;
 
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
+use crate::instruction::Instruction;
+use crate::rom::{self, Rom};
+
+/// The return addresses of the subroutines being run.
 #[derive(Debug)]
-struct Stack {
+pub struct Stack {
     top_index : usize,
-    values : [u16; 16]
+    values : [u16; Stack::CAPACITY]
 }
 
 impl Stack {
+    /// How deep subroutines can nest.
+    pub const CAPACITY : usize = 16;
+
     fn new() -> Self {
         Self { 
             top_index: 0, 
-            values: [0; 16]
+            values: [0; Self::CAPACITY]
         }
     }
 
-    fn push(&mut self, vl : u16) {
-        self.values[self.top_index] = vl;
+    fn push(&mut self, vl : u16) -> Result<(), Fault> {
+        let value = self.values.get_mut(self.top_index).ok_or(Fault::StackOverflow)?;
+        *value = vl;
         self.top_index += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<u16, Fault> {
+        self.top_index = self.top_index.checked_sub(1).ok_or(Fault::StackUnderflow)?;
+        Ok(self.values[self.top_index])
+    }
+
+    /// The return addresses, the most recent call last.
+    pub fn frames(&self) -> &[u16] {
+        &self.values[..self.top_index]
+    }
+
+    pub fn len(&self) -> usize {
+        self.top_index
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.top_index == 0
+    }
+}
+
+/// Behaviours that differ between CHIP-8 interpreters. ROMs written for
+/// one interpreter may misbehave when run with the quirks of another.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quirks {
+    /// 8xy6/8xyE shift Vx in place instead of shifting Vy into Vx.
+    pub shift: bool,
+    /// Fx55/Fx65 leave I untouched instead of incrementing it.
+    pub load_store: bool,
+    /// Bnnn jumps to nnn + Vx instead of nnn + V0.
+    pub jump: bool,
+    /// 8xy1/8xy2/8xy3 reset VF to 0.
+    pub logic: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Self {
+            shift: true,
+            load_store: true,
+            jump: false,
+            logic: false,
+        }
+    }
+}
+
+/// The interpreters a rom may have been written for, each with the quirks
+/// it is known for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Platform {
+    /// The original COSMAC VIP interpreter.
+    Chip8,
+    Schip,
+    XoChip,
+}
+
+impl Platform {
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Platform::Chip8 => Quirks {
+                shift: false,
+                load_store: false,
+                jump: false,
+                logic: true,
+            },
+            Platform::Schip => Quirks {
+                shift: true,
+                load_store: true,
+                jump: true,
+                logic: false,
+            },
+            Platform::XoChip => Quirks {
+                shift: false,
+                load_store: false,
+                jump: false,
+                logic: false,
+            },
+        }
+    }
+}
+
+impl std::str::FromStr for Platform {
+    type Err = anyhow::Error;
+
+    fn from_str(name : &str) -> anyhow::Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "chip8" | "chip-8" | "vip" => Ok(Platform::Chip8),
+            "schip" | "superchip" => Ok(Platform::Schip),
+            "xochip" | "xo-chip" => Ok(Platform::XoChip),
+            _ => Err(anyhow::anyhow!("unknown platform {name}, expected chip8, schip or xochip")),
+        }
     }
+}
 
-    fn pop(&mut self) -> u16 {
-        self.top_index -= 1;
-        self.values[self.top_index]
+/// The name `FromStr` reads back.
+impl std::fmt::Display for Platform {
+    fn fmt(&self, f : &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Platform::Chip8 => "chip8",
+            Platform::Schip => "schip",
+            Platform::XoChip => "xochip",
+        })
     }
 }
 
+/// A read or write of memory made by an instruction. Fetching the
+/// instruction itself does not count.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryAccess {
+    pub addr: u16,
+    pub write: bool,
+    /// The byte before the access.
+    pub old: u8,
+    /// The byte after the access, the same as `old` for reads.
+    pub new: u8,
+}
+
+/// Something a rom did that the machine can not carry on from. The pc is
+/// left on the instruction at fault.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// A call with every level of the stack taken.
+    StackOverflow,
+    /// A return with no call to return from.
+    StackUnderflow,
+    /// A key instruction with a register holding no key.
+    InvalidKey(u8),
+}
+
+impl std::fmt::Display for Fault {
+    fn fmt(&self, f : &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fault::StackOverflow => write!(f, "stack overflow, more than {} calls deep", Stack::CAPACITY),
+            Fault::StackUnderflow => write!(f, "stack underflow, returned without a call"),
+            Fault::InvalidKey(key) => write!(f, "there is no key 0x{key:X}"),
+        }
+    }
+}
+
+impl std::error::Error for Fault {}
+
 #[derive(PartialEq, Debug)]
 pub enum GpuInstruction {
     Clear,
@@ -46,6 +189,11 @@ pub struct Cpu {
     stack: Stack,
     keypad_view: Arc<[bool; 16]>,
     waiting_for_key: (bool, usize),
+    pub quirks: Quirks,
+    /// The memory accesses of the last instruction, when recording them.
+    accesses: Option<Vec<MemoryAccess>>,
+    /// Where RND gets its numbers.
+    rng: StdRng,
 }
 
 impl Cpu {
@@ -92,22 +240,164 @@ impl Cpu {
             stack: Stack::new(),
             keypad_view,
             waiting_for_key: (false, 17),
+            quirks: Quirks::default(),
+            accesses: None,
+            rng: StdRng::from_os_rng(),
         }        
     }
 
-    pub fn put_rom(&mut self, rom_path : &str) {
-        use std::fs::File;
-        use std::io::Read;
-        use std::io::BufReader;
+    /// Loads the rom at `rom_path` into memory. If the rom carries its own
+    /// options (like an Octo cartridge does) its quirks are applied as well,
+    /// otherwise the quirks of the platform its name points to, if any.
+    /// The rest of the options are left for the caller.
+    pub fn put_rom(&mut self, rom_path : &str) -> anyhow::Result<Rom> {
+        self.put_rom_entry(rom_path, None)
+    }
+
+    /// Same as `put_rom`, picking `entry` when the rom is an archive.
+    pub fn put_rom_entry(&mut self, rom_path : &str, entry : Option<&str>) -> anyhow::Result<Rom> {
+        let rom = rom::load_entry(rom_path, entry)?;
+        self.load_program(&rom.program);
+        if let Some(options) = &rom.options {
+            self.quirks = options.quirks;
+        } else if let Some(platform) = rom.platform {
+            self.quirks = platform.quirks();
+        }
+
+        Ok(rom)
+    }
 
-        let rom_reader = BufReader::new(File::open(rom_path).unwrap());
+    pub fn load_program(&mut self, program : &[u8]) {
         //0x200 is the start of the program to load to the chip 8
-        let mut mem_addr: usize = 0x200;
-        for byte in  rom_reader.bytes() {
-            self.memory[mem_addr] = byte.unwrap();
-            mem_addr += 1;
+        for (mem_value, byte) in zip(&mut self.memory[0x200..], program) {
+            *mem_value = *byte;
         }
+    }
+
+    /// Calls the subroutine at `addr` the way CALL does, so it returns to
+    /// the pc.
+    pub fn call(&mut self, addr : u16) -> Result<(), Fault> {
+        self.stack.push(self.pc)?;
+        self.pc = addr & 0x0FFF;
+        Ok(())
+    }
 
+    /// Makes RND give the same numbers on every run with the same seed.
+    pub fn seed_rng(&mut self, seed : u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Decrements the delay and sound timers, should be called at 60Hz.
+    pub fn tick_timers(&mut self) {
+        self.delay_timer = self.delay_timer.saturating_sub(1);
+        self.sound_timer = self.sound_timer.saturating_sub(1);
+    }
+
+    /// Replaces the view of the keypad the instructions read from.
+    pub fn set_keypad(&mut self, keypad : [bool; 16]) {
+        if *self.keypad_view != keypad {
+            self.keypad_view = Arc::new(keypad);
+        }
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc : u16) {
+        self.pc = pc & 0x0FFF;
+    }
+
+    pub fn i_reg(&self) -> u16 {
+        self.i_reg
+    }
+
+    pub fn set_i_reg(&mut self, i_reg : u16) {
+        self.i_reg = i_reg;
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    pub fn set_delay_timer(&mut self, value : u8) {
+        self.delay_timer = value;
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+
+    pub fn set_sound_timer(&mut self, value : u8) {
+        self.sound_timer = value;
+    }
+
+    pub fn memory(&self) -> &[u8; 4096] {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8; 4096] {
+        &mut self.memory
+    }
+
+    pub fn stack(&self) -> &Stack {
+        &self.stack
+    }
+
+    /// Moves the top of the stack, keeping whatever values are below it.
+    pub fn set_stack_pointer(&mut self, sp : usize) {
+        self.stack.top_index = sp.min(self.stack.values.len());
+    }
+
+    /// Starts or stops recording the memory accesses of each instruction,
+    /// which watchpoints and the coverage look at.
+    pub fn record_accesses(&mut self, record : bool) {
+        match (record, &self.accesses) {
+            (true, None) => self.accesses = Some(Vec::new()),
+            (false, Some(_)) => self.accesses = None,
+            _ => {}
+        }
+    }
+
+    /// The memory accesses of the last instruction executed while
+    /// recording.
+    pub fn accesses(&self) -> &[MemoryAccess] {
+        self.accesses.as_deref().unwrap_or_default()
+    }
+
+    // Instructions access memory through these so the accesses can be
+    // watched. Addresses wrap around the end of memory.
+    fn read_memory(&mut self, addr : usize) -> u8 {
+        let addr = addr % self.memory.len();
+        let value = self.memory[addr];
+        if let Some(accesses) = &mut self.accesses {
+            accesses.push(MemoryAccess { addr: addr as u16, write: false, old: value, new: value });
+        }
+        value
+    }
+
+    fn write_memory(&mut self, addr : usize, value : u8) {
+        let addr = addr % self.memory.len();
+        if let Some(accesses) = &mut self.accesses {
+            accesses.push(MemoryAccess { addr: addr as u16, write: true, old: self.memory[addr], new: value });
+        }
+        self.memory[addr] = value;
+    }
+
+    /// The instruction that will be executed next.
+    pub fn current_instruction(&self) -> u16 {
+        let pc = self.pc as usize;
+        ((self.memory[pc] as u16) << 8) | (self.memory[(pc + 1) % 4096] as u16)
+    }
+
+    // The pc is 12 bits wide like the addresses, so it wraps around the
+    // end of memory too.
+    fn skip(&mut self) {
+        self.pc = (self.pc + 2) & 0x0FFF;
+    }
+
+    fn key(&self, vx : usize) -> Result<bool, Fault> {
+        self.keypad_view.get(self.reg[vx] as usize).copied().ok_or(Fault::InvalidKey(self.reg[vx]))
     }
 
     /// Returns the value of the first key that is being pressed.
@@ -119,20 +409,76 @@ impl Cpu {
         None
     }
 
-    fn arith_operations_execution(&mut self, instr : u16) {
-        let vx = ((instr & 0x0F00) >> 8) as usize;
-        let vy = ((instr & 0x00F0) >> 4) as usize;
-        match instr & 0x000F {
+    fn decode_and_execute(&mut self, instr : u16) -> Result<GpuInstruction, Fault> {
+        match Instruction::decode(instr) {
+            //Clear screen - CLS
+            Instruction::Cls => { return Ok(GpuInstruction::Clear);}
+
+            // Return - RET
+            Instruction::Ret => { self.pc = self.stack.pop()?;}
+
+            // Machine code routines can't run here
+            Instruction::Sys(_) => {log::warn!("ignoring machine code routine 0x{:X}", instr);}
+
+            // jump - JP addr 
+            Instruction::Jp(addr) => { self.pc = addr; }
+
+            //call addr
+            Instruction::Call(addr) => { 
+                self.stack.push(self.pc)?;
+                self.pc = addr;
+            }
+
+            //Skip if equal - SE vx, kk
+            Instruction::SeByte(vx, kk) => {
+                if self.reg[vx] == kk {
+                    self.skip();
+                }
+            }
+
+            //Skip if not equal - SE vx, kk
+            Instruction::SneByte(vx, kk) => {
+                if self.reg[vx] != kk {
+                    self.skip();
+                }
+            }
+
+            //Skip if register is equal - SE vx, vy
+            Instruction::SeReg(vx, vy) => {
+                if self.reg[vx] == self.reg[vy] {
+                    self.skip();
+                }
+            }
+
+            // load immediate - LD vx byte 
+            Instruction::LdByte(vx, immediate) => {
+                self.reg[vx] = immediate; 
+            }
+
+            // Add immdiate and save - ADD vx, nn
+            Instruction::AddByte(vx, immediate) => {
+                self.reg[vx] = self.reg[vx].wrapping_add(immediate); 
+            }
+
             //LD Vx, Vy
-            0x0000 => {self.reg[vx] = self.reg[vy];}
+            Instruction::LdReg(vx, vy) => {self.reg[vx] = self.reg[vy];}
             //OR Vx, Vy
-            0x0001 => {self.reg[vx] = self.reg[vx] | self.reg[vy]}
+            Instruction::Or(vx, vy) => {
+                self.reg[vx] |= self.reg[vy];
+                if self.quirks.logic {self.reg[15] = 0;}
+            }
             //AND Vx, Vy
-            0x0002 => {self.reg[vx] = self.reg[vx] & self.reg[vy]}
+            Instruction::And(vx, vy) => {
+                self.reg[vx] &= self.reg[vy];
+                if self.quirks.logic {self.reg[15] = 0;}
+            }
             //XOR Vx, Vy
-            0x0003 => {self.reg[vx] = self.reg[vx] ^ self.reg[vy]}
+            Instruction::Xor(vx, vy) => {
+                self.reg[vx] ^= self.reg[vy];
+                if self.quirks.logic {self.reg[15] = 0;}
+            }
             //8xy4 - ADD Vx, Vy
-            0x0004 => {
+            Instruction::AddReg(vx, vy) => {
                 let a = self.reg[vx] as u16;
                 let b = self.reg[vy] as u16;
                 let res = a.wrapping_add(b);
@@ -144,264 +490,171 @@ impl Cpu {
                 }
             }
             //SUB Vx, Vy
-            0x0005 => {
+            Instruction::Sub(vx, vy) => {
                 let a = self.reg[vx] as u16;
                 let b = self.reg[vy] as u16;
                 let res = a.wrapping_sub(b);
                 self.reg[vx] = (res & 0x00FF) as u8;
-                if a > b {
+                // VF is NOT borrow, so equal values set it too
+                if a >= b {
                     self.reg[15] = 1;
                 } else {
                     self.reg[15] = 0;
                 }
             }
             //SHR Vx {, Vy}
-            0x0006 => {
-                if (self.reg[vx] & 0x1) == 1 {
-                    self.reg[15] = 1;
-                } else {
-                    self.reg[15] = 1;
-                }
-
-                self.reg[vx] = self.reg[vx] >> 1;
+            Instruction::Shr(vx, vy) => {
+                let value = if self.quirks.shift {self.reg[vx]} else {self.reg[vy]};
+                self.reg[vx] = value >> 1;
+                self.reg[15] = value & 0x1;
             }
             //SUBN Vx, Vy
-            0x0007 => {
+            Instruction::Subn(vx, vy) => {
                 let a = self.reg[vx] as u16;
                 let b = self.reg[vy] as u16;
                 let res = b.wrapping_sub(a);
                 self.reg[vx] = (res & 0x00FF) as u8;
-                if b > a {
+                if b >= a {
                     self.reg[15] = 1;
                 } else {
                     self.reg[15] = 0;
                 }
             }
             //SHL Vx {, Vy}
-            0x000E => {
-                if (self.reg[vx] & 128) == 1 {
-                    self.reg[15] = 1;
-                } else {
-                    self.reg[15] = 1;
-                }
-
-                self.reg[vx] = self.reg[vx] << 1;
-            }
-            _ => println!("invalid instruction {:X}", instr)
-        }
-    }
-
-    fn decode_and_execute(&mut self, instr : u16) -> GpuInstruction {
-        match instr & 0xF000 {
-            0x0000 => {
-                // Considering only the base chip8 instructions, only the
-                // last 4 bits matter for instructions with 0.
-                match instr & 0x000F {
-                    //Clear screen - CLS
-                    0x0000 => { return GpuInstruction::Clear;}
-
-                    // Return - RET
-                    0x000E => { self.pc = self.stack.pop();}
-                    _ => {println!("invalid instruction");}
-                }
-            }
-
-            // jump - JP addr 
-            0x1000 => { self.pc = instr & 0x0FFF; }
-
-            //call addr
-            0x2000 => { 
-                self.stack.push(self.pc);
-                self.pc = instr & 0x0FFF;
-            }
-
-            //Skip if equal - SE vx, kk
-            0x3000 => {
-                let vx = ((instr & 0x0F00) >> 8) as usize;
-                if self.reg[vx] == ((instr & 0x00FF) as u8) {
-                    self.pc += 2;             
-                }
-            }
-
-            //Skip if not equal - SE vx, kk
-            0x4000 => {
-                let vx = ((instr & 0x0F00) >> 8) as usize;
-                if self.reg[vx] != ((instr & 0x00FF) as u8) {
-                    self.pc += 2;             
-                }
-            }
-
-            //Skip if register is equal - SE vx, vy
-            0x5000 => {
-                let vx = ((instr & 0x0F00) >> 8) as usize;
-                let vy = ((instr & 0x00F0) >> 4) as usize;
-                if self.reg[vx] == self.reg[vy] {
-                    self.pc += 2;             
-                }
+            Instruction::Shl(vx, vy) => {
+                let value = if self.quirks.shift {self.reg[vx]} else {self.reg[vy]};
+                self.reg[vx] = value << 1;
+                self.reg[15] = value >> 7;
             }
 
-            // load immediate - LD vx byte 
-            0x6000 => {
-                let vx = ((instr & 0x0F00) >> 8) as usize;
-                let immediate: u8 = (instr & 0x00FF) as u8;
-                self.reg[vx] = immediate; 
-            }
-
-            // Add immdiate and save - ADD vx, nn
-            0x7000 => {
-                let vx: usize = ((instr & 0x0F00) >> 8) as usize;
-                let immediate: u8 = (instr & 0x00FF) as u8;
-                self.reg[vx] = self.reg[vx].wrapping_add(immediate); 
-            }
-
-            // Arithmetic operations
-            0x8000 => { self.arith_operations_execution(instr);}
-
             //Skip if reg not equal 
-            0x9000 => {
-                let vx = ((instr & 0x0F00) >> 8) as usize;
-                let vy = ((instr & 0x00F0) >> 4) as usize;
+            Instruction::SneReg(vx, vy) => {
                 if self.reg[vx] != self.reg[vy] {
-                    self.pc += 2;             
+                    self.skip();
                 }
             }
 
             // load immediate to i - LD I, addr
-            0xA000 => {
-                let immediate = instr & 0x0FFF;
+            Instruction::LdI(immediate) => {
                 self.i_reg = immediate;
             }
 
             // Branch = v0 + immediate
-            0xB000 => {
-                let immediate = instr & 0x0FFF;
-                self.pc = (self.reg[0] as u16) + immediate;
+            Instruction::JpV0(immediate) => {
+                let offset = if self.quirks.jump {
+                    self.reg[((instr & 0x0F00) >> 8) as usize]
+                } else {
+                    self.reg[0]
+                };
+                self.pc = ((offset as u16) + immediate) & 0x0FFF;
             }
 
             // RND Vx, byte
-            0xC000 => {
-                let vx = ((instr & 0x0F00) >> 8) as usize;
-                let mut rng = rand::rng();
-                let rand = rng.random_range(0..256) as u8;
-                self.reg[vx] = rand & ((instr & 0x00FF) as u8);
+            Instruction::Rnd(vx, kk) => {
+                let rand = self.rng.random_range(0..256) as u8;
+                self.reg[vx] = rand & kk;
             }
 
             // Dxyn - DRW Vx, Vy, nibble
-            0xD000 => {
-                let vx = ((instr & 0x0F00) >> 8) as usize;
-                let vy = ((instr & 0x00F0) >> 4) as usize;
+            Instruction::Drw(vx, vy, n) => {
                 let pos_x = self.reg[vx] as usize;
                 let pos_y= self.reg[vy] as usize;
-                let qtt = (instr & 0x000F) as usize;
-                let mut indexer = self.i_reg as usize;
-                let mut sprite_vec: Vec<u8> = Vec::new();
+                let qtt = n as usize;
+                let indexer = self.i_reg as usize;
+                let sprite_vec = (indexer..(indexer + qtt)).map(|addr| self.read_memory(addr)).collect();
 
-                for _ in 0..qtt {
-                    sprite_vec.push(self.memory[indexer]);
-                    indexer += 1;
-                }
+                return Ok(GpuInstruction::XorSprite(pos_x, pos_y, sprite_vec));
+            }
 
-                return GpuInstruction::XorSprite(pos_x, pos_y, sprite_vec);
+            // Skip if key is pressed
+            Instruction::Skp(vx) => {
+                if self.key(vx)? {
+                    self.skip();
+                }
             }
 
-            //E instructions
-            0xE000 => {
-                let vx = ((instr & 0x0F00) >> 8) as usize;
-                match instr & 0x00FF {
-                    // Skip if key is pressed
-                    0x9E => {
-                        if self.keypad_view[self.reg[vx] as usize] {
-                            self.pc += 2;
-                        }
-                    }
-
-                    //Skip if key is not pressed
-                    0xA1 => {
-                        if !self.keypad_view[self.reg[vx] as usize] {
-                            self.pc += 2;
-                        }
-                    }
-                    _ => println!("Invalid instruction 0x{:X}", instr)
+            //Skip if key is not pressed
+            Instruction::Sknp(vx) => {
+                if !self.key(vx)? {
+                    self.skip();
                 }
             }
 
-            // F instructions
-            0xF000 => {
-                let vx = ((instr & 0x0F00) >> 8) as usize;
-                match instr & 0x00FF {
-                    // Load delay timer
-                    0x07 => self.reg[vx] = self.delay_timer,
-                    //Load pressed key
-                    0x0A => {
-                        if let Some(key) = self.check_if_key_is_pressed() {
-                            self.reg[vx] = key as u8;
-                        } else {
-                            self.waiting_for_key = (true, vx);
-                        }
-                    }
-                    // Set delay timer to vx
-                    0x15 => self.delay_timer = self.reg[vx],
-                    // Set sound timer to vx
-                    0x18 => self.sound_timer = self.reg[vx],
-                    //Ad vx to i
-                    0x1E => self.i_reg = self.i_reg.wrapping_add(self.reg[vx] as u16),
-                    //Set i to location of font with value of vx
-                    0x29 => self.i_reg = self.reg[vx].wrapping_add(Self::FONT_START_ADDRES as u8) as u16,
-                    // Bcd representation of vx
-                    0x33 => {
-                        let unitary = self.reg[vx] % 10;
-                        let decimal = (self.reg[vx] / 10) % 10;
-                        let centesimal = self.reg[vx] / 100;
-                        self.memory[self.i_reg as usize] = centesimal;
-                        self.memory[(self.i_reg + 1) as usize] = decimal;
-                        self.memory[(self.i_reg + 2) as usize] = unitary;
-                    }
-                    // Store all registers to addres I
-                    0x55 => {
-                        let mut indexer = self.i_reg as usize;
-                        for i in 0..(vx + 1) {
-                            self.memory[indexer] = self.reg[i];
-                            indexer += 1;
-                        }
-                    }
-                    // Read to all registers starting at addres I
-                    0x65 => {
-                        let mut indexer = self.i_reg as usize;
-                        for i in 0..(vx + 1) {
-                            self.reg[i] = self.memory[indexer];
-                            indexer += 1;
-                        }
-                    }
-
-                    _ => println!("Invalid instruction 0x{:X}", instr)
+            // Load delay timer
+            Instruction::LdVxDt(vx) => self.reg[vx] = self.delay_timer,
+            //Load pressed key
+            Instruction::LdVxK(vx) => {
+                if let Some(key) = self.check_if_key_is_pressed() {
+                    self.reg[vx] = key as u8;
+                } else {
+                    self.waiting_for_key = (true, vx);
+                }
+            }
+            // Set delay timer to vx
+            Instruction::LdDtVx(vx) => self.delay_timer = self.reg[vx],
+            // Set sound timer to vx
+            Instruction::LdStVx(vx) => self.sound_timer = self.reg[vx],
+            //Ad vx to i
+            Instruction::AddI(vx) => self.i_reg = self.i_reg.wrapping_add(self.reg[vx] as u16),
+            //Set i to location of font with value of vx, glyphs are 5 bytes each
+            Instruction::LdF(vx) => self.i_reg = (Self::FONT_START_ADDRES + 5 * (self.reg[vx] & 0xF) as usize) as u16,
+            // Bcd representation of vx
+            Instruction::LdB(vx) => {
+                let unitary = self.reg[vx] % 10;
+                let decimal = (self.reg[vx] / 10) % 10;
+                let centesimal = self.reg[vx] / 100;
+                let indexer = self.i_reg as usize;
+                self.write_memory(indexer, centesimal);
+                self.write_memory(indexer + 1, decimal);
+                self.write_memory(indexer + 2, unitary);
+            }
+            // Store all registers to addres I
+            Instruction::LdIVx(vx) => {
+                let indexer = self.i_reg as usize;
+                for offset in 0..=vx {
+                    self.write_memory(indexer + offset, self.reg[offset]);
+                }
+                if !self.quirks.load_store {
+                    self.i_reg = self.i_reg.wrapping_add((vx + 1) as u16);
+                }
+            }
+            // Read to all registers starting at addres I
+            Instruction::LdVxI(vx) => {
+                let indexer = self.i_reg as usize;
+                for offset in 0..=vx {
+                    self.reg[offset] = self.read_memory(indexer + offset);
+                }
+                if !self.quirks.load_store {
+                    self.i_reg = self.i_reg.wrapping_add((vx + 1) as u16);
                 }
             }
 
-            _ => {
-                println!("Invalid instruction 0x{:X}", instr);
+            Instruction::Unknown(_) => {
+                log::warn!("Invalid instruction 0x{:X}", instr);
             }
         }
 
-        return GpuInstruction::Nothing;
+        Ok(GpuInstruction::Nothing)
     }
 
-    pub fn process(&mut self) -> GpuInstruction {
-        self.delay_timer = self.delay_timer.wrapping_add(1);
+    /// Executes the next instruction. On a fault the machine is left as it
+    /// was before it, so running it again faults again.
+    pub fn process(&mut self) -> Result<GpuInstruction, Fault> {
+        if let Some(accesses) = &mut self.accesses {
+            accesses.clear();
+        }
 
-        if self.waiting_for_key.0 {
-            if let Some(key) = Self::check_if_key_is_pressed(self) {
-                self.reg[self.waiting_for_key.1] = key as u8;
-                self.waiting_for_key.0 = false;
-            }
+        if self.waiting_for_key.0
+            && let Some(key) = Self::check_if_key_is_pressed(self) {
+            self.reg[self.waiting_for_key.1] = key as u8;
+            self.waiting_for_key.0 = false;
         }
 
-        let left_8_bits= self.memory[self.pc as usize] as u16;
-        let right_8_bits = self.memory[(self.pc + 1) as usize] as u16;
-        let instr: u16 = (left_8_bits << 8) | right_8_bits; 
-        self.pc += 2;
-        //println!("what instruction {:X}", instr);
+        let pc = self.pc;
+        let instr = self.current_instruction();
+        self.skip();
 
-        self.decode_and_execute(instr)
+        self.decode_and_execute(instr).inspect_err(|_| self.pc = pc)
     }
 }
 
@@ -438,10 +691,8 @@ pub mod tests {
         let cpu = Cpu::new(Arc::new(keypad_array));
         assert_eq!(cpu.pc, START_ADDRES as u16);
 
-        let mut indexer : usize = 0;
-        for _ in 0..80 {
-            assert_eq!(cpu.memory[FONT_START_ADDRES + indexer], FONT_DATA[indexer]);
-            indexer += 1;
+        for (indexer, font_data) in FONT_DATA.iter().enumerate() {
+            assert_eq!(cpu.memory[FONT_START_ADDRES + indexer], *font_data);
         }
     }
 
@@ -449,14 +700,44 @@ pub mod tests {
     fn test_clear_instruction() {
         let keypad_array = [false; 16];
         let mut cpu = Cpu::new(Arc::new(keypad_array));
-        cpu.memory[START_ADDRES] = 0x02;
-        cpu.memory[START_ADDRES + 1] = 0x40;
+        cpu.memory[START_ADDRES] = 0x00;
+        cpu.memory[START_ADDRES + 1] = 0xE0;
 
-        let ret = cpu.process();
+        let ret = cpu.process().unwrap();
         assert_eq!(ret, GpuInstruction::Clear);
         assert_eq!(cpu.pc, (START_ADDRES + 2) as u16);
     }
 
+    #[test]
+    fn test_near_misses() {
+        // 0x0240 and 0x024E are machine code routines, not CLS and RET,
+        // and 5XY1 and 9XY1 are not skips
+        let mut cpu = Cpu::new(Arc::new([false; 16]));
+        cpu.load_program(&[0x02, 0x40, 0x02, 0x4E, 0x50, 0x01, 0x90, 0x11]);
+        cpu.reg[1] = 1;
+        for _ in 0..4 {
+            assert_eq!(cpu.process(), Ok(GpuInstruction::Nothing));
+        }
+        assert_eq!(cpu.pc, (START_ADDRES + 8) as u16);
+    }
+
+    #[test]
+    fn test_font_character() {
+        let mut cpu = Cpu::new(Arc::new([false; 16]));
+        cpu.load_program(&[0xF0, 0x29, 0xF1, 0x29, 0xF2, 0x29]);
+        cpu.reg[1] = 0xA;
+        // Only the low nibble picks the glyph
+        cpu.reg[2] = 0x1F;
+
+        cpu.process().unwrap();
+        assert_eq!(cpu.i_reg, FONT_START_ADDRES as u16);
+        cpu.process().unwrap();
+        assert_eq!(cpu.i_reg, (FONT_START_ADDRES + 50) as u16);
+        assert_eq!(cpu.memory[cpu.i_reg as usize..][..5], FONT_DATA[50..55]);
+        cpu.process().unwrap();
+        assert_eq!(cpu.i_reg, (FONT_START_ADDRES + 75) as u16);
+    }
+
     #[test]
     fn test_call() {
         let keypad_array = [false; 16];
@@ -464,7 +745,7 @@ pub mod tests {
         cpu.memory[START_ADDRES] = 0x20;
         cpu.memory[START_ADDRES + 1] = 0x04;
 
-        cpu.process();
+        cpu.process().unwrap();
         assert_eq!(cpu.pc, 4);
         assert_eq!(cpu.stack.values[0], 0x202);
         assert_eq!(cpu.stack.top_index, 1);
@@ -480,9 +761,82 @@ pub mod tests {
         cpu.memory[4] = 0x00;
         cpu.memory[4 + 1] = 0xEE;
 
-        cpu.process();
-        cpu.process();
+        cpu.process().unwrap();
+        cpu.process().unwrap();
         assert_eq!(cpu.pc, (START_ADDRES + 2) as u16);
         assert_eq!(cpu.stack.top_index, 0);
     }
+
+    /// Runs `instr` with V0 and V1 loaded, returning V0 and VF.
+    fn arith(instr : u16, v0 : u8, v1 : u8) -> (u8, u8) {
+        let keypad_array = [false; 16];
+        let mut cpu = Cpu::new(Arc::new(keypad_array));
+        cpu.memory[START_ADDRES] = (instr >> 8) as u8;
+        cpu.memory[START_ADDRES + 1] = instr as u8;
+        cpu.reg[0] = v0;
+        cpu.reg[1] = v1;
+
+        cpu.process().unwrap();
+        (cpu.reg[0], cpu.reg[15])
+    }
+
+    #[test]
+    fn test_sub_flags() {
+        assert_eq!(arith(0x8015, 5, 3), (2, 1));
+        assert_eq!(arith(0x8015, 3, 3), (0, 1));
+        assert_eq!(arith(0x8015, 3, 5), (254, 0));
+        assert_eq!(arith(0x8017, 3, 5), (2, 1));
+        assert_eq!(arith(0x8017, 5, 5), (0, 1));
+        assert_eq!(arith(0x8017, 5, 3), (254, 0));
+    }
+
+    #[test]
+    fn test_shift_flags() {
+        assert_eq!(arith(0x8006, 0b11, 0), (0b1, 1));
+        assert_eq!(arith(0x8006, 0b10, 0), (0b1, 0));
+        assert_eq!(arith(0x800E, 0x81, 0), (0x02, 1));
+        assert_eq!(arith(0x800E, 0x41, 0), (0x82, 0));
+    }
+
+    #[test]
+    fn test_faults() {
+        let mut cpu = Cpu::new(Arc::new([false; 16]));
+        cpu.load_program(&[0x00, 0xEE]);
+        assert_eq!(cpu.process(), Err(Fault::StackUnderflow));
+        assert_eq!(cpu.pc, START_ADDRES as u16);
+
+        // CALL 0x200 forever
+        cpu.load_program(&[0x22, 0x00]);
+        for _ in 0..Stack::CAPACITY {
+            cpu.process().unwrap();
+        }
+        assert_eq!(cpu.process(), Err(Fault::StackOverflow));
+        assert_eq!(cpu.stack.len(), Stack::CAPACITY);
+
+        let mut cpu = Cpu::new(Arc::new([false; 16]));
+        cpu.load_program(&[0xE0, 0x9E]);
+        cpu.reg[0] = 0x10;
+        assert_eq!(cpu.process(), Err(Fault::InvalidKey(0x10)));
+
+        // An instruction straddling the end of memory, and a skip past it
+        cpu.memory[0xFFF] = 0x30;
+        cpu.memory[0] = 0x00;
+        cpu.reg[0] = 0;
+        cpu.set_pc(0xFFF);
+        cpu.process().unwrap();
+        assert_eq!(cpu.pc, 0x003);
+
+        // JP V0, 0xFFF, then FX55 and FX33 with I at the end of memory
+        cpu.load_program(&[0xBF, 0xFF]);
+        cpu.set_pc(0x200);
+        cpu.reg[0] = 0xFF;
+        cpu.process().unwrap();
+        assert_eq!(cpu.pc, 0x0FE);
+        cpu.memory[0x0FE..0x102].copy_from_slice(&[0xFF, 0x55, 0xF0, 0x33]);
+        cpu.i_reg = 0xFFFF;
+        cpu.quirks.load_store = false;
+        cpu.process().unwrap();
+        assert_eq!(cpu.i_reg, 0x000F);
+        cpu.process().unwrap();
+    }
 }
@@ -0,0 +1,682 @@
+/* A Debug Adapter Protocol server, so editors like VS Code can launch roms
+and debug them. There is a single thread and a single frame per stop: the
+pc, followed by a frame for every call on the stack.
+
+Breakpoints can be set by address, through instruction breakpoints, or by
+source line when the launch request names the Octo source the rom was
+assembled from as `source`. The source is assembled again to learn where
+each line's instructions went, and its labels name addresses next to the
+symbol file's. Sources are matched by file name only, so the editor may
+keep them anywhere.
+
+Breakpoint conditions and log messages use the expression language of
+`expr`. Hit conditions are `N` to stop from the Nth hit on, an operator and
+a number like `== 3`, or `%N` to stop every Nth hit. `evaluate` takes any
+expression.
+*/
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use anyhow::{anyhow, bail, Context};
+use serde_json::{json, Value};
+
+use crate::arch::Arch;
+use crate::cli::{self, Args};
+use crate::debugger::{self, Breakpoint, Debugger, Register, Stop};
+use crate::expr::{BinaryOp, Expr};
+use crate::octo::{self, Assembly};
+use crate::symbols::Symbols;
+
+const THREAD_ID : u64 = 1;
+
+const REGISTERS_REFERENCE : u64 = 1;
+const STACK_REFERENCE : u64 = 2;
+const MEMORY_REFERENCE : u64 = 3;
+
+/// Addresses and the source lines they came from.
+#[derive(Debug, Default)]
+pub struct LineMap {
+    lines: Vec<(u16, String, u64)>,
+}
+
+impl LineMap {
+    /// Maps the instructions of the Octo source read from `path`.
+    pub fn from_assembly(path : &str, assembly : &Assembly) -> Self {
+        let lines = assembly.lines.iter()
+            .map(|(addr, line)| (*addr, path.to_string(), *line as u64))
+            .collect();
+        Self { lines }
+    }
+
+    pub fn address_of(&self, source : &str, line : u64) -> Option<u16> {
+        let name = file_name(source);
+        self.lines.iter()
+            .find(|(_, file, source_line)| file_name(file) == name && *source_line == line)
+            .map(|(addr, _, _)| *addr)
+    }
+
+    pub fn line_of(&self, addr : u16) -> Option<(&str, u64)> {
+        self.lines.iter()
+            .find(|(mapped, _, _)| *mapped == addr)
+            .map(|(_, file, line)| (file.as_str(), *line))
+    }
+}
+
+fn file_name(path : &str) -> &str {
+    Path::new(path).file_name().and_then(|name| name.to_str()).unwrap_or(path)
+}
+
+fn base64(bytes : &[u8]) -> String {
+    const ALPHABET : &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let value = chunk.iter().enumerate().fold(0u32, |value, (index, byte)| value | (*byte as u32) << (16 - 8 * index));
+        for index in 0..4 {
+            if index <= chunk.len() {
+                out.push(ALPHABET[((value >> (18 - 6 * index)) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Reads `Content-Length` framed messages, handing them over one by one.
+fn read_messages(input : impl Read + Send + 'static) -> Receiver<Value> {
+    let (sender, messages) = mpsc::channel();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(input);
+        loop {
+            let mut length = None;
+            loop {
+                let mut header = String::new();
+                match reader.read_line(&mut header) {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) => {}
+                }
+                let header = header.trim();
+                if header.is_empty() {
+                    break;
+                }
+                if let Some(value) = header.strip_prefix("Content-Length:") {
+                    length = value.trim().parse::<usize>().ok();
+                }
+            }
+
+            let Some(length) = length else { continue };
+            let mut body = vec![0u8; length];
+            if reader.read_exact(&mut body).is_err() {
+                return;
+            }
+            match serde_json::from_slice(&body) {
+                Ok(message) => {
+                    if sender.send(message).is_err() {
+                        return;
+                    }
+                }
+                Err(error) => log::warn!("dap: ignoring a message that is not JSON: {error}"),
+            }
+        }
+    });
+    messages
+}
+
+pub struct DapServer {
+    messages: Receiver<Value>,
+    output: Box<dyn Write + Send>,
+    seq: u64,
+    args: Args,
+    pub debugger: Debugger,
+    line_map: LineMap,
+    source_breakpoints: HashMap<String, Vec<(u16, Breakpoint)>>,
+    instruction_breakpoints: Vec<(u16, Breakpoint)>,
+    stop_on_entry: bool,
+    /// Set once the editor is done configuring and the rom may run.
+    configured: bool,
+    /// Set while the editor waits for the machine to stop.
+    running: bool,
+}
+
+impl DapServer {
+    /// Serves the editor over `input` and `output`. `args` says what to
+    /// load when the launch request does not name a rom.
+    pub fn new(input : impl Read + Send + 'static, output : impl Write + Send + 'static, args : Args) -> Self {
+        Self {
+            messages: read_messages(input),
+            output: Box::new(output),
+            seq: 1,
+            args,
+            debugger: Debugger::new(),
+            line_map: LineMap::default(),
+            source_breakpoints: HashMap::new(),
+            instruction_breakpoints: Vec::new(),
+            stop_on_entry: true,
+            configured: false,
+            running: false,
+        }
+    }
+
+    pub fn stdio(args : Args) -> Self {
+        Self::new(io::stdin(), io::stdout(), args)
+    }
+
+    /// Waits for an editor to connect to `port` on localhost.
+    pub fn accept(port : u16, args : Args) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        eprintln!("Waiting for an editor on {}", listener.local_addr()?);
+        let (stream, _) = listener.accept()?;
+        Ok(Self::new(stream.try_clone()?, stream, args))
+    }
+
+    /// Runs a frame worth of instructions when the editor asked to, then
+    /// handles its requests. When `wait` is set it blocks for a request while
+    /// paused instead of returning. Returns false once the editor has gone.
+    pub fn frame(&mut self, arch : &mut Arch, wait : bool) -> io::Result<bool> {
+        if self.running {
+            let stop = self.debugger.run(arch, arch.cycles_per_frame.max(1));
+            let messages: Vec<String> = self.debugger.messages.drain(..).collect();
+            for message in messages {
+                self.event("output", json!({"category": "console", "output": format!("{message}\n")}))?;
+            }
+            if let Some(stop) = stop {
+                self.running = false;
+                self.stopped(stop)?;
+            }
+        }
+
+        let mut block = wait && !self.running;
+        loop {
+            let message = if block {
+                match self.messages.recv() {
+                    Ok(message) => message,
+                    Err(_) => return Ok(false),
+                }
+            } else {
+                match self.messages.try_recv() {
+                    Ok(message) => message,
+                    Err(TryRecvError::Empty) => return Ok(true),
+                    Err(TryRecvError::Disconnected) => return Ok(false),
+                }
+            };
+
+            if !self.handle(arch, &message)? {
+                return Ok(false);
+            }
+            block = false;
+        }
+    }
+
+    fn send(&mut self, mut message : Value) -> io::Result<()> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        let body = message.to_string();
+        write!(self.output, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+        self.output.flush()
+    }
+
+    fn event(&mut self, event : &str, body : Value) -> io::Result<()> {
+        self.send(json!({"type": "event", "event": event, "body": body}))
+    }
+
+    fn stopped(&mut self, stop : Stop) -> io::Result<()> {
+        let reason = match stop {
+            Stop::Breakpoint(_) => "breakpoint",
+            Stop::Watchpoint { .. } => "data breakpoint",
+            Stop::Step | Stop::Finish => "step",
+            Stop::Interrupt => "pause",
+            Stop::Fault(_) => "exception",
+        };
+        let mut body = json!({"reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true});
+        if let Stop::Watchpoint { pc, hit } = stop {
+            body["text"] = json!(format!("0x{pc:03X} {hit}"));
+        }
+        if let Stop::Fault(fault) = stop {
+            body["text"] = json!(fault.to_string());
+        }
+        self.event("stopped", body)
+    }
+
+    /// Handles a request, returns false when the editor is done with us.
+    fn handle(&mut self, arch : &mut Arch, message : &Value) -> io::Result<bool> {
+        let command = message["command"].as_str().unwrap_or_default().to_string();
+        let arguments = &message["arguments"];
+
+        let result = match command.as_str() {
+            "disconnect" | "terminate" => {
+                self.respond(message, Ok(Value::Null))?;
+                self.event("terminated", json!({}))?;
+                return Ok(false);
+            }
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsSetVariable": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsHitConditionalBreakpoints": true,
+                "supportsLogPoints": true,
+                "supportsReadMemoryRequest": true,
+            })),
+            "launch" => self.launch(arch, arguments),
+            "setBreakpoints" => self.set_breakpoints(arguments),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(arguments),
+            "setExceptionBreakpoints" => Ok(json!({"breakpoints": []})),
+            "configurationDone" => {
+                self.configured = true;
+                if !self.stop_on_entry {
+                    self.debugger.resume_continue();
+                    self.running = true;
+                }
+                Ok(Value::Null)
+            }
+            "threads" => Ok(json!({"threads": [{"id": THREAD_ID, "name": "CHIP-8"}]})),
+            "stackTrace" => Ok(self.stack_trace(arch)),
+            "scopes" => Ok(json!({"scopes": [
+                {"name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false},
+                {"name": "Stack", "variablesReference": STACK_REFERENCE, "expensive": false},
+                {"name": "Memory", "variablesReference": MEMORY_REFERENCE, "expensive": true},
+            ]})),
+            "variables" => Ok(variables(arch, arguments["variablesReference"].as_u64().unwrap_or_default())),
+            "setVariable" => set_variable(arch, arguments),
+            "evaluate" => evaluate(arch, arguments),
+            "readMemory" => read_memory(arch, arguments),
+            "continue" => {
+                self.debugger.resume_continue();
+                self.running = true;
+                Ok(json!({"allThreadsContinued": true}))
+            }
+            "next" => {
+                self.debugger.resume_next(arch);
+                self.running = true;
+                Ok(Value::Null)
+            }
+            "stepIn" => {
+                self.debugger.resume_step(1);
+                self.running = true;
+                Ok(Value::Null)
+            }
+            "stepOut" => self.debugger.resume_finish(arch).map(|()| {
+                self.running = true;
+                Value::Null
+            }),
+            "pause" => {
+                if self.running {
+                    self.debugger.pause();
+                    self.running = false;
+                    self.respond(message, Ok(Value::Null))?;
+                    self.stopped(Stop::Interrupt)?;
+                    return Ok(true);
+                }
+                Ok(Value::Null)
+            }
+            other => Err(anyhow!("{other} is not supported")),
+        };
+
+        self.respond(message, result)?;
+
+        if command == "launch" {
+            self.event("initialized", json!({}))?;
+        } else if command == "configurationDone" && self.stop_on_entry {
+            self.event("stopped", json!({"reason": "entry", "threadId": THREAD_ID, "allThreadsStopped": true}))?;
+        }
+        Ok(true)
+    }
+
+    fn respond(&mut self, request : &Value, result : anyhow::Result<Value>) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(Value::Null) => {}
+            Ok(body) => response["body"] = body,
+            Err(error) => response["message"] = json!(format!("{error:#}")),
+        }
+        self.send(response)
+    }
+
+    fn launch(&mut self, arch : &mut Arch, arguments : &Value) -> anyhow::Result<Value> {
+        let mut args = self.args.clone();
+        if let Some(program) = arguments["program"].as_str() {
+            args.rom_path = program.to_string();
+            args.entry = arguments["entry"].as_str().map(str::to_string);
+        }
+        if let Some(symbols) = arguments["symbols"].as_str() {
+            args.symbols = Some(symbols.to_string());
+        }
+        let rom;
+        (*arch, rom) = cli::load_arch(&args)?;
+        self.debugger.symbols = cli::load_symbols(&args, &rom)?;
+
+        if let Some(path) = arguments["source"].as_str() {
+            let source = std::fs::read_to_string(path)
+                .with_context(|| format!("could not read {path}"))?;
+            let assembly = octo::assemble(&source).with_context(|| format!("could not assemble {path}"))?;
+            self.line_map = LineMap::from_assembly(path, &assembly);
+            self.debugger.symbols.extend(&Symbols::from_assembly(&assembly));
+        }
+        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(true);
+        self.debugger.pause();
+        Ok(Value::Null)
+    }
+
+    /// Hands every breakpoint to the debugger, keeping the hit counts of
+    /// those that were already there.
+    fn update_breakpoints(&mut self) {
+        let old = std::mem::take(&mut self.debugger.breakpoints);
+        self.debugger.breakpoints = self.source_breakpoints.values()
+            .flatten()
+            .chain(&self.instruction_breakpoints)
+            .map(|(addr, breakpoint)| {
+                let hits = old.get(addr).map_or(0, |old| old.hits);
+                (*addr, Breakpoint { hits, ..breakpoint.clone() })
+            })
+            .collect();
+    }
+
+    fn set_breakpoints(&mut self, arguments : &Value) -> anyhow::Result<Value> {
+        let source = arguments["source"]["path"].as_str()
+            .or_else(|| arguments["source"]["name"].as_str())
+            .context("setBreakpoints needs a source")?
+            .to_string();
+
+        let mut addresses = Vec::new();
+        let mut replies = Vec::new();
+        for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+            let line = breakpoint["line"].as_u64().unwrap_or_default();
+            let addr = self.line_map.address_of(&source, line).context("no instruction maps to this line");
+            match addr.and_then(|addr| Ok((addr, parse_breakpoint(breakpoint)?))) {
+                Ok((addr, parsed)) => {
+                    addresses.push((addr, parsed));
+                    replies.push(json!({"verified": true, "line": line, "instructionReference": format!("0x{addr:03X}")}));
+                }
+                Err(error) => replies.push(json!({"verified": false, "line": line, "message": format!("{error:#}")})),
+            }
+        }
+
+        self.source_breakpoints.insert(source, addresses);
+        self.update_breakpoints();
+        Ok(json!({"breakpoints": replies}))
+    }
+
+    fn set_instruction_breakpoints(&mut self, arguments : &Value) -> anyhow::Result<Value> {
+        let mut replies = Vec::new();
+        self.instruction_breakpoints.clear();
+        for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+            let reference = breakpoint["instructionReference"].as_str().unwrap_or_default();
+            let offset = breakpoint["offset"].as_i64().unwrap_or_default();
+            let addr = debugger::parse_number(reference).and_then(|addr| match (addr as i64).saturating_add(offset) {
+                addr @ 0..=0xFFF => Ok(addr as u16),
+                _ => bail!("{reference}{offset:+} is outside memory"),
+            });
+            match addr.and_then(|addr| Ok((addr, parse_breakpoint(breakpoint)?))) {
+                Ok((addr, parsed)) => {
+                    self.instruction_breakpoints.push((addr, parsed));
+                    replies.push(json!({"verified": true, "instructionReference": format!("0x{addr:03X}")}));
+                }
+                Err(error) => replies.push(json!({"verified": false, "message": format!("{error:#}")})),
+            }
+        }
+
+        self.update_breakpoints();
+        Ok(json!({"breakpoints": replies}))
+    }
+
+    fn frame_json(&self, id : usize, name : String, addr : u16) -> Value {
+        let mut frame = json!({
+            "id": id,
+            "name": name,
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": format!("0x{addr:03X}"),
+        });
+        if let Some((file, line)) = self.line_map.line_of(addr) {
+            frame["source"] = json!({"name": file_name(file), "path": file});
+            frame["line"] = json!(line);
+        }
+        frame
+    }
+
+    fn stack_trace(&self, arch : &Arch) -> Value {
+        let pc = arch.cpu.pc();
+        let name = |addr : u16| self.debugger.symbols.label(addr).unwrap_or_else(|| format!("0x{addr:03X}"));
+        let mut frames = vec![self.frame_json(0, name(pc), pc)];
+        // Each return address points just past the call that pushed it
+        for (depth, addr) in arch.cpu.stack().frames().iter().rev().enumerate() {
+            let call = addr.wrapping_sub(2);
+            frames.push(self.frame_json(depth + 1, name(call), call));
+        }
+
+        json!({"stackFrames": frames, "totalFrames": frames.len()})
+    }
+}
+
+/// Reads the condition, hit condition and log message of a breakpoint.
+fn parse_breakpoint(breakpoint : &Value) -> anyhow::Result<Breakpoint> {
+    let condition = breakpoint["condition"].as_str()
+        .filter(|text| !text.trim().is_empty())
+        .map(str::parse::<Expr>)
+        .transpose()?;
+    let hit_condition = breakpoint["hitCondition"].as_str()
+        .filter(|text| !text.trim().is_empty())
+        .map(parse_hit_condition)
+        .transpose()?;
+
+    let condition = match (condition, hit_condition) {
+        (Some(condition), Some(hits)) => Some(Expr::Binary(BinaryOp::And, Box::new(condition), Box::new(hits))),
+        (condition, hits) => condition.or(hits),
+    };
+    Ok(Breakpoint {
+        condition,
+        log: breakpoint["logMessage"].as_str().map(str::to_string),
+        hits: 0,
+    })
+}
+
+fn parse_hit_condition(text : &str) -> anyhow::Result<Expr> {
+    let text = text.trim();
+    let condition = if let Some(every) = text.strip_prefix('%') {
+        format!("hits % ({every}) == 0")
+    } else if text.starts_with(['=', '!', '<', '>']) {
+        format!("hits {text}")
+    } else {
+        format!("hits >= ({text})")
+    };
+    condition.parse().with_context(|| format!("{text} is not a hit condition"))
+}
+
+fn variables(arch : &Arch, reference : u64) -> Value {
+    let variables: Vec<Value> = match reference {
+        REGISTERS_REFERENCE => {
+            let mut registers: Vec<Register> = (0..16).map(Register::V).collect();
+            registers.extend([Register::I, Register::Pc, Register::Sp, Register::Dt, Register::St]);
+            registers.iter()
+                .map(|register| json!({
+                    "name": register.to_string(),
+                    "value": format!("0x{:02X}", register.read(arch)),
+                    "variablesReference": 0,
+                }))
+                .collect()
+        }
+        STACK_REFERENCE => arch.cpu.stack().frames().iter()
+            .enumerate()
+            .map(|(index, addr)| json!({"name": format!("[{index}]"), "value": format!("0x{addr:03X}"), "variablesReference": 0}))
+            .collect(),
+        MEMORY_REFERENCE => arch.cpu.memory().chunks(16)
+            .enumerate()
+            .map(|(row, bytes)| json!({
+                "name": format!("0x{:03X}", row * 16),
+                "value": bytes.iter().map(|byte| format!("{byte:02X}")).collect::<Vec<_>>().join(" "),
+                "variablesReference": 0,
+                "memoryReference": format!("0x{:03X}", row * 16),
+            }))
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    json!({"variables": variables})
+}
+
+fn set_variable(arch : &mut Arch, arguments : &Value) -> anyhow::Result<Value> {
+    if arguments["variablesReference"].as_u64() != Some(REGISTERS_REFERENCE) {
+        bail!("only registers can be changed");
+    }
+    let register: Register = arguments["name"].as_str().unwrap_or_default().parse()?;
+    let value = debugger::parse_number(arguments["value"].as_str().unwrap_or_default().trim())?;
+    register.write(arch, value)?;
+    Ok(json!({"value": format!("0x{:02X}", register.read(arch))}))
+}
+
+fn evaluate(arch : &Arch, arguments : &Value) -> anyhow::Result<Value> {
+    let expression: Expr = arguments["expression"].as_str().unwrap_or_default().parse()?;
+    let value = expression.eval(&arch.cpu)?;
+    Ok(json!({"result": format!("0x{value:02X}"), "variablesReference": 0}))
+}
+
+fn read_memory(arch : &Arch, arguments : &Value) -> anyhow::Result<Value> {
+    let reference = arguments["memoryReference"].as_str().unwrap_or_default();
+    let start = (debugger::parse_number(reference)? as i64).saturating_add(arguments["offset"].as_i64().unwrap_or_default());
+    let requested = arguments["count"].as_u64().unwrap_or_default();
+
+    let memory = arch.cpu.memory();
+    let start = start.clamp(0, memory.len() as i64) as usize;
+    // Whatever runs past the end of memory can't be read
+    let count = requested.min((memory.len() - start) as u64);
+    Ok(json!({
+        "address": format!("0x{start:03X}"),
+        "data": base64(&memory[start..start + count as usize]),
+        "unreadableBytes": requested - count,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+
+    use super::*;
+
+    struct Editor {
+        stream: BufReader<TcpStream>,
+        seq: u64,
+    }
+
+    impl Editor {
+        fn send(&mut self, command : &str, arguments : Value) {
+            let body = json!({"seq": self.seq, "type": "request", "command": command, "arguments": arguments}).to_string();
+            self.seq += 1;
+            write!(self.stream.get_mut(), "Content-Length: {}\r\n\r\n{body}", body.len()).unwrap();
+        }
+
+        fn receive(&mut self) -> Value {
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                self.stream.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                length = header.trim().trim_start_matches("Content-Length:").trim().parse().unwrap();
+            }
+            let mut body = vec![0u8; length];
+            self.stream.read_exact(&mut body).unwrap();
+            serde_json::from_slice(&body).unwrap()
+        }
+
+        fn request(&mut self, command : &str, arguments : Value) -> Value {
+            self.send(command, arguments);
+            let response = self.receive();
+            assert_eq!(response["command"], command);
+            assert_eq!(response["success"], true, "{response}");
+            response["body"].clone()
+        }
+    }
+
+    #[test]
+    fn test_line_map() {
+        let assembly = octo::assemble("# comment\n\n: main v0 := 1\n\n  v1 := 2\n").unwrap();
+        let map = LineMap::from_assembly("src/game.8o", &assembly);
+        assert_eq!(map.address_of("/home/me/src/game.8o", 5), Some(0x202));
+        assert_eq!(map.address_of("game.8o", 4), None);
+        assert_eq!(map.line_of(0x200), Some(("src/game.8o", 3)));
+    }
+
+    #[test]
+    fn test_dap_session() {
+        let dir = std::env::temp_dir().join(format!("chip8-dap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rom = dir.join("loop.ch8");
+        // 0x200: LD V0, 5, 0x202: CALL 0x206, 0x204: JP 0x204, 0x206: ADD V0, 1, 0x208: RET
+        std::fs::write(&rom, [0x60, 0x05, 0x22, 0x06, 0x12, 0x04, 0x70, 0x01, 0x00, 0xEE]).unwrap();
+        let source = dir.join("loop.8o");
+        std::fs::write(&source, ": main v0 := 5\n  add-one\n  # and spin\n  : spin jump spin\n: add-one v0 += 1\n  ;\n").unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut editor = Editor {
+            stream: BufReader::new(TcpStream::connect(listener.local_addr().unwrap()).unwrap()),
+            seq: 1,
+        };
+        let (stream, _) = listener.accept().unwrap();
+        let mut server = DapServer::new(stream.try_clone().unwrap(), stream, Args::default());
+        let session = thread::spawn(move || {
+            let mut arch = Arch::new();
+            while server.frame(&mut arch, true).unwrap() {}
+            arch.cpu.reg[0]
+        });
+
+        assert_eq!(editor.request("initialize", json!({}))["supportsInstructionBreakpoints"], true);
+        editor.request("launch", json!({"program": rom, "source": source, "stopOnEntry": true}));
+        assert_eq!(editor.receive()["event"], "initialized");
+
+        let breakpoints = editor.request("setBreakpoints", json!({"source": {"path": "/elsewhere/loop.8o"}, "breakpoints": [{"line": 5}, {"line": 3}]}));
+        assert_eq!(breakpoints["breakpoints"][0]["verified"], true);
+        assert_eq!(breakpoints["breakpoints"][1]["verified"], false);
+
+        editor.request("configurationDone", json!({}));
+        assert_eq!(editor.receive()["body"]["reason"], "entry");
+
+        editor.request("continue", json!({"threadId": 1}));
+        assert_eq!(editor.receive()["body"]["reason"], "breakpoint");
+
+        let trace = editor.request("stackTrace", json!({"threadId": 1}));
+        assert_eq!(trace["stackFrames"][0]["line"], 5);
+        // Named after the source's labels
+        assert_eq!(trace["stackFrames"][0]["name"], "add-one");
+        assert_eq!(trace["stackFrames"][1]["instructionPointerReference"], "0x202");
+        assert_eq!(trace["stackFrames"][1]["line"], 2);
+
+        let registers = editor.request("variables", json!({"variablesReference": REGISTERS_REFERENCE}));
+        assert_eq!(registers["variables"][0]["value"], "0x05");
+        editor.request("setVariable", json!({"variablesReference": REGISTERS_REFERENCE, "name": "V0", "value": "0x10"}));
+
+        let breakpoints = editor.request("setInstructionBreakpoints", json!({"breakpoints": [
+            {"instructionReference": "0x204", "condition": "V0 == 0x11"},
+            {"instructionReference": "0x200", "condition": "V0 =="},
+            {"instructionReference": "0xFFE", "offset": 2},
+            {"instructionReference": "0x000", "offset": -2},
+        ]}));
+        assert_eq!(breakpoints["breakpoints"][1]["verified"], false);
+        assert_eq!(breakpoints["breakpoints"][2]["verified"], false);
+        assert_eq!(breakpoints["breakpoints"][3]["verified"], false);
+
+        editor.request("stepOut", json!({"threadId": 1}));
+        assert_eq!(editor.receive()["body"]["reason"], "step");
+        assert_eq!(editor.request("evaluate", json!({"expression": "PC"}))["result"], "0x204");
+
+        let memory = editor.request("readMemory", json!({"memoryReference": "0x200", "count": 3}));
+        assert_eq!(memory["data"], "YAUi");
+        let memory = editor.request("readMemory", json!({"memoryReference": "0xFFE", "count": u64::MAX}));
+        assert_eq!(memory["data"], "AAA=");
+        assert_eq!(memory["unreadableBytes"], u64::MAX - 2);
+
+        editor.request("disconnect", json!({}));
+        assert_eq!(session.join().unwrap(), 0x11);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,882 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{self, Write as _};
+use std::iter::zip;
+use std::io::{BufRead, Write as _};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use anyhow::{anyhow, bail, Context};
+
+use crate::arch::Arch;
+use crate::cpu::{Cpu, Fault, Stack};
+use crate::expr::{self, Expr};
+use crate::instruction::Instruction;
+use crate::symbols::Symbols;
+use crate::watch::{WatchHit, WatchKind, WatchTarget, Watchpoint};
+
+pub const HELP : &str = "\
+Numbers starting with 0x are hexadecimal, the rest are decimal. Addresses
+may also be symbols, like draw_paddle or draw_paddle+4, with --symbols.
+
+    break [addr] [if <condition>]
+                        Stop before executing the instruction at addr when
+                        the condition holds, list breakpoints without addr
+    condition <addr> [condition]
+                        Change the condition of a breakpoint, or remove it
+    log <addr> <message>
+                        Print message each time addr is reached, without
+                        stopping. {expression} is replaced with its value
+    delete [addr]       Delete the breakpoint at addr, or all of them
+    watch [target]      Stop after target is written, list watchpoints without
+                        one. target is an address, a range like 0x300-0x30F,
+                        V0-VF or I
+    rwatch <target>     Stop after memory in target is read
+    awatch <target>     Stop after memory in target is read or written
+    unwatch [n]         Delete watchpoint n, or all of them
+    step [n]            Execute n instructions, 1 by default
+    next                Like step, but runs over subroutine calls
+    finish              Run until the current subroutine returns
+    continue            Run until a breakpoint is hit
+    regs                Show the registers and timers
+    mem <addr> <len>    Show len bytes of memory starting at addr
+    stack               Show the return addresses on the stack
+    disas [addr]        Disassemble from addr, the pc by default
+    print <expression>  Show the value of an expression
+    set <reg> = <value> Set V0-VF, I, PC, SP, DT or ST
+    help                Show this message
+    quit                Leave the emulator
+
+Conditions and expressions are made of V0-VF, I, PC, SP, DT, ST, mem[addr],
+numbers and the operators of Rust, like `V3 == 0x10 && mem[I] != 0`. In
+breakpoints `hits` counts the times it was reached, this one included.
+
+An empty line repeats the last command, or stops the machine when it is
+running. Other lines typed while it runs wait until it stops.";
+
+/// The registers that can be inspected and changed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Register {
+    V(usize),
+    I,
+    Pc,
+    Sp,
+    Dt,
+    St,
+}
+
+impl FromStr for Register {
+    type Err = anyhow::Error;
+
+    fn from_str(name : &str) -> anyhow::Result<Self> {
+        let upper = name.to_ascii_uppercase();
+        match upper.as_str() {
+            "I" => Ok(Register::I),
+            "PC" => Ok(Register::Pc),
+            "SP" => Ok(Register::Sp),
+            "DT" => Ok(Register::Dt),
+            "ST" => Ok(Register::St),
+            _ => upper.strip_prefix('V')
+                .filter(|index| index.len() == 1)
+                .and_then(|index| usize::from_str_radix(index, 16).ok())
+                .map(Register::V)
+                .ok_or_else(|| anyhow!("unknown register {name}")),
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Register::V(x) => write!(f, "V{x:X}"),
+            Register::I => write!(f, "I"),
+            Register::Pc => write!(f, "PC"),
+            Register::Sp => write!(f, "SP"),
+            Register::Dt => write!(f, "DT"),
+            Register::St => write!(f, "ST"),
+        }
+    }
+}
+
+impl Register {
+    pub fn read(self, arch : &Arch) -> u16 {
+        self.value(&arch.cpu)
+    }
+
+    pub fn value(self, cpu : &Cpu) -> u16 {
+        match self {
+            Register::V(x) => cpu.reg[x] as u16,
+            Register::I => cpu.i_reg(),
+            Register::Pc => cpu.pc(),
+            Register::Sp => cpu.stack().len() as u16,
+            Register::Dt => cpu.delay_timer() as u16,
+            Register::St => cpu.sound_timer() as u16,
+        }
+    }
+
+    pub fn write(self, arch : &mut Arch, value : u16) -> anyhow::Result<()> {
+        let byte = || u8::try_from(value).map_err(|_| anyhow!("0x{value:X} does not fit in a byte"));
+        match self {
+            Register::V(x) => arch.cpu.reg[x] = byte()?,
+            Register::I => arch.cpu.set_i_reg(value),
+            Register::Pc => arch.cpu.set_pc(value),
+            Register::Sp => {
+                if value as usize > Stack::CAPACITY {
+                    bail!("the stack only holds {} addresses", Stack::CAPACITY);
+                }
+                arch.cpu.set_stack_pointer(value as usize);
+            }
+            Register::Dt => arch.cpu.set_delay_timer(byte()?),
+            Register::St => arch.cpu.set_sound_timer(byte()?),
+        }
+        Ok(())
+    }
+}
+
+/// A place to stop at, maybe only when a condition holds, or to log a
+/// message at without stopping.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Breakpoint {
+    pub condition: Option<Expr>,
+    /// Logged instead of stopping, see `expr::interpolate`.
+    pub log: Option<String>,
+    /// The times the breakpoint was reached, whether the condition held or
+    /// not.
+    pub hits: u64,
+}
+
+impl Breakpoint {
+    pub fn when(condition : Option<Expr>) -> Self {
+        Self { condition, ..Self::default() }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(condition) = &self.condition {
+            write!(f, " if {condition}")?;
+        }
+        if let Some(log) = &self.log {
+            write!(f, " log \"{log}\"")?;
+        }
+        write!(f, ", hit {} times", self.hits)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Break(Option<(u16, Option<Expr>)>),
+    Condition(u16, Option<Expr>),
+    Log(u16, String),
+    Delete(Option<u16>),
+    Watch(Option<Watchpoint>),
+    Unwatch(Option<usize>),
+    Step(usize),
+    Next,
+    Finish,
+    Continue,
+    Regs,
+    Mem(u16, usize),
+    Stack,
+    Disas(Option<u16>),
+    Print(Expr),
+    Set(Register, u16),
+    Help,
+    Quit,
+}
+
+/// Parses `0x` prefixed numbers as hexadecimal and anything else as decimal.
+pub fn parse_number(text : &str) -> anyhow::Result<u16> {
+    let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.with_context(|| format!("{text} is not a number"))
+}
+
+impl FromStr for Command {
+    type Err = anyhow::Error;
+
+    fn from_str(line : &str) -> anyhow::Result<Self> {
+        Self::parse(line, &Symbols::new())
+    }
+}
+
+impl Command {
+    /// Parses a command, looking addresses up in `symbols` when they are
+    /// not numbers.
+    pub fn parse(line : &str, symbols : &Symbols) -> anyhow::Result<Self> {
+        let line = line.trim();
+        let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        // Spaces around the = of set are optional
+        let line = if name == "set" {line.replace('=', " = ")} else {line.to_string()};
+        let words: Vec<&str> = line.split_whitespace().collect();
+        // The text after the address, for conditions and messages
+        let after_addr = || rest.split_once(char::is_whitespace).map(|(_, text)| text.trim()).unwrap_or_default();
+        let condition = |text : &str| (!text.is_empty()).then(|| text.parse::<Expr>()).transpose();
+        let number = |index : usize, name : &str| {
+            words.get(index)
+                .ok_or_else(|| anyhow!("{} expects {name}", words[0]))
+                .and_then(|word| parse_number(word))
+        };
+        let address = |index : usize| {
+            words.get(index)
+                .ok_or_else(|| anyhow!("{} expects an address", words[0]))
+                .and_then(|word| symbols.parse_address(word))
+        };
+
+        let command = match words.first().copied() {
+            Some("break" | "b") if words.len() == 1 => Command::Break(None),
+            Some("break" | "b") => {
+                let text = after_addr();
+                let text = match text.strip_prefix("if") {
+                    Some(text) if text.starts_with(char::is_whitespace) => text,
+                    _ if text.is_empty() => text,
+                    _ => bail!("break expects an address and maybe if <condition>"),
+                };
+                Command::Break(Some((address(1)?, condition(text)?)))
+            }
+            Some("condition") => Command::Condition(address(1)?, condition(after_addr())?),
+            Some("log") => {
+                let message = after_addr();
+                if message.is_empty() {
+                    bail!("log expects an address and a message");
+                }
+                Command::Log(address(1)?, message.to_string())
+            }
+            Some("delete" | "d") => Command::Delete(words.get(1).map(|_| address(1)).transpose()?),
+            Some("watch" | "w") => Command::Watch(words.get(1)
+                .map(|word| Watchpoint::new(word.parse::<WatchTarget>()?, WatchKind::Write))
+                .transpose()?),
+            Some("rwatch") => Command::Watch(Some(Watchpoint::new(words.get(1).context("rwatch expects a target")?.parse()?, WatchKind::Read)?)),
+            Some("awatch") => Command::Watch(Some(Watchpoint::new(words.get(1).context("awatch expects a target")?.parse()?, WatchKind::Access)?)),
+            Some("unwatch") => Command::Unwatch(words.get(1).map(|word| parse_number(word)).transpose()?.map(usize::from)),
+            Some("step" | "s") => Command::Step(words.get(1).map(|word| parse_number(word)).transpose()?.unwrap_or(1) as usize),
+            Some("next" | "n") => Command::Next,
+            Some("finish" | "f") => Command::Finish,
+            Some("continue" | "c") => Command::Continue,
+            Some("regs" | "r") => Command::Regs,
+            Some("mem" | "m") => Command::Mem(address(1)?, number(2, "a length")? as usize),
+            Some("stack" | "bt") => Command::Stack,
+            Some("disas") => Command::Disas(words.get(1).map(|_| address(1)).transpose()?),
+            Some("print" | "p") => Command::Print(rest.parse()?),
+            Some("set") => {
+                if words.len() != 4 || words[2] != "=" {
+                    bail!("set expects <register> = <value>");
+                }
+                Command::Set(words[1].parse()?, number(3, "a value")?)
+            }
+            Some("help" | "h") => Command::Help,
+            Some("quit" | "q") => Command::Quit,
+            Some(other) => bail!("unknown command {other}, try help"),
+            None => bail!("no command given"),
+        };
+
+        Ok(command)
+    }
+}
+
+/// Why the machine stopped running.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    Breakpoint(u16),
+    /// A watchpoint was triggered by the instruction at `pc`.
+    Watchpoint { pc: u16, hit: WatchHit },
+    Step,
+    Finish,
+    Interrupt,
+    /// The instruction at the pc faulted, and will again if resumed.
+    Fault(Fault),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Paused,
+    Continue,
+    Step(usize),
+    /// Runs until the pc is at `return_to` with the stack back at `depth`.
+    Next { return_to: u16, depth: usize },
+    /// Runs until the stack is shallower than `depth`.
+    Finish { depth: usize },
+}
+
+/// Run control over an `Arch`: breakpoints and the ways to run up to
+/// them. The debugger only decides when to stop, the caller decides how many
+/// instructions it may run at a time through `run`.
+#[derive(Debug)]
+pub struct Debugger {
+    pub breakpoints: BTreeMap<u16, Breakpoint>,
+    /// Names for addresses, shown wherever addresses are.
+    pub symbols: Symbols,
+    pub watchpoints: Vec<Watchpoint>,
+    mode: Mode,
+    /// Set when resuming, so the breakpoint we are sitting on does not stop
+    /// us again before executing anything.
+    resuming: bool,
+    pub quit: bool,
+    /// Printed by log breakpoints and failing conditions, for the frontend
+    /// to show.
+    pub messages: Vec<String>,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: BTreeMap::new(),
+            symbols: Symbols::new(),
+            watchpoints: Vec::new(),
+            mode: Mode::Paused,
+            resuming: false,
+            quit: false,
+            messages: Vec::new(),
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.mode == Mode::Paused
+    }
+
+    pub fn pause(&mut self) {
+        self.mode = Mode::Paused;
+    }
+
+    fn resume(&mut self, mode : Mode) {
+        self.mode = mode;
+        self.resuming = true;
+    }
+
+    pub fn resume_continue(&mut self) {
+        self.resume(Mode::Continue);
+    }
+
+    pub fn resume_step(&mut self, count : usize) {
+        if count > 0 {
+            self.resume(Mode::Step(count));
+        }
+    }
+
+    /// Steps over the instruction under the pc, running the whole
+    /// subroutine when it is a call.
+    pub fn resume_next(&mut self, arch : &Arch) {
+        match Instruction::decode(arch.cpu.current_instruction()) {
+            Instruction::Call(_) => self.resume(Mode::Next {
+                return_to: arch.cpu.pc().wrapping_add(2) & 0x0FFF,
+                depth: arch.cpu.stack().len(),
+            }),
+            _ => self.resume(Mode::Step(1)),
+        }
+    }
+
+    pub fn resume_finish(&mut self, arch : &Arch) -> anyhow::Result<()> {
+        let depth = arch.cpu.stack().len();
+        if depth == 0 {
+            bail!("not inside a subroutine");
+        }
+        self.resume(Mode::Finish { depth });
+        Ok(())
+    }
+
+    /// Runs at most `budget` instructions in the current mode. Returns why
+    /// the machine stopped, or `None` when it is still running or was
+    /// paused to begin with.
+    pub fn run(&mut self, arch : &mut Arch, budget : usize) -> Option<Stop> {
+        for _ in 0..budget {
+            if self.is_paused() {
+                return None;
+            }
+
+            let pc = arch.cpu.pc();
+            if !self.resuming && self.reached(arch, pc) {
+                self.pause();
+                return Some(Stop::Breakpoint(pc));
+            }
+            self.resuming = false;
+
+            arch.cpu.record_accesses(!self.watchpoints.is_empty() || arch.coverage.is_some());
+            let before: Vec<Option<u16>> = self.watchpoints.iter().map(|watchpoint| watchpoint.before(arch)).collect();
+            if let Err(fault) = arch.step() {
+                self.pause();
+                return Some(Stop::Fault(fault));
+            }
+
+            let hit = zip(&self.watchpoints, before).find_map(|(watchpoint, before)| watchpoint.check(arch, before));
+            if let Some(hit) = hit {
+                self.pause();
+                return Some(Stop::Watchpoint { pc, hit });
+            }
+
+            let stop = match self.mode {
+                Mode::Step(1) => Some(Stop::Step),
+                Mode::Step(count) => {
+                    self.mode = Mode::Step(count - 1);
+                    None
+                }
+                Mode::Next { return_to, depth }
+                    if arch.cpu.pc() == return_to && arch.cpu.stack().len() == depth => Some(Stop::Step),
+                Mode::Finish { depth } if arch.cpu.stack().len() < depth => Some(Stop::Finish),
+                _ => None,
+            };
+
+            if stop.is_some() {
+                self.pause();
+                return stop;
+            }
+        }
+
+        None
+    }
+
+    /// Counts a hit of the breakpoint at `pc`, if any, and tells whether to
+    /// stop there. A condition that can not be evaluated stops as well.
+    fn reached(&mut self, arch : &Arch, pc : u16) -> bool {
+        let Some(breakpoint) = self.breakpoints.get_mut(&pc) else {
+            return false;
+        };
+
+        breakpoint.hits += 1;
+        let holds = match &breakpoint.condition {
+            Some(condition) => match condition.eval_at_breakpoint(&arch.cpu, breakpoint.hits) {
+                Ok(value) => value != 0,
+                Err(error) => {
+                    self.messages.push(format!("Condition of the breakpoint at 0x{pc:03X} failed: {error:#}"));
+                    return true;
+                }
+            },
+            None => true,
+        };
+
+        match &breakpoint.log {
+            Some(message) if holds => {
+                self.messages.push(expr::interpolate(message, &arch.cpu, Some(breakpoint.hits)));
+                false
+            }
+            Some(_) => false,
+            None => holds,
+        }
+    }
+
+    /// Executes a command, returning what should be shown to the user. The
+    /// commands that run the machine only set the mode, `run` does the rest.
+    pub fn execute(&mut self, arch : &mut Arch, command : &Command) -> anyhow::Result<String> {
+        let mut out = String::new();
+        match *command {
+            Command::Break(Some((addr, ref condition))) => {
+                self.breakpoints.insert(addr, Breakpoint::when(condition.clone()));
+                write!(out, "Breakpoint set at 0x{addr:03X}")?;
+            }
+            Command::Break(None) if self.breakpoints.is_empty() => write!(out, "No breakpoints")?,
+            Command::Break(None) => {
+                out = self.breakpoints.iter()
+                    .map(|(addr, breakpoint)| format!("{}{breakpoint}", self.symbols.describe(*addr)))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+            }
+            Command::Condition(addr, ref condition) => {
+                let breakpoint = self.breakpoints.get_mut(&addr).with_context(|| format!("no breakpoint at 0x{addr:03X}"))?;
+                breakpoint.condition = condition.clone();
+                match condition {
+                    Some(condition) => write!(out, "Breakpoint at 0x{addr:03X} stops if {condition}")?,
+                    None => write!(out, "Breakpoint at 0x{addr:03X} always stops")?,
+                }
+            }
+            Command::Log(addr, ref message) => {
+                self.breakpoints.entry(addr).or_default().log = Some(message.clone());
+                write!(out, "Logging at 0x{addr:03X}")?;
+            }
+            Command::Delete(Some(addr)) => {
+                if self.breakpoints.remove(&addr).is_none() {
+                    bail!("no breakpoint at 0x{addr:03X}");
+                }
+                write!(out, "Deleted breakpoint at 0x{addr:03X}")?;
+            }
+            Command::Delete(None) => {
+                self.breakpoints.clear();
+                write!(out, "Deleted all breakpoints")?;
+            }
+            Command::Watch(Some(ref watchpoint)) => {
+                self.watchpoints.push(watchpoint.clone());
+                write!(out, "Watchpoint {} on {watchpoint}", self.watchpoints.len() - 1)?;
+            }
+            Command::Watch(None) if self.watchpoints.is_empty() => write!(out, "No watchpoints")?,
+            Command::Watch(None) => {
+                out = self.watchpoints.iter()
+                    .enumerate()
+                    .map(|(index, watchpoint)| format!("{index}: {watchpoint}"))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+            }
+            Command::Unwatch(Some(index)) => {
+                if index >= self.watchpoints.len() {
+                    bail!("no watchpoint {index}");
+                }
+                let watchpoint = self.watchpoints.remove(index);
+                write!(out, "Deleted watchpoint on {watchpoint}")?;
+            }
+            Command::Unwatch(None) => {
+                self.watchpoints.clear();
+                write!(out, "Deleted all watchpoints")?;
+            }
+            Command::Step(count) => self.resume_step(count),
+            Command::Next => self.resume_next(arch),
+            Command::Finish => self.resume_finish(arch)?,
+            Command::Continue => self.resume_continue(),
+            Command::Regs => out = registers(arch),
+            Command::Mem(addr, len) => out = memory(arch, addr, len)?,
+            Command::Stack => out = stack(arch, &self.symbols),
+            Command::Disas(addr) => out = disassemble(arch, addr.unwrap_or(arch.cpu.pc()), 10, &self.breakpoints, &self.symbols),
+            Command::Print(ref expr) => {
+                let value = expr.eval(&arch.cpu)?;
+                write!(out, "{expr} = 0x{value:X} ({value})")?;
+            }
+            Command::Set(register, value) => {
+                register.write(arch, value)?;
+                write!(out, "{register} = 0x{:X}", register.read(arch))?;
+            }
+            Command::Help => out = HELP.to_string(),
+            Command::Quit => self.quit = true,
+        }
+
+        Ok(out)
+    }
+}
+
+pub fn registers(arch : &Arch) -> String {
+    let mut out = String::new();
+    for (x, value) in arch.cpu.reg.iter().enumerate() {
+        let separator = if x % 8 == 7 {'\n'} else {' '};
+        let _ = write!(out, "V{x:X}={value:02X}{separator}");
+    }
+    let _ = write!(out, "I={:03X} PC={:03X} SP={:X} DT={:02X} ST={:02X}",
+        arch.cpu.i_reg(), arch.cpu.pc(), arch.cpu.stack().len(), arch.cpu.delay_timer(), arch.cpu.sound_timer());
+    out
+}
+
+pub fn memory(arch : &Arch, addr : u16, len : usize) -> anyhow::Result<String> {
+    let start = addr as usize;
+    let end = start + len;
+    if end > 4096 {
+        bail!("0x{addr:03X} + {len} goes past the end of memory");
+    }
+
+    let mut out = String::new();
+    for (line, bytes) in arch.cpu.memory()[start..end].chunks(16).enumerate() {
+        if line > 0 {
+            out.push('\n');
+        }
+        let _ = write!(out, "0x{:03X}:", start + line * 16);
+        for byte in bytes {
+            let _ = write!(out, " {byte:02X}");
+        }
+    }
+    Ok(out)
+}
+
+pub fn stack(arch : &Arch, symbols : &Symbols) -> String {
+    let frames = arch.cpu.stack().frames();
+    if frames.is_empty() {
+        return String::from("Stack is empty");
+    }
+
+    frames.iter()
+        .rev()
+        .enumerate()
+        .map(|(depth, addr)| format!("#{depth} returns to {}", symbols.describe(*addr)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Disassembles `count` instructions starting at `addr`, marking the pc
+/// with `>` and breakpoints with `*`, with a line for each symbol.
+pub fn disassemble(arch : &Arch, addr : u16, count : usize, breakpoints : &BTreeMap<u16, Breakpoint>, symbols : &Symbols) -> String {
+    let memory = arch.cpu.memory();
+    let mut lines = Vec::new();
+    let mut addr = addr as usize;
+
+    for _ in 0..count {
+        if addr + 1 >= memory.len() {
+            break;
+        }
+        let instr = ((memory[addr] as u16) << 8) | (memory[addr + 1] as u16);
+        if let Some(name) = symbols.name_of(addr as u16) {
+            lines.push(format!("{name}:"));
+        }
+        let pc_mark = if addr == arch.cpu.pc() as usize {'>'} else {' '};
+        let break_mark = if breakpoints.contains_key(&(addr as u16)) {'*'} else {' '};
+        lines.push(format!("{pc_mark}{break_mark}0x{addr:03X}: {:02X} {:02X}  {}",
+            instr >> 8, instr & 0xFF, symbols.instruction(Instruction::decode(instr))));
+        addr += 2;
+    }
+
+    lines.join("\n")
+}
+
+pub fn describe_stop(arch : &Arch, stop : Stop, symbols : &Symbols) -> String {
+    let reason = match stop {
+        Stop::Breakpoint(addr) => format!("Breakpoint at {}", symbols.describe(addr)),
+        Stop::Watchpoint { pc, hit } => format!("Watchpoint hit by {}, {hit}", symbols.describe(pc)),
+        Stop::Step => String::from("Stepped"),
+        Stop::Finish => String::from("Returned"),
+        Stop::Interrupt => String::from("Interrupted"),
+        Stop::Fault(fault) => format!("Faulted, {fault}"),
+    };
+
+    format!("{reason}\n{}", disassemble(arch, arch.cpu.pc(), 1, &BTreeMap::new(), symbols))
+}
+
+/// Drives a `Debugger` from lines typed on stdin. The lines are read on a
+/// thread of their own so the window can keep going while nothing is typed.
+pub struct Repl {
+    pub debugger: Debugger,
+    input: Receiver<String>,
+    /// Lines typed while the machine was running.
+    pending: VecDeque<String>,
+    last_command: Option<Command>,
+}
+
+impl Repl {
+    pub fn new(arch : &Arch, symbols : Symbols) -> Self {
+        let (sender, input) = mpsc::channel();
+        thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                let Ok(line) = line else { break };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        println!("Paused, type help for the commands\n{}", disassemble(arch, arch.cpu.pc(), 1, &BTreeMap::new(), &symbols));
+        prompt();
+
+        Self {
+            debugger: Debugger { symbols, ..Debugger::new() },
+            input,
+            pending: VecDeque::new(),
+            last_command: None,
+        }
+    }
+
+    /// Runs a frame worth of instructions, or handles a command while
+    /// paused. When `wait` is set it blocks until a command is typed instead
+    /// of returning while paused. Returns false once the user has quit.
+    pub fn frame(&mut self, arch : &mut Arch, wait : bool) -> bool {
+        if self.debugger.is_paused() {
+            let line = if let Some(line) = self.pending.pop_front() {
+                line
+            } else if wait {
+                match self.input.recv() {
+                    Ok(line) => line,
+                    Err(_) => return false,
+                }
+            } else {
+                match self.input.try_recv() {
+                    Ok(line) => line,
+                    Err(TryRecvError::Empty) => return true,
+                    Err(TryRecvError::Disconnected) => return false,
+                }
+            };
+
+            self.handle(arch, &line);
+            return !self.debugger.quit;
+        }
+
+        let mut stop = self.debugger.run(arch, arch.cycles_per_frame.max(1));
+        for message in self.debugger.messages.drain(..) {
+            println!("{message}");
+        }
+        if stop.is_none() && !self.debugger.is_paused() {
+            self.pending.extend(self.input.try_iter());
+            if let Some(index) = self.pending.iter().position(|line| line.trim().is_empty()) {
+                self.pending.remove(index);
+                self.debugger.pause();
+                stop = Some(Stop::Interrupt);
+            }
+        }
+
+        if let Some(stop) = stop {
+            println!("{}", describe_stop(arch, stop, &self.debugger.symbols));
+            prompt();
+        }
+        true
+    }
+
+    fn handle(&mut self, arch : &mut Arch, line : &str) {
+        let command = if line.trim().is_empty() {
+            match &self.last_command {
+                Some(command) => Ok(command.clone()),
+                None => {
+                    prompt();
+                    return;
+                }
+            }
+        } else {
+            Command::parse(line, &self.debugger.symbols)
+        };
+
+        let result = command.and_then(|command| {
+            let out = self.debugger.execute(arch, &command);
+            self.last_command = Some(command);
+            out
+        });
+
+        match result {
+            Ok(out) if !out.is_empty() => println!("{out}"),
+            Ok(_) => {}
+            Err(error) => println!("{error:#}"),
+        }
+
+        if self.debugger.is_paused() && !self.debugger.quit {
+            prompt();
+        }
+    }
+}
+
+fn prompt() {
+    print!("(chip8) ");
+    let _ = std::io::stdout().flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arch_with_program(program : &[u8]) -> Arch {
+        let mut arch = Arch::new();
+        arch.cpu.load_program(program);
+        arch
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!("break 0x210".parse::<Command>().unwrap(), Command::Break(Some((0x210, None))));
+        assert_eq!("b 0x210 if V3 == 0x10".parse::<Command>().unwrap(), Command::Break(Some((0x210, Some("V3 == 16".parse().unwrap())))));
+        assert!("break 0x210 V3 == 0x10".parse::<Command>().is_err());
+        assert_eq!("log 0x210 V0={V0}".parse::<Command>().unwrap(), Command::Log(0x210, String::from("V0={V0}")));
+        assert_eq!("step".parse::<Command>().unwrap(), Command::Step(1));
+        assert_eq!("mem 0x300 16".parse::<Command>().unwrap(), Command::Mem(0x300, 16));
+        assert_eq!("set V3 = 0x10".parse::<Command>().unwrap(), Command::Set(Register::V(3), 0x10));
+        assert_eq!("set i=768".parse::<Command>().unwrap(), Command::Set(Register::I, 768));
+        assert!("set VG = 1".parse::<Command>().is_err());
+        assert!("jump".parse::<Command>().is_err());
+
+        let mut arch = Arch::new();
+        Register::Sp.write(&mut arch, Stack::CAPACITY as u16).unwrap();
+        assert!(Register::Sp.write(&mut arch, Stack::CAPACITY as u16 + 1).is_err());
+    }
+
+    #[test]
+    fn test_next_and_finish() {
+        // 0x200: CALL 0x206, 0x202: JP 0x202, 0x206: LD V0, 1, 0x208: RET
+        let mut arch = arch_with_program(&[0x22, 0x06, 0x12, 0x02, 0x00, 0x00, 0x60, 0x01, 0x00, 0xEE]);
+        let mut debugger = Debugger::new();
+
+        debugger.execute(&mut arch, &Command::Next).unwrap();
+        assert_eq!(debugger.run(&mut arch, 100), Some(Stop::Step));
+        assert_eq!(arch.cpu.pc(), 0x202);
+        assert_eq!(arch.cpu.reg[0], 1);
+
+        let mut arch = arch_with_program(&[0x22, 0x06, 0x12, 0x02, 0x00, 0x00, 0x60, 0x01, 0x00, 0xEE]);
+        debugger.execute(&mut arch, &Command::Step(1)).unwrap();
+        assert_eq!(debugger.run(&mut arch, 100), Some(Stop::Step));
+        assert_eq!(arch.cpu.pc(), 0x206);
+        debugger.execute(&mut arch, &Command::Finish).unwrap();
+        assert_eq!(debugger.run(&mut arch, 100), Some(Stop::Finish));
+        assert_eq!(arch.cpu.pc(), 0x202);
+
+        // A call at the end of memory returns to its start
+        arch.cpu.memory_mut()[0xFFE..].copy_from_slice(&[0x22, 0x06]);
+        arch.cpu.set_pc(0xFFE);
+        debugger.execute(&mut arch, &Command::Next).unwrap();
+        assert_eq!(debugger.run(&mut arch, 100), Some(Stop::Step));
+        assert_eq!(arch.cpu.pc(), 0x000);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        // 0x200: ADD V0, 1, 0x202: JP 0x200
+        let mut arch = arch_with_program(&[0x70, 0x01, 0x12, 0x00]);
+        let mut debugger = Debugger::new();
+
+        debugger.execute(&mut arch, &Command::Break(Some((0x202, None)))).unwrap();
+        debugger.execute(&mut arch, &Command::Continue).unwrap();
+        assert_eq!(debugger.run(&mut arch, 100), Some(Stop::Breakpoint(0x202)));
+        assert_eq!(arch.cpu.reg[0], 1);
+
+        // Continuing runs the loop once more before stopping again
+        debugger.execute(&mut arch, &Command::Continue).unwrap();
+        assert_eq!(debugger.run(&mut arch, 100), Some(Stop::Breakpoint(0x202)));
+        assert_eq!(arch.cpu.reg[0], 2);
+
+        debugger.execute(&mut arch, &Command::Delete(None)).unwrap();
+        debugger.execute(&mut arch, &Command::Continue).unwrap();
+        assert_eq!(debugger.run(&mut arch, 100), None);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        // 0x200: ADD V0, 1, 0x202: LD I, 0x300, 0x204: LD [I], V0, 0x206: JP 0x200
+        let mut arch = arch_with_program(&[0x70, 0x01, 0xA3, 0x00, 0xF0, 0x55, 0x12, 0x00]);
+        let mut debugger = Debugger::new();
+
+        debugger.execute(&mut arch, &"watch 0x300".parse().unwrap()).unwrap();
+        debugger.execute(&mut arch, &Command::Continue).unwrap();
+        let stop = debugger.run(&mut arch, 100).unwrap();
+        assert_eq!(describe_stop(&arch, stop, &Symbols::new()).lines().next(), Some("Watchpoint hit by 0x204, 0x300 written: 0x00 -> 0x01"));
+        assert_eq!(arch.cpu.pc(), 0x206);
+
+        debugger.execute(&mut arch, &"unwatch".parse().unwrap()).unwrap();
+        debugger.execute(&mut arch, &"watch i".parse().unwrap()).unwrap();
+        debugger.execute(&mut arch, &Command::Continue).unwrap();
+        let stop = debugger.run(&mut arch, 100).unwrap();
+        assert_eq!(describe_stop(&arch, stop, &Symbols::new()).lines().next(), Some("Watchpoint hit by 0x202, I written: 0x300 -> 0x300"));
+        assert!("rwatch V0".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_conditions_and_logs() {
+        // 0x200: ADD V0, 1, 0x202: JP 0x200
+        let mut arch = arch_with_program(&[0x70, 0x01, 0x12, 0x00]);
+        let mut debugger = Debugger::new();
+
+        debugger.execute(&mut arch, &"break 0x202 if V0 == 3 || hits == 5".parse().unwrap()).unwrap();
+        debugger.execute(&mut arch, &"log 0x200 V0 is {V0}".parse().unwrap()).unwrap();
+        debugger.execute(&mut arch, &Command::Continue).unwrap();
+        assert_eq!(debugger.run(&mut arch, 100), Some(Stop::Breakpoint(0x202)));
+        assert_eq!(arch.cpu.reg[0], 3);
+        // The pc starts on the log point, which resuming steps over
+        assert_eq!(debugger.messages, ["V0 is 0x1", "V0 is 0x2"]);
+
+        debugger.execute(&mut arch, &Command::Continue).unwrap();
+        assert_eq!(debugger.run(&mut arch, 100), Some(Stop::Breakpoint(0x202)));
+        assert_eq!(arch.cpu.reg[0], 5);
+
+        debugger.execute(&mut arch, &"condition 0x202 mem[I] / V1".parse().unwrap()).unwrap();
+        debugger.execute(&mut arch, &Command::Continue).unwrap();
+        assert_eq!(debugger.run(&mut arch, 100), Some(Stop::Breakpoint(0x202)));
+        assert!(debugger.messages.last().unwrap().contains("division by zero"));
+    }
+
+    #[test]
+    fn test_symbols() {
+        // 0x200: CALL 0x206, 0x202: JP 0x202, 0x206: LD V0, 1, 0x208: RET
+        let mut arch = arch_with_program(&[0x22, 0x06, 0x12, 0x02, 0x00, 0x00, 0x60, 0x01, 0x00, 0xEE]);
+        let mut debugger = Debugger::new();
+        debugger.symbols = Symbols::parse("0x200 main\n0x206 set_flag").unwrap();
+
+        let command = Command::parse("break set_flag+2", &debugger.symbols).unwrap();
+        debugger.execute(&mut arch, &command).unwrap();
+        debugger.execute(&mut arch, &Command::Continue).unwrap();
+        let stop = debugger.run(&mut arch, 100).unwrap();
+        assert_eq!(describe_stop(&arch, stop, &debugger.symbols).lines().next(), Some("Breakpoint at 0x208 <set_flag+0x2>"));
+        assert_eq!(stack(&arch, &debugger.symbols), "#0 returns to 0x202 <main+0x2>");
+        assert_eq!(debugger.execute(&mut arch, &Command::Disas(Some(0x200))).unwrap().lines().take(2).collect::<Vec<_>>(),
+            ["main:", "  0x200: 22 06  CALL set_flag"]);
+    }
+}
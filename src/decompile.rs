@@ -0,0 +1,529 @@
+/* Lifts a rom into Octo source. The whole program is written out in
+address order, the code reachable from 0x200 as statements and everything
+else as bytes, so assembling the output with Octo gives the rom back.
+
+Octo's structured statements are plain skips and jumps once assembled,
+which is how they are recovered:
+
+    SNE V0, 5; <stmt>                     if v0 == 0x05 then <stmt>
+    SE V0, 5; JP else; ...; JP end; ...   if v0 == 0x05 begin ... else ... end
+    ...; JP back to the start             loop ... again
+    SE V0, 5; JP past the again           while v0 == 0x05
+
+A coverage report of a run can say which parts are code and which are
+data where walking the code from 0x200 can not tell.
+
+Subroutines are labelled after their entry, registers get aliases after
+what they are used for (sprite coordinates, timers, keys...) and symbols
+name everything they cover. Jumps left over get labels of their own.
+*/
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::ops::RangeInclusive;
+
+use crate::cfg::{Cfg, Program, START};
+use crate::coverage::Usage;
+use crate::instruction::Instruction;
+use crate::symbols::Symbols;
+
+/// Octo names may only hold letters, digits, `-` and `_`.
+fn identifier(name : &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' {c} else {'_'}).collect()
+}
+
+fn negate(condition : &str) -> String {
+    if let Some(reg) = condition.strip_suffix(" -key") {
+        format!("{reg} key")
+    } else if let Some(reg) = condition.strip_suffix(" key") {
+        format!("{reg} -key")
+    } else if condition.contains(" == ") {
+        condition.replace(" == ", " != ")
+    } else {
+        condition.replace(" != ", " == ")
+    }
+}
+
+/// Names registers after what the code mostly does with them, leaving
+/// the ones without a clear use and VF alone.
+fn aliases(code : &BTreeMap<u16, u16>) -> [Option<String>; 16] {
+    let mut uses: [BTreeMap<&str, usize>; 16] = Default::default();
+    let mut total = [0; 16];
+    for opcode in code.values() {
+        let pattern = Instruction::decode(*opcode).pattern();
+        if pattern.contains('X') {
+            total[((opcode >> 8) & 0xF) as usize] += 1;
+        }
+        if pattern.contains('Y') {
+            total[((opcode >> 4) & 0xF) as usize] += 1;
+        }
+
+        let mut role = |x : usize, role| *uses[x].entry(role).or_default() += 1;
+        match Instruction::decode(*opcode) {
+            Instruction::Drw(x, y, _) => {
+                role(x, "sprite-x");
+                role(y, "sprite-y");
+            }
+            Instruction::LdVxDt(x) | Instruction::LdDtVx(x) => role(x, "timer"),
+            Instruction::LdStVx(x) => role(x, "sound"),
+            Instruction::LdVxK(x) | Instruction::Skp(x) | Instruction::Sknp(x) => role(x, "keycode"),
+            Instruction::Rnd(x, _) => role(x, "rnd"),
+            Instruction::LdF(x) | Instruction::LdB(x) => role(x, "number"),
+            _ => {}
+        }
+    }
+
+    let mut roles: [Option<&str>; 16] = [None; 16];
+    for (x, uses) in uses.iter().enumerate().take(15) {
+        let mut best: Option<(&str, usize)> = None;
+        for (role, count) in uses {
+            if best.is_none_or(|(_, most)| *count > most) {
+                best = Some((role, *count));
+            }
+        }
+        // Registers used for all sorts are better off without a name
+        roles[x] = best.filter(|(_, count)| count * 3 >= total[x]).map(|(role, _)| role);
+    }
+
+    let mut aliases: [Option<String>; 16] = Default::default();
+    for (x, role) in roles.iter().enumerate() {
+        if let Some(role) = role {
+            let shared = roles.iter().filter(|other| *other == &Some(*role)).count() > 1;
+            aliases[x] = Some(if shared {format!("{role}-{x:x}")} else {role.to_string()});
+        }
+    }
+    aliases
+}
+
+struct Decompiler<'a> {
+    program: Program<'a>,
+    /// The instructions written as statements, which never overlap.
+    code: BTreeMap<u16, u16>,
+    /// The labels at each address, the first is the one referred to.
+    labels: BTreeMap<u16, Vec<String>>,
+    /// Labels already written, each goes out once.
+    written: BTreeSet<u16>,
+    /// Where the jumps left as `jump` go, which need labels.
+    jumps: BTreeSet<u16>,
+    aliases: [Option<String>; 16],
+    out: String,
+}
+
+impl Decompiler<'_> {
+    fn reg(&self, x : usize) -> String {
+        match &self.aliases[x] {
+            Some(alias) => alias.clone(),
+            None => format!("v{x:x}"),
+        }
+    }
+
+    fn end(&self) -> u16 {
+        START + self.program.len() as u16
+    }
+
+    fn size(&self, pc : u16) -> u16 {
+        if self.code.contains_key(&pc) {self.program.size(pc)} else {1}
+    }
+
+    /// Whether something starts at `addr` rather than it being in the
+    /// middle of an instruction.
+    fn is_boundary(&self, addr : u16) -> bool {
+        addr == self.end() || self.code.range(..addr).next_back().is_none_or(|(pc, _)| addr >= pc + self.program.size(*pc))
+    }
+
+    fn target(&self, addr : u16) -> String {
+        match self.labels.get(&addr) {
+            Some(labels) if self.is_boundary(addr) => labels[0].clone(),
+            _ => format!("0x{addr:03X}"),
+        }
+    }
+
+    fn line(&mut self, indent : usize, text : &str) {
+        let _ = writeln!(self.out, "{:width$}{text}", "", width = indent * 2);
+    }
+
+    /// An instruction Octo has no statement for, as its bytes.
+    fn raw(&mut self, indent : usize, pc : u16, opcode : u16) {
+        let instr = Instruction::decode(opcode);
+        self.line(indent, &format!("0x{:02X} 0x{:02X} # 0x{pc:03X}: {instr}", opcode >> 8, opcode & 0xFF));
+    }
+
+    /// The condition a skip instruction skips on.
+    fn condition(&self, instr : Instruction) -> Option<String> {
+        Some(match instr {
+            Instruction::SeByte(x, kk) => format!("{} == 0x{kk:02X}", self.reg(x)),
+            Instruction::SneByte(x, kk) => format!("{} != 0x{kk:02X}", self.reg(x)),
+            Instruction::SeReg(x, y) => format!("{} == {}", self.reg(x), self.reg(y)),
+            Instruction::SneReg(x, y) => format!("{} != {}", self.reg(x), self.reg(y)),
+            Instruction::Skp(x) => format!("{} key", self.reg(x)),
+            Instruction::Sknp(x) => format!("{} -key", self.reg(x)),
+            _ => return None,
+        })
+    }
+
+    fn statement(&mut self, pc : u16, opcode : u16) -> Option<String> {
+        let reg = |x| self.reg(x);
+        let x = ((opcode >> 8) & 0xF) as usize;
+        let y = ((opcode >> 4) & 0xF) as usize;
+        let statement = match Instruction::decode(opcode) {
+            Instruction::Cls => "clear".to_string(),
+            Instruction::Ret => "return".to_string(),
+            Instruction::Jp(addr) => format!("jump {}", self.target(addr)),
+            Instruction::Call(addr) => match self.labels.get(&addr) {
+                Some(_) if self.is_boundary(addr) => self.target(addr),
+                _ => format!(":call 0x{addr:03X}"),
+            },
+            Instruction::LdByte(x, kk) => format!("{} := 0x{kk:02X}", reg(x)),
+            Instruction::AddByte(x, kk) => format!("{} += 0x{kk:02X}", reg(x)),
+            Instruction::LdReg(x, y) => format!("{} := {}", reg(x), reg(y)),
+            Instruction::Or(x, y) => format!("{} |= {}", reg(x), reg(y)),
+            Instruction::And(x, y) => format!("{} &= {}", reg(x), reg(y)),
+            Instruction::Xor(x, y) => format!("{} ^= {}", reg(x), reg(y)),
+            Instruction::AddReg(x, y) => format!("{} += {}", reg(x), reg(y)),
+            Instruction::Sub(x, y) => format!("{} -= {}", reg(x), reg(y)),
+            Instruction::Shr(x, y) => format!("{} >>= {}", reg(x), reg(y)),
+            Instruction::Subn(x, y) => format!("{} =- {}", reg(x), reg(y)),
+            Instruction::Shl(x, y) => format!("{} <<= {}", reg(x), reg(y)),
+            Instruction::LdI(addr) => format!("i := {}", self.target(addr)),
+            Instruction::JpV0(addr) => format!("jump0 {}", self.target(addr)),
+            Instruction::Rnd(x, kk) => format!("{} := random 0x{kk:02X}", reg(x)),
+            Instruction::Drw(x, y, n) => format!("sprite {} {} {n}", reg(x), reg(y)),
+            Instruction::LdVxDt(x) => format!("{} := delay", reg(x)),
+            Instruction::LdVxK(x) => format!("{} := key", reg(x)),
+            Instruction::LdDtVx(x) => format!("delay := {}", reg(x)),
+            Instruction::LdStVx(x) => format!("buzzer := {}", reg(x)),
+            Instruction::AddI(x) => format!("i += {}", reg(x)),
+            Instruction::LdF(x) => format!("i := hex {}", reg(x)),
+            Instruction::LdB(x) => format!("bcd {}", reg(x)),
+            Instruction::LdIVx(x) => format!("save {}", reg(x)),
+            Instruction::LdVxI(x) => format!("load {}", reg(x)),
+            _ => match opcode {
+                0x00C0..=0x00CF => format!("scroll-down {}", opcode & 0xF),
+                0x00D0..=0x00DF => format!("scroll-up {}", opcode & 0xF),
+                0x00FB => "scroll-right".to_string(),
+                0x00FC => "scroll-left".to_string(),
+                0x00FD => "exit".to_string(),
+                0x00FE => "lores".to_string(),
+                0x00FF => "hires".to_string(),
+                0xF002 => "audio".to_string(),
+                0xF000 => format!("i := long {}", self.target(self.program.opcode(pc + 2)?)),
+                _ if opcode & 0xF00F == 0x5002 => format!("save {} - {}", reg(x), reg(y)),
+                _ if opcode & 0xF00F == 0x5003 => format!("load {} - {}", reg(x), reg(y)),
+                _ => match opcode & 0xF0FF {
+                    0xF001 => format!("plane {x}"),
+                    0xF030 => format!("i := bighex {}", reg(x)),
+                    0xF03A => format!("pitch := {}", reg(x)),
+                    0xF075 => format!("saveflags {}", reg(x)),
+                    0xF085 => format!("loadflags {}", reg(x)),
+                    _ => return None,
+                },
+            },
+        };
+
+        if let Instruction::Jp(addr) = Instruction::decode(opcode) {
+            self.jumps.insert(addr);
+        }
+        Some(statement)
+    }
+
+    /// The jump back closing a loop from `pc` to before `end`, the furthest
+    /// one when there are several.
+    fn again(&self, pc : u16, end : u16) -> Option<u16> {
+        self.code.range(pc..end.saturating_sub(1))
+            .rev()
+            .find(|(addr, opcode)| {
+                Instruction::decode(**opcode) == Instruction::Jp(pc)
+                    && !self.labels.contains_key(addr)
+                    // A skipped jump back is an `if ... then jump`
+                    && self.code.get(&addr.wrapping_sub(2))
+                        .is_none_or(|before| self.program.flow(**addr - 2, *before).next.len() != 2)
+            })
+            .map(|(addr, _)| *addr)
+    }
+
+    fn write_labels(&mut self, pc : u16, indent : usize) {
+        if let Some(labels) = self.labels.get(&pc).cloned()
+            && self.written.insert(pc) {
+            if indent == 1 && pc != START {
+                self.out.push('\n');
+            }
+            for label in labels {
+                self.line(indent - 1, &format!(": {label}"));
+            }
+        }
+    }
+
+    /// Writes everything from `pc` up to `end`, inside the loop ending at
+    /// `loop_end` if there is one.
+    fn block(&mut self, mut pc : u16, end : u16, loop_end : Option<u16>, indent : usize) {
+        while pc < end {
+            self.write_labels(pc, indent);
+
+            let Some(&opcode) = self.code.get(&pc) else {
+                // Data up to the next code or label, eight bytes a line
+                let mut bytes = Vec::new();
+                while pc < end && !self.code.contains_key(&pc) && bytes.len() < 8
+                    && (bytes.is_empty() || !self.labels.contains_key(&pc)) {
+                    bytes.push(format!("0x{:02X}", self.program.byte(pc)));
+                    pc += 1;
+                }
+                self.line(indent, &bytes.join(" "));
+                continue;
+            };
+
+            if let Some(again) = self.again(pc, end) {
+                self.line(indent, "loop");
+                self.block(pc, again, Some(again + 2), indent + 1);
+                self.line(indent, "again");
+                pc = again + 2;
+                continue;
+            }
+
+            let Some(condition) = self.condition(Instruction::decode(opcode)) else {
+                match self.statement(pc, opcode) {
+                    Some(statement) => self.line(indent, &statement),
+                    None => self.raw(indent, pc, opcode),
+                }
+                pc += self.size(pc);
+                continue;
+            };
+
+            // Skips go with the instruction they skip, which nothing else
+            // may jump to
+            let next = pc + 2;
+            let Some(next_opcode) = self.code.get(&next).copied().filter(|_| next < end && !self.labels.contains_key(&next)) else {
+                self.raw(indent, pc, opcode);
+                pc = next;
+                continue;
+            };
+
+            match Instruction::decode(next_opcode) {
+                Instruction::Jp(target) if Some(target) == loop_end => {
+                    self.line(indent, &format!("while {condition}"));
+                    pc = next + 2;
+                }
+                Instruction::Jp(target) if target >= next + 2 && target <= end && self.is_boundary(target) => {
+                    // A jump over the rest ending the block is the else
+                    let otherwise = self.code.get(&(target - 2))
+                        .and_then(|opcode| match Instruction::decode(*opcode) {
+                            Instruction::Jp(after) if target >= next + 4 => Some(after),
+                            _ => None,
+                        })
+                        .filter(|after| *after > target && *after <= end && self.is_boundary(*after)
+                            && !self.labels.contains_key(&(target - 2)));
+
+                    self.line(indent, &format!("if {condition} begin"));
+                    match otherwise {
+                        Some(after) => {
+                            self.block(next + 2, target - 2, loop_end, indent + 1);
+                            self.line(indent, "else");
+                            self.block(target, after, loop_end, indent + 1);
+                            pc = after;
+                        }
+                        None => {
+                            self.block(next + 2, target, loop_end, indent + 1);
+                            pc = target;
+                        }
+                    }
+                    self.line(indent, "end");
+                }
+                next_instr => {
+                    let statement = self.statement(next, next_opcode)
+                        .filter(|_| self.condition(next_instr).is_none() && next + self.size(next) <= end);
+                    match statement {
+                        Some(statement) => {
+                            self.line(indent, &format!("if {} then {statement}", negate(&condition)));
+                            pc = next + self.size(next);
+                        }
+                        None => {
+                            self.raw(indent, pc, opcode);
+                            pc = next;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The rom loaded at 0x200 as Octo source.
+pub fn decompile(bytes : &[u8], symbols : &Symbols) -> String {
+    decompile_with_coverage(bytes, symbols, &[])
+}
+
+/// Same as `decompile`, with the code and data told apart by the regions
+/// of a coverage report as well as by walking the code.
+pub fn decompile_with_coverage(bytes : &[u8], symbols : &Symbols, regions : &[(RangeInclusive<u16>, Usage)]) -> String {
+    let program = Program::new(bytes);
+    let cfg = Cfg::with_coverage(bytes, regions);
+    let end = START + program.len() as u16;
+
+    // Code reached in the middle of another instruction stays bytes
+    let starts: BTreeMap<u16, u16> = cfg.blocks.values().flat_map(|block| block.instructions.iter().copied()).collect();
+    let mut code = BTreeMap::new();
+    let mut addr = START;
+    while addr < end {
+        match starts.get(&addr) {
+            Some(opcode) if addr + program.size(addr) <= end => {
+                code.insert(addr, *opcode);
+                addr += program.size(addr);
+            }
+            _ => addr += 1,
+        }
+    }
+
+    let mut labels: BTreeMap<u16, Vec<String>> = BTreeMap::new();
+    labels.insert(START, vec!["main".to_string()]);
+    for (addr, name) in symbols.iter().filter(|(addr, _)| program.contains(*addr)) {
+        let name = identifier(name);
+        let names = labels.entry(addr).or_default();
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    let mut name = |addr : u16, prefix : &str| if program.contains(addr) {
+        labels.entry(addr).or_insert_with(|| vec![format!("{prefix}-{addr:03X}")]);
+    };
+    for entry in cfg.functions.keys() {
+        name(*entry, "sub");
+    }
+    for (pc, opcode) in &code {
+        match Instruction::decode(*opcode) {
+            Instruction::LdI(addr) => name(addr, "data"),
+            Instruction::JpV0(addr) => name(addr, "table"),
+            _ if *opcode == 0xF000 => name(program.opcode(pc + 2).unwrap_or_default(), "data"),
+            _ => {}
+        }
+    }
+
+    let mut decompiler = Decompiler {
+        program,
+        aliases: aliases(&code),
+        code,
+        labels,
+        written: BTreeSet::new(),
+        jumps: BTreeSet::new(),
+        out: String::new(),
+    };
+
+    // Labelling the jumps left over can stop some from being structured,
+    // which leaves more jumps over
+    loop {
+        decompiler.written.clear();
+        decompiler.jumps.clear();
+        decompiler.out.clear();
+        for (x, alias) in decompiler.aliases.iter().enumerate() {
+            if let Some(alias) = alias {
+                let _ = writeln!(decompiler.out, ":alias {alias} v{x:x}");
+            }
+        }
+        if decompiler.aliases.iter().any(Option::is_some) {
+            decompiler.out.push('\n');
+        }
+        decompiler.block(START, end, None, 1);
+
+        let missing: Vec<u16> = decompiler.jumps.iter()
+            .copied()
+            .filter(|addr| decompiler.program.contains(*addr) && decompiler.is_boundary(*addr) && !decompiler.labels.contains_key(addr))
+            .collect();
+        if missing.is_empty() {
+            return decompiler.out;
+        }
+        for addr in missing {
+            decompiler.labels.insert(addr, vec![format!("label-{addr:03X}")]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decompile() {
+        let program = [
+            0x60, 0x00, // 0x200: LD V0, 0x00
+            0xA2, 0x1A, // 0x202: LD I, 0x21A
+            0x22, 0x14, // 0x204: CALL 0x214
+            0x30, 0x05, // 0x206: SE V0, 0x05
+            0x12, 0x0E, // 0x208: JP 0x20E
+            0x61, 0x01, // 0x20A: LD V1, 0x01
+            0x12, 0x10, // 0x20C: JP 0x210
+            0x61, 0x02, // 0x20E: LD V1, 0x02
+            0x12, 0x04, // 0x210: JP 0x204
+            0x00, 0x00, // 0x212: unreachable
+            0x70, 0x01, // 0x214: ADD V0, 0x01
+            0x41, 0x00, // 0x216: SNE V1, 0x00
+            0xD0, 0x11, // 0x218: DRW V0, V1, 1
+            0x00, 0xEE, // 0x21A: RET
+        ];
+        let symbols = Symbols::parse("0x214 step").unwrap();
+        assert_eq!(decompile(&program, &symbols), "\
+: main
+  v0 := 0x00
+  i := data-21A
+  loop
+    step
+    if v0 == 0x05 begin
+      v1 := 0x01
+    else
+      v1 := 0x02
+    end
+  again
+  0x00 0x00
+
+: step
+  v0 += 0x01
+  if v1 == 0x00 then sprite v0 v1 1
+
+: data-21A
+  return
+");
+    }
+
+    #[test]
+    fn test_leftover_jumps_get_labels() {
+        // 0x200: JP 0x204, 0x202: LD V0, 0x01, 0x204: LD V1, 0x02, 0x206: JP 0x202
+        let source = decompile(&[0x12, 0x04, 0x60, 0x01, 0x61, 0x02, 0x12, 0x02], &Symbols::new());
+        assert_eq!(source, "\
+: main
+  jump label-204
+  loop
+    v0 := 0x01
+  : label-204
+    v1 := 0x02
+  again
+");
+    }
+
+    #[test]
+    fn test_decompile_with_coverage() {
+        let program = [
+            0x22, 0x06, // 0x200: CALL 0x206
+            0xF0, 0x90, // 0x202: sprite, as the call never returns
+            0xA2, 0x02, // 0x204: never run
+            0xB2, 0x08, // 0x206: JP V0, 0x208
+            0xD0, 0x12, // 0x208: DRW V0, V1, 2
+            0x12, 0x0A, // 0x20A: JP 0x20A
+        ];
+        let regions = crate::coverage::parse_regions("0x200-0x201 code\n0x202-0x203 data read\n0x206-0x20B code").unwrap();
+        // Only running it shows that the jump lands on code
+        assert!(decompile(&program, &Symbols::new()).contains(": table-208\n  0xD0 0x12 0x12 0x0A\n"));
+        assert_eq!(decompile_with_coverage(&program, &Symbols::new(), &regions), "\
+:alias sprite-x v0
+:alias sprite-y v1
+
+: main
+  sub-206
+  0xF0 0x90 0xA2 0x02
+
+: sub-206
+  jump0 table-208
+
+: table-208
+  sprite sprite-x sprite-y 2
+  loop
+  again
+");
+    }
+}
@@ -0,0 +1,109 @@
+pub const WIDTH : usize = 64;
+pub const HEIGHT : usize = 32;
+
+/// The screen as the machine sees it, independent of whatever draws it.
+#[derive(Debug, Clone)]
+pub struct Display {
+    pixels : [bool; WIDTH * HEIGHT],
+    /// Set whenever the pixels change, frontends clear it once drawn.
+    pub dirty: bool,
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display {
+    pub fn new() -> Self {
+        Self {
+            pixels: [false; WIDTH * HEIGHT],
+            dirty: true,
+        }
+    }
+
+    pub fn pixels(&self) -> &[bool; WIDTH * HEIGHT] {
+        &self.pixels
+    }
+
+    /// The width and height in pixels the screen is at now. There is no
+    /// high resolution mode yet, SCHIP's 128x64 comes with its
+    /// instructions, so this is always 64x32.
+    pub fn resolution(&self) -> (usize, usize) {
+        (WIDTH, HEIGHT)
+    }
+
+    pub fn pixel(&self, x : usize, y : usize) -> bool {
+        self.pixels[y * WIDTH + x]
+    }
+
+    pub fn clear(&mut self) {
+        self.pixels = [false; WIDTH * HEIGHT];
+        self.dirty = true;
+    }
+
+    /// Draws the sprite with its top left corner at the given position,
+    /// returning whether any pixel was turned off. The position wraps around
+    /// the screen, but the parts of the sprite that go past the edges are cut.
+    pub fn xor_sprite(&mut self, pos_x : usize, pos_y : usize, sprite_data : &[u8]) -> bool {
+        let pos_x = pos_x % WIDTH;
+        let pos_y = pos_y % HEIGHT;
+        let mut ret_flag = false;
+
+        for (row, value) in sprite_data.iter().enumerate() {
+            let y = pos_y + row;
+            if y >= HEIGHT {
+                break;
+            }
+
+            for column in 0..8 {
+                let x = pos_x + column;
+                if x >= WIDTH {
+                    break;
+                }
+
+                if (value & (0x80 >> column)) != 0 {
+                    let pixel = &mut self.pixels[y * WIDTH + x];
+                    ret_flag |= *pixel;
+                    *pixel = !*pixel;
+                }
+            }
+        }
+
+        self.dirty = true;
+        ret_flag
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xor_sprite() {
+        let mut display = Display::new();
+        assert!(!display.xor_sprite(1, 2, &[0xC0]));
+        assert!(display.pixel(1, 2) && display.pixel(2, 2) && !display.pixel(3, 2));
+        // Drawing it again turns it off and reports the collision
+        assert!(display.xor_sprite(1, 2, &[0xC0]));
+        assert!(display.pixels().iter().all(|pixel| !pixel));
+    }
+
+    #[test]
+    fn test_xor_sprite_wraps_start() {
+        let mut display = Display::new();
+        display.xor_sprite(WIDTH + 3, HEIGHT + 4, &[0x80]);
+        assert!(display.pixel(3, 4));
+        assert_eq!(display.pixels().iter().filter(|pixel| **pixel).count(), 1);
+    }
+
+    #[test]
+    fn test_xor_sprite_clips_at_edges() {
+        let mut display = Display::new();
+        display.xor_sprite(WIDTH - 2, HEIGHT - 1, &[0xFF, 0xFF]);
+        assert!(display.pixel(WIDTH - 2, HEIGHT - 1) && display.pixel(WIDTH - 1, HEIGHT - 1));
+        // Nothing wraps around to the other side
+        assert_eq!(display.pixels().iter().filter(|pixel| **pixel).count(), 2);
+    }
+}
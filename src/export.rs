@@ -0,0 +1,180 @@
+/* Writes what the machine shows and plays as files for a video encoder: the
+screen as a Y4M stream at 60 frames a second and the beeper as a WAV, so
+
+    ffmpeg -i game.y4m -i game.wav -c:v libx264 -crf 0 game.mp4
+
+makes a video of it. The audio runs at 48kHz, exactly 800 samples a frame,
+so it never drifts from the video.
+
+The video is 4:4:4 so the edges of pixels stay sharp. The beeper is a
+square wave for every frame the sound timer runs; XO-CHIP audio patterns are
+not emulated, so roms using them get the plain tone. */
+
+use std::io::{self, Seek, SeekFrom, Write};
+
+use crate::display::{Display, HEIGHT, WIDTH};
+use crate::palette::Palette;
+
+pub const SAMPLE_RATE : u32 = 48_000;
+pub const SAMPLES_PER_FRAME : u32 = SAMPLE_RATE / 60;
+/// The pitch of the beeper, in Hz.
+pub const TONE : u32 = 440;
+const AMPLITUDE : i16 = i16::MAX / 4;
+
+/// A colour as BT.601 Y, Cb and Cr in the limited range encoders expect.
+fn ycbcr([r, g, b] : [u8; 3]) -> [u8; 3] {
+    let (r, g, b) = (r as f32, g as f32, b as f32);
+    let y = 16.0 + (65.481 * r + 128.553 * g + 24.966 * b) / 255.0;
+    let cb = 128.0 + (-37.797 * r - 74.203 * g + 112.0 * b) / 255.0;
+    let cr = 128.0 + (112.0 * r - 93.786 * g - 18.214 * b) / 255.0;
+    [y.round() as u8, cb.round() as u8, cr.round() as u8]
+}
+
+/// The screen as a Y4M stream.
+pub struct Video<W : Write> {
+    output: W,
+    scale: usize,
+    /// The colours of the palette, as Y, Cb and Cr.
+    colours: [[u8; 3]; 4],
+    plane: Vec<u8>,
+}
+
+impl<W : Write> Video<W> {
+    pub fn new(mut output : W, palette : &Palette, scale : usize) -> io::Result<Self> {
+        let scale = scale.max(1);
+        writeln!(output, "YUV4MPEG2 W{} H{} F60:1 Ip A1:1 C444 XCOLORRANGE=LIMITED", WIDTH * scale, HEIGHT * scale)?;
+        Ok(Self {
+            output,
+            scale,
+            colours: palette.colors().map(ycbcr),
+            plane: Vec::with_capacity(WIDTH * HEIGHT * scale * scale),
+        })
+    }
+
+    pub fn frame(&mut self, display : &Display) -> io::Result<()> {
+        self.output.write_all(b"FRAME\n")?;
+        for component in 0..3 {
+            self.plane.clear();
+            for y in 0..HEIGHT * self.scale {
+                for x in 0..WIDTH * self.scale {
+                    let on = display.pixel(x / self.scale, y / self.scale);
+                    self.plane.push(self.colours[on as usize][component]);
+                }
+            }
+            self.output.write_all(&self.plane)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.output.flush()?;
+        Ok(self.output)
+    }
+}
+
+/// The beeper as a 16 bit mono WAV. The sizes in the header are only known
+/// at the end, so the output has to seek.
+pub struct Audio<W : Write + Seek> {
+    output: W,
+    /// Samples written so far, which also keeps the phase of the tone.
+    samples: u32,
+    frame: Vec<u8>,
+}
+
+impl<W : Write + Seek> Audio<W> {
+    pub fn new(mut output : W) -> io::Result<Self> {
+        output.write_all(&header(0))?;
+        Ok(Self { output, samples: 0, frame: Vec::with_capacity(SAMPLES_PER_FRAME as usize * 2) })
+    }
+
+    /// Writes a frame of sound, the tone when `beeping` and silence
+    /// otherwise.
+    pub fn frame(&mut self, beeping : bool) -> io::Result<()> {
+        self.frame.clear();
+        for sample in self.samples..self.samples + SAMPLES_PER_FRAME {
+            let high = (sample as u64 * TONE as u64 * 2 / SAMPLE_RATE as u64).is_multiple_of(2);
+            let value = match (beeping, high) {
+                (false, _) => 0,
+                (true, true) => AMPLITUDE,
+                (true, false) => -AMPLITUDE,
+            };
+            self.frame.extend(value.to_le_bytes());
+        }
+        self.samples += SAMPLES_PER_FRAME;
+        self.output.write_all(&self.frame)
+    }
+
+    /// Fills in the sizes in the header.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.output.seek(SeekFrom::Start(0))?;
+        self.output.write_all(&header(self.samples))?;
+        self.output.seek(SeekFrom::End(0))?;
+        self.output.flush()?;
+        Ok(self.output)
+    }
+}
+
+fn header(samples : u32) -> Vec<u8> {
+    let data = samples * 2;
+    let mut header = Vec::with_capacity(44);
+    header.extend(b"RIFF");
+    header.extend((36 + data).to_le_bytes());
+    header.extend(b"WAVEfmt ");
+    header.extend(16u32.to_le_bytes());
+    // PCM, mono
+    header.extend(1u16.to_le_bytes());
+    header.extend(1u16.to_le_bytes());
+    header.extend(SAMPLE_RATE.to_le_bytes());
+    header.extend((SAMPLE_RATE * 2).to_le_bytes());
+    // Bytes a sample, bits a sample
+    header.extend(2u16.to_le_bytes());
+    header.extend(16u16.to_le_bytes());
+    header.extend(b"data");
+    header.extend(data.to_le_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_video() {
+        let mut display = Display::new();
+        display.xor_sprite(0, 0, &[0x80]);
+        let mut video = Video::new(Vec::new(), &Palette::default(), 2).unwrap();
+        video.frame(&display).unwrap();
+        video.frame(&display).unwrap();
+        let bytes = video.finish().unwrap();
+
+        let header = b"YUV4MPEG2 W128 H64 F60:1 Ip A1:1 C444 XCOLORRANGE=LIMITED\n";
+        assert!(bytes.starts_with(header));
+        let frame = &bytes[header.len()..];
+        assert!(frame.starts_with(b"FRAME\n"));
+        assert_eq!(bytes.len(), header.len() + 2 * (6 + 128 * 64 * 3));
+        // White then black, in luma then chroma
+        assert_eq!(frame[6..9], [235, 235, 16]);
+        assert_eq!(frame[6 + 128 * 64], 128);
+    }
+
+    #[test]
+    fn test_audio() {
+        let mut audio = Audio::new(Cursor::new(Vec::new())).unwrap();
+        audio.frame(false).unwrap();
+        audio.frame(true).unwrap();
+        let bytes = audio.finish().unwrap().into_inner();
+
+        assert_eq!(bytes.len(), 44 + 2 * 800 * 2);
+        assert_eq!(bytes[4..8], (36 + 3200u32).to_le_bytes());
+        assert_eq!(bytes[40..44], 3200u32.to_le_bytes());
+        let sample = |index : usize| i16::from_le_bytes([bytes[44 + index * 2], bytes[45 + index * 2]]);
+        assert_eq!(sample(799), 0);
+        assert_ne!(sample(800), 0);
+        // A whole period of the tone has as many high samples as low ones
+        let period = (SAMPLE_RATE / TONE) as usize;
+        let high = (800..800 + period).filter(|index| sample(*index) > 0).count();
+        assert!(high.abs_diff(period / 2) <= 1);
+    }
+}
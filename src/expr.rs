@@ -0,0 +1,417 @@
+/* A small expression language over the state of the machine, for
+breakpoint conditions and for printing values in the debuggers:
+
+    V3 == 0x10 && I > 0x300
+    mem[0x2F0] != 0
+    DT == 0 || hits % 3 == 0
+
+Values are V0-VF, I, PC, SP, DT, ST, `mem[addr]` for a byte of memory and
+`hits`, the times the breakpoint being checked was reached. Operators and
+their precedence follow Rust, comparisons and logic giving 1 or 0. Numbers
+starting with 0x are hexadecimal, 0b binary and the rest decimal.
+*/
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail};
+
+use crate::cpu::Cpu;
+use crate::debugger::Register;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    BitNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    BitAnd,
+    BitXor,
+    BitOr,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+impl BinaryOp {
+    /// Higher binds tighter.
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => 9,
+            BinaryOp::Add | BinaryOp::Sub => 8,
+            BinaryOp::Shl | BinaryOp::Shr => 7,
+            BinaryOp::BitAnd => 6,
+            BinaryOp::BitXor => 5,
+            BinaryOp::BitOr => 4,
+            BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => 3,
+            BinaryOp::And => 2,
+            BinaryOp::Or => 1,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Rem => "%",
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Shl => "<<",
+            BinaryOp::Shr => ">>",
+            BinaryOp::BitAnd => "&",
+            BinaryOp::BitXor => "^",
+            BinaryOp::BitOr => "|",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::And => "&&",
+            BinaryOp::Or => "||",
+        }
+    }
+
+    fn from_symbol(symbol : &str) -> Option<Self> {
+        const OPS : [BinaryOp; 18] = [
+            BinaryOp::Mul, BinaryOp::Div, BinaryOp::Rem, BinaryOp::Add, BinaryOp::Sub, BinaryOp::Shl,
+            BinaryOp::Shr, BinaryOp::BitAnd, BinaryOp::BitXor, BinaryOp::BitOr, BinaryOp::Eq, BinaryOp::Ne,
+            BinaryOp::Lt, BinaryOp::Le, BinaryOp::Gt, BinaryOp::Ge, BinaryOp::And, BinaryOp::Or,
+        ];
+        OPS.into_iter().find(|op| op.symbol() == symbol)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(i64),
+    Register(Register),
+    Hits,
+    Mem(Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    /// Evaluates the expression, `hits` being an error outside of
+    /// breakpoints.
+    pub fn eval(&self, cpu : &Cpu) -> anyhow::Result<i64> {
+        self.evaluate(cpu, None)
+    }
+
+    /// Evaluates the expression for a breakpoint reached `hits` times.
+    pub fn eval_at_breakpoint(&self, cpu : &Cpu, hits : u64) -> anyhow::Result<i64> {
+        self.evaluate(cpu, Some(hits))
+    }
+
+    fn evaluate(&self, cpu : &Cpu, hits : Option<u64>) -> anyhow::Result<i64> {
+        let value = match self {
+            Expr::Number(value) => *value,
+            Expr::Register(register) => register.value(cpu) as i64,
+            Expr::Hits => hits.ok_or_else(|| anyhow!("hits only has a value in breakpoint conditions"))? as i64,
+            Expr::Mem(addr) => {
+                let addr = addr.evaluate(cpu, hits)?;
+                cpu.memory()[addr.rem_euclid(4096) as usize] as i64
+            }
+            Expr::Unary(op, operand) => {
+                let operand = operand.evaluate(cpu, hits)?;
+                match op {
+                    UnaryOp::Neg => operand.wrapping_neg(),
+                    UnaryOp::Not => (operand == 0) as i64,
+                    UnaryOp::BitNot => !operand,
+                }
+            }
+            // Short circuit, so `hits > 3 && mem[I] == 0` can guard the rest
+            Expr::Binary(BinaryOp::And, left, right) => (left.evaluate(cpu, hits)? != 0 && right.evaluate(cpu, hits)? != 0) as i64,
+            Expr::Binary(BinaryOp::Or, left, right) => (left.evaluate(cpu, hits)? != 0 || right.evaluate(cpu, hits)? != 0) as i64,
+            Expr::Binary(op, left, right) => {
+                let left = left.evaluate(cpu, hits)?;
+                let right = right.evaluate(cpu, hits)?;
+                match op {
+                    BinaryOp::Mul => left.wrapping_mul(right),
+                    BinaryOp::Div | BinaryOp::Rem if right == 0 => bail!("division by zero"),
+                    BinaryOp::Div => left.wrapping_div(right),
+                    BinaryOp::Rem => left.wrapping_rem(right),
+                    BinaryOp::Add => left.wrapping_add(right),
+                    BinaryOp::Sub => left.wrapping_sub(right),
+                    BinaryOp::Shl => left.wrapping_shl(right as u32),
+                    BinaryOp::Shr => left.wrapping_shr(right as u32),
+                    BinaryOp::BitAnd => left & right,
+                    BinaryOp::BitXor => left ^ right,
+                    BinaryOp::BitOr => left | right,
+                    BinaryOp::Eq => (left == right) as i64,
+                    BinaryOp::Ne => (left != right) as i64,
+                    BinaryOp::Lt => (left < right) as i64,
+                    BinaryOp::Le => (left <= right) as i64,
+                    BinaryOp::Gt => (left > right) as i64,
+                    BinaryOp::Ge => (left >= right) as i64,
+                    BinaryOp::And | BinaryOp::Or => unreachable!(),
+                }
+            }
+        };
+
+        Ok(value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Symbol(&'static str),
+}
+
+fn tokenize(text : &str) -> anyhow::Result<Vec<Token>> {
+    // Longest first, so `<=` is not read as `<` then `=`
+    const SYMBOLS : [&str; 24] = [
+        "==", "!=", "<=", ">=", "&&", "||", "<<", ">>",
+        "<", ">", "+", "-", "*", "/", "%", "&", "|", "^", "!", "~", "(", ")", "[", "]",
+    ];
+
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let word_len = rest.find(|c : char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+        if word_len > 0 {
+            let word = &rest[..word_len];
+            if word.starts_with(|c : char| c.is_ascii_digit()) {
+                tokens.push(Token::Number(parse_number(word)?));
+            } else {
+                tokens.push(Token::Name(word.to_string()));
+            }
+            rest = &rest[word_len..];
+        } else if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        } else {
+            bail!("unexpected {} in {text}", rest.chars().next().unwrap_or_default());
+        }
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+fn parse_number(word : &str) -> anyhow::Result<i64> {
+    let lower = word.to_ascii_lowercase();
+    let parsed = if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16)
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2)
+    } else {
+        lower.parse()
+    };
+    parsed.map_err(|_| anyhow!("{word} is not a number"))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, symbol : &str) -> anyhow::Result<()> {
+        match self.next() {
+            Some(Token::Symbol(found)) if found == symbol => Ok(()),
+            _ => bail!("expected {symbol}"),
+        }
+    }
+
+    /// Parses operators binding at least as tight as `min_precedence`.
+    fn binary(&mut self, min_precedence : u8) -> anyhow::Result<Expr> {
+        let mut left = self.unary()?;
+        while let Some(Token::Symbol(symbol)) = self.peek()
+            && let Some(op) = BinaryOp::from_symbol(symbol)
+            && op.precedence() >= min_precedence {
+            self.position += 1;
+            let right = self.binary(op.precedence() + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> anyhow::Result<Expr> {
+        let op = match self.peek() {
+            Some(Token::Symbol("-")) => UnaryOp::Neg,
+            Some(Token::Symbol("!")) => UnaryOp::Not,
+            Some(Token::Symbol("~")) => UnaryOp::BitNot,
+            _ => return self.primary(),
+        };
+        self.position += 1;
+        Ok(Expr::Unary(op, Box::new(self.unary()?)))
+    }
+
+    fn primary(&mut self) -> anyhow::Result<Expr> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Expr::Number(value)),
+            Some(Token::Symbol("(")) => {
+                let expr = self.binary(0)?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Some(Token::Name(name)) if name.eq_ignore_ascii_case("mem") => {
+                self.expect("[")?;
+                let addr = self.binary(0)?;
+                self.expect("]")?;
+                Ok(Expr::Mem(Box::new(addr)))
+            }
+            Some(Token::Name(name)) if name.eq_ignore_ascii_case("hits") => Ok(Expr::Hits),
+            Some(Token::Name(name)) => Ok(Expr::Register(name.parse()?)),
+            Some(Token::Symbol(symbol)) => bail!("unexpected {symbol}"),
+            None => bail!("the expression ends too early"),
+        }
+    }
+}
+
+impl FromStr for Expr {
+    type Err = anyhow::Error;
+
+    fn from_str(text : &str) -> anyhow::Result<Self> {
+        let mut parser = Parser { tokens: tokenize(text)?, position: 0 };
+        let expr = parser.binary(0)?;
+        if parser.position < parser.tokens.len() {
+            bail!("unexpected {:?} after the expression", parser.tokens[parser.position]);
+        }
+        Ok(expr)
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Number(value) if *value > 9 => write!(f, "0x{value:X}"),
+            Expr::Number(value) => write!(f, "{value}"),
+            Expr::Register(register) => write!(f, "{register}"),
+            Expr::Hits => write!(f, "hits"),
+            Expr::Mem(addr) => write!(f, "mem[{addr}]"),
+            Expr::Unary(op, operand) => {
+                let symbol = match op {
+                    UnaryOp::Neg => "-",
+                    UnaryOp::Not => "!",
+                    UnaryOp::BitNot => "~",
+                };
+                match **operand {
+                    Expr::Binary(..) => write!(f, "{symbol}({operand})"),
+                    _ => write!(f, "{symbol}{operand}"),
+                }
+            }
+            Expr::Binary(op, left, right) => {
+                // Operators are left associative, so only the right side
+                // needs parentheses at the same precedence
+                let wrap = |side : &Expr, right : bool| match side {
+                    Expr::Binary(inner, ..) => inner.precedence() < op.precedence() || (right && inner.precedence() == op.precedence()),
+                    _ => false,
+                };
+                if wrap(left, false) {write!(f, "({left})")?} else {write!(f, "{left}")?}
+                write!(f, " {} ", op.symbol())?;
+                if wrap(right, true) {write!(f, "({right})")} else {write!(f, "{right}")}
+            }
+        }
+    }
+}
+
+/// Replaces each `{expression}` in `message` with its value, the way log
+/// points print. `{{` and `}}` stand for braces.
+pub fn interpolate(message : &str, cpu : &Cpu, hits : Option<u64>) -> String {
+    let mut out = String::new();
+    let mut rest = message;
+    while let Some(start) = rest.find(['{', '}']) {
+        out.push_str(&rest[..start]);
+        let brace = &rest[start..];
+        if brace.starts_with("{{") || brace.starts_with("}}") {
+            out.push_str(&brace[..1]);
+            rest = &brace[2..];
+            continue;
+        }
+
+        match brace.strip_prefix('{').and_then(|inner| inner.split_once('}')) {
+            Some((source, after)) => {
+                let value = source.parse::<Expr>().and_then(|expr| expr.evaluate(cpu, hits));
+                match value {
+                    Ok(value) => out.push_str(&format!("0x{value:X}")),
+                    Err(error) => out.push_str(&format!("<{error:#}>")),
+                }
+                rest = after;
+            }
+            None => {
+                out.push_str(&brace[..1]);
+                rest = &brace[1..];
+            }
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn cpu() -> Cpu {
+        let mut cpu = Cpu::new(Arc::new([false; 16]));
+        cpu.reg[3] = 0x10;
+        cpu.set_i_reg(0x310);
+        cpu.memory_mut()[0x2F0] = 7;
+        cpu
+    }
+
+    #[test]
+    fn test_eval() {
+        let cpu = cpu();
+        let eval = |text : &str| text.parse::<Expr>().unwrap().eval(&cpu).unwrap();
+        assert_eq!(eval("V3 == 0x10 && I > 0x300"), 1);
+        assert_eq!(eval("mem[0x2F0] != 0"), 1);
+        assert_eq!(eval("mem[0x2E0 + 0x10] * 2 + 1"), 15);
+        assert_eq!(eval("dt == 0 || v3 == 0"), 1);
+        assert_eq!(eval("1 + 2 * 3 == 7 & 0xF"), 1);
+        assert_eq!(eval("!(v3 & 0x10) - -2"), 2);
+        assert!("V3 / V0".parse::<Expr>().unwrap().eval(&cpu).is_err());
+        assert!("hits".parse::<Expr>().unwrap().eval(&cpu).is_err());
+        assert_eq!("hits % 3 == 0".parse::<Expr>().unwrap().eval_at_breakpoint(&cpu, 6).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_parse_errors_and_display() {
+        assert!("V3 ==".parse::<Expr>().is_err());
+        assert!("VG".parse::<Expr>().is_err());
+        assert!("mem[1".parse::<Expr>().is_err());
+        assert!("1 2".parse::<Expr>().is_err());
+        assert_eq!("(v3==16)&&(I>0x300)".parse::<Expr>().unwrap().to_string(), "V3 == 0x10 && I > 0x300");
+        assert_eq!("(1 + 2) * (3 - (4 - 5))".parse::<Expr>().unwrap().to_string(), "(1 + 2) * (3 - (4 - 5))");
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let cpu = cpu();
+        assert_eq!(interpolate("V3 is {V3}, {{literal}} {nope}", &cpu, None), "V3 is 0x10, {literal} <unknown register nope>");
+        assert_eq!(interpolate("hit {hits} times", &cpu, Some(2)), "hit 0x2 times");
+    }
+}
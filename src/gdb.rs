@@ -0,0 +1,584 @@
+/* A stub speaking the GDB remote serial protocol, so gdb, lldb and the
+editors built on them can debug roms. The machine is described to them
+through target.xml as 21 registers, in the order of `REGISTERS`:
+
+    v0-vf  8 bits
+    i      16 bits, little endian like everything the protocol sends
+    pc     16 bits
+    sp     8 bits, how many addresses are on the stack
+    dt     8 bits
+    st     8 bits
+
+Only one client is served at a time. The machine stays paused until one
+connects and for as long as it does not ask it to run.
+
+`monitor` passes its line to the debugger's own commands, for the ones gdb
+has no equivalent of like `monitor break 0x204 if V3 == 0x10` or
+`monitor log 0x204 V0 is {V0}`.
+*/
+
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::arch::Arch;
+use crate::debugger::{Command, Debugger, Register, Stop};
+use crate::watch::{Location, WatchHit, WatchKind, WatchTarget, Watchpoint};
+
+const REGISTERS : [Register; 21] = [
+    Register::V(0), Register::V(1), Register::V(2), Register::V(3),
+    Register::V(4), Register::V(5), Register::V(6), Register::V(7),
+    Register::V(8), Register::V(9), Register::V(10), Register::V(11),
+    Register::V(12), Register::V(13), Register::V(14), Register::V(15),
+    Register::I, Register::Pc, Register::Sp, Register::Dt, Register::St,
+];
+
+fn register_size(register : Register) -> usize {
+    match register {
+        Register::I | Register::Pc => 2,
+        _ => 1,
+    }
+}
+
+fn target_xml() -> String {
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\"?>\n",
+        "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n",
+        "<target version=\"1.0\">\n",
+        "<feature name=\"org.chip8.core\">\n",
+    ));
+    for register in REGISTERS {
+        let kind = match register {
+            Register::I => "data_ptr",
+            Register::Pc => "code_ptr",
+            _ => "uint8",
+        };
+        let _ = writeln!(xml, "<reg name=\"{}\" bitsize=\"{}\" type=\"{kind}\"/>",
+            register.to_string().to_lowercase(), register_size(register) * 8);
+    }
+    xml.push_str("</feature>\n</target>\n");
+    xml
+}
+
+enum Packet {
+    Command(String),
+    Interrupt,
+}
+
+pub struct GdbStub {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    pub debugger: Debugger,
+    buffer: Vec<u8>,
+    no_ack: bool,
+    /// Set while the client waits for the machine to stop.
+    running: bool,
+}
+
+impl GdbStub {
+    pub fn bind(addr : impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr)?,
+            client: None,
+            debugger: Debugger::new(),
+            buffer: Vec::new(),
+            no_ack: false,
+            running: false,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serves the client for a frame: runs a frame worth of instructions
+    /// when it asked to continue, otherwise handles its packets. When `wait`
+    /// is set it blocks for a client or a packet instead of returning.
+    /// Returns false once the client has gone.
+    pub fn frame(&mut self, arch : &mut Arch, wait : bool) -> io::Result<bool> {
+        if self.client.is_none() {
+            self.listener.set_nonblocking(!wait)?;
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nodelay(true)?;
+                    self.client = Some(stream);
+                    self.buffer.clear();
+                    self.no_ack = false;
+                    self.running = false;
+                    self.debugger.pause();
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+                Err(error) => return Err(error),
+            }
+        }
+
+        if self.running {
+            let stop = self.debugger.run(arch, arch.cycles_per_frame.max(1));
+            self.send_messages()?;
+            if let Some(stop) = stop {
+                self.running = false;
+                self.send(&stop_reply(stop))?;
+            }
+        }
+
+        // While running, only an interrupt can come, so never block for it
+        if !self.receive(wait && !self.running)? {
+            return Ok(self.disconnect());
+        }
+
+        while let Some(packet) = self.next_packet()? {
+            let keep_going = match packet {
+                Packet::Interrupt => {
+                    if self.running {
+                        self.debugger.pause();
+                        self.running = false;
+                        self.send(&stop_reply(Stop::Interrupt))?;
+                    }
+                    true
+                }
+                Packet::Command(command) => self.handle(arch, &command)?,
+            };
+
+            if !keep_going {
+                return Ok(self.disconnect());
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn disconnect(&mut self) -> bool {
+        self.client = None;
+        self.running = false;
+        false
+    }
+
+    /// Reads whatever the client sent, returns false when it hung up.
+    fn receive(&mut self, block : bool) -> io::Result<bool> {
+        let Some(client) = &mut self.client else {
+            return Ok(false);
+        };
+
+        client.set_nonblocking(!block)?;
+        let mut chunk = [0u8; 4096];
+        match client.read(&mut chunk) {
+            Ok(0) => Ok(false),
+            Ok(read) => {
+                self.buffer.extend_from_slice(&chunk[..read]);
+                Ok(true)
+            }
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => Ok(true),
+            Err(error) if error.kind() == io::ErrorKind::ConnectionReset => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Takes the next complete packet out of the buffer, acknowledging it.
+    fn next_packet(&mut self) -> io::Result<Option<Packet>> {
+        loop {
+            match self.buffer.first() {
+                None => return Ok(None),
+                Some(0x03) => {
+                    self.buffer.remove(0);
+                    return Ok(Some(Packet::Interrupt));
+                }
+                Some(b'$') => break,
+                // Acks of our replies and line noise
+                Some(_) => {
+                    self.buffer.remove(0);
+                }
+            }
+        }
+
+        let Some(end) = self.buffer.iter().position(|byte| *byte == b'#') else {
+            return Ok(None);
+        };
+        if self.buffer.len() < end + 3 {
+            return Ok(None);
+        }
+
+        let packet: Vec<u8> = self.buffer.drain(..(end + 3)).collect();
+        let data = &packet[1..end];
+        let checksum = std::str::from_utf8(&packet[(end + 1)..])
+            .ok()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        let valid = checksum == Some(data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)));
+
+        if !self.no_ack {
+            self.write_raw(if valid {b"+"} else {b"-"})?;
+        }
+        if !valid {
+            return self.next_packet();
+        }
+
+        Ok(Some(Packet::Command(String::from_utf8_lossy(data).into_owned())))
+    }
+
+    fn write_raw(&mut self, bytes : &[u8]) -> io::Result<()> {
+        match &mut self.client {
+            Some(client) => {
+                client.set_nonblocking(false)?;
+                client.write_all(bytes)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn send(&mut self, data : &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        self.write_raw(format!("${data}#{checksum:02x}").as_bytes())
+    }
+
+    /// Sends what log breakpoints printed to the gdb console.
+    fn send_messages(&mut self) -> io::Result<()> {
+        let messages: Vec<String> = self.debugger.messages.drain(..).collect();
+        for message in messages {
+            self.send(&format!("O{}", encode_hex(format!("{message}\n").as_bytes())))?;
+        }
+        Ok(())
+    }
+
+    /// Runs a debugger command for `monitor`, its output going to the gdb
+    /// console.
+    fn monitor(&mut self, arch : &mut Arch, hex : &str) -> io::Result<String> {
+        let Some(line) = decode_hex(hex).and_then(|bytes| String::from_utf8(bytes).ok()) else {
+            return Ok(String::from("E01"));
+        };
+
+        let output = match Command::parse(&line, &self.debugger.symbols) {
+            Ok(Command::Step(_) | Command::Next | Command::Finish | Command::Continue | Command::Quit) => {
+                String::from("Use gdb's own commands to run the machine")
+            }
+            Ok(command) => match self.debugger.execute(arch, &command) {
+                Ok(output) => output,
+                Err(error) => format!("{error:#}"),
+            },
+            Err(error) => format!("{error:#}"),
+        };
+
+        if !output.is_empty() {
+            self.send(&format!("O{}", encode_hex(format!("{output}\n").as_bytes())))?;
+        }
+        Ok(String::from("OK"))
+    }
+
+    /// Handles a packet, returns false when the client is done with us.
+    fn handle(&mut self, arch : &mut Arch, packet : &str) -> io::Result<bool> {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => stop_reply(Stop::Step),
+            Some(b'g') => read_registers(arch),
+            Some(b'G') => ok_or_error(write_registers(arch, &packet[1..])),
+            Some(b'p') => read_register(arch, &packet[1..]).unwrap_or_else(|| String::from("E01")),
+            Some(b'P') => ok_or_error(write_register(arch, &packet[1..])),
+            Some(b'm') => read_memory(arch, &packet[1..]).unwrap_or_else(|| String::from("E01")),
+            Some(b'M') => ok_or_error(write_memory(arch, &packet[1..])),
+            Some(b'Z') | Some(b'z') => self.breakpoint(packet),
+            Some(b's') => self.step(arch)?,
+            Some(b'c') => return self.resume(),
+            Some(b'v') if packet.starts_with("vCont;") => {
+                match packet.as_bytes().get(6) {
+                    Some(b's') => self.step(arch)?,
+                    Some(b'c') => return self.resume(),
+                    _ => String::new(),
+                }
+            }
+            Some(b'v') if packet == "vCont?" => String::from("vCont;c;s"),
+            Some(b'H') => String::from("OK"),
+            Some(b'D') => {
+                self.send("OK")?;
+                return Ok(false);
+            }
+            Some(b'k') => return Ok(false),
+            Some(b'q') if packet.starts_with("qRcmd,") => self.monitor(arch, &packet[6..])?,
+            Some(b'q') | Some(b'Q') => self.query(packet),
+            _ => String::new(),
+        };
+
+        self.send(&reply)?;
+        Ok(true)
+    }
+
+    fn query(&mut self, packet : &str) -> String {
+        if packet.starts_with("qSupported") {
+            return String::from("PacketSize=1000;qXfer:features:read+;QStartNoAckMode+;vContSupported+");
+        }
+        if packet == "QStartNoAckMode" {
+            // The OK still gets acked, the mode starts with the next packet
+            self.no_ack = true;
+            return String::from("OK");
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return read_annex(&target_xml(), range).unwrap_or_else(|| String::from("E01"));
+        }
+
+        match packet {
+            "qAttached" => String::from("1"),
+            "qC" => String::from("QC1"),
+            "qfThreadInfo" => String::from("m1"),
+            "qsThreadInfo" => String::from("l"),
+            _ => String::new(),
+        }
+    }
+
+    fn breakpoint(&mut self, packet : &str) -> String {
+        // Z0 and Z1 (software and hardware) are the same thing for us, Z2-Z4
+        // are write, read and access watchpoints
+        let mut fields = packet[1..].split(',');
+        let kind = fields.next();
+        let addr = fields.next().and_then(|addr| u16::from_str_radix(addr, 16).ok());
+        match (kind, addr) {
+            (Some("0") | Some("1"), Some(addr)) => {
+                if packet.starts_with('Z') {
+                    self.debugger.breakpoints.entry(addr).or_default();
+                } else {
+                    self.debugger.breakpoints.remove(&addr);
+                }
+                String::from("OK")
+            }
+            (Some(kind @ ("2" | "3" | "4")), Some(addr)) => {
+                let len = fields.next().and_then(|len| u16::from_str_radix(len, 16).ok()).unwrap_or(1).max(1);
+                let kind = match kind {
+                    "2" => WatchKind::Write,
+                    "3" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                let watchpoint = Watchpoint {
+                    target: WatchTarget::Memory(addr..=addr.saturating_add(len - 1)),
+                    kind,
+                };
+                if packet.starts_with('Z') {
+                    self.debugger.watchpoints.push(watchpoint);
+                } else {
+                    self.debugger.watchpoints.retain(|other| *other != watchpoint);
+                }
+                String::from("OK")
+            }
+            _ => String::new(),
+        }
+    }
+
+    fn step(&mut self, arch : &mut Arch) -> io::Result<String> {
+        self.debugger.resume_step(1);
+        let stop = self.debugger.run(arch, 1).unwrap_or(Stop::Step);
+        self.send_messages()?;
+        Ok(stop_reply(stop))
+    }
+
+    fn resume(&mut self) -> io::Result<bool> {
+        self.debugger.resume_continue();
+        self.running = true;
+        Ok(true)
+    }
+}
+
+fn stop_reply(stop : Stop) -> String {
+    match stop {
+        // SIGINT
+        Stop::Interrupt => String::from("S02"),
+        // gdb reads the old and new values itself
+        Stop::Watchpoint { hit: WatchHit { location: Location::Memory(addr), write, .. }, .. } => {
+            let kind = if write {"watch"} else {"rwatch"};
+            format!("T05{kind}:{addr:x};")
+        }
+        // SIGSEGV
+        Stop::Fault(_) => String::from("S0b"),
+        // SIGTRAP
+        _ => String::from("S05"),
+    }
+}
+
+fn ok_or_error(result : Option<()>) -> String {
+    match result {
+        Some(()) => String::from("OK"),
+        None => String::from("E01"),
+    }
+}
+
+fn encode_register(arch : &Arch, register : Register, out : &mut String) {
+    let value = register.read(arch);
+    for byte in value.to_le_bytes().iter().take(register_size(register)) {
+        let _ = write!(out, "{byte:02x}");
+    }
+}
+
+fn read_registers(arch : &Arch) -> String {
+    let mut out = String::new();
+    for register in REGISTERS {
+        encode_register(arch, register, &mut out);
+    }
+    out
+}
+
+fn encode_hex(bytes : &[u8]) -> String {
+    let mut out = String::new();
+    for byte in bytes {
+        let _ = write!(out, "{byte:02x}");
+    }
+    out
+}
+
+fn decode_hex(hex : &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..(index + 2))?, 16).ok())
+        .collect()
+}
+
+fn write_registers(arch : &mut Arch, hex : &str) -> Option<()> {
+    let bytes = decode_hex(hex)?;
+    let mut offset = 0;
+    for register in REGISTERS {
+        let size = register_size(register);
+        let value = match bytes.get(offset..(offset + size))? {
+            [low] => *low as u16,
+            [low, high] => u16::from_le_bytes([*low, *high]),
+            _ => return None,
+        };
+        register.write(arch, value).ok()?;
+        offset += size;
+    }
+    Some(())
+}
+
+fn read_register(arch : &Arch, number : &str) -> Option<String> {
+    let register = *REGISTERS.get(usize::from_str_radix(number, 16).ok()?)?;
+    let mut out = String::new();
+    encode_register(arch, register, &mut out);
+    Some(out)
+}
+
+fn write_register(arch : &mut Arch, assignment : &str) -> Option<()> {
+    let (number, hex) = assignment.split_once('=')?;
+    let register = *REGISTERS.get(usize::from_str_radix(number, 16).ok()?)?;
+    let bytes = decode_hex(hex)?;
+    let value = match bytes.as_slice() {
+        [low] => *low as u16,
+        [low, high] => u16::from_le_bytes([*low, *high]),
+        _ => return None,
+    };
+    register.write(arch, value).ok()
+}
+
+/// Parses the `addr,length` most packets carry.
+fn parse_range(range : &str) -> Option<(usize, usize)> {
+    let (addr, length) = range.split_once(',')?;
+    Some((usize::from_str_radix(addr, 16).ok()?, usize::from_str_radix(length, 16).ok()?))
+}
+
+fn read_memory(arch : &Arch, range : &str) -> Option<String> {
+    let (addr, length) = parse_range(range)?;
+    let bytes = arch.cpu.memory().get(addr..(addr.checked_add(length)?))?;
+    Some(encode_hex(bytes))
+}
+
+fn write_memory(arch : &mut Arch, packet : &str) -> Option<()> {
+    let (range, hex) = packet.split_once(':')?;
+    let (addr, length) = parse_range(range)?;
+    let bytes = decode_hex(hex)?;
+    if bytes.len() != length {
+        return None;
+    }
+    arch.cpu.memory_mut()
+        .get_mut(addr..(addr.checked_add(length)?))?
+        .copy_from_slice(&bytes);
+    Some(())
+}
+
+fn read_annex(annex : &str, range : &str) -> Option<String> {
+    let (offset, length) = parse_range(range)?;
+    if offset >= annex.len() {
+        return Some(String::from("l"));
+    }
+    let end = (offset + length).min(annex.len());
+    let marker = if end == annex.len() {'l'} else {'m'};
+    Some(format!("{marker}{}", &annex[offset..end]))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        /// Sends a packet and returns the reply, acking it.
+        fn request(&mut self, data : &str) -> String {
+            let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+            self.stream.write_all(format!("${data}#{checksum:02x}").as_bytes()).unwrap();
+            self.reply()
+        }
+
+        fn reply(&mut self) -> String {
+            let mut received = Vec::new();
+            let mut byte = [0u8; 1];
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                match byte[0] {
+                    b'+' if received.is_empty() => continue,
+                    b'#' => break,
+                    other => received.push(other),
+                }
+            }
+            let mut checksum = [0u8; 2];
+            self.stream.read_exact(&mut checksum).unwrap();
+            self.stream.write_all(b"+").unwrap();
+            String::from_utf8(received[1..].to_vec()).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_gdb_session() {
+        let mut stub = GdbStub::bind("127.0.0.1:0").unwrap();
+        let addr = stub.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut arch = Arch::new();
+            // 0x200: LD V0, 5, 0x202: ADD V0, 1, 0x204: SE V0, 0xFF, 0x206: JP 0x202,
+            // 0x208: JP 0x208, so V0 stops at 0xFF instead of wrapping
+            arch.cpu.load_program(&[0x60, 0x05, 0x70, 0x01, 0x30, 0xFF, 0x12, 0x02, 0x12, 0x08]);
+            while stub.frame(&mut arch, true).unwrap() {}
+            arch.cpu.reg[0]
+        });
+
+        let mut client = Client { stream: TcpStream::connect(addr).unwrap() };
+        assert!(client.request("qSupported:swbreak+").contains("qXfer:features:read+"));
+        assert!(client.request("qXfer:features:read:target.xml:0,1000").contains("name=\"pc\""));
+        assert_eq!(client.request("?"), "S05");
+
+        // All 21 registers, the pc at 0x200 little endian
+        let registers = client.request("g");
+        assert_eq!(registers.len(), 23 * 2);
+        assert_eq!(&registers[36..40], "0002");
+
+        assert_eq!(client.request("s"), "S05");
+        assert_eq!(client.request("p0"), "05");
+        assert_eq!(client.request("p11"), "0202");
+
+        assert_eq!(client.request("Z0,204,2"), "OK");
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(client.request("p11"), "0402");
+        assert_eq!(client.request("p0"), "06");
+        assert_eq!(client.request("z0,204,2"), "OK");
+
+        assert_eq!(client.request("P3=2a"), "OK");
+        assert_eq!(client.request("p3"), "2a");
+        assert_eq!(client.request("M300,2:beef"), "OK");
+        assert_eq!(client.request("m300,2"), "beef");
+        assert_eq!(client.request("mfff,2"), "E01");
+
+        // Run freely until interrupted
+        client.stream.write_all(b"$c#63").unwrap();
+        thread::sleep(std::time::Duration::from_millis(50));
+        client.stream.write_all(&[0x03]).unwrap();
+        assert_eq!(client.reply(), "S02");
+
+        assert_eq!(client.request("D"), "OK");
+        assert!(server.join().unwrap() > 6);
+    }
+}
@@ -1,10 +1,14 @@
 use std::{sync::Arc};
+use anyhow::Context;
 use wgpu::{util::DeviceExt};
 use winit::{
     dpi::PhysicalSize,
     window::Window
 };
 
+use crate::display::Display;
+use crate::palette::{self, Palette};
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 struct Vertex {
@@ -50,6 +54,12 @@ impl PixelColor {
     }
 }
 
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct PaletteUniform {
+    colors: [[f32; 4]; 4],
+}
+
 pub struct Gpu {
     surface : wgpu::Surface<'static>,
     device  : wgpu::Device,
@@ -61,7 +71,10 @@ pub struct Gpu {
     num_indices : u32,
     pub window : Arc<Window>,
     pixel_array : [PixelColor; 2048],
-    pixel_buffer : wgpu::Buffer
+    pixel_buffer : wgpu::Buffer,
+    palette : Palette,
+    palette_buffer : wgpu::Buffer,
+    palette_bind_group : wgpu::BindGroup,
 }
 
 impl Gpu {
@@ -75,7 +88,7 @@ impl Gpu {
 
         let instance = wgpu::Instance::new(&instance_descriptor);
         let surface = instance.create_surface(Arc::clone(&window))
-            .unwrap();
+            .context("could not create a surface for the window")?;
 
         let adapter_descriptor = wgpu::RequestAdapterOptionsBase {
             power_preference : wgpu::PowerPreference::default(),
@@ -85,7 +98,7 @@ impl Gpu {
 
         let adapter = instance.request_adapter(&adapter_descriptor)
             .await
-            .unwrap();
+            .context("no graphics adapter to draw with")?;
 
         let device_descriptor = wgpu::DeviceDescriptor {
             required_features : wgpu::Features::empty(),
@@ -97,16 +110,16 @@ impl Gpu {
 
         let (device, queue) = adapter
             .request_device(&device_descriptor)
-            .await.unwrap();
+            .await.context("could not open the graphics device")?;
 
         let surface_capabilites = surface.get_capabilities(&adapter);
         let surface_format = surface_capabilites
             .formats
             .iter()
             .copied()
-            .filter(|f| f.is_srgb())
-            .next()
-            .unwrap_or(surface_capabilites.formats[0]);
+            .find(|f| f.is_srgb())
+            .or(surface_capabilites.formats.first().copied())
+            .context("the adapter can not draw to the window")?;
 
         let config = wgpu::SurfaceConfiguration {
             usage : wgpu::TextureUsages::RENDER_ATTACHMENT,
@@ -126,10 +139,44 @@ impl Gpu {
         source: wgpu::ShaderSource::Wgsl(include_str!("shader.wgsl").into()),
         });
 
+        let palette = Palette::default();
+        let palette_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Palette Buffer"),
+                contents: bytemuck::cast_slice(&[Self::palette_uniform(&palette, config.format)]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
+
+        let palette_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Palette Bind Group Layout"),
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            }
+        );
+
+        let palette_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Palette Bind Group"),
+            layout: &palette_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: palette_buffer.as_entire_binding(),
+            }],
+        });
+
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Render Pipeline Layout"),
-                bind_group_layouts: &[],
+                bind_group_layouts: &[&palette_bind_group_layout],
                 push_constant_ranges: &[],
             }
         );
@@ -232,10 +279,36 @@ impl Gpu {
             num_indices,
             window,
             pixel_array,
-            pixel_buffer
+            pixel_buffer,
+            palette,
+            palette_buffer,
+            palette_bind_group,
         })
     }
 
+    /* The palette is written in sRGB, when the surface is sRGB as well the gpu
+    converts the output of the shader, so it has to be given linear colours. */
+    fn palette_uniform(palette : &Palette, format : wgpu::TextureFormat) -> PaletteUniform {
+        let convert = |color : [u8; 3]| {
+            if format.is_srgb() {
+                palette::to_linear(color)
+            } else {
+                [color[0] as f32 / 255.0, color[1] as f32 / 255.0, color[2] as f32 / 255.0, 1.0]
+            }
+        };
+
+        PaletteUniform {
+            colors: palette.colors().map(convert),
+        }
+    }
+
+    pub fn set_palette(&mut self, palette : Palette) {
+        self.palette = palette;
+        let uniform = Self::palette_uniform(&self.palette, self.config.format);
+        self.queue.write_buffer(&self.palette_buffer, 0, bytemuck::cast_slice(&[uniform]));
+        self.window.request_redraw();
+    }
+
     pub fn resize(&mut self, new_size : PhysicalSize<u32>) {
         if new_size.width > 0 && new_size.height > 0 {
             self.config.width = new_size.width;
@@ -255,6 +328,7 @@ impl Gpu {
         let mut encoder = self.device.create_command_encoder(&command_encoder_descriptor);
 
         {
+            let background = Self::palette_uniform(&self.palette, self.config.format).colors[0];
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
                 color_attachments: &[Some(wgpu::RenderPassColorAttachment {
@@ -262,9 +336,9 @@ impl Gpu {
                     resolve_target: None,
                     ops: wgpu::Operations {
                         load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.1,
-                            g: 0.2,
-                            b: 0.3,
+                            r: background[0] as f64,
+                            g: background[1] as f64,
+                            b: background[2] as f64,
                             a: 1.0,
                         }),
                         store: wgpu::StoreOp::Store,
@@ -276,6 +350,7 @@ impl Gpu {
             });
 
             render_pass.set_pipeline(&self.render_pipeline);
+            render_pass.set_bind_group(0, &self.palette_bind_group, &[]);
 
             render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
             render_pass.set_vertex_buffer(1, self.pixel_buffer.slice(..));
@@ -291,30 +366,13 @@ impl Gpu {
         Ok(())
     }
 
-    pub fn clear_screen(&mut self) {
-        self.pixel_array = [PixelColor {col : 0.0}; 2048];
-        self.queue.write_buffer(&self.pixel_buffer, 0, bytemuck::cast_slice(&[self.pixel_array]));
-        self.window.request_redraw();
-    }
-
-    pub fn xor_sprite(&mut self, pos_x : usize, pos_y : usize, sprite_data : Vec<u8>) -> bool {
-        let mut ret_flag = false;
-        for (row, value) in sprite_data.iter().enumerate() {
-            for column in 0..8 {
-                if (value & (0x80 >> column)) != 0 {
-                    let pixel_at_position = self.pixel_array[(pos_y+row)*64 + (column + pos_x)].col;
-                    if pixel_at_position > 0.3 {
-                        self.pixel_array[(pos_y+row)*64 + (column + pos_x)].col = 0.0;
-                        ret_flag = true;
-                    } else {
-                        self.pixel_array[(pos_y+row)*64 + (column + pos_x)].col = 1.0;
-                    }
-                }
-            }
+    /// Uploads the pixels of the display to be shown on the next render.
+    pub fn draw(&mut self, display : &Display) {
+        for (pixel, on) in self.pixel_array.iter_mut().zip(display.pixels()) {
+            pixel.col = if *on {1.0} else {0.0};
         }
 
         self.queue.write_buffer(&self.pixel_buffer, 0, bytemuck::cast_slice(&[self.pixel_array]));
         self.window.request_redraw();
-        ret_flag
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,64 @@
+use std::thread;
+use std::time::Duration;
+
+use anyhow::bail;
+
+use crate::arch::Arch;
+use crate::cli::{self, Args};
+use crate::cpu::Fault;
+use crate::debugger::Repl;
+use crate::gdb::GdbStub;
+use crate::testing::{self, Input};
+
+/// Runs the rom with nothing to show it on. Under the debugger the machine
+/// runs as fast as it can between stops, otherwise at its normal speed
+/// unless it only runs for a number of frames.
+pub fn run(args : &Args) -> anyhow::Result<()> {
+    if cli::uses_dap(args) {
+        let mut arch = Arch::new();
+        let mut server = cli::start_dap(args)?;
+        while server.frame(&mut arch, true)? {}
+        return arch.finish();
+    }
+
+    let (mut arch, rom) = cli::load_arch(args)?;
+    let palette = cli::choose_palette(args, &rom)?;
+    let inputs = match &args.inputs {
+        Some(path) => testing::parse_inputs(&std::fs::read_to_string(path)?)?,
+        None => Vec::new(),
+    };
+
+    if args.debug {
+        let mut repl = Repl::new(&arch, cli::load_symbols(args, &rom)?);
+        while repl.frame(&mut arch, true) {}
+    } else if let Some(port) = args.gdb {
+        let mut stub = GdbStub::bind(("127.0.0.1", port))?;
+        stub.debugger.symbols = cli::load_symbols(args, &rom)?;
+        eprintln!("Waiting for gdb on {}", stub.local_addr()?);
+        while stub.frame(&mut arch, true)? {}
+    } else if let Err(fault) = run_frames(&mut arch, args.frames, &inputs) {
+        cli::save_screenshot(args, &arch.display, &palette)?;
+        arch.finish()?;
+        bail!("the rom faulted at 0x{:03X}: {fault}", arch.cpu.pc());
+    }
+
+    cli::save_screenshot(args, &arch.display, &palette)?;
+    arch.finish()
+}
+
+/// Runs `frames` frames, or forever, until the rom faults, pressing keys as
+/// the inputs say.
+fn run_frames(arch : &mut Arch, frames : Option<u64>, inputs : &[Input]) -> Result<(), Fault> {
+    let mut frame = 0;
+    while frames.is_none_or(|frames| frame < frames) {
+        for input in inputs.iter().filter(|input| input.frame == frame) {
+            arch.keypad[input.key as usize & 0xF] = input.pressed;
+        }
+        arch.emulate()?;
+        frame += 1;
+        if frames.is_none() {
+            thread::sleep(Duration::from_millis(16));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,270 @@
+use std::fmt;
+
+/// A decoded CHIP-8 instruction. Registers are kept as their index, so
+/// `x` is 3 for V3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// 00E0
+    Cls,
+    /// 00EE
+    Ret,
+    /// 0nnn, a machine code routine, ignored by interpreters
+    Sys(u16),
+    /// 1nnn
+    Jp(u16),
+    /// 2nnn
+    Call(u16),
+    /// 3xkk
+    SeByte(usize, u8),
+    /// 4xkk
+    SneByte(usize, u8),
+    /// 5xy0
+    SeReg(usize, usize),
+    /// 6xkk
+    LdByte(usize, u8),
+    /// 7xkk
+    AddByte(usize, u8),
+    /// 8xy0
+    LdReg(usize, usize),
+    /// 8xy1
+    Or(usize, usize),
+    /// 8xy2
+    And(usize, usize),
+    /// 8xy3
+    Xor(usize, usize),
+    /// 8xy4
+    AddReg(usize, usize),
+    /// 8xy5
+    Sub(usize, usize),
+    /// 8xy6
+    Shr(usize, usize),
+    /// 8xy7
+    Subn(usize, usize),
+    /// 8xyE
+    Shl(usize, usize),
+    /// 9xy0
+    SneReg(usize, usize),
+    /// Annn
+    LdI(u16),
+    /// Bnnn
+    JpV0(u16),
+    /// Cxkk
+    Rnd(usize, u8),
+    /// Dxyn
+    Drw(usize, usize, u8),
+    /// Ex9E
+    Skp(usize),
+    /// ExA1
+    Sknp(usize),
+    /// Fx07
+    LdVxDt(usize),
+    /// Fx0A
+    LdVxK(usize),
+    /// Fx15
+    LdDtVx(usize),
+    /// Fx18
+    LdStVx(usize),
+    /// Fx1E
+    AddI(usize),
+    /// Fx29
+    LdF(usize),
+    /// Fx33
+    LdB(usize),
+    /// Fx55
+    LdIVx(usize),
+    /// Fx65
+    LdVxI(usize),
+    Unknown(u16),
+}
+
+impl Instruction {
+    pub fn decode(instr : u16) -> Self {
+        let x = ((instr & 0x0F00) >> 8) as usize;
+        let y = ((instr & 0x00F0) >> 4) as usize;
+        let n = (instr & 0x000F) as u8;
+        let kk = (instr & 0x00FF) as u8;
+        let nnn = instr & 0x0FFF;
+
+        match instr & 0xF000 {
+            0x0000 => match instr {
+                0x00E0 => Instruction::Cls,
+                0x00EE => Instruction::Ret,
+                _ => Instruction::Sys(nnn),
+            },
+            0x1000 => Instruction::Jp(nnn),
+            0x2000 => Instruction::Call(nnn),
+            0x3000 => Instruction::SeByte(x, kk),
+            0x4000 => Instruction::SneByte(x, kk),
+            0x5000 if n == 0 => Instruction::SeReg(x, y),
+            0x6000 => Instruction::LdByte(x, kk),
+            0x7000 => Instruction::AddByte(x, kk),
+            0x8000 => match n {
+                0x0 => Instruction::LdReg(x, y),
+                0x1 => Instruction::Or(x, y),
+                0x2 => Instruction::And(x, y),
+                0x3 => Instruction::Xor(x, y),
+                0x4 => Instruction::AddReg(x, y),
+                0x5 => Instruction::Sub(x, y),
+                0x6 => Instruction::Shr(x, y),
+                0x7 => Instruction::Subn(x, y),
+                0xE => Instruction::Shl(x, y),
+                _ => Instruction::Unknown(instr),
+            },
+            0x9000 if n == 0 => Instruction::SneReg(x, y),
+            0xA000 => Instruction::LdI(nnn),
+            0xB000 => Instruction::JpV0(nnn),
+            0xC000 => Instruction::Rnd(x, kk),
+            0xD000 => Instruction::Drw(x, y, n),
+            0xE000 => match kk {
+                0x9E => Instruction::Skp(x),
+                0xA1 => Instruction::Sknp(x),
+                _ => Instruction::Unknown(instr),
+            },
+            0xF000 => match kk {
+                0x07 => Instruction::LdVxDt(x),
+                0x0A => Instruction::LdVxK(x),
+                0x15 => Instruction::LdDtVx(x),
+                0x18 => Instruction::LdStVx(x),
+                0x1E => Instruction::AddI(x),
+                0x29 => Instruction::LdF(x),
+                0x33 => Instruction::LdB(x),
+                0x55 => Instruction::LdIVx(x),
+                0x65 => Instruction::LdVxI(x),
+                _ => Instruction::Unknown(instr),
+            },
+            _ => Instruction::Unknown(instr),
+        }
+    }
+
+    /// The opcode pattern the instruction was decoded from, like `8XY4`.
+    pub fn pattern(&self) -> &'static str {
+        match self {
+            Instruction::Cls => "00E0",
+            Instruction::Ret => "00EE",
+            Instruction::Sys(_) => "0NNN",
+            Instruction::Jp(_) => "1NNN",
+            Instruction::Call(_) => "2NNN",
+            Instruction::SeByte(..) => "3XKK",
+            Instruction::SneByte(..) => "4XKK",
+            Instruction::SeReg(..) => "5XY0",
+            Instruction::LdByte(..) => "6XKK",
+            Instruction::AddByte(..) => "7XKK",
+            Instruction::LdReg(..) => "8XY0",
+            Instruction::Or(..) => "8XY1",
+            Instruction::And(..) => "8XY2",
+            Instruction::Xor(..) => "8XY3",
+            Instruction::AddReg(..) => "8XY4",
+            Instruction::Sub(..) => "8XY5",
+            Instruction::Shr(..) => "8XY6",
+            Instruction::Subn(..) => "8XY7",
+            Instruction::Shl(..) => "8XYE",
+            Instruction::SneReg(..) => "9XY0",
+            Instruction::LdI(_) => "ANNN",
+            Instruction::JpV0(_) => "BNNN",
+            Instruction::Rnd(..) => "CXKK",
+            Instruction::Drw(..) => "DXYN",
+            Instruction::Skp(_) => "EX9E",
+            Instruction::Sknp(_) => "EXA1",
+            Instruction::LdVxDt(_) => "FX07",
+            Instruction::LdVxK(_) => "FX0A",
+            Instruction::LdDtVx(_) => "FX15",
+            Instruction::LdStVx(_) => "FX18",
+            Instruction::AddI(_) => "FX1E",
+            Instruction::LdF(_) => "FX29",
+            Instruction::LdB(_) => "FX33",
+            Instruction::LdIVx(_) => "FX55",
+            Instruction::LdVxI(_) => "FX65",
+            Instruction::Unknown(_) => "????",
+        }
+    }
+}
+
+/// Writes the instruction the way Cowgod's reference does, `LD V3, 0x10`.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Sys(nnn) => write!(f, "SYS 0x{nnn:03X}"),
+            Instruction::Jp(nnn) => write!(f, "JP 0x{nnn:03X}"),
+            Instruction::Call(nnn) => write!(f, "CALL 0x{nnn:03X}"),
+            Instruction::SeByte(x, kk) => write!(f, "SE V{x:X}, 0x{kk:02X}"),
+            Instruction::SneByte(x, kk) => write!(f, "SNE V{x:X}, 0x{kk:02X}"),
+            Instruction::SeReg(x, y) => write!(f, "SE V{x:X}, V{y:X}"),
+            Instruction::LdByte(x, kk) => write!(f, "LD V{x:X}, 0x{kk:02X}"),
+            Instruction::AddByte(x, kk) => write!(f, "ADD V{x:X}, 0x{kk:02X}"),
+            Instruction::LdReg(x, y) => write!(f, "LD V{x:X}, V{y:X}"),
+            Instruction::Or(x, y) => write!(f, "OR V{x:X}, V{y:X}"),
+            Instruction::And(x, y) => write!(f, "AND V{x:X}, V{y:X}"),
+            Instruction::Xor(x, y) => write!(f, "XOR V{x:X}, V{y:X}"),
+            Instruction::AddReg(x, y) => write!(f, "ADD V{x:X}, V{y:X}"),
+            Instruction::Sub(x, y) => write!(f, "SUB V{x:X}, V{y:X}"),
+            Instruction::Shr(x, y) => write!(f, "SHR V{x:X}, V{y:X}"),
+            Instruction::Subn(x, y) => write!(f, "SUBN V{x:X}, V{y:X}"),
+            Instruction::Shl(x, y) => write!(f, "SHL V{x:X}, V{y:X}"),
+            Instruction::SneReg(x, y) => write!(f, "SNE V{x:X}, V{y:X}"),
+            Instruction::LdI(nnn) => write!(f, "LD I, 0x{nnn:03X}"),
+            Instruction::JpV0(nnn) => write!(f, "JP V0, 0x{nnn:03X}"),
+            Instruction::Rnd(x, kk) => write!(f, "RND V{x:X}, 0x{kk:02X}"),
+            Instruction::Drw(x, y, n) => write!(f, "DRW V{x:X}, V{y:X}, {n}"),
+            Instruction::Skp(x) => write!(f, "SKP V{x:X}"),
+            Instruction::Sknp(x) => write!(f, "SKNP V{x:X}"),
+            Instruction::LdVxDt(x) => write!(f, "LD V{x:X}, DT"),
+            Instruction::LdVxK(x) => write!(f, "LD V{x:X}, K"),
+            Instruction::LdDtVx(x) => write!(f, "LD DT, V{x:X}"),
+            Instruction::LdStVx(x) => write!(f, "LD ST, V{x:X}"),
+            Instruction::AddI(x) => write!(f, "ADD I, V{x:X}"),
+            Instruction::LdF(x) => write!(f, "LD F, V{x:X}"),
+            Instruction::LdB(x) => write!(f, "LD B, V{x:X}"),
+            Instruction::LdIVx(x) => write!(f, "LD [I], V{x:X}"),
+            Instruction::LdVxI(x) => write!(f, "LD V{x:X}, [I]"),
+            Instruction::Unknown(instr) => write!(f, "DW 0x{instr:04X}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(Instruction::decode(0x00E0), Instruction::Cls);
+        assert_eq!(Instruction::decode(0x00EE), Instruction::Ret);
+        assert_eq!(Instruction::decode(0x0240), Instruction::Sys(0x240));
+        assert_eq!(Instruction::decode(0x024E), Instruction::Sys(0x24E));
+        assert_eq!(Instruction::decode(0x3A12), Instruction::SeByte(0xA, 0x12));
+        assert_eq!(Instruction::decode(0x5AB0), Instruction::SeReg(0xA, 0xB));
+        assert_eq!(Instruction::decode(0x5AB1), Instruction::Unknown(0x5AB1));
+        assert_eq!(Instruction::decode(0x8AB6), Instruction::Shr(0xA, 0xB));
+        assert_eq!(Instruction::decode(0x8AB8), Instruction::Unknown(0x8AB8));
+        assert_eq!(Instruction::decode(0x9AB0), Instruction::SneReg(0xA, 0xB));
+        assert_eq!(Instruction::decode(0x9AB1), Instruction::Unknown(0x9AB1));
+        assert_eq!(Instruction::decode(0xD125), Instruction::Drw(1, 2, 5));
+        assert_eq!(Instruction::decode(0xE39E), Instruction::Skp(3));
+        assert_eq!(Instruction::decode(0xE3A2), Instruction::Unknown(0xE3A2));
+        assert_eq!(Instruction::decode(0xF365), Instruction::LdVxI(3));
+        assert_eq!(Instruction::decode(0xF000), Instruction::Unknown(0xF000));
+    }
+
+    #[test]
+    fn test_display() {
+        let text = |instr : u16| Instruction::decode(instr).to_string();
+        assert_eq!(text(0x00E0), "CLS");
+        assert_eq!(text(0x0123), "SYS 0x123");
+        assert_eq!(text(0x2ABC), "CALL 0xABC");
+        assert_eq!(text(0x6A0F), "LD VA, 0x0F");
+        assert_eq!(text(0x8AB4), "ADD VA, VB");
+        assert_eq!(text(0xB200), "JP V0, 0x200");
+        assert_eq!(text(0xD01F), "DRW V0, V1, 15");
+        assert_eq!(text(0xF029), "LD F, V0");
+        assert_eq!(text(0xF155), "LD [I], V1");
+        assert_eq!(text(0xFFFF), "DW 0xFFFF");
+    }
+
+    #[test]
+    fn test_pattern() {
+        assert_eq!(Instruction::decode(0x8AB4).pattern(), "8XY4");
+        assert_eq!(Instruction::decode(0x5AB1).pattern(), "????");
+    }
+}
@@ -1,4 +1,32 @@
 pub mod app;
 pub mod gpu;
+pub mod software;
 pub mod cpu;
-pub mod arch;
\ No newline at end of file
+pub mod arch;
+pub mod cartridge;
+pub mod octo;
+pub mod palette;
+pub mod screenshot;
+pub mod recording;
+pub mod export;
+pub mod rom;
+pub mod cli;
+pub mod debugger;
+pub mod display;
+pub mod headless;
+pub mod tui;
+pub mod instruction;
+pub mod gdb;
+pub mod dap;
+pub mod trace;
+pub mod watch;
+pub mod expr;
+pub mod symbols;
+pub mod profiler;
+pub mod coverage;
+pub mod lint;
+pub mod cfg;
+pub mod decompile;
+pub mod testing;
+pub mod lockstep;
+pub mod romtest;
@@ -0,0 +1,448 @@
+/* Looks at a rom without running it. Everything the program can reach from
+0x200 is followed through jumps, calls and skips while keeping track of
+where I points, which is enough to find:
+
+- stores into the program's own instructions
+- jumps and calls leaving the rom, and code running off its end
+- bytes nothing reaches, which are data or dead code
+- how deep calls nest, against the 16 return addresses the stack holds
+- instructions that behave differently depending on the quirks
+- SCHIP and XO-CHIP instructions, which decide the platform to suggest
+
+Computed jumps (Bnnn) are not followed, so code only they reach shows up as
+unreachable. I is forgotten across calls.
+*/
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Write as _};
+use std::ops::RangeInclusive;
+
+use crate::cfg::{Program, START};
+use crate::cpu::{Platform, Quirks, Stack};
+use crate::instruction::Instruction;
+
+/// Names the instructions only later interpreters have, with the first
+/// platform that has them. The decoder takes them for unknown or SYS
+/// instructions.
+pub fn extension(opcode : u16) -> Option<(Platform, &'static str)> {
+    match opcode {
+        0x00C0..=0x00CF => Some((Platform::Schip, "00CN scroll down")),
+        0x00FB => Some((Platform::Schip, "00FB scroll right")),
+        0x00FC => Some((Platform::Schip, "00FC scroll left")),
+        0x00FD => Some((Platform::Schip, "00FD exit")),
+        0x00FE => Some((Platform::Schip, "00FE low resolution")),
+        0x00FF => Some((Platform::Schip, "00FF high resolution")),
+        0x00D0..=0x00DF => Some((Platform::XoChip, "00DN scroll up")),
+        0xF000 => Some((Platform::XoChip, "F000 NNNN long I")),
+        0xF002 => Some((Platform::XoChip, "F002 audio pattern")),
+        _ if opcode & 0xF00F == 0xD000 => Some((Platform::Schip, "DXY0 16x16 sprite")),
+        _ if opcode & 0xF00F == 0x5002 => Some((Platform::XoChip, "5XY2 save VX-VY")),
+        _ if opcode & 0xF00F == 0x5003 => Some((Platform::XoChip, "5XY3 load VX-VY")),
+        _ => match opcode & 0xF0FF {
+            0xF030 => Some((Platform::Schip, "FX30 big digit")),
+            0xF075 => Some((Platform::Schip, "FX75 save flags")),
+            0xF085 => Some((Platform::Schip, "FX85 load flags")),
+            0xF001 => Some((Platform::XoChip, "FN01 planes")),
+            0xF03A => Some((Platform::XoChip, "FX3A pitch")),
+            _ => None,
+        },
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Warning,
+    Note,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Finding {
+    /// A store at `pc` overwrites instructions.
+    SelfModifying { pc: u16, target: RangeInclusive<u16> },
+    /// A jump or call at `pc` to outside the rom.
+    OutsideRom { pc: u16, target: u16 },
+    /// Execution carries on past the end of the rom after `pc`.
+    RunsOffEnd { pc: u16 },
+    Invalid { pc: u16, opcode: u16 },
+    Unreachable(RangeInclusive<u16>),
+    /// Calls nest deeper than the stack, following `path` from 0x200.
+    CallDepth { path: Vec<u16> },
+    /// Subroutines calling themselves back along `path`.
+    Recursion { path: Vec<u16> },
+    /// 8xy6 or 8xyE with x and y different.
+    ShiftQuirk { pc: u16, instr: Instruction },
+    /// Bxnn with x not 0.
+    JumpQuirk { pc: u16, instr: Instruction },
+    /// Fx55 or Fx65 at `pc` whose I is used again at `used_at`.
+    LoadStoreQuirk { pc: u16, used_at: u16 },
+    ComputedJump { pc: u16 },
+    Extension { pc: u16, platform: Platform, name: &'static str },
+}
+
+impl Finding {
+    pub fn severity(&self) -> Severity {
+        match self {
+            Finding::Unreachable(_) | Finding::ComputedJump { .. } | Finding::Extension { .. } => Severity::Note,
+            _ => Severity::Warning,
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        let chain = |path : &[u16]| path.iter().map(|addr| format!("0x{addr:03X}")).collect::<Vec<_>>().join(" -> ");
+        match self {
+            Finding::SelfModifying { pc, target } =>
+                write!(f, "0x{pc:03X}: stores to 0x{:03X}-0x{:03X}, which holds instructions", target.start(), target.end()),
+            Finding::OutsideRom { pc, target } => write!(f, "0x{pc:03X}: goes to 0x{target:03X}, outside the rom"),
+            Finding::RunsOffEnd { pc } => write!(f, "0x{pc:03X}: runs off the end of the rom"),
+            Finding::Invalid { pc, opcode } => write!(f, "0x{pc:03X}: {opcode:04X} is not an instruction"),
+            Finding::Unreachable(range) => write!(
+                f, "0x{:03X}-0x{:03X}: {} bytes nothing reaches, data or dead code",
+                range.start(), range.end(), range.end() - range.start() + 1,
+            ),
+            Finding::CallDepth { path } => write!(
+                f, "calls nest {} deep, more than the {} the stack holds: {}",
+                path.len() - 1, Stack::CAPACITY, chain(path),
+            ),
+            Finding::Recursion { path } => write!(f, "recursion may overflow the stack: {}", chain(path)),
+            Finding::ShiftQuirk { pc, instr } =>
+                write!(f, "0x{pc:03X}: {instr} shifts VY on chip8 and VX on schip (shift quirk)"),
+            Finding::JumpQuirk { pc, instr } =>
+                write!(f, "0x{pc:03X}: {instr} adds V0 on chip8 and VX on schip (jump quirk)"),
+            Finding::LoadStoreQuirk { pc, used_at } => write!(
+                f, "0x{pc:03X}: moves I on chip8 but not on schip, and 0x{used_at:03X} uses I after it (load_store quirk)",
+            ),
+            Finding::ComputedJump { pc } => write!(f, "0x{pc:03X}: jumps to a computed address, which is not followed"),
+            Finding::Extension { pc, platform, name } => write!(f, "0x{pc:03X}: {name} needs {platform}"),
+        }
+    }
+}
+
+/// What is known about I before an instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Index {
+    Known(u16),
+    /// Right after the Fx55 or Fx65 at this address, where the quirks
+    /// disagree.
+    AfterLoadStore(u16),
+    Unknown,
+}
+
+fn transfer(program : &Program, pc : u16, opcode : u16, index : Index) -> Index {
+    match Instruction::decode(opcode) {
+        Instruction::LdI(addr) => Index::Known(addr),
+        Instruction::LdIVx(_) | Instruction::LdVxI(_) => Index::AfterLoadStore(pc),
+        Instruction::AddI(_) | Instruction::LdF(_) => Index::Unknown,
+        _ if opcode == 0xF000 => program.opcode(pc + 2).map_or(Index::Unknown, Index::Known),
+        _ if opcode & 0xF0FF == 0xF030 => Index::Unknown,
+        _ => index,
+    }
+}
+
+fn uses_index(instr : Instruction, opcode : u16) -> bool {
+    matches!(
+        instr,
+        Instruction::Drw(..) | Instruction::LdB(_) | Instruction::LdIVx(_) | Instruction::LdVxI(_) | Instruction::AddI(_)
+    ) || matches!(opcode & 0xF00F, 0x5002 | 0x5003)
+}
+
+/// The memory a store writes when I is `index`. XO-CHIP can point I at the
+/// very end of memory, so the end stops there.
+fn stores(instr : Instruction, opcode : u16, index : u16) -> Option<RangeInclusive<u16>> {
+    match instr {
+        Instruction::LdB(_) => Some(index..=index.saturating_add(2)),
+        Instruction::LdIVx(x) => Some(index..=index.saturating_add(x as u16)),
+        _ if opcode & 0xF00F == 0x5002 => {
+            let (x, y) = ((opcode >> 8) & 0xF, (opcode >> 4) & 0xF);
+            Some(index..=index.saturating_add(x.abs_diff(y)))
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+pub struct Analysis {
+    pub findings: Vec<Finding>,
+    /// The addresses of the instructions reachable from 0x200.
+    pub code: BTreeSet<u16>,
+    /// The subroutines each subroutine calls, 0x200 standing for the main
+    /// program.
+    pub calls: BTreeMap<u16, BTreeSet<u16>>,
+    /// The longest chain of calls from 0x200, none when there is
+    /// recursion.
+    pub deepest: Option<Vec<u16>>,
+    /// The oldest platform with every instruction the rom uses.
+    pub platform: Platform,
+}
+
+impl Analysis {
+    /// Analyses a program loaded at 0x200.
+    pub fn new(program : &[u8]) -> Self {
+        let program = Program::new(program);
+
+        // Follow everything reachable, meeting what is known about I where
+        // paths join until nothing changes
+        let mut states: BTreeMap<u16, Index> = BTreeMap::new();
+        let mut work = vec![(START, Index::Known(0))];
+        while let Some((pc, index)) = work.pop() {
+            let Some(opcode) = program.opcode(pc) else {
+                continue;
+            };
+            let index = match states.get(&pc) {
+                Some(old) if *old == index || *old == Index::Unknown => continue,
+                Some(_) => Index::Unknown,
+                None => index,
+            };
+            states.insert(pc, index);
+
+            let after = transfer(&program, pc, opcode, index);
+            let flow = program.flow(pc, opcode);
+            // Whatever the subroutine did to I is lost
+            let resumed = if flow.call.is_some() {Index::Unknown} else {after};
+            work.extend(flow.next.iter().map(|next| (*next, resumed)));
+            work.extend(flow.jump.iter().chain(&flow.call).map(|target| (*target, after)));
+        }
+
+        let mut code_bytes = BTreeSet::new();
+        for pc in states.keys() {
+            code_bytes.extend((0..program.size(*pc)).map(|offset| pc + offset));
+        }
+
+        let mut findings = Vec::new();
+        let mut platform = Platform::Chip8;
+        let mut load_stores = BTreeSet::new();
+        let mut entries = BTreeSet::from([START]);
+        for (&pc, &index) in &states {
+            let Some(opcode) = program.opcode(pc) else {
+                continue;
+            };
+            let instr = Instruction::decode(opcode);
+
+            match extension(opcode) {
+                Some((needed, name)) => {
+                    if needed == Platform::XoChip || platform == Platform::Chip8 {
+                        platform = needed;
+                    }
+                    findings.push(Finding::Extension { pc, platform: needed, name });
+                }
+                None if matches!(instr, Instruction::Unknown(_)) => findings.push(Finding::Invalid { pc, opcode }),
+                None => {}
+            }
+
+            match instr {
+                Instruction::Shr(x, y) | Instruction::Shl(x, y) if x != y => findings.push(Finding::ShiftQuirk { pc, instr }),
+                Instruction::JpV0(addr) => {
+                    // Bxnn adds VX on schip, the same as V0 when x is 0
+                    if addr & 0xF00 != 0 {
+                        findings.push(Finding::JumpQuirk { pc, instr });
+                    }
+                    findings.push(Finding::ComputedJump { pc });
+                }
+                _ => {}
+            }
+
+            if uses_index(instr, opcode)
+                && let Index::AfterLoadStore(source) = index
+                && load_stores.insert(source) {
+                findings.push(Finding::LoadStoreQuirk { pc: source, used_at: pc });
+            }
+            if let Index::Known(index) = index
+                && let Some(target) = stores(instr, opcode, index)
+                && code_bytes.range(target.clone()).next().is_some() {
+                findings.push(Finding::SelfModifying { pc, target });
+            }
+
+            let flow = program.flow(pc, opcode);
+            for target in flow.jump.iter().chain(&flow.call) {
+                if !program.contains(*target) {
+                    findings.push(Finding::OutsideRom { pc, target: *target });
+                }
+            }
+            if flow.next.iter().any(|next| !program.contains(*next)) {
+                findings.push(Finding::RunsOffEnd { pc });
+            }
+            if let Some(call) = flow.call
+                && program.contains(call) {
+                entries.insert(call);
+            }
+        }
+
+        let mut unreachable: Vec<RangeInclusive<u16>> = Vec::new();
+        for addr in (START..).take(program.len()).filter(|addr| !code_bytes.contains(addr)) {
+            match unreachable.last_mut() {
+                Some(range) if *range.end() + 1 == addr => *range = *range.start()..=addr,
+                _ => unreachable.push(addr..=addr),
+            }
+        }
+        findings.extend(unreachable.into_iter().map(Finding::Unreachable));
+
+        let calls: BTreeMap<u16, BTreeSet<u16>> = entries.iter().map(|entry| (*entry, program.callees(*entry))).collect();
+        let deepest = match deepest_calls(&calls, START, &mut Vec::new(), &mut BTreeMap::new()) {
+            Ok(path) => {
+                if path.len() - 1 > Stack::CAPACITY {
+                    findings.push(Finding::CallDepth { path: path.clone() });
+                }
+                Some(path)
+            }
+            Err(path) => {
+                findings.push(Finding::Recursion { path });
+                None
+            }
+        };
+
+        Self {
+            findings,
+            code: states.into_keys().collect(),
+            calls,
+            deepest,
+            platform,
+        }
+    }
+
+    /// The deepest calls nest, none when there is recursion.
+    pub fn max_depth(&self) -> Option<usize> {
+        self.deepest.as_ref().map(|path| path.len() - 1)
+    }
+
+    /// The quirks the rom behaves differently under.
+    pub fn sensitive_quirks(&self) -> Vec<&'static str> {
+        let mut quirks = Vec::new();
+        for finding in &self.findings {
+            let quirk = match finding {
+                Finding::ShiftQuirk { .. } => "shift",
+                Finding::LoadStoreQuirk { .. } => "load_store",
+                Finding::JumpQuirk { .. } => "jump",
+                _ => continue,
+            };
+            if !quirks.contains(&quirk) {
+                quirks.push(quirk);
+            }
+        }
+        quirks
+    }
+
+    /// The quirks to run the rom with.
+    pub fn quirks(&self) -> Quirks {
+        self.platform.quirks()
+    }
+
+    pub fn has_warnings(&self) -> bool {
+        self.findings.iter().any(|finding| finding.severity() == Severity::Warning)
+    }
+
+    /// The findings, warnings first, followed by the summary and the
+    /// suggested platform.
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        for severity in [Severity::Warning, Severity::Note] {
+            for finding in self.findings.iter().filter(|finding| finding.severity() == severity) {
+                let kind = if severity == Severity::Warning {"warning"} else {"note"};
+                let _ = writeln!(out, "{kind}: {finding}");
+            }
+        }
+
+        let _ = writeln!(out, "\n{} instructions reachable, {} subroutines", self.code.len(), self.calls.len() - 1);
+        match self.max_depth() {
+            Some(depth) => { let _ = writeln!(out, "calls nest at most {depth} deep of {}", Stack::CAPACITY); }
+            None => { let _ = writeln!(out, "calls nest without bound"); }
+        }
+
+        let quirks = self.quirks();
+        let on = |on : bool| if on {"on"} else {"off"};
+        let _ = writeln!(out, "suggested: --platform {}", self.platform);
+        let _ = writeln!(
+            out, "quirks: shift {}, load_store {}, jump {}, logic {}",
+            on(quirks.shift), on(quirks.load_store), on(quirks.jump), on(quirks.logic),
+        );
+        let sensitive = self.sensitive_quirks();
+        if !sensitive.is_empty() {
+            let _ = writeln!(out, "the rom depends on: {}", sensitive.join(", "));
+        }
+        out
+    }
+}
+
+/// The longest chain of calls from `entry`, or the calls going around in a
+/// circle when there is one.
+fn deepest_calls(
+    calls : &BTreeMap<u16, BTreeSet<u16>>,
+    entry : u16,
+    path : &mut Vec<u16>,
+    known : &mut BTreeMap<u16, Vec<u16>>,
+) -> Result<Vec<u16>, Vec<u16>> {
+    if let Some(deepest) = known.get(&entry) {
+        return Ok(deepest.clone());
+    }
+    if let Some(start) = path.iter().position(|addr| *addr == entry) {
+        let mut circle = path[start..].to_vec();
+        circle.push(entry);
+        return Err(circle);
+    }
+
+    path.push(entry);
+    let mut deepest = Vec::new();
+    for callee in calls.get(&entry).into_iter().flatten() {
+        let chain = deepest_calls(calls, *callee, path, known)?;
+        if chain.len() > deepest.len() {
+            deepest = chain;
+        }
+    }
+    path.pop();
+
+    deepest.insert(0, entry);
+    known.insert(entry, deepest.clone());
+    Ok(deepest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_findings() {
+        let program = [
+            0xA2, 0x02, // 0x200: LD I, 0x202
+            0xF1, 0x55, // 0x202: LD [I], V1, over the instruction itself
+            0xD0, 0x15, // 0x204: DRW V0, V1, 5, I moved by the store on chip8
+            0x81, 0x26, // 0x206: SHR V1, V2
+            0x22, 0x10, // 0x208: CALL 0x210
+            0x00, 0xFF, // 0x20A: HIGH
+            0x13, 0x00, // 0x20C: JP 0x300
+            0xFF, 0xFF, // 0x20E: unreachable
+            0x22, 0x14, // 0x210: CALL 0x214
+            0x00, 0xEE, // 0x212: RET
+            0x00, 0xEE, // 0x214: RET
+        ];
+        let analysis = Analysis::new(&program);
+        let findings = &analysis.findings;
+
+        assert!(findings.contains(&Finding::SelfModifying { pc: 0x202, target: 0x202..=0x203 }));
+        assert!(findings.contains(&Finding::LoadStoreQuirk { pc: 0x202, used_at: 0x204 }));
+        assert!(findings.contains(&Finding::ShiftQuirk { pc: 0x206, instr: Instruction::Shr(1, 2) }));
+        assert!(findings.contains(&Finding::OutsideRom { pc: 0x20C, target: 0x300 }));
+        assert!(findings.contains(&Finding::Unreachable(0x20E..=0x20F)));
+        assert!(findings.contains(&Finding::Extension { pc: 0x20A, platform: Platform::Schip, name: "00FF high resolution" }));
+        assert_eq!(analysis.deepest, Some(vec![0x200, 0x210, 0x214]));
+        assert_eq!(analysis.platform, Platform::Schip);
+        assert_eq!(analysis.sensitive_quirks(), ["load_store", "shift"]);
+        assert!(analysis.report().contains("suggested: --platform schip"));
+    }
+
+    #[test]
+    fn test_recursion() {
+        // 0x200: CALL 0x204, 0x202: JP 0x202, 0x204: CALL 0x204, 0x206: RET
+        let analysis = Analysis::new(&[0x22, 0x04, 0x12, 0x02, 0x22, 0x04, 0x00, 0xEE]);
+        assert_eq!(analysis.deepest, None);
+        assert!(analysis.findings.contains(&Finding::Recursion { path: vec![0x204, 0x204] }));
+        assert!(!analysis.findings.iter().any(|finding| matches!(finding, Finding::RunsOffEnd { .. })));
+    }
+
+    #[test]
+    fn test_store_at_end_of_memory() {
+        // 0x200: LD I, 0xFFFF (XO-CHIP), 0x204: LD B, V0, 0x206: JP 0x206
+        let analysis = Analysis::new(&[0xF0, 0x00, 0xFF, 0xFF, 0xF0, 0x33, 0x12, 0x06]);
+        assert!(!analysis.findings.iter().any(|finding| matches!(finding, Finding::SelfModifying { .. })));
+        assert_eq!(analysis.platform, Platform::XoChip);
+    }
+}
@@ -0,0 +1,248 @@
+/* Runs a rom on two machines at once, one instruction at a time, and stops
+at the first instruction after which they no longer agree: on the pc, I,
+the registers, stack and timers, memory or the screen.
+
+The machines are configured by the quirks they run with, written as a
+platform followed by quirks to turn on or off:
+
+    chip8
+    schip,shift=off,jump=off
+
+so running a rom under two presets that only differ in one quirk points
+at the instruction that depends on it. Both machines get the same inputs
+and the same random numbers, so anything else that differs is a bug.
+*/
+
+use std::fmt::{self, Write as _};
+
+use anyhow::{anyhow, bail, Context};
+
+use crate::arch::Arch;
+use crate::cpu::{Fault, Platform, Quirks};
+use crate::instruction::Instruction;
+use crate::symbols::Symbols;
+use crate::testing::Input;
+
+/// How many differing bytes of memory a divergence lists.
+const MAX_MEMORY_DIFFERENCES : usize = 8;
+
+/// Parses a configuration like `schip,shift=off`, see above.
+pub fn parse_config(spec : &str) -> anyhow::Result<Quirks> {
+    let mut parts = spec.split(',').map(str::trim);
+    let platform = parts.next().unwrap_or_default();
+    let mut quirks = if platform == "default" {Quirks::default()} else {platform.parse::<Platform>()?.quirks()};
+
+    for part in parts {
+        let (name, value) = part.split_once('=').with_context(|| format!("{part} should be <quirk>=on|off"))?;
+        let value = match value {
+            "on" | "true" | "1" => true,
+            "off" | "false" | "0" => false,
+            _ => bail!("{value} should be on or off"),
+        };
+        *quirk_mut(&mut quirks, name)? = value;
+    }
+    Ok(quirks)
+}
+
+fn quirk_mut<'a>(quirks : &'a mut Quirks, name : &str) -> anyhow::Result<&'a mut bool> {
+    match name {
+        "shift" => Ok(&mut quirks.shift),
+        "load_store" => Ok(&mut quirks.load_store),
+        "jump" => Ok(&mut quirks.jump),
+        "logic" => Ok(&mut quirks.logic),
+        _ => Err(anyhow!("unknown quirk {name}, expected shift, load_store, jump or logic")),
+    }
+}
+
+/// The names of the quirks set one way in `a` and the other in `b`.
+pub fn differing_quirks(a : Quirks, b : Quirks) -> Vec<&'static str> {
+    [
+        ("shift", a.shift != b.shift),
+        ("load_store", a.load_store != b.load_store),
+        ("jump", a.jump != b.jump),
+        ("logic", a.logic != b.logic),
+    ]
+    .into_iter()
+    .filter_map(|(name, differs)| differs.then_some(name))
+    .collect()
+}
+
+/// One thing the machines disagree on, as `a` then `b`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Difference {
+    Pc(u16, u16),
+    I(u16, u16),
+    Register(usize, u8, u8),
+    Stack(Vec<u16>, Vec<u16>),
+    DelayTimer(u8, u8),
+    SoundTimer(u8, u8),
+    Memory { addr: u16, a: u8, b: u8 },
+    /// The pixels that are on in one screen only, and the first of them.
+    Screen { count: usize, first: (usize, usize) },
+    /// Only one of the machines faulted, or they faulted differently.
+    Fault(Option<Fault>, Option<Fault>),
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Difference::Pc(a, b) => write!(f, "PC {a:03X} vs {b:03X}"),
+            Difference::I(a, b) => write!(f, "I {a:04X} vs {b:04X}"),
+            Difference::Register(index, a, b) => write!(f, "V{index:X} {a:02X} vs {b:02X}"),
+            Difference::Stack(a, b) => write!(f, "stack {a:03X?} vs {b:03X?}"),
+            Difference::DelayTimer(a, b) => write!(f, "DT {a:02X} vs {b:02X}"),
+            Difference::SoundTimer(a, b) => write!(f, "ST {a:02X} vs {b:02X}"),
+            Difference::Memory { addr, a, b } => write!(f, "[{addr:03X}] {a:02X} vs {b:02X}"),
+            Difference::Screen { count, first: (x, y) } => write!(f, "{count} pixels on the screen, the first at {x},{y}"),
+            Difference::Fault(a, b) => {
+                let describe = |fault : &Option<Fault>| fault.map_or(String::from("ran"), |fault| fault.to_string());
+                write!(f, "{} vs {}", describe(a), describe(b))
+            }
+        }
+    }
+}
+
+/// Everything the machines disagree on, in the order of `Difference`.
+pub fn compare(a : &Arch, b : &Arch) -> Vec<Difference> {
+    let (cpu_a, cpu_b) = (&a.cpu, &b.cpu);
+    let mut differences = Vec::new();
+    if cpu_a.pc() != cpu_b.pc() {
+        differences.push(Difference::Pc(cpu_a.pc(), cpu_b.pc()));
+    }
+    if cpu_a.i_reg() != cpu_b.i_reg() {
+        differences.push(Difference::I(cpu_a.i_reg(), cpu_b.i_reg()));
+    }
+    for (index, (a, b)) in cpu_a.reg.iter().zip(&cpu_b.reg).enumerate() {
+        if a != b {
+            differences.push(Difference::Register(index, *a, *b));
+        }
+    }
+    if cpu_a.stack().frames() != cpu_b.stack().frames() {
+        differences.push(Difference::Stack(cpu_a.stack().frames().to_vec(), cpu_b.stack().frames().to_vec()));
+    }
+    if cpu_a.delay_timer() != cpu_b.delay_timer() {
+        differences.push(Difference::DelayTimer(cpu_a.delay_timer(), cpu_b.delay_timer()));
+    }
+    if cpu_a.sound_timer() != cpu_b.sound_timer() {
+        differences.push(Difference::SoundTimer(cpu_a.sound_timer(), cpu_b.sound_timer()));
+    }
+    differences.extend(
+        cpu_a.memory().iter().zip(cpu_b.memory()).enumerate()
+            .filter(|(_, (a, b))| a != b)
+            .take(MAX_MEMORY_DIFFERENCES)
+            .map(|(addr, (a, b))| Difference::Memory { addr: addr as u16, a: *a, b: *b }),
+    );
+
+    let (pixels_a, pixels_b) = (a.display.pixels(), b.display.pixels());
+    let mut differing = (0..pixels_a.len()).filter(|index| pixels_a[*index] != pixels_b[*index]);
+    if let Some(first) = differing.next() {
+        let width = crate::display::WIDTH;
+        differences.push(Difference::Screen { count: differing.count() + 1, first: (first % width, first / width) });
+    }
+    differences
+}
+
+/// The instruction after which the machines stopped agreeing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    /// Instructions both machines ran before it.
+    pub cycle: u64,
+    pub frame: u64,
+    pub pc: u16,
+    pub opcode: u16,
+    pub differences: Vec<Difference>,
+}
+
+impl Divergence {
+    /// A few lines saying where and how the machines went apart, naming
+    /// them `names`.
+    pub fn report(&self, names : (&str, &str), symbols : &Symbols) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out, "{} and {} diverge at cycle {} (frame {}) after {}: {:04X} {}",
+            names.0, names.1, self.cycle, self.frame, symbols.describe(self.pc), self.opcode, Instruction::decode(self.opcode),
+        );
+        for difference in &self.differences {
+            let _ = writeln!(out, "    {difference}");
+        }
+        out
+    }
+}
+
+/// Two machines running the same rom with the same inputs.
+pub struct Lockstep {
+    pub a: Arch,
+    pub b: Arch,
+    pub inputs: Vec<Input>,
+}
+
+impl Lockstep {
+    /// The machines should start out the same but for their quirks, any
+    /// other difference shows up as a divergence on the first instruction.
+    pub fn new(mut a : Arch, mut b : Arch, seed : u64) -> Self {
+        a.cpu.seed_rng(seed);
+        b.cpu.seed_rng(seed);
+        Self { a, b, inputs: Vec::new() }
+    }
+
+    /// Runs until the machines diverge or `frames` frames have run. When
+    /// both fault the same way there is nothing left to compare and the
+    /// fault is returned.
+    pub fn run(&mut self, frames : u64) -> Result<Option<Divergence>, Fault> {
+        let cycles_per_frame = self.a.cycles_per_frame.max(1) as u64;
+        while self.a.cycles < frames * cycles_per_frame {
+            let cycle = self.a.cycles;
+            let frame = cycle / cycles_per_frame;
+            if cycle.is_multiple_of(cycles_per_frame) {
+                for input in self.inputs.iter().filter(|input| input.frame == frame) {
+                    self.a.keypad[input.key as usize & 0xF] = input.pressed;
+                    self.b.keypad[input.key as usize & 0xF] = input.pressed;
+                }
+            }
+
+            let (pc, opcode) = (self.a.cpu.pc(), self.a.cpu.current_instruction());
+            let mut differences = match (self.a.step(), self.b.step()) {
+                (Err(a), Err(b)) if a == b => return Err(a),
+                (Ok(()), Ok(())) => Vec::new(),
+                (a, b) => vec![Difference::Fault(a.err(), b.err())],
+            };
+            differences.extend(compare(&self.a, &self.b));
+            if !differences.is_empty() {
+                return Ok(Some(Divergence { cycle, frame, pc, opcode, differences }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine(program : &[u8], spec : &str) -> Arch {
+        let mut arch = Arch::new();
+        arch.cpu.load_program(program);
+        arch.cpu.quirks = parse_config(spec).unwrap();
+        arch
+    }
+
+    #[test]
+    fn test_lockstep() {
+        // 0x200: RND V2, 0xFF, 0x202: LD V0, 0x01, 0x204: LD V1, 0x04,
+        // 0x206: SHR V0, V1, 0x208: JP 0x208
+        let program = [0xC2, 0xFF, 0x60, 0x01, 0x61, 0x04, 0x80, 0x16, 0x12, 0x08];
+
+        let mut same = Lockstep::new(machine(&program, "chip8"), machine(&program, "xochip,logic=on"), 1);
+        assert_eq!(same.run(10), Ok(None));
+
+        let mut lockstep = Lockstep::new(machine(&program, "chip8"), machine(&program, "chip8,shift=on"), 1);
+        let divergence = lockstep.run(10).unwrap().unwrap();
+        assert_eq!((divergence.cycle, divergence.pc, divergence.opcode), (3, 0x206, 0x8016));
+        assert_eq!(divergence.differences, [Difference::Register(0, 0x02, 0x00), Difference::Register(15, 0, 1)]);
+        assert_eq!(differing_quirks(lockstep.a.cpu.quirks, lockstep.b.cpu.quirks), ["shift"]);
+        assert!(divergence.report(("a", "b"), &Symbols::default()).contains("V0 02 vs 00"));
+
+        assert!(parse_config("chip8,wrap=on").is_err());
+        assert!(parse_config("schip,shift").is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use chip8::{app::App};
+use chip8::{app::App, cli, headless, tui};
 use winit::{
     event_loop::EventLoop, 
     platform::pump_events::{EventLoopExtPumpEvents, PumpStatus}
@@ -11,9 +11,39 @@ use std::thread;
 fn run() -> std::process::ExitCode {
     env_logger::init();
 
+    let mut args = match cli::parse(std::env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{}", cli::USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(error) => {
+            eprintln!("{error:#}\n\n{}", cli::USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Under --dap stdin belongs to the editor
+    if !cli::uses_dap(&args)
+        && let Err(error) = cli::choose_entry(&mut args) {
+        eprintln!("{error:#}");
+        return ExitCode::FAILURE;
+    }
+
+    if args.headless || args.tui {
+        let result = if args.tui {tui::run(&args)} else {headless::run(&args)};
+        return match result {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(error) => {
+                eprintln!("{error:#}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
     let mut event_loop = EventLoop::builder().build().unwrap();
-    let mut app = App::new();
-    loop {
+    let mut app = App::new(args);
+    let code = loop {
         let timeout = Some(Duration::ZERO);
         let status = event_loop.pump_app_events(timeout, &mut app);
 
@@ -21,13 +51,19 @@ fn run() -> std::process::ExitCode {
             break ExitCode::from(exit_code as u8);
         }
 
-        if let Some(arch) = &mut app.arch {
-            arch.emulate();
-            thread::sleep(Duration::from_millis(8));
-        };
+        if !app.frame() {
+            break ExitCode::SUCCESS;
+        }
+        thread::sleep(Duration::from_millis(16));
+    };
+
+    if let Err(error) = app.finish() {
+        eprintln!("{error:#}");
+        return ExitCode::FAILURE;
     }
+    code
 }
 
-fn main() {
-    let _ = run();
+fn main() -> ExitCode {
+    run()
 }
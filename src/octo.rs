@@ -0,0 +1,976 @@
+/* Assembles Octo source, the language of the Octo IDE, which is what
+cartridges usually carry instead of bytes:
+
+    : main
+        v0 := 0
+        loop
+            sprite v0 v0 5
+            v0 += 1
+            while v0 != 10
+        again
+
+Covered are the instructions of CHIP-8, SCHIP and XO-CHIP, labels and
+forward references, `loop`/`while`/`again`, `if ... then` and `if ...
+begin ... else ... end`, the `<`, `>`, `<=` and `>=` comparisons through
+VF, `:alias`, `:const`, `:calc` and `{ }` calculations (evaluated right to
+left, like Octo), `:macro`, `:unpack`, `:next`, `:org`, `:byte`,
+`:pointer` and `:call`. `:stringmode` is not; `:breakpoint`, `:monitor`
+and `:proto` are skipped.
+
+Like Octo, 0x200 holds a jump to `main` unless `main` comes first.
+*/
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::f64::consts::{E, PI};
+
+use anyhow::{anyhow, bail, Context};
+
+/// Where programs are loaded.
+const START : usize = 0x200;
+/// The end of the XO-CHIP's memory.
+const END : usize = 0x10000;
+
+/// An assembled program and where its instructions came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assembly {
+    pub program: Vec<u8>,
+    /// The address of every instruction with its line in the source,
+    /// counting from 1, in the order they were assembled.
+    pub lines: Vec<(u16, usize)>,
+    /// The address of every label.
+    pub labels: BTreeMap<String, u16>,
+}
+
+pub fn assemble(source : &str) -> anyhow::Result<Assembly> {
+    let mut assembler = Assembler::new(tokenize(source)?);
+    while let Some(token) = assembler.tokens.pop_front() {
+        let line = token.line;
+        assembler.line = line;
+        assembler.statement(token).with_context(|| format!("line {line}"))?;
+    }
+    assembler.finish()
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+fn tokenize(source : &str) -> anyhow::Result<VecDeque<Token>> {
+    let mut tokens = VecDeque::new();
+    for (number, mut rest) in source.lines().enumerate() {
+        loop {
+            rest = rest.trim_start();
+            if rest.is_empty() || rest.starts_with('#') {
+                break;
+            }
+            let end = match rest.strip_prefix('"') {
+                Some(string) => string.find('"').map(|end| end + 2)
+                    .with_context(|| format!("line {}: the string is not closed", number + 1))?,
+                None => rest.find(char::is_whitespace).unwrap_or(rest.len()),
+            };
+            tokens.push_back(Token { text: rest[..end].to_string(), line: number + 1 });
+            rest = &rest[end..];
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_number(text : &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        u32::from_str_radix(binary, 2).ok()?
+    } else if !digits.is_empty() && digits.bytes().all(|digit| digit.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative {-(value as f64)} else {value as f64})
+}
+
+fn to_byte(value : f64) -> anyhow::Result<u8> {
+    let value = value.floor() as i64;
+    if !(-128..=255).contains(&value) {
+        bail!("{value} does not fit in a byte");
+    }
+    Ok(value as u8)
+}
+
+/// Bytes to fill in once the address they refer to is known.
+#[derive(Debug, Clone, Copy)]
+enum Fixup {
+    /// The low 12 bits of the instruction at this offset.
+    Address(usize),
+    /// The two bytes at this offset.
+    Long(usize),
+    /// The immediates of the two instructions at this offset, the first
+    /// getting the nibble followed by the top of the address, or the whole
+    /// top byte for `long`, and the second the bottom byte.
+    Unpack(usize, Option<u8>),
+}
+
+enum Flow {
+    /// The start of the loop and the jumps out of it its `while`s made.
+    Loop { start: usize, exits: Vec<usize> },
+    /// The jump over the body when the condition does not hold.
+    If(usize),
+    /// The jump over the `else` branch.
+    Else(usize),
+}
+
+#[derive(Clone)]
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+enum Condition {
+    /// Whether the key in the register is pressed, or not for `-key`.
+    Key(u8, bool),
+    Compare(u8, String, Operand),
+}
+
+enum Operand {
+    Register(u8),
+    Byte(u8),
+}
+
+struct Assembler {
+    tokens: VecDeque<Token>,
+    /// The program from 0x200 on.
+    rom: Vec<u8>,
+    here: usize,
+    /// The line of the statement being assembled.
+    line: usize,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    /// Uses of labels not defined yet, with the line they are on.
+    fixups: Vec<(String, Fixup, usize)>,
+    flow: Vec<Flow>,
+    lines: Vec<(u16, usize)>,
+    expansions: usize,
+}
+
+impl Assembler {
+    fn new(tokens : VecDeque<Token>) -> Self {
+        Self {
+            tokens,
+            // Room for the jump to main
+            rom: vec![0; 2],
+            here: START + 2,
+            line: 0,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            flow: Vec::new(),
+            lines: Vec::new(),
+            expansions: 0,
+        }
+    }
+
+    fn next(&mut self) -> anyhow::Result<Token> {
+        self.tokens.pop_front().ok_or_else(|| anyhow!("the source ends in the middle of a statement"))
+    }
+
+    fn expect(&mut self, text : &str) -> anyhow::Result<()> {
+        let token = self.next()?;
+        if token.text != text {
+            bail!("expected {text}, found {}", token.text);
+        }
+        Ok(())
+    }
+
+    fn peek_is(&self, text : &str) -> bool {
+        self.tokens.front().is_some_and(|token| token.text == text)
+    }
+
+    fn register(&self, text : &str) -> Option<u8> {
+        if let Some(register) = self.aliases.get(text) {
+            return Some(*register);
+        }
+        let digit = text.strip_prefix(['v', 'V'])?;
+        if digit.len() != 1 {
+            return None;
+        }
+        u8::from_str_radix(digit, 16).ok()
+    }
+
+    fn next_register(&mut self) -> anyhow::Result<u8> {
+        let token = self.next()?;
+        self.register(&token.text).ok_or_else(|| anyhow!("expected a register, found {}", token.text))
+    }
+
+    fn name(&mut self) -> anyhow::Result<String> {
+        let token = self.next()?;
+        if !is_name(&token.text) {
+            bail!("{} can not be used as a name", token.text);
+        }
+        Ok(token.text)
+    }
+
+    /// The value of a number, a constant or a label defined already.
+    fn constant(&self, text : &str) -> Option<f64> {
+        parse_number(text)
+            .or_else(|| self.constants.get(text).copied())
+            .or_else(|| self.labels.get(text).map(|addr| *addr as f64))
+    }
+
+    /// A value known now: a number, a constant, a label defined already or
+    /// a calculation in braces.
+    fn value(&mut self) -> anyhow::Result<f64> {
+        let token = self.next()?;
+        if token.text == "{" {
+            let body = self.block()?;
+            return self.calculate(&body);
+        }
+        self.constant(&token.text).ok_or_else(|| anyhow!("{} is not a known value", token.text))
+    }
+
+    fn byte(&mut self) -> anyhow::Result<u8> {
+        to_byte(self.value()?)
+    }
+
+    fn nibble(&mut self) -> anyhow::Result<u8> {
+        let value = self.value()?;
+        if !(0.0..16.0).contains(&value) {
+            bail!("{value} does not fit in a nibble");
+        }
+        Ok(value as u8)
+    }
+
+    fn operand(&mut self) -> anyhow::Result<Operand> {
+        if let Some(register) = self.tokens.front().and_then(|token| self.register(&token.text)) {
+            self.next()?;
+            return Ok(Operand::Register(register));
+        }
+        Ok(Operand::Byte(self.byte()?))
+    }
+
+    /// The tokens up to the `}` closing a `{` already taken.
+    fn block(&mut self) -> anyhow::Result<Vec<Token>> {
+        let mut body = Vec::new();
+        let mut depth = 0;
+        loop {
+            let token = self.next()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 0 => return Ok(body),
+                "}" => depth -= 1,
+                _ => {}
+            }
+            body.push(token);
+        }
+    }
+
+    fn emit(&mut self, byte : u8) -> anyhow::Result<()> {
+        if self.here >= END {
+            bail!("the program does not fit in memory");
+        }
+        let offset = self.here - START;
+        if self.rom.len() <= offset {
+            self.rom.resize(offset + 1, 0);
+        }
+        self.rom[offset] = byte;
+        self.here += 1;
+        Ok(())
+    }
+
+    fn instruction(&mut self, opcode : u16) -> anyhow::Result<()> {
+        self.lines.push((self.here as u16, self.line));
+        self.emit((opcode >> 8) as u8)?;
+        self.emit(opcode as u8)
+    }
+
+    fn define(&mut self, name : String, addr : usize) -> anyhow::Result<()> {
+        if self.labels.contains_key(&name) {
+            bail!("{name} is defined twice");
+        }
+        self.labels.insert(name, addr as u16);
+        Ok(())
+    }
+
+    /// Fills in what `token` refers to now if it is known, or once it is
+    /// defined.
+    fn refer(&mut self, token : Token, fixup : Fixup) -> anyhow::Result<()> {
+        match self.constant(&token.text) {
+            Some(addr) => self.fill(fixup, addr),
+            None if is_name(&token.text) && self.register(&token.text).is_none() => {
+                self.fixups.push((token.text, fixup, token.line));
+                Ok(())
+            }
+            None => bail!("{} is not an address", token.text),
+        }
+    }
+
+    fn fill(&mut self, fixup : Fixup, addr : f64) -> anyhow::Result<()> {
+        let addr = addr.floor() as i64;
+        match fixup {
+            Fixup::Address(at) => {
+                if !(0..=0xFFF).contains(&addr) {
+                    bail!("0x{addr:X} is out of reach of a 12 bit address");
+                }
+                self.rom[at] |= (addr >> 8) as u8;
+                self.rom[at + 1] = addr as u8;
+            }
+            Fixup::Long(at) => {
+                if !(0..=0xFFFF).contains(&addr) {
+                    bail!("0x{addr:X} is outside of memory");
+                }
+                self.rom[at] = (addr >> 8) as u8;
+                self.rom[at + 1] = addr as u8;
+            }
+            Fixup::Unpack(at, nibble) => {
+                if !(0..=0xFFFF).contains(&addr) {
+                    bail!("0x{addr:X} is outside of memory");
+                }
+                self.rom[at + 1] = match nibble {
+                    Some(nibble) => (nibble << 4) | ((addr >> 8) & 0xF) as u8,
+                    None => (addr >> 8) as u8,
+                };
+                self.rom[at + 3] = addr as u8;
+            }
+        }
+        Ok(())
+    }
+
+    /// An instruction taking a 12 bit address from the next token.
+    fn address_instruction(&mut self, opcode : u16) -> anyhow::Result<()> {
+        let token = self.next()?;
+        let at = self.here - START;
+        self.instruction(opcode)?;
+        self.refer(token, Fixup::Address(at))
+    }
+
+    fn statement(&mut self, token : Token) -> anyhow::Result<()> {
+        if let Some(x) = self.register(&token.text) {
+            return self.assignment(x);
+        }
+
+        let register = |assembler : &mut Self| assembler.next_register().map(|x| (x as u16) << 8);
+        match token.text.as_str() {
+            ":" => {
+                let name = self.name()?;
+                // Nothing comes before main, so the jump to it is not needed
+                if name == "main" && self.here == START + 2 && self.rom.len() == 2 && self.labels.is_empty() {
+                    self.rom.clear();
+                    self.here = START;
+                }
+                self.define(name, self.here)
+            }
+            ":next" => {
+                let name = self.name()?;
+                self.define(name, self.here + 1)
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let register = self.next_register()?;
+                self.aliases.insert(name, register);
+                Ok(())
+            }
+            ":const" | ":calc" => {
+                let name = self.name()?;
+                let value = self.value()?;
+                self.constants.insert(name, value);
+                Ok(())
+            }
+            ":byte" => {
+                let byte = self.byte()?;
+                self.emit(byte)
+            }
+            ":org" => {
+                let addr = self.value()? as usize;
+                if !(START..END).contains(&addr) {
+                    bail!("0x{addr:X} is outside of the program's memory");
+                }
+                self.here = addr;
+                Ok(())
+            }
+            ":call" => self.address_instruction(0x2000),
+            ":pointer" => {
+                let token = self.next()?;
+                let at = self.here - START;
+                self.emit(0)?;
+                self.emit(0)?;
+                self.refer(token, Fixup::Long(at))
+            }
+            ":unpack" => {
+                let kind = self.next()?;
+                let nibble = match kind.text.as_str() {
+                    "long" => None,
+                    _ => Some(self.constant(&kind.text).filter(|value| (0.0..16.0).contains(value))
+                        .ok_or_else(|| anyhow!("{} is not a nibble", kind.text))? as u8),
+                };
+                let target = self.next()?;
+                let at = self.here - START;
+                self.instruction(0x6000)?;
+                self.instruction(0x6100)?;
+                self.refer(target, Fixup::Unpack(at, nibble))
+            }
+            ":breakpoint" | ":proto" => self.next().map(drop),
+            ":monitor" => {
+                self.next()?;
+                self.next().map(drop)
+            }
+            ":assert" => {
+                let message = match self.tokens.front() {
+                    Some(token) if token.text.starts_with('"') => self.next()?.text.trim_matches('"').to_string(),
+                    _ => "assertion failed".to_string(),
+                };
+                if self.value()? == 0.0 {
+                    bail!("{message}");
+                }
+                Ok(())
+            }
+            ":stringmode" => bail!(":stringmode is not supported"),
+            ":macro" => {
+                let name = self.name()?;
+                let mut args = Vec::new();
+                loop {
+                    let token = self.next()?;
+                    if token.text == "{" {
+                        break;
+                    }
+                    args.push(token.text);
+                }
+                let body = self.block()?;
+                self.macros.insert(name, Macro { args, body });
+                Ok(())
+            }
+
+            ";" | "return" => self.instruction(0x00EE),
+            "clear" => self.instruction(0x00E0),
+            "scroll-right" => self.instruction(0x00FB),
+            "scroll-left" => self.instruction(0x00FC),
+            "exit" => self.instruction(0x00FD),
+            "lores" => self.instruction(0x00FE),
+            "hires" => self.instruction(0x00FF),
+            "audio" => self.instruction(0xF002),
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.instruction(0x00C0 | n as u16)
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.instruction(0x00D0 | n as u16)
+            }
+            "plane" => {
+                let n = self.nibble()?;
+                self.instruction(0xF001 | (n as u16) << 8)
+            }
+            "bcd" => {
+                let x = register(self)?;
+                self.instruction(0xF033 | x)
+            }
+            "saveflags" => {
+                let x = register(self)?;
+                self.instruction(0xF075 | x)
+            }
+            "loadflags" => {
+                let x = register(self)?;
+                self.instruction(0xF085 | x)
+            }
+            "save" | "load" => {
+                let save = token.text == "save";
+                let x = register(self)?;
+                if self.peek_is("-") {
+                    self.next()?;
+                    let y = self.next_register()? as u16;
+                    self.instruction(if save {0x5002} else {0x5003} | x | y << 4)
+                } else {
+                    self.instruction(if save {0xF055} else {0xF065} | x)
+                }
+            }
+            "sprite" => {
+                let x = register(self)?;
+                let y = self.next_register()? as u16;
+                let n = self.nibble()? as u16;
+                self.instruction(0xD000 | x | y << 4 | n)
+            }
+            "jump" => self.address_instruction(0x1000),
+            "jump0" => self.address_instruction(0xB000),
+            "native" => self.address_instruction(0x0000),
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = register(self)?;
+                let opcode = match token.text.as_str() {
+                    "delay" => 0xF015,
+                    "buzzer" => 0xF018,
+                    _ => 0xF03A,
+                };
+                self.instruction(opcode | x)
+            }
+            "i" => self.index(),
+
+            "loop" => {
+                self.flow.push(Flow::Loop { start: self.here, exits: Vec::new() });
+                Ok(())
+            }
+            "while" => {
+                let condition = self.condition()?;
+                self.skip(condition, true)?;
+                let exit = self.here;
+                self.instruction(0x1000)?;
+                match self.flow.iter_mut().rev().find_map(|flow| match flow {
+                    Flow::Loop { exits, .. } => Some(exits),
+                    _ => None,
+                }) {
+                    Some(exits) => exits.push(exit),
+                    None => bail!("while outside of a loop"),
+                }
+                Ok(())
+            }
+            "again" => {
+                let Some(Flow::Loop { start, exits }) = self.flow.pop() else {
+                    bail!("again without a loop");
+                };
+                self.jump(start)?;
+                for exit in exits {
+                    self.fill(Fixup::Address(exit - START), self.here as f64)?;
+                }
+                Ok(())
+            }
+            "if" => {
+                let condition = self.condition()?;
+                match self.next()?.text.as_str() {
+                    "then" => self.skip(condition, false),
+                    "begin" => {
+                        self.skip(condition, true)?;
+                        self.flow.push(Flow::If(self.here));
+                        self.instruction(0x1000)
+                    }
+                    other => bail!("expected then or begin, found {other}"),
+                }
+            }
+            "else" => {
+                let Some(Flow::If(jump)) = self.flow.pop() else {
+                    bail!("else without an if ... begin");
+                };
+                self.flow.push(Flow::Else(self.here));
+                self.instruction(0x1000)?;
+                self.fill(Fixup::Address(jump - START), self.here as f64)
+            }
+            "end" => match self.flow.pop() {
+                Some(Flow::If(jump) | Flow::Else(jump)) => self.fill(Fixup::Address(jump - START), self.here as f64),
+                _ => bail!("end without an if ... begin"),
+            },
+
+            text => {
+                if let Some(value) = parse_number(text) {
+                    let byte = to_byte(value)?;
+                    return self.emit(byte);
+                }
+                if let Some(definition) = self.macros.get(text).cloned() {
+                    return self.expand(text, definition);
+                }
+                if !is_name(text) {
+                    bail!("unexpected {text}");
+                }
+                // Anything else names a subroutine to call
+                let at = self.here - START;
+                self.instruction(0x2000)?;
+                self.refer(token, Fixup::Address(at))
+            }
+        }
+    }
+
+    fn jump(&mut self, addr : usize) -> anyhow::Result<()> {
+        let at = self.here - START;
+        self.instruction(0x1000)?;
+        self.fill(Fixup::Address(at), addr as f64)
+    }
+
+    fn assignment(&mut self, x : u8) -> anyhow::Result<()> {
+        let op = self.next()?.text;
+        let x = (x as u16) << 8;
+        if let Some(y) = self.tokens.front().and_then(|token| self.register(&token.text)) {
+            self.next()?;
+            let operation = match op.as_str() {
+                ":=" => 0x0,
+                "|=" => 0x1,
+                "&=" => 0x2,
+                "^=" => 0x3,
+                "+=" => 0x4,
+                "-=" => 0x5,
+                ">>=" => 0x6,
+                "=-" => 0x7,
+                "<<=" => 0xE,
+                _ => bail!("{op} is not an assignment"),
+            };
+            return self.instruction(0x8000 | x | (y as u16) << 4 | operation);
+        }
+
+        match op.as_str() {
+            ":=" if self.peek_is("key") => {
+                self.next()?;
+                self.instruction(0xF00A | x)
+            }
+            ":=" if self.peek_is("delay") => {
+                self.next()?;
+                self.instruction(0xF007 | x)
+            }
+            ":=" if self.peek_is("random") => {
+                self.next()?;
+                let mask = self.byte()?;
+                self.instruction(0xC000 | x | mask as u16)
+            }
+            ":=" => {
+                let byte = self.byte()?;
+                self.instruction(0x6000 | x | byte as u16)
+            }
+            "+=" => {
+                let byte = self.byte()?;
+                self.instruction(0x7000 | x | byte as u16)
+            }
+            "-=" => {
+                let byte = self.byte()?;
+                self.instruction(0x7000 | x | byte.wrapping_neg() as u16)
+            }
+            _ => bail!("{op} needs a register on both sides"),
+        }
+    }
+
+    fn index(&mut self) -> anyhow::Result<()> {
+        let op = self.next()?.text;
+        match op.as_str() {
+            "+=" => {
+                let x = self.next_register()? as u16;
+                self.instruction(0xF01E | x << 8)
+            }
+            ":=" if self.peek_is("hex") || self.peek_is("bighex") => {
+                let opcode = if self.next()?.text == "hex" {0xF029} else {0xF030};
+                let x = self.next_register()? as u16;
+                self.instruction(opcode | x << 8)
+            }
+            ":=" if self.peek_is("long") => {
+                self.next()?;
+                let token = self.next()?;
+                self.instruction(0xF000)?;
+                let at = self.here - START;
+                self.emit(0)?;
+                self.emit(0)?;
+                self.refer(token, Fixup::Long(at))
+            }
+            ":=" => self.address_instruction(0xA000),
+            _ => bail!("{op} can not be done to i"),
+        }
+    }
+
+    fn condition(&mut self) -> anyhow::Result<Condition> {
+        let x = self.next_register()?;
+        let op = self.next()?.text;
+        match op.as_str() {
+            "key" => Ok(Condition::Key(x, true)),
+            "-key" => Ok(Condition::Key(x, false)),
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => Ok(Condition::Compare(x, op, self.operand()?)),
+            _ => bail!("{op} is not a comparison"),
+        }
+    }
+
+    /// Emits what skips the next instruction when the condition is `when`.
+    fn skip(&mut self, condition : Condition, when : bool) -> anyhow::Result<()> {
+        match condition {
+            Condition::Key(x, pressed) => {
+                let opcode = if pressed == when {0xE09E} else {0xE0A1};
+                self.instruction(opcode | (x as u16) << 8)
+            }
+            Condition::Compare(x, op, operand) if op == "==" || op == "!=" => {
+                let skip_equal = (op == "==") == when;
+                let x = (x as u16) << 8;
+                match operand {
+                    Operand::Register(y) => self.instruction(if skip_equal {0x5000} else {0x9000} | x | (y as u16) << 4),
+                    Operand::Byte(byte) => self.instruction(if skip_equal {0x3000} else {0x4000} | x | byte as u16),
+                }
+            }
+            Condition::Compare(x, op, operand) => {
+                // Subtracting sets VF when there is no borrow, which holds
+                // the answer for one comparison and its opposite
+                let temp = self.aliases.get("compare-temp").copied().unwrap_or(0xF) as u16;
+                match operand {
+                    Operand::Register(y) => self.instruction(0x8000 | temp << 8 | (y as u16) << 4)?,
+                    Operand::Byte(byte) => self.instruction(0x6000 | temp << 8 | byte as u16)?,
+                }
+                // VF after temp - x, or after x - temp
+                let (subtract, flag) = match op.as_str() {
+                    ">" => (0x5, 0),
+                    "<=" => (0x5, 1),
+                    "<" => (0x7, 0),
+                    _ => (0x7, 1),
+                };
+                self.instruction(0x8000 | temp << 8 | (x as u16) << 4 | subtract)?;
+                self.instruction(if when {0x3F00} else {0x4F00} | flag)
+            }
+        }
+    }
+
+    fn expand(&mut self, name : &str, definition : Macro) -> anyhow::Result<()> {
+        self.expansions += 1;
+        if self.expansions > 100_000 {
+            bail!("{name} expands without end");
+        }
+        let mut values = Vec::new();
+        for _ in &definition.args {
+            values.push(self.next()?.text);
+        }
+        // The expansion counts as being on the line of the use
+        for token in definition.body.iter().rev() {
+            let text = match definition.args.iter().position(|arg| *arg == token.text) {
+                Some(index) => values[index].clone(),
+                None => token.text.clone(),
+            };
+            self.tokens.push_front(Token { text, line: self.line });
+        }
+        Ok(())
+    }
+
+    fn calculate(&self, tokens : &[Token]) -> anyhow::Result<f64> {
+        let mut position = 0;
+        let value = self.calc_expression(tokens, &mut position)?;
+        if let Some(token) = tokens.get(position) {
+            bail!("unexpected {} in a calculation", token.text);
+        }
+        Ok(value)
+    }
+
+    /// A term, then an operator applied to it and everything after it, so
+    /// there is no precedence and calculations go right to left.
+    fn calc_expression(&self, tokens : &[Token], position : &mut usize) -> anyhow::Result<f64> {
+        let left = self.calc_term(tokens, position)?;
+        let op = match tokens.get(*position) {
+            None => return Ok(left),
+            Some(token) if token.text == ")" => return Ok(left),
+            Some(token) => token.text.as_str(),
+        };
+        *position += 1;
+        let right = self.calc_expression(tokens, position)?;
+        let (a, b) = (left as i64, right as i64);
+        Ok(match op {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "&" => (a & b) as f64,
+            "|" => (a | b) as f64,
+            "^" => (a ^ b) as f64,
+            "<<" => (a << b) as f64,
+            ">>" => (a >> b) as f64,
+            "<" => (left < right) as u8 as f64,
+            ">" => (left > right) as u8 as f64,
+            "<=" => (left <= right) as u8 as f64,
+            ">=" => (left >= right) as u8 as f64,
+            "==" => (left == right) as u8 as f64,
+            "!=" => (left != right) as u8 as f64,
+            _ => bail!("{op} is not an operator"),
+        })
+    }
+
+    fn calc_term(&self, tokens : &[Token], position : &mut usize) -> anyhow::Result<f64> {
+        let token = tokens.get(*position).context("a calculation ends too early")?;
+        *position += 1;
+        let unary : fn(f64) -> f64 = match token.text.as_str() {
+            "(" => {
+                let value = self.calc_expression(tokens, position)?;
+                if tokens.get(*position).is_none_or(|token| token.text != ")") {
+                    bail!("a ( is not closed");
+                }
+                *position += 1;
+                return Ok(value);
+            }
+            "HERE" => return Ok(self.here as f64),
+            "PI" => return Ok(PI),
+            "E" => return Ok(E),
+            "-" => |value| -value,
+            "~" => |value| !(value as i64) as f64,
+            "!" => |value| (value == 0.0) as u8 as f64,
+            "sin" => f64::sin,
+            "cos" => f64::cos,
+            "tan" => f64::tan,
+            "exp" => f64::exp,
+            "log" => f64::ln,
+            "abs" => f64::abs,
+            "sqrt" => f64::sqrt,
+            "sign" => |value| if value == 0.0 {0.0} else {value.signum()},
+            "ceil" => f64::ceil,
+            "floor" => f64::floor,
+            "@" => {
+                let addr = self.calc_term(tokens, position)? as usize;
+                let byte = addr.checked_sub(START).and_then(|offset| self.rom.get(offset));
+                return Ok(byte.copied().unwrap_or(0) as f64);
+            }
+            text => return self.constant(text).ok_or_else(|| anyhow!("{text} is not a known value")),
+        };
+        Ok(unary(self.calc_term(tokens, position)?))
+    }
+
+    fn finish(mut self) -> anyhow::Result<Assembly> {
+        match self.flow.last() {
+            Some(Flow::Loop { .. }) => bail!("a loop has no again"),
+            Some(_) => bail!("an if ... begin has no end"),
+            None => {}
+        }
+        for (name, fixup, line) in std::mem::take(&mut self.fixups) {
+            let addr = self.constant(&name).with_context(|| format!("line {line}: {name} is not defined"))?;
+            self.fill(fixup, addr).with_context(|| format!("line {line}"))?;
+        }
+
+        let main = *self.labels.get("main").context("the program has no main label")?;
+        if main as usize != START {
+            self.fill(Fixup::Address(0), main as f64).context("main")?;
+            self.rom[0] |= 0x10;
+        }
+        Ok(Assembly { program: self.rom, lines: self.lines, labels: self.labels.into_iter().collect() })
+    }
+}
+
+/// Whether the token can name a label, a constant or a macro.
+fn is_name(text : &str) -> bool {
+    !text.starts_with(['"', ':', '{', '}', '(', ')', ';']) && parse_number(text).is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble() {
+        let assembly = assemble("\
+: main
+    v0 := 0
+    loop
+        v0 += 1
+        draw                # forward reference
+        while v0 != 10
+    again
+    if v0 == 10 begin
+        clear
+    else
+        v1 := key
+    end
+    i := long sprite-data
+    jump main
+: draw
+    sprite v0 v1 8
+    ;
+: sprite-data
+    0xFF 0b10000001 -1
+").unwrap();
+        assert_eq!(assembly.program, [
+            0x60, 0x00, 0x70, 0x01, 0x22, 0x1C, 0x40, 0x0A, 0x12, 0x0C, 0x12, 0x02, 0x30, 0x0A, 0x12, 0x14,
+            0x00, 0xE0, 0x12, 0x16, 0xF1, 0x0A, 0xF0, 0x00, 0x02, 0x20, 0x12, 0x00, 0xD0, 0x18, 0x00, 0xEE,
+            0xFF, 0x81, 0xFF,
+        ]);
+        assert_eq!(assembly.lines[..3], [(0x200, 2), (0x202, 4), (0x204, 5)]);
+        assert!(assembly.lines.contains(&(0x21C, 16)));
+    }
+
+    #[test]
+    fn test_const() {
+        let assembly = assemble("\
+:const speed 3
+:const mask 0x0F
+: main
+    v0 := speed
+    v1 += speed
+    v2 := random mask
+").unwrap();
+        assert_eq!(assembly.program, [0x60, 0x03, 0x71, 0x03, 0xC2, 0x0F]);
+    }
+
+    #[test]
+    fn test_calc() {
+        let assembly = assemble("\
+:const speed 3
+:calc double { speed * 2 }
+:calc right-to-left { 2 * 3 + 1 }
+: main
+    v0 := double
+    v1 := right-to-left
+    v2 := { ( 2 * 3 ) + 1 }
+    :byte { HERE - 0x200 }
+").unwrap();
+        assert_eq!(assembly.program, [0x60, 0x06, 0x61, 0x08, 0x62, 0x07, 0x06]);
+    }
+
+    #[test]
+    fn test_macro() {
+        let assembly = assemble("\
+:macro bump reg amount { reg += amount }
+: main
+    bump v3 2
+    bump v4 7
+").unwrap();
+        assert_eq!(assembly.program, [0x73, 0x02, 0x74, 0x07]);
+        // Each expansion is on the line of its use
+        assert_eq!(assembly.lines, [(0x200, 3), (0x202, 4)]);
+    }
+
+    #[test]
+    fn test_org() {
+        let assembly = assemble("\
+: main
+    jump far
+:org 0x210
+: far
+    v0 := 1
+").unwrap();
+        let mut program = vec![0; 0x12];
+        program[..2].copy_from_slice(&[0x12, 0x10]);
+        program[0x10..].copy_from_slice(&[0x60, 0x01]);
+        assert_eq!(assembly.program, program);
+    }
+
+    #[test]
+    fn test_directives() {
+        let assembly = assemble("\
+:alias counter v3
+: helper
+    return
+: main
+    if v1 > 5 then v2 := 1
+    counter += 1
+    :unpack 0xA helper
+    :pointer helper
+:next patched
+    v4 := 0
+").unwrap();
+        // The jump to main first, as main is not
+        assert_eq!(assembly.program, [
+            0x12, 0x04, 0x00, 0xEE, 0x6F, 0x05, 0x8F, 0x15, 0x4F, 0x00, 0x62, 0x01, 0x73, 0x01, 0x60, 0xA2,
+            0x61, 0x02, 0x02, 0x02, 0x64, 0x00,
+        ]);
+        assert!(assembly.lines.contains(&(0x20C, 6)));
+        assert_eq!(assembly.labels, BTreeMap::from([
+            ("helper".to_string(), 0x202),
+            ("main".to_string(), 0x204),
+            ("patched".to_string(), 0x215),
+        ]));
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source : &str| format!("{:#}", assemble(source).unwrap_err());
+        assert!(error(": helper ;").contains("no main"));
+        assert_eq!(error(": main\n jump nowhere"), "line 2: nowhere is not defined");
+        assert!(error(": main loop").contains("no again"));
+        assert!(error(": main jump 0x1000").contains("out of reach"));
+        assert!(error(": main v0 := 256").starts_with("line 1: 256"));
+        assert!(error(": main :stringmode").contains("stringmode"));
+        assert!(error(": main :org 0x100").contains("outside of the program's memory"));
+        assert!(error(":macro forever { forever }\n: main forever").contains("without end"));
+        assert!(error(": main :calc broken { 1 + }").contains("ends too early"));
+        assert!(error(": main :const speed").contains("ends in the middle"));
+    }
+}
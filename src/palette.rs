@@ -0,0 +1,177 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+
+/// Colours used to draw the screen, stored as 8 bit sRGB. XO-CHIP draws on
+/// two planes, so a pixel takes one of four colours depending on the planes
+/// it is set on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Palette {
+    pub background: [u8; 3],
+    /// Pixels set on the first plane, the only one before XO-CHIP.
+    pub foreground: [u8; 3],
+    /// Pixels set on the second plane only.
+    pub foreground2: [u8; 3],
+    /// Pixels set on both planes.
+    pub blend: [u8; 3],
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self {
+            background: [0x00, 0x00, 0x00],
+            foreground: [0xFF, 0xFF, 0xFF],
+            foreground2: [0xAA, 0xAA, 0xAA],
+            blend: [0x55, 0x55, 0x55],
+        }
+    }
+}
+
+impl Palette {
+    /// The colours by the planes a pixel is set on, the first plane being
+    /// the lowest bit.
+    pub fn colors(&self) -> [[u8; 3]; 4] {
+        [self.background, self.foreground, self.foreground2, self.blend]
+    }
+}
+
+/// The themes that come with the emulator.
+pub const THEMES : [(&str, Palette); 4] = [
+    ("default", Palette {
+        background: [0x00, 0x00, 0x00],
+        foreground: [0xFF, 0xFF, 0xFF],
+        foreground2: [0xAA, 0xAA, 0xAA],
+        blend: [0x55, 0x55, 0x55],
+    }),
+    // The defaults of Octo
+    ("octo", Palette {
+        background: [0x99, 0x66, 0x00],
+        foreground: [0xFF, 0xCC, 0x00],
+        foreground2: [0xFF, 0x66, 0x00],
+        blend: [0x66, 0x22, 0x00],
+    }),
+    // Amber phosphor, like a COSMAC VIP on a monitor of its day
+    ("vip", Palette {
+        background: [0x1F, 0x13, 0x00],
+        foreground: [0xFF, 0xB0, 0x00],
+        foreground2: [0xFF, 0x78, 0x00],
+        blend: [0x7A, 0x4A, 0x00],
+    }),
+    // A green LCD, like the HP48 calculators SCHIP ran on
+    ("lcd", Palette {
+        background: [0x9B, 0xBC, 0x0F],
+        foreground: [0x0F, 0x38, 0x0F],
+        foreground2: [0x30, 0x62, 0x30],
+        blend: [0x8B, 0xAC, 0x0F],
+    }),
+];
+
+/// Where users keep their own themes, `chip8/themes` in the configuration
+/// directory.
+pub fn themes_path() -> Option<PathBuf> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config.join("chip8").join("themes"))
+}
+
+/// Parses themes, one a line: a name, then the background and foreground
+/// colours and optionally the XO-CHIP second plane and blend colours, with
+/// lines starting with `#` left out.
+///
+/// ```text
+/// paper  #F0F0E8 #202020
+/// neon   #000000 #00FFCC #FF00AA #FFFFFF
+/// ```
+pub fn parse_themes(text : &str) -> anyhow::Result<Vec<(String, Palette)>> {
+    let mut themes = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (name, colors) = match words[..] {
+            [name, ref colors @ ..] if colors.len() == 2 || colors.len() == 4 => (name, colors),
+            _ => bail!("line {} should be a name followed by 2 or 4 colours", number + 1),
+        };
+        let colors = colors.iter()
+            .map(|color| parse_color(color).with_context(|| format!("{color} is not a colour on line {}", number + 1)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mut palette = Palette { background: colors[0], foreground: colors[1], ..Palette::default() };
+        if let [_, _, foreground2, blend] = colors[..] {
+            palette.foreground2 = foreground2;
+            palette.blend = blend;
+        }
+        themes.push((name.to_string(), palette));
+    }
+    Ok(themes)
+}
+
+/// The theme called `name`, looked for in the user's themes before the
+/// built in ones.
+pub fn load_theme(name : &str) -> anyhow::Result<Palette> {
+    let mut themes = Vec::new();
+    if let Some(path) = themes_path()
+        && path.exists() {
+        let text = std::fs::read_to_string(&path).with_context(|| format!("could not read {}", path.display()))?;
+        themes = parse_themes(&text).with_context(|| format!("in {}", path.display()))?;
+    }
+    themes.extend(THEMES.iter().map(|(name, palette)| (name.to_string(), *palette)));
+
+    match themes.iter().find(|(theme, _)| theme.eq_ignore_ascii_case(name)) {
+        Some((_, palette)) => Ok(*palette),
+        None => {
+            let names: Vec<&str> = themes.iter().map(|(name, _)| name.as_str()).collect();
+            bail!("unknown theme {name}, expected one of {}", names.join(", "))
+        }
+    }
+}
+
+/// Parses a colour written as `#RRGGBB`, the `#` being optional.
+pub fn parse_color(text : &str) -> Option<[u8; 3]> {
+    let hex = text.trim().trim_start_matches('#');
+    if hex.len() != 6 {
+        return None;
+    }
+
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    Some([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+}
+
+/// Converts an sRGB colour to the linear values the gpu expects when
+/// drawing to an sRGB surface.
+pub fn to_linear(color : [u8; 3]) -> [f32; 4] {
+    let convert = |channel : u8| {
+        let c = channel as f32 / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+
+    [convert(color[0]), convert(color[1]), convert(color[2]), 1.0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_themes() {
+        let text = "# name background foreground\npaper #F0F0E8 202020\n\nneon #000000 #00FFCC #FF00AA #FFFFFF\n";
+        let themes = parse_themes(text).unwrap();
+        assert_eq!(themes[0].0, "paper");
+        assert_eq!(themes[0].1.background, [0xF0, 0xF0, 0xE8]);
+        assert_eq!(themes[0].1.foreground2, Palette::default().foreground2);
+        assert_eq!(themes[1].1.colors()[2..], [[0xFF, 0x00, 0xAA], [0xFF, 0xFF, 0xFF]]);
+
+        assert!(parse_themes("odd #000000 #FFFFFF #FF0000").is_err());
+        assert!(parse_themes("bad #000000 #GGGGGG").is_err());
+        assert_eq!(THEMES[0].1, Palette::default());
+    }
+}
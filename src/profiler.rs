@@ -0,0 +1,222 @@
+/* Counts where a rom spends its cycles: executions per address and per
+opcode pattern, and per subroutine the cycles spent inside it (inclusive)
+and in its own instructions (exclusive).
+
+Subroutines are followed by watching the depth of the stack between
+instructions rather than decoding CALL and RET, so the frame entered is
+always where the pc landed. The frames are named after their entry
+address, or its symbol.
+
+The folded output has a line per call path with the cycles spent at its
+end, `main;draw_paddle;draw_digit 1200`, which flamegraph.pl and inferno
+turn into flame graphs.
+*/
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+
+use anyhow::Context;
+
+use crate::instruction::Instruction;
+use crate::symbols::Symbols;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SubroutineStats {
+    pub calls: u64,
+    pub inclusive: u64,
+    pub exclusive: u64,
+}
+
+#[derive(Debug)]
+pub struct Profiler {
+    pub cycles: u64,
+    pub per_address: Box<[u64; 4096]>,
+    pub per_pattern: HashMap<&'static str, u64>,
+    pub subroutines: BTreeMap<u16, SubroutineStats>,
+    /// Entry addresses of the frames being run, the outermost first.
+    frames: Vec<u16>,
+    folded: HashMap<Vec<u16>, u64>,
+    pub symbols: Symbols,
+    /// Where `save` writes the report.
+    pub report_path: Option<String>,
+    /// Where `save` writes the folded stacks.
+    pub folded_path: Option<String>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            cycles: 0,
+            per_address: Box::new([0; 4096]),
+            per_pattern: HashMap::new(),
+            subroutines: BTreeMap::new(),
+            frames: Vec::new(),
+            folded: HashMap::new(),
+            symbols: Symbols::new(),
+            report_path: None,
+            folded_path: None,
+        }
+    }
+
+    /// Counts the instruction `opcode` at `pc` once it ran, `calls` being
+    /// how deep the stack was before it ran.
+    pub fn record(&mut self, pc : u16, opcode : u16, calls : usize) {
+        // The machine runs from wherever it started, which is the outermost
+        // frame
+        if self.frames.is_empty() {
+            self.enter(pc);
+        }
+        let depth = calls + 1;
+        while self.frames.len() > depth {
+            self.frames.pop();
+        }
+        if self.frames.len() < depth {
+            self.enter(pc);
+        }
+
+        self.cycles += 1;
+        self.per_address[pc as usize] += 1;
+        *self.per_pattern.entry(Instruction::decode(opcode).pattern()).or_default() += 1;
+
+        for (index, entry) in self.frames.iter().enumerate() {
+            // Recursion counts once
+            if !self.frames[..index].contains(entry) {
+                self.subroutines.entry(*entry).or_default().inclusive += 1;
+            }
+        }
+        if let Some(top) = self.frames.last() {
+            self.subroutines.entry(*top).or_default().exclusive += 1;
+        }
+
+        match self.folded.get_mut(&self.frames[..]) {
+            Some(count) => *count += 1,
+            None => {
+                self.folded.insert(self.frames.clone(), 1);
+            }
+        }
+    }
+
+    fn enter(&mut self, entry : u16) {
+        self.frames.push(entry);
+        self.subroutines.entry(entry).or_default().calls += 1;
+    }
+
+    fn name(&self, addr : u16) -> String {
+        match self.symbols.name_of(addr) {
+            Some(name) => name.to_string(),
+            None => format!("sub_{addr:03X}"),
+        }
+    }
+
+    /// A text report of the hottest addresses, opcodes and subroutines.
+    pub fn report(&self, memory : &[u8; 4096]) -> String {
+        let mut out = String::new();
+        let percent = |count : u64| count as f64 * 100.0 / self.cycles.max(1) as f64;
+        let _ = writeln!(out, "{} instructions executed", self.cycles);
+
+        let _ = writeln!(out, "\nHottest addresses");
+        let mut addresses: Vec<(usize, u64)> = self.per_address.iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        addresses.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for (addr, count) in addresses.into_iter().take(20) {
+            let instr = ((memory[addr] as u16) << 8) | memory[(addr + 1) % 4096] as u16;
+            let _ = writeln!(out, "{count:>12} {:>6.2}%  {:<28} {}",
+                percent(count), self.symbols.describe(addr as u16), self.symbols.instruction(Instruction::decode(instr)));
+        }
+
+        let _ = writeln!(out, "\nOpcodes");
+        let mut patterns: Vec<(&str, u64)> = self.per_pattern.iter().map(|(pattern, count)| (*pattern, *count)).collect();
+        patterns.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        for (pattern, count) in patterns {
+            let _ = writeln!(out, "{count:>12} {:>6.2}%  {pattern}", percent(count));
+        }
+
+        let _ = writeln!(out, "\nSubroutines    inclusive            exclusive        calls");
+        let mut subroutines: Vec<(&u16, &SubroutineStats)> = self.subroutines.iter().collect();
+        subroutines.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(b.0)));
+        for (entry, stats) in subroutines {
+            let _ = writeln!(out, "{:>12} {:>6.2}% {:>12} {:>6.2}% {:>12}  {}",
+                stats.inclusive, percent(stats.inclusive), stats.exclusive, percent(stats.exclusive), stats.calls, self.name(*entry));
+        }
+
+        out
+    }
+
+    /// The call paths and the cycles spent at their end, one per line.
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<String> = self.folded.iter()
+            .map(|(frames, count)| {
+                let path: Vec<String> = frames.iter().map(|entry| self.name(*entry)).collect();
+                format!("{} {count}", path.join(";"))
+            })
+            .collect();
+        lines.sort();
+        lines.join("\n") + "\n"
+    }
+
+    /// Writes the report and the folded stacks where they were asked for.
+    pub fn save(&self, memory : &[u8; 4096]) -> anyhow::Result<()> {
+        if let Some(path) = &self.report_path {
+            std::fs::write(path, self.report(memory)).with_context(|| format!("could not write the profile to {path}"))?;
+        }
+        if let Some(path) = &self.folded_path {
+            std::fs::write(path, self.folded()).with_context(|| format!("could not write the folded stacks to {path}"))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::Arch;
+
+    #[test]
+    fn test_profile() {
+        let mut arch = Arch::new();
+        // 0x200: CALL 0x206, 0x202: JP 0x200, 0x206: ADD V0, 1, 0x208: CALL 0x20C,
+        // 0x20A: RET, 0x20C: RET
+        arch.cpu.load_program(&[0x22, 0x06, 0x12, 0x00, 0x00, 0x00, 0x70, 0x01, 0x22, 0x0C, 0x00, 0xEE, 0x00, 0xEE]);
+        let mut profiler = Profiler::new();
+        profiler.symbols = Symbols::parse("0x200 main\n0x206 update").unwrap();
+        arch.profiler = Some(profiler);
+        // Two rounds of the loop
+        for _ in 0..12 {
+            arch.step().unwrap();
+        }
+
+        let profiler = arch.profiler.as_ref().unwrap();
+        assert_eq!(profiler.cycles, 12);
+        assert_eq!(profiler.per_address[0x206], 2);
+        assert_eq!(profiler.per_pattern["00EE"], 4);
+        assert_eq!(profiler.subroutines[&0x200], SubroutineStats { calls: 1, inclusive: 12, exclusive: 4 });
+        assert_eq!(profiler.subroutines[&0x206], SubroutineStats { calls: 2, inclusive: 8, exclusive: 6 });
+        assert_eq!(profiler.subroutines[&0x20C], SubroutineStats { calls: 2, inclusive: 2, exclusive: 2 });
+        assert_eq!(profiler.folded(), "main 4\nmain;update 6\nmain;update;sub_20C 2\n");
+        assert!(profiler.report(arch.cpu.memory()).contains("0x206 <update>"));
+    }
+
+    #[test]
+    fn test_fault_is_not_counted() {
+        let mut arch = Arch::new();
+        // LD V0, 1; RET with nothing to return to
+        arch.cpu.load_program(&[0x60, 0x01, 0x00, 0xEE]);
+        arch.profiler = Some(Profiler::new());
+        arch.step().unwrap();
+        assert!(arch.step().is_err());
+
+        let profiler = arch.profiler.as_ref().unwrap();
+        assert_eq!(profiler.cycles, 1);
+        assert_eq!(profiler.per_address[0x202], 0);
+        assert!(!profiler.per_pattern.contains_key("00EE"));
+    }
+}
@@ -0,0 +1,189 @@
+/* Records the screen as an animated GIF, a frame each 60Hz frame, in the
+colours of the palette. The GIF carries all four colours of the palette,
+though with the single plane of the display only two of them show.
+
+GIF delays are in hundredths of a second and viewers slow delays under 2 of
+them right down, so the recording runs at 50 frames a second: a frame
+shorter than a fiftieth of a second is replaced by the one after it. Frames
+that are the same as the one before only make it last longer, and the
+others only store the rectangle that changed, which keeps recordings of
+mostly still games small. */
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::display::{Display, HEIGHT, WIDTH};
+use crate::palette::Palette;
+
+type Pixels = [bool; WIDTH * HEIGHT];
+
+pub struct Recorder {
+    encoder: gif::Encoder<Box<dyn Write + Send>>,
+    scale: usize,
+    /// Frames recorded so far.
+    frames: u64,
+    /// The frame that is not written yet, as we do not know how long it
+    /// lasts, and the frame it started on.
+    pending: Option<(Pixels, u64)>,
+    /// The last frame written, the changes are made against it.
+    written: Option<Pixels>,
+}
+
+impl Recorder {
+    pub fn new(output : impl Write + Send + 'static, palette : &Palette, scale : usize) -> anyhow::Result<Self> {
+        let scale = scale.max(1);
+        let colours = palette.colors().concat();
+        let output : Box<dyn Write + Send> = Box::new(output);
+        let mut encoder = gif::Encoder::new(output, (WIDTH * scale) as u16, (HEIGHT * scale) as u16, &colours)?;
+        encoder.set_repeat(gif::Repeat::Infinite)?;
+        Ok(Self { encoder, scale, frames: 0, pending: None, written: None })
+    }
+
+    pub fn create(path : &Path, palette : &Palette, scale : usize) -> anyhow::Result<Self> {
+        let file = File::create(path).with_context(|| format!("could not create recording {}", path.display()))?;
+        Self::new(BufWriter::new(file), palette, scale)
+    }
+
+    /// Records the screen at the end of a frame.
+    pub fn record(&mut self, display : &Display) -> anyhow::Result<()> {
+        let pixels = display.pixels();
+        match self.pending {
+            Some((pending, _)) if pending == *pixels => {}
+            // It would not have lasted a fiftieth of a second
+            Some((_, start)) if fiftieths(start) == fiftieths(self.frames) => self.pending = Some((*pixels, start)),
+            Some(_) => {
+                self.write_pending()?;
+                self.pending = Some((*pixels, self.frames));
+            }
+            None => self.pending = Some((*pixels, self.frames)),
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// Writes the last frame and the end of the GIF.
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        self.write_pending()?;
+        self.encoder.into_inner()?.flush()?;
+        Ok(())
+    }
+
+    fn write_pending(&mut self) -> anyhow::Result<()> {
+        let Some((pixels, start)) = self.pending.take() else {
+            return Ok(());
+        };
+        let (left, top, right, bottom) = match &self.written {
+            Some(written) => changed(written, &pixels).unwrap_or((0, 0, 1, 1)),
+            None => (0, 0, WIDTH, HEIGHT),
+        };
+
+        let scale = self.scale;
+        let (width, height) = ((right - left) * scale, (bottom - top) * scale);
+        let mut indices = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let row = &pixels[(top + y / scale) * WIDTH..][..WIDTH];
+            indices.extend((0..width).map(|x| row[left + x / scale] as u8));
+        }
+
+        let mut frame = gif::Frame::from_indexed_pixels(width as u16, height as u16, indices, None);
+        frame.left = (left * scale) as u16;
+        frame.top = (top * scale) as u16;
+        let lasted = (fiftieths(self.frames) - fiftieths(start)).max(1);
+        frame.delay = u16::try_from(lasted * 2).unwrap_or(u16::MAX);
+        frame.dispose = gif::DisposalMethod::Keep;
+        self.encoder.write_frame(&frame)?;
+        self.written = Some(pixels);
+        Ok(())
+    }
+}
+
+/// When a 60Hz frame starts, rounded down to fiftieths of a second.
+fn fiftieths(frame : u64) -> u64 {
+    frame * 50 / 60
+}
+
+/// The smallest rectangle holding every pixel that differs, as left, top,
+/// right and bottom with the ends excluded.
+fn changed(a : &Pixels, b : &Pixels) -> Option<(usize, usize, usize, usize)> {
+    let mut bounds : Option<(usize, usize, usize, usize)> = None;
+    for (index, _) in a.iter().zip(b).enumerate().filter(|(_, (a, b))| a != b) {
+        let (x, y) = (index % WIDTH, index / WIDTH);
+        bounds = Some(match bounds {
+            Some((left, top, right, bottom)) => (left.min(x), top.min(y), right.max(x + 1), bottom.max(y + 1)),
+            None => (x, y, x + 1, y + 1),
+        });
+    }
+    bounds
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_recorder() {
+        let output = Shared::default();
+        let mut recorder = Recorder::new(output.clone(), &Palette::default(), 2).unwrap();
+        let mut display = Display::new();
+        for _ in 0..3 {
+            recorder.record(&display).unwrap();
+        }
+        display.xor_sprite(10, 4, &[0xC0, 0xC0]);
+        recorder.record(&display).unwrap();
+        recorder.record(&display).unwrap();
+        recorder.finish().unwrap();
+
+        let bytes = output.0.lock().unwrap().clone();
+        let mut decoder = gif::DecodeOptions::new().read_info(io::Cursor::new(bytes)).unwrap();
+        assert_eq!((decoder.width(), decoder.height()), (128, 64));
+
+        // Three blank frames as one, then only the square that appeared
+        let first = decoder.read_next_frame().unwrap().unwrap();
+        assert_eq!((first.width, first.height, first.delay), (128, 64, 4));
+        let second = decoder.read_next_frame().unwrap().unwrap().clone();
+        assert_eq!((second.left, second.top, second.width, second.height), (20, 8, 4, 4));
+        assert_eq!(second.delay, 4);
+        assert!(decoder.read_next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_moving_sprite() {
+        let output = Shared::default();
+        let mut recorder = Recorder::new(output.clone(), &Palette::default(), 1).unwrap();
+        let mut display = Display::new();
+        for x in 0..60 {
+            display.clear();
+            display.xor_sprite(x, 0, &[0x80]);
+            recorder.record(&display).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        let bytes = output.0.lock().unwrap().clone();
+        let mut decoder = gif::DecodeOptions::new().read_info(io::Cursor::new(bytes)).unwrap();
+        let mut delays = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+        }
+        // A second at 50 frames a second
+        assert_eq!(delays, [2; 50]);
+    }
+}
@@ -0,0 +1,261 @@
+use std::fmt;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+use anyhow::{bail, Context};
+
+use crate::cartridge::{self, Options};
+use crate::cpu::Platform;
+use crate::symbols::Symbols;
+
+/// The biggest program that fits between 0x200 and the end of memory.
+pub const MAX_PROGRAM_SIZE : usize = 4096 - 0x200;
+
+/// The most unpacked out of an archive, plenty for a cartridge while
+/// keeping a compressed bomb from taking all the memory.
+const MAX_UNPACKED_SIZE : u64 = 1 << 20;
+
+/// Extensions of the files that are picked out of archives.
+pub const ROM_EXTENSIONS : [&str; 4] = ["ch8", "c8", "sc8", "xo8"];
+
+/// A program ready to be put in memory, with the options it was
+/// distributed with, if any.
+#[derive(Debug)]
+pub struct Rom {
+    pub program: Vec<u8>,
+    pub options: Option<Options>,
+    /// The platform the file name suggests, like `.sc8` for SCHIP.
+    pub platform: Option<Platform>,
+    /// The labels of the Octo source a cartridge was assembled from.
+    pub symbols: Symbols,
+}
+
+/// Returned when an archive holds more than one rom and no entry was
+/// chosen, so the caller can let the user pick one.
+#[derive(Debug)]
+pub struct AmbiguousArchive {
+    pub entries: Vec<String>,
+}
+
+impl fmt::Display for AmbiguousArchive {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "archive holds several roms, pick one of: {}", self.entries.join(", "))
+    }
+}
+
+impl std::error::Error for AmbiguousArchive {}
+
+/// Reads the rom at `rom_path`. Zip and gzip archives are unpacked, Octo
+/// cartridges are decoded and anything else is taken as a raw program.
+pub fn load(rom_path : &str) -> anyhow::Result<Rom> {
+    load_entry(rom_path, None)
+}
+
+/// Same as `load`, picking `entry` when the rom is a zip archive.
+pub fn load_entry(rom_path : &str, entry : Option<&str>) -> anyhow::Result<Rom> {
+    let bytes = std::fs::read(rom_path)
+        .with_context(|| format!("could not read rom {rom_path}"))?;
+
+    from_bytes(rom_path, bytes, entry)
+        .with_context(|| format!("could not load rom {rom_path}"))
+}
+
+/// Lists the roms inside the archive at `rom_path`. Files that are not
+/// archives list nothing.
+pub fn list_entries(rom_path : &str) -> anyhow::Result<Vec<String>> {
+    let bytes = std::fs::read(rom_path)
+        .with_context(|| format!("could not read rom {rom_path}"))?;
+
+    if is_zip(&bytes) {
+        zip_entries(&bytes)
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+pub fn from_bytes(name : &str, bytes : Vec<u8>, entry : Option<&str>) -> anyhow::Result<Rom> {
+    from_unpacked(name, bytes, entry, false)
+}
+
+/// Only one archive is unpacked, so an archive inside an archive, and a
+/// gzip quine that unpacks to itself, is refused instead of recursing.
+fn from_unpacked(name : &str, bytes : Vec<u8>, entry : Option<&str>, nested : bool) -> anyhow::Result<Rom> {
+    if nested && (is_zip(&bytes) || is_gzip(&bytes)) {
+        bail!("{name} is an archive inside an archive");
+    }
+
+    if is_zip(&bytes) {
+        let (entry_name, entry_bytes) = read_zip(&bytes, entry)?;
+        return from_unpacked(&entry_name, entry_bytes, None, true);
+    }
+
+    if is_gzip(&bytes) {
+        let mut decoder = flate2::read::GzDecoder::new(bytes.as_slice());
+        let unpacked = unpack(&mut decoder).context("could not unpack gzip")?;
+
+        // The header may keep the original name, otherwise it is ours minus .gz
+        let inner_name = decoder.header()
+            .and_then(|header| header.filename())
+            .map(|filename| String::from_utf8_lossy(filename).into_owned())
+            .unwrap_or_else(|| name.trim_end_matches(".gz").to_string());
+        return from_unpacked(&inner_name, unpacked, None, true);
+    }
+
+    let rom = if cartridge::is_cartridge(&bytes) {
+        let cartridge = cartridge::decode(&bytes)?;
+        Rom {
+            program: cartridge.program,
+            options: Some(cartridge.options),
+            platform: None,
+            symbols: cartridge.symbols,
+        }
+    } else {
+        Rom {
+            program: bytes,
+            options: None,
+            platform: platform_for(name),
+            symbols: Symbols::new(),
+        }
+    };
+
+    if rom.program.len() > MAX_PROGRAM_SIZE {
+        bail!("{name} has {} bytes, the maximum is {MAX_PROGRAM_SIZE}", rom.program.len());
+    }
+
+    Ok(rom)
+}
+
+/// The platform a rom is meant for, going by its extension. Plain `.ch8`
+/// roms give no hint, they were written for all sorts of interpreters.
+pub fn platform_for(name : &str) -> Option<Platform> {
+    match extension(name)?.as_str() {
+        "sc8" => Some(Platform::Schip),
+        "xo8" => Some(Platform::XoChip),
+        _ => None,
+    }
+}
+
+fn extension(name : &str) -> Option<String> {
+    Path::new(name)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+}
+
+fn is_zip(bytes : &[u8]) -> bool {
+    bytes.starts_with(b"PK\x03\x04")
+}
+
+fn is_gzip(bytes : &[u8]) -> bool {
+    bytes.starts_with(&[0x1F, 0x8B])
+}
+
+fn zip_entries(bytes : &[u8]) -> anyhow::Result<Vec<String>> {
+    let archive = zip::ZipArchive::new(Cursor::new(bytes))?;
+    Ok(archive.file_names()
+        .filter(|name| extension(name).is_some_and(|extension| ROM_EXTENSIONS.contains(&extension.as_str())))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Picks `entry` out of the archive, matching either its full path or just
+/// its file name. With no entry given the archive must hold a single rom.
+fn read_zip(bytes : &[u8], entry : Option<&str>) -> anyhow::Result<(String, Vec<u8>)> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes))?;
+
+    let name = match entry {
+        Some(entry) => archive.file_names()
+            .find(|name| *name == entry || Path::new(name).file_name().is_some_and(|file| file == entry))
+            .map(str::to_string)
+            .with_context(|| format!("archive has no entry named {entry}"))?,
+        None => {
+            let mut entries = zip_entries(bytes)?;
+            match entries.len() {
+                0 => bail!("archive holds no roms"),
+                1 => entries.remove(0),
+                _ => {
+                    entries.sort();
+                    return Err(AmbiguousArchive { entries }.into());
+                }
+            }
+        }
+    };
+
+    let unpacked = unpack(&mut archive.by_name(&name)?)?;
+    Ok((name, unpacked))
+}
+
+fn unpack(reader : &mut impl Read) -> anyhow::Result<Vec<u8>> {
+    let mut unpacked = Vec::new();
+    reader.take(MAX_UNPACKED_SIZE + 1).read_to_end(&mut unpacked)?;
+    if unpacked.len() as u64 > MAX_UNPACKED_SIZE {
+        bail!("unpacks to more than {MAX_UNPACKED_SIZE} bytes");
+    }
+    Ok(unpacked)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn make_zip(files : &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents) in files {
+            writer.start_file(*name, zip::write::SimpleFileOptions::default()).unwrap();
+            writer.write_all(contents).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_zip_entry_selection() {
+        let archive = make_zip(&[
+            ("readme.txt", b"not a rom"),
+            ("games/pong.ch8", &[0x12, 0x00]),
+            ("games/ant.sc8", &[0x00, 0xFF]),
+        ]);
+
+        let error = from_bytes("roms.zip", archive.clone(), None).unwrap_err();
+        let ambiguous = error.downcast_ref::<AmbiguousArchive>().unwrap();
+        assert_eq!(ambiguous.entries, vec!["games/ant.sc8", "games/pong.ch8"]);
+
+        let rom = from_bytes("roms.zip", archive.clone(), Some("pong.ch8")).unwrap();
+        assert_eq!(rom.program, vec![0x12, 0x00]);
+        assert_eq!(rom.platform, None);
+
+        let rom = from_bytes("roms.zip", archive.clone(), Some("games/ant.sc8")).unwrap();
+        assert_eq!(rom.program, vec![0x00, 0xFF]);
+        assert_eq!(rom.platform, Some(Platform::Schip));
+
+        assert!(from_bytes("roms.zip", archive, Some("missing.ch8")).is_err());
+    }
+
+    #[test]
+    fn test_gzip() {
+        let mut encoder = flate2::GzBuilder::new()
+            .filename("game.xo8")
+            .write(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&[0x00, 0xE0]).unwrap();
+        let archive = encoder.finish().unwrap();
+
+        let rom = from_bytes("whatever.gz", archive, None).unwrap();
+        assert_eq!(rom.program, vec![0x00, 0xE0]);
+        assert_eq!(rom.platform, Some(Platform::XoChip));
+    }
+
+    #[test]
+    fn test_nested_archives() {
+        let mut archive = vec![0x00, 0xE0];
+        for _ in 0..3 {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&archive).unwrap();
+            archive = encoder.finish().unwrap();
+        }
+        let error = from_bytes("game.ch8.gz.gz.gz", archive, None).unwrap_err();
+        assert!(format!("{error:#}").contains("archive inside an archive"));
+
+        let archive = make_zip(&[("inner.ch8", &make_zip(&[("game.ch8", &[0x00, 0xE0])]))]);
+        assert!(from_bytes("roms.zip", archive, None).is_err());
+    }
+}
@@ -0,0 +1,263 @@
+/* Tests for the subroutines of a rom, written in a file of their own so
+they can be run by `chip8-test` without any Rust:
+
+    # Paths are relative to this file
+    rom pong.ch8
+    symbols pong.sym
+    platform chip8
+
+    test the score is drawn as two digits
+    set V0=42 I=0x300
+    call draw_score
+    expect V0=42 [0x300]=0,4,2
+    screen snapshots/score.txt
+
+    test the ball bounces off the top
+    set V1=0 V3=0xFF
+    call move_ball 500
+    expect V3=1
+
+The header picks the rom, and optionally symbols to name addresses with and
+the platform whose quirks to run with. Each test then starts from the rom
+freshly loaded and goes through its lines in order:
+
+    set <assignments>      Sets registers (V0-VF, I, PC, SP, DT, ST) and
+                           memory, [addr]=b1,b2,... writing from addr on
+    call <addr> [budget]   Calls addr as if from a CALL and runs until it
+                           returns, failing after budget instructions (10000)
+    expect <assignments>   Fails unless the registers and memory hold these
+    screen <file>          Compares the screen with a snapshot, see testing
+
+Addresses and values may be numbers or symbols. `#` starts a comment.
+*/
+
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+
+use crate::cpu::Platform;
+use crate::debugger::Register;
+use crate::symbols::Symbols;
+use crate::testing::{self, Harness};
+
+pub const DEFAULT_BUDGET : u64 = 10_000;
+
+/// A register or memory set to, or expected to hold, a value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Assignment {
+    Register(Register, u16),
+    Memory(u16, Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    Set(Vec<Assignment>),
+    Call { addr: u16, budget: u64 },
+    Expect(Vec<Assignment>),
+    Screen(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Test {
+    pub name: String,
+    /// The line the test starts on, to point at it when it fails.
+    pub line: usize,
+    pub steps: Vec<Step>,
+}
+
+/// A parsed test file.
+#[derive(Debug, Clone, Default)]
+pub struct Suite {
+    pub rom: Option<PathBuf>,
+    pub platform: Option<Platform>,
+    pub symbols: Symbols,
+    pub tests: Vec<Test>,
+}
+
+impl Suite {
+    pub fn load(path : &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("could not read {}", path.display()))?;
+        Self::parse(&text, path.parent().unwrap_or(Path::new(".")))
+            .with_context(|| format!("in {}", path.display()))
+    }
+
+    /// Parses a test file, with its paths relative to `dir`.
+    pub fn parse(text : &str, dir : &Path) -> anyhow::Result<Self> {
+        let mut suite = Suite::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (directive, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim();
+            suite.parse_line(directive, rest, index + 1, dir)
+                .with_context(|| format!("line {}", index + 1))?;
+        }
+        Ok(suite)
+    }
+
+    fn parse_line(&mut self, directive : &str, rest : &str, line : usize, dir : &Path) -> anyhow::Result<()> {
+        if directive == "test" {
+            self.tests.push(Test { name: rest.to_string(), line, steps: Vec::new() });
+            return Ok(());
+        }
+
+        let Some(test) = self.tests.last_mut() else {
+            match directive {
+                "rom" => self.rom = Some(dir.join(rest)),
+                "symbols" => self.symbols = Symbols::load(&dir.join(rest).to_string_lossy())?,
+                "platform" => self.platform = Some(rest.parse()?),
+                _ => bail!("{directive} should come after a test line"),
+            }
+            return Ok(());
+        };
+
+        let step = match directive {
+            "set" => Step::Set(parse_assignments(rest, &self.symbols)?),
+            "expect" => Step::Expect(parse_assignments(rest, &self.symbols)?),
+            "call" => {
+                let mut words = rest.split_whitespace();
+                let addr = self.symbols.parse_address(words.next().context("call needs an address")?)?;
+                let budget = match words.next() {
+                    Some(budget) => budget.parse().with_context(|| format!("{budget} is not a number of instructions"))?,
+                    None => DEFAULT_BUDGET,
+                };
+                Step::Call { addr, budget }
+            }
+            "screen" => Step::Screen(dir.join(rest)),
+            "rom" | "symbols" | "platform" => bail!("{directive} should come before the tests"),
+            _ => bail!("unknown directive {directive}"),
+        };
+        test.steps.push(step);
+        Ok(())
+    }
+
+    /// Loads the rom, or `rom` in its place, and runs every test on it.
+    pub fn run(&self, rom : Option<&str>) -> anyhow::Result<Vec<(&Test, anyhow::Result<()>)>> {
+        let rom = match rom {
+            Some(rom) => rom.to_string(),
+            None => self.rom.as_ref().context("the tests name no rom")?.to_string_lossy().into_owned(),
+        };
+        // Fail once for a rom that can not be loaded rather than once a test
+        Harness::load(&rom)?;
+        Ok(self.tests.iter().map(|test| (test, self.run_test(test, &rom))).collect())
+    }
+
+    pub fn run_test(&self, test : &Test, rom : &str) -> anyhow::Result<()> {
+        let mut harness = Harness::load(rom)?;
+        if let Some(platform) = self.platform {
+            harness.arch.cpu.quirks = platform.quirks();
+        }
+
+        for step in &test.steps {
+            match step {
+                Step::Set(assignments) => {
+                    for assignment in assignments {
+                        match assignment {
+                            Assignment::Register(register, value) => register.write(&mut harness.arch, *value)?,
+                            Assignment::Memory(addr, bytes) => {
+                                for (offset, byte) in bytes.iter().enumerate() {
+                                    harness.arch.cpu.memory_mut()[(*addr as usize + offset) % 4096] = *byte;
+                                }
+                            }
+                        }
+                    }
+                }
+                Step::Call { addr, budget } => {
+                    harness.call(*addr, *budget).with_context(|| format!("calling {}", self.symbols.describe(*addr)))?;
+                }
+                Step::Expect(assignments) => {
+                    let mismatches = mismatches(&harness, assignments);
+                    if !mismatches.is_empty() {
+                        bail!("{}", mismatches.trim_end());
+                    }
+                }
+                Step::Screen(path) => testing::check_snapshot(&harness.arch.display, path)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A line for each assignment the machine does not hold.
+fn mismatches(harness : &Harness, assignments : &[Assignment]) -> String {
+    let mut out = String::new();
+    for assignment in assignments {
+        match assignment {
+            Assignment::Register(register, expected) => {
+                let actual = register.read(&harness.arch);
+                if actual != *expected {
+                    let _ = writeln!(out, "{register} is 0x{actual:02X}, expected 0x{expected:02X}");
+                }
+            }
+            Assignment::Memory(addr, expected) => {
+                let memory = harness.arch.cpu.memory();
+                let actual: Vec<u8> = (0..expected.len()).map(|offset| memory[(*addr as usize + offset) % 4096]).collect();
+                if actual != *expected {
+                    let _ = writeln!(out, "[0x{addr:03X}] is {actual:02X?}, expected {expected:02X?}");
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Parses `V0=5 I=sprite [0x300]=1,2,3`.
+fn parse_assignments(text : &str, symbols : &Symbols) -> anyhow::Result<Vec<Assignment>> {
+    text.split_whitespace()
+        .map(|word| {
+            let (target, value) = word.split_once('=').with_context(|| format!("{word} should be <target>=<value>"))?;
+            if let Some(addr) = target.strip_prefix('[').and_then(|target| target.strip_suffix(']')) {
+                let bytes = value.split(',')
+                    .map(|byte| {
+                        let value = symbols.parse_address(byte)?;
+                        u8::try_from(value).with_context(|| format!("{byte} does not fit in a byte"))
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                Ok(Assignment::Memory(symbols.parse_address(addr)?, bytes))
+            } else {
+                Ok(Assignment::Register(target.parse()?, symbols.parse_address(value)?))
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suite() {
+        // 0x200: JP 0x200
+        // double, 0x202: ADD V0, V0 (8004), 0x204: LD [I], V0 (F055), 0x206: RET
+        let rom = std::env::temp_dir().join(format!("chip8-romtest-{}.ch8", std::process::id()));
+        std::fs::write(&rom, [0x12, 0x00, 0x80, 0x04, 0xF0, 0x55, 0x00, 0xEE]).unwrap();
+
+        let suite = Suite::parse("\
+            platform schip
+            test doubles V0
+            set V0=21 I=0x300 [0x300]=9,9
+            call 0x202
+            expect V0=42 VF=0 I=0x300 [0x300]=42,9
+
+            test overflows
+            set V0=0x80
+            call 0x202 2 # RET is the third instruction
+        ", Path::new(".")).unwrap();
+        let results = suite.run(Some(&rom.to_string_lossy())).unwrap();
+        std::fs::remove_file(&rom).unwrap();
+
+        assert_eq!(results[0].0.name, "doubles V0");
+        assert!(results[0].1.is_ok());
+        let error = format!("{:#}", results[1].1.as_ref().unwrap_err());
+        assert!(error.contains("did not return within 2 instructions"), "{error}");
+
+        let results = Suite::parse("test\nexpect V0=1\n", Path::new(".")).unwrap();
+        assert_eq!(results.tests[0].steps, [Step::Expect(vec![Assignment::Register(Register::V(0), 1)])]);
+        assert!(Suite::parse("test\nexpect V0\n", Path::new(".")).is_err());
+        assert!(Suite::parse("test\nrom a.ch8\n", Path::new(".")).is_err());
+    }
+}
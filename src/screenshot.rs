@@ -0,0 +1,65 @@
+/* Screenshots of the screen as PNG, drawn from the display in the colours
+of the palette rather than read back from whatever shows it, so they work
+the same with or without a window. */
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::display::{Display, HEIGHT, WIDTH};
+use crate::palette::Palette;
+
+/// The screen as an RGB PNG, `scale` pixels a side for each of its pixels.
+pub fn png(display : &Display, palette : &Palette, scale : usize) -> anyhow::Result<Vec<u8>> {
+    let scale = scale.max(1);
+    let (width, height) = (WIDTH * scale, HEIGHT * scale);
+    let mut data = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            let on = display.pixel(x / scale, y / scale);
+            data.extend(if on {palette.foreground} else {palette.background});
+        }
+    }
+
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&data)?;
+    Ok(png)
+}
+
+pub fn save(display : &Display, palette : &Palette, scale : usize, path : &Path) -> anyhow::Result<()> {
+    std::fs::write(path, png(display, palette, scale)?)
+        .with_context(|| format!("could not write the screenshot to {}", path.display()))
+}
+
+/// The first of `<rom>-001.<extension>`, `<rom>-002.<extension>`... in the
+/// current directory that is not taken yet.
+pub fn next_path(rom_path : &str, extension : &str) -> PathBuf {
+    let stem = Path::new(rom_path).file_stem().map_or("screenshot".into(), |stem| stem.to_string_lossy());
+    (1..)
+        .map(|number| PathBuf::from(format!("{stem}-{number:03}.{extension}")))
+        .find(|path| !path.exists())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_png() {
+        let mut display = Display::new();
+        display.xor_sprite(0, 0, &[0x80]);
+        let palette = Palette { background: [0x10, 0x20, 0x30], foreground: [0xFF, 0x80, 0x00], ..Palette::default() };
+
+        let bytes = png(&display, &palette, 2).unwrap();
+        let mut reader = png::Decoder::new(std::io::Cursor::new(bytes)).read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut data).unwrap();
+        assert_eq!((info.width, info.height), (128, 64));
+        assert_eq!(data[..9], [0xFF, 0x80, 0x00, 0xFF, 0x80, 0x00, 0x10, 0x20, 0x30]);
+        assert_eq!(data[128 * 3..128 * 3 + 3], [0xFF, 0x80, 0x00]);
+    }
+}
@@ -32,10 +32,18 @@ fn vs_main(
 
 // Fragment shader
 
+// Indexed by the planes a pixel is set on
+struct Palette {
+    colors: array<vec4<f32>, 4>,
+};
+
+@group(0) @binding(0)
+var<uniform> palette: Palette;
+
 @fragment
 fn fs_main(
     in: VertexOutput
 ) -> @location(0) vec4<f32> 
 {
-    return vec4<f32>(in.instance_col, in.instance_col, in.instance_col, 1.0);
+    return palette.colors[u32(in.instance_col)];
 }
@@ -0,0 +1,95 @@
+/* Draws the screen with the CPU into a plain buffer of pixels shown in the
+window, for machines where wgpu finds no adapter. Like the gpu it stretches
+the 64x32 pixels over the whole window. */
+
+use std::num::NonZeroU32;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use winit::window::Window;
+
+use crate::display::{Display, HEIGHT, WIDTH};
+use crate::palette::Palette;
+
+pub struct Software {
+    surface: softbuffer::Surface<Arc<Window>, Arc<Window>>,
+    pub window: Arc<Window>,
+    pixels: [bool; WIDTH * HEIGHT],
+    palette: Palette,
+}
+
+impl Software {
+    pub fn new(window : Arc<Window>) -> anyhow::Result<Self> {
+        let context = softbuffer::Context::new(Arc::clone(&window))
+            .map_err(|error| anyhow!("{error}"))
+            .context("could not draw to the display")?;
+        let surface = softbuffer::Surface::new(&context, Arc::clone(&window))
+            .map_err(|error| anyhow!("{error}"))
+            .context("could not draw to the window")?;
+
+        Ok(Self {
+            surface,
+            window,
+            pixels: [false; WIDTH * HEIGHT],
+            palette: Palette::default(),
+        })
+    }
+
+    pub fn set_palette(&mut self, palette : Palette) {
+        self.palette = palette;
+        self.window.request_redraw();
+    }
+
+    pub fn render(&mut self) -> anyhow::Result<()> {
+        let size = self.window.inner_size();
+        let (Some(width), Some(height)) = (NonZeroU32::new(size.width), NonZeroU32::new(size.height)) else {
+            return Ok(());
+        };
+        self.surface.resize(width, height).map_err(|error| anyhow!("{error}"))?;
+
+        let mut buffer = self.surface.buffer_mut().map_err(|error| anyhow!("{error}"))?;
+        rasterise(&self.pixels, &self.palette, size.width as usize, size.height as usize, &mut buffer);
+        buffer.present().map_err(|error| anyhow!("{error}"))
+    }
+
+    /// Keeps the pixels of the display to be shown on the next render.
+    pub fn draw(&mut self, display : &Display) {
+        self.pixels = *display.pixels();
+        self.window.request_redraw();
+    }
+}
+
+/// Scales the screen up to `width` by `height` pixels of 0RGB, picking the
+/// nearest pixel of the screen for each.
+pub fn rasterise(pixels : &[bool; WIDTH * HEIGHT], palette : &Palette, width : usize, height : usize, out : &mut [u32]) {
+    let rgb = |[r, g, b] : [u8; 3]| ((r as u32) << 16) | ((g as u32) << 8) | b as u32;
+    let (background, foreground) = (rgb(palette.background), rgb(palette.foreground));
+
+    for (y, row) in out.chunks_exact_mut(width).take(height).enumerate() {
+        let screen_row = &pixels[(y * HEIGHT / height) * WIDTH..][..WIDTH];
+        for (x, out) in row.iter_mut().enumerate() {
+            *out = if screen_row[x * WIDTH / width] {foreground} else {background};
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rasterise() {
+        let mut pixels = [false; WIDTH * HEIGHT];
+        pixels[0] = true;
+        pixels[WIDTH * HEIGHT - 1] = true;
+        let palette = Palette { background: [0x10, 0x20, 0x30], foreground: [0xFF, 0x80, 0x00], ..Palette::default() };
+
+        let (width, height) = (WIDTH * 3, HEIGHT * 2);
+        let mut out = vec![0; width * height];
+        rasterise(&pixels, &palette, width, height, &mut out);
+        assert_eq!(out[..4], [0xFF8000, 0xFF8000, 0xFF8000, 0x102030]);
+        assert_eq!(out[width..width + 4], out[..4]);
+        assert_eq!(out[width * 2], 0x102030);
+        assert_eq!(out[width * height - 1], 0xFF8000);
+    }
+}
@@ -0,0 +1,213 @@
+/* Names for addresses, loaded from symbol files or taken from the labels
+of assembled Octo source, so the debuggers and the trace can say
+`draw_paddle+0x4` instead of `0x20A`. Two kinds of files are read:
+
+    # Text, one symbol a line, either way around, # and ; start comments
+    0x200 main
+    draw_paddle = 0x204
+    score: 0x2F0
+
+    {"labels": {"main": 512, "draw_paddle": "0x204"}}
+
+The JSON may also be a flat object of names, as Octo exports them.
+*/
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{anyhow, bail, Context};
+use serde_json::Value;
+
+use crate::debugger;
+use crate::instruction::Instruction;
+use crate::octo::Assembly;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Symbols {
+    by_addr: BTreeMap<u16, String>,
+    by_name: HashMap<String, u16>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load(path : &str) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("could not read symbols {path}"))?;
+        Self::parse(&text).with_context(|| format!("could not load symbols {path}"))
+    }
+
+    /// The labels of an assembled Octo program.
+    pub fn from_assembly(assembly : &Assembly) -> Self {
+        let mut symbols = Self::new();
+        for (name, addr) in &assembly.labels {
+            symbols.insert(name, *addr);
+        }
+        symbols
+    }
+
+    pub fn parse(text : &str) -> anyhow::Result<Self> {
+        if text.trim_start().starts_with('{') {
+            return Self::parse_json(text);
+        }
+
+        let mut symbols = Self::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split(['#', ';']).next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let line = line.replace(['=', ':'], " ");
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let symbol = match words[..] {
+                [first, second] => match debugger::parse_number(first) {
+                    Ok(addr) => Some((second, addr)),
+                    Err(_) => debugger::parse_number(second).ok().map(|addr| (first, addr)),
+                },
+                _ => None,
+            };
+            let (name, addr) = symbol.with_context(|| format!("line {} should be <addr> <name> or <name> = <addr>", number + 1))?;
+            symbols.insert(name, addr);
+        }
+        Ok(symbols)
+    }
+
+    fn parse_json(text : &str) -> anyhow::Result<Self> {
+        let json: Value = serde_json::from_str(text)?;
+        let labels = json.get("labels").unwrap_or(&json);
+        let Some(labels) = labels.as_object() else {
+            bail!("expected an object of names and addresses");
+        };
+
+        let mut symbols = Self::new();
+        for (name, addr) in labels {
+            let addr = match addr {
+                Value::Number(number) => number.as_u64().and_then(|addr| u16::try_from(addr).ok()),
+                Value::String(text) => debugger::parse_number(text).ok(),
+                _ => None,
+            };
+            symbols.insert(name, addr.ok_or_else(|| anyhow!("{name} does not have an address"))?);
+        }
+        Ok(symbols)
+    }
+
+    /// Adds a symbol. When several name the same address the first one is
+    /// shown, all of them can be looked up.
+    pub fn insert(&mut self, name : &str, addr : u16) {
+        self.by_addr.entry(addr).or_insert_with(|| name.to_string());
+        self.by_name.insert(name.to_string(), addr);
+    }
+
+    /// Adds the symbols of `other` whose names are not taken yet.
+    pub fn extend(&mut self, other : &Symbols) {
+        for (name, addr) in &other.by_name {
+            if !self.by_name.contains_key(name) {
+                self.insert(name, *addr);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// The symbols by address, the ones shown for each.
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.by_addr.iter().map(|(addr, name)| (*addr, name.as_str()))
+    }
+
+    /// The name of exactly `addr`.
+    pub fn name_of(&self, addr : u16) -> Option<&str> {
+        self.by_addr.get(&addr).map(String::as_str)
+    }
+
+    /// `addr` relative to the closest symbol at or before it, like
+    /// `draw_paddle+0x4`.
+    pub fn label(&self, addr : u16) -> Option<String> {
+        let (start, name) = self.by_addr.range(..=addr).next_back()?;
+        Some(match addr - start {
+            0 => name.clone(),
+            offset => format!("{name}+0x{offset:X}"),
+        })
+    }
+
+    /// `addr` followed by its label, if it has one.
+    pub fn describe(&self, addr : u16) -> String {
+        match self.label(addr) {
+            Some(label) => format!("0x{addr:03X} <{label}>"),
+            None => format!("0x{addr:03X}"),
+        }
+    }
+
+    /// Parses an address given as a number, a symbol or a symbol with an
+    /// offset like `draw_paddle+4`.
+    pub fn parse_address(&self, text : &str) -> anyhow::Result<u16> {
+        if let Ok(addr) = debugger::parse_number(text) {
+            return Ok(addr);
+        }
+
+        let (name, offset) = match text.split_once('+') {
+            Some((name, offset)) => (name, debugger::parse_number(offset.trim())?),
+            None => (text, 0),
+        };
+        let addr = self.by_name.get(name.trim()).with_context(|| format!("{text} is neither a number nor a symbol"))?;
+        Ok(addr.wrapping_add(offset))
+    }
+
+    /// The instruction in the debugger's syntax, with the address it
+    /// jumps to or points I at replaced by its name.
+    pub fn instruction(&self, instr : Instruction) -> String {
+        let target = match instr {
+            Instruction::Sys(addr) | Instruction::Jp(addr) | Instruction::Call(addr)
+                | Instruction::LdI(addr) | Instruction::JpV0(addr) => self.name_of(addr).map(|name| (addr, name)),
+            _ => None,
+        };
+
+        let text = instr.to_string();
+        match target {
+            Some((addr, name)) => text.replace(&format!("0x{addr:03X}"), name),
+            None => text,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_label() {
+        let symbols = Symbols::parse("# game\n0x200 main\ndraw_paddle = 0x204 ; paddles\nscore: 752\n").unwrap();
+        assert_eq!(symbols.name_of(0x204), Some("draw_paddle"));
+        assert_eq!(symbols.label(0x20A).unwrap(), "draw_paddle+0x6");
+        assert_eq!(symbols.label(0x100), None);
+        assert_eq!(symbols.describe(0x2F0), "0x2F0 <score>");
+        assert_eq!(symbols.parse_address("draw_paddle+4").unwrap(), 0x208);
+        assert_eq!(symbols.parse_address("0x300").unwrap(), 0x300);
+        assert!(symbols.parse_address("nowhere").is_err());
+        assert!(Symbols::parse("0x200 main extra").is_err());
+    }
+
+    #[test]
+    fn test_parse_json_and_instructions() {
+        let symbols = Symbols::parse(r#"{"labels": {"main": 512, "sprite": "0x300"}}"#).unwrap();
+        assert_eq!(symbols.instruction(Instruction::decode(0x2200)), "CALL main");
+        assert_eq!(symbols.instruction(Instruction::decode(0xA300)), "LD I, sprite");
+        assert_eq!(symbols.instruction(Instruction::decode(0xA302)), "LD I, 0x302");
+
+        let flat = Symbols::parse(r#"{"main": 512}"#).unwrap();
+        assert_eq!(flat.name_of(0x200), Some("main"));
+    }
+
+    #[test]
+    fn test_from_assembly() {
+        let assembly = crate::octo::assemble(": main\n  draw\n: draw\n  ;\n").unwrap();
+        let mut symbols = Symbols::parse("0x202 draw_ball\n0x200 start").unwrap();
+        symbols.extend(&Symbols::from_assembly(&assembly));
+        // Names already given are kept and shown first
+        assert_eq!(symbols.name_of(0x202), Some("draw_ball"));
+        assert_eq!(symbols.parse_address("draw").unwrap(), 0x202);
+        assert_eq!(symbols.parse_address("main").unwrap(), 0x200);
+    }
+}
@@ -0,0 +1,262 @@
+/* Helpers for testing roms: run one with scripted key presses for a number
+of frames, then compare the screen with a snapshot kept next to the tests.
+Nothing here needs a window or a GPU.
+
+    let mut harness = Harness::load("game.ch8")?;
+    harness.press(30, 0x5);
+    harness.release(40, 0x5);
+    harness.run(120)?;
+    assert_snapshot(&harness.arch.display, "tests/snapshots/jump.txt");
+
+Subroutines can be tested on their own, with the registers and memory set
+up beforehand:
+
+    harness.arch.cpu.reg[0] = 42;
+    harness.call(0x2A0, 1000)?;
+    assert_eq!(harness.arch.cpu.reg[1], 0x2A);
+
+Snapshots are text art, `#` for pixels that are on, or PNG when the path
+ends in `.png`. Missing snapshots are an error; set UPDATE_GOLDEN=1 to write
+them instead.
+*/
+
+use std::io::Cursor;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+
+use crate::arch::Arch;
+use crate::cpu::Fault;
+use crate::display::{Display, HEIGHT, WIDTH};
+use crate::palette::Palette;
+use crate::screenshot;
+
+/// Turns a key on or off at the start of a frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Input {
+    pub frame: u64,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+/// Parses a script of inputs, one a line: `<frame> press|release <key>`
+/// with the key in hex, and `#` starting comments.
+pub fn parse_inputs(text : &str) -> anyhow::Result<Vec<Input>> {
+    let mut inputs = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let [frame, action, key] = words[..] else {
+            bail!("line {} should be <frame> press|release <key>", number + 1);
+        };
+        let frame = frame.parse().with_context(|| format!("{frame} is not a frame"))?;
+        let pressed = match action {
+            "press" => true,
+            "release" => false,
+            _ => bail!("{action} should be press or release"),
+        };
+        let key = u8::from_str_radix(key.trim_start_matches("0x"), 16).ok()
+            .filter(|key| *key < 16)
+            .with_context(|| format!("{key} is not a key"))?;
+        inputs.push(Input { frame, key, pressed });
+    }
+    Ok(inputs)
+}
+
+/// A machine running a rom, fed its inputs frame by frame.
+pub struct Harness {
+    pub arch: Arch,
+    pub inputs: Vec<Input>,
+    /// Frames run so far.
+    pub frame: u64,
+}
+
+impl Harness {
+    pub fn new(program : &[u8]) -> Self {
+        let mut arch = Arch::new();
+        arch.cpu.load_program(program);
+        Self { arch, inputs: Vec::new(), frame: 0 }
+    }
+
+    /// Loads the rom the way the emulator does, with the quirks and speed
+    /// it comes with.
+    pub fn load(rom_path : &str) -> anyhow::Result<Self> {
+        let mut arch = Arch::new();
+        arch.load_rom(rom_path, None)?;
+        Ok(Self { arch, inputs: Vec::new(), frame: 0 })
+    }
+
+    pub fn press(&mut self, frame : u64, key : u8) {
+        self.inputs.push(Input { frame, key, pressed: true });
+    }
+
+    pub fn release(&mut self, frame : u64, key : u8) {
+        self.inputs.push(Input { frame, key, pressed: false });
+    }
+
+    /// Runs `frames` more frames, returning the screen they end on, or
+    /// stops short at a fault.
+    pub fn run(&mut self, frames : u64) -> Result<&Display, Fault> {
+        for _ in 0..frames {
+            for input in self.inputs.iter().filter(|input| input.frame == self.frame) {
+                self.arch.keypad[input.key as usize & 0xF] = input.pressed;
+            }
+            self.arch.emulate()?;
+            self.frame += 1;
+        }
+        Ok(&self.arch.display)
+    }
+
+    /// Calls the subroutine at `addr` as if from a CALL at the pc, and runs
+    /// it until it returns, giving up after `budget` instructions. Returns
+    /// the instructions it took.
+    pub fn call(&mut self, addr : u16, budget : u64) -> anyhow::Result<u64> {
+        let depth = self.arch.cpu.stack().len();
+        self.arch.cpu.call(addr)?;
+        for cycles in 1..=budget {
+            self.arch.step().with_context(|| format!("faulted at 0x{:03X}", self.arch.cpu.pc()))?;
+            if self.arch.cpu.stack().len() == depth {
+                return Ok(cycles);
+            }
+        }
+        bail!("0x{addr:03X} did not return within {budget} instructions, it is at 0x{:03X}", self.arch.cpu.pc())
+    }
+}
+
+/// The screen as text, a line a row with `#` for pixels that are on.
+pub fn to_text(display : &Display) -> String {
+    let mut text = String::with_capacity((WIDTH + 1) * HEIGHT);
+    for y in 0..HEIGHT {
+        text.extend((0..WIDTH).map(|x| if display.pixel(x, y) {'#'} else {'.'}));
+        text.push('\n');
+    }
+    text
+}
+
+pub fn from_text(text : &str) -> anyhow::Result<Vec<bool>> {
+    let rows: Vec<&str> = text.lines().collect();
+    if rows.len() != HEIGHT || rows.iter().any(|row| row.chars().count() != WIDTH) {
+        bail!("the screen should be {HEIGHT} rows of {WIDTH} pixels");
+    }
+    Ok(rows.iter().flat_map(|row| row.chars().map(|c| c == '#')).collect())
+}
+
+/// The screen as a black and white PNG, `scale` pixels a side for each of
+/// its pixels.
+pub fn to_png(display : &Display, scale : usize) -> anyhow::Result<Vec<u8>> {
+    screenshot::png(display, &Palette::default(), scale)
+}
+
+/// Reads the pixels back out of a screen saved as a PNG at any scale,
+/// bright pixels being on.
+pub fn from_png(bytes : &[u8]) -> anyhow::Result<Vec<bool>> {
+    let mut decoder = png::Decoder::new(Cursor::new(bytes));
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let mut data = vec![0; reader.output_buffer_size().context("the image is too large")?];
+    let info = reader.next_frame(&mut data)?;
+
+    let (width, height) = (info.width as usize, info.height as usize);
+    if width % WIDTH != 0 || height % HEIGHT != 0 || width / WIDTH != height / HEIGHT {
+        bail!("a {width}x{height} image is not a scaled {WIDTH}x{HEIGHT} screen");
+    }
+    let scale = width / WIDTH;
+    let samples = info.color_type.samples();
+    Ok((0..WIDTH * HEIGHT)
+        .map(|index| {
+            let offset = ((index / WIDTH) * scale * width + (index % WIDTH) * scale) * samples;
+            data[offset] > 0x7F
+        })
+        .collect())
+}
+
+/// The two screens overlaid: `#` on in both, `+` only on in `actual`,
+/// `-` only on in `expected`.
+pub fn diff(expected : &[bool], actual : &[bool]) -> String {
+    let mut text = String::new();
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let index = y * WIDTH + x;
+            text.push(match (expected[index], actual[index]) {
+                (true, true) => '#',
+                (false, true) => '+',
+                (true, false) => '-',
+                (false, false) => '.',
+            });
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// Compares the screen with the snapshot at `path`, panicking with the
+/// differences when they do not match.
+#[track_caller]
+pub fn assert_snapshot(display : &Display, path : impl AsRef<Path>) {
+    if let Err(error) = check_snapshot(display, path.as_ref()) {
+        panic!("{error:#}");
+    }
+}
+
+/// Compares the screen with the snapshot at `path`, or writes it there
+/// when UPDATE_GOLDEN is set.
+pub fn check_snapshot(display : &Display, path : &Path) -> anyhow::Result<()> {
+    let png = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("png"));
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let bytes = if png {to_png(display, 8)?} else {to_text(display).into_bytes()};
+        return std::fs::write(path, bytes).with_context(|| format!("could not write {}", path.display()));
+    }
+
+    let bytes = std::fs::read(path)
+        .with_context(|| format!("no snapshot at {}, run with UPDATE_GOLDEN=1 to write it", path.display()))?;
+    let expected = if png {from_png(&bytes)?} else {from_text(&String::from_utf8_lossy(&bytes))?};
+    let actual = display.pixels();
+    if expected[..] != actual[..] {
+        let count = expected.iter().zip(actual).filter(|(expected, actual)| expected != actual).count();
+        bail!(
+            "the screen differs from {} in {count} pixels (+ only on now, - only on before):\n{}",
+            path.display(), diff(&expected, actual),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshots() {
+        // Draws the font's 0 at V0, V1, moving it right while key 5 is held:
+        // 0x200: LD F, V2, 0x202: DRW V0, V1, 5, 0x204: LD V3, 5, 0x206: SKNP V3,
+        // 0x208: JP 0x20C, 0x20A: JP 0x206, 0x20C: DRW V0, V1, 5, 0x20E: ADD V0, 1,
+        // 0x210: DRW V0, V1, 5, 0x212: JP 0x206
+        let mut harness = Harness::new(&[
+            0xF2, 0x29, 0xD0, 0x15, 0x63, 0x05, 0xE3, 0xA1, 0x12, 0x0C,
+            0x12, 0x06, 0xD0, 0x15, 0x70, 0x01, 0xD0, 0x15, 0x12, 0x06,
+        ]);
+        harness.inputs = parse_inputs("# hold 5 for a frame\n1 press 5\n2 release 5\n").unwrap();
+        let display = harness.run(4).unwrap().clone();
+        let moved = harness.arch.cpu.reg[0] as usize;
+        assert!(moved > 0);
+        harness.run(2).unwrap();
+        assert_eq!(harness.arch.cpu.reg[0] as usize, moved);
+
+        let text = to_text(&display);
+        assert!(text.starts_with(&format!("{}####.", ".".repeat(moved))));
+        let pixels = from_text(&text).unwrap();
+        assert_eq!(pixels[..], display.pixels()[..]);
+        assert_eq!(from_png(&to_png(&display, 3).unwrap()).unwrap(), pixels);
+
+        let left: Vec<bool> = (0..WIDTH * HEIGHT).map(|index| pixels.get(index + 1).copied().unwrap_or_default()).collect();
+        assert!(diff(&left, &pixels)[moved - 1..].starts_with("-###+"));
+        assert!(parse_inputs("1 hold 5").is_err());
+    }
+}
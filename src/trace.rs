@@ -0,0 +1,341 @@
+/* Logs every executed instruction, one line each, with the state of the
+machine right before it runs. The columns come from a template, by default
+this crate's own layout:
+
+    {pc} {opcode} {regs} I={i} SP={sp} DT={dt} ST={st} ; {asm}
+
+which gives
+
+    0200 6005 V0=00 V1=00 ... VF=00 I=0000 SP=0 DT=00 ST=00 ; LD V0, 0x05
+
+and with symbols `; main+0x2: CALL draw_paddle` for the mnemonics. There is
+no common log format for CHIP-8 emulators, so to diff against another one
+the template is changed to print the columns it prints, in its order. The
+fields are:
+
+    {pc} {opcode} {i}        4 hex digits
+    {v0} ... {vf} {dt} {st}  2 hex digits
+    {sp}                     how many return addresses are on the stack, in hex
+    {regs}                   V0=00 through VF=00
+    {cycle}                  instructions executed before, in decimal
+    {asm}                    the mnemonic, after its label if it has one
+
+Hex fields are in capitals, `{pc:x}` writes them in lower case, and `{{`
+and `}}` are literal braces. Everything but `{asm}` and `{cycle}` only
+depends on the state of the machine.
+*/
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+
+use anyhow::{bail, Context};
+
+use crate::cpu::Cpu;
+use crate::debugger;
+use crate::instruction::Instruction;
+use crate::symbols::Symbols;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Pc,
+    Opcode,
+    I,
+    V(usize),
+    Dt,
+    St,
+    Sp,
+    Regs,
+    Cycle,
+    Asm,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Column {
+    Text(String),
+    Field { field: Field, lower: bool },
+}
+
+/// The columns of a trace line, parsed from a template like
+/// `{pc} {opcode} ; {asm}`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFormat(Vec<Column>);
+
+impl TraceFormat {
+    pub const DEFAULT : &str = "{pc} {opcode} {regs} I={i} SP={sp} DT={dt} ST={st} ; {asm}";
+    /// The default without the mnemonics.
+    pub const PLAIN : &str = "{pc} {opcode} {regs} I={i} SP={sp} DT={dt} ST={st}";
+
+    pub fn parse(template : &str) -> anyhow::Result<Self> {
+        let mut columns = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let (name, rest) = chars.as_str().split_once('}').with_context(|| format!("unclosed {{ in {template}"))?;
+                    let (name, lower) = match name.split_once(':') {
+                        Some((name, "x")) => (name, true),
+                        Some((_, spec)) => bail!("unknown format :{spec} in {{{name}}}, only :x is known"),
+                        None => (name, false),
+                    };
+                    let field = match name {
+                        "pc" => Field::Pc,
+                        "opcode" => Field::Opcode,
+                        "i" => Field::I,
+                        "dt" => Field::Dt,
+                        "st" => Field::St,
+                        "sp" => Field::Sp,
+                        "regs" => Field::Regs,
+                        "cycle" => Field::Cycle,
+                        "asm" => Field::Asm,
+                        _ => match name.strip_prefix('v').and_then(|index| usize::from_str_radix(index, 16).ok()) {
+                            Some(index) if index < 16 && name.len() == 2 => Field::V(index),
+                            _ => bail!("unknown trace field {{{name}}}"),
+                        },
+                    };
+                    if !text.is_empty() {
+                        columns.push(Column::Text(std::mem::take(&mut text)));
+                    }
+                    columns.push(Column::Field { field, lower });
+                    chars = rest.chars();
+                }
+                '}' => bail!("unmatched }} in {template}"),
+                _ => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            columns.push(Column::Text(text));
+        }
+        Ok(Self(columns))
+    }
+}
+
+impl Default for TraceFormat {
+    fn default() -> Self {
+        Self::parse(Self::DEFAULT).unwrap()
+    }
+}
+
+/// Which instructions make it to the log.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TraceFilter {
+    /// Only instructions at these addresses.
+    pub addresses: Option<RangeInclusive<u16>>,
+    /// Only instructions executed while the cycle count is in this range.
+    pub cycles: Option<RangeInclusive<u64>>,
+}
+
+impl TraceFilter {
+    pub fn accepts(&self, pc : u16, cycle : u64) -> bool {
+        self.addresses.as_ref().is_none_or(|addresses| addresses.contains(&pc))
+            && self.cycles.as_ref().is_none_or(|cycles| cycles.contains(&cycle))
+    }
+}
+
+/// Parses `START-END` or a single value, with the numbers in the format the
+/// debugger takes.
+pub fn parse_address_range(text : &str) -> anyhow::Result<RangeInclusive<u16>> {
+    let (start, end) = text.split_once('-').unwrap_or((text, text));
+    Ok(debugger::parse_number(start.trim())?..=debugger::parse_number(end.trim())?)
+}
+
+/// Parses `START-END` or `START-` for every cycle from START on.
+pub fn parse_cycle_range(text : &str) -> anyhow::Result<RangeInclusive<u64>> {
+    let parse = |number : &str| number.trim().parse::<u64>().with_context(|| format!("{number} is not a cycle count"));
+    match text.split_once('-') {
+        Some((start, "")) => Ok(parse(start)?..=u64::MAX),
+        Some((start, end)) => Ok(parse(start)?..=parse(end)?),
+        None => Ok(parse(text)?..=parse(text)?),
+    }
+}
+
+pub struct Tracer {
+    output: Box<dyn Write + Send>,
+    pub filter: TraceFilter,
+    pub format: TraceFormat,
+    /// Names the instructions and their targets in the mnemonics.
+    pub symbols: Symbols,
+    line: String,
+    /// Whether `line` is waiting for its instruction to run.
+    pending: bool,
+}
+
+impl Tracer {
+    pub fn new(output : impl Write + Send + 'static, filter : TraceFilter) -> Self {
+        Self {
+            output: Box::new(output),
+            filter,
+            format: TraceFormat::default(),
+            symbols: Symbols::new(),
+            line: String::new(),
+            pending: false,
+        }
+    }
+
+    pub fn create(path : &str, filter : TraceFilter) -> anyhow::Result<Self> {
+        let file = File::create(path).with_context(|| format!("could not create trace {path}"))?;
+        Ok(Self::new(BufWriter::new(file), filter))
+    }
+
+    /// Lays out the line of the instruction `cpu` is about to execute as
+    /// the `cycle`th one. `commit` logs it once the instruction ran, so an
+    /// instruction that faults is left out.
+    pub fn record(&mut self, cpu : &Cpu, cycle : u64) {
+        let pc = cpu.pc();
+        self.pending = self.filter.accepts(pc, cycle);
+        if !self.pending {
+            return;
+        }
+
+        let opcode = cpu.current_instruction();
+        self.line.clear();
+        for column in &self.format.0 {
+            let (field, lower) = match column {
+                Column::Text(text) => {
+                    self.line.push_str(text);
+                    continue;
+                }
+                Column::Field { field, lower } => (*field, *lower),
+            };
+            let start = self.line.len();
+            let _ = match field {
+                Field::Pc => write!(self.line, "{pc:04X}"),
+                Field::Opcode => write!(self.line, "{opcode:04X}"),
+                Field::I => write!(self.line, "{:04X}", cpu.i_reg()),
+                Field::V(index) => write!(self.line, "{:02X}", cpu.reg[index]),
+                Field::Dt => write!(self.line, "{:02X}", cpu.delay_timer()),
+                Field::St => write!(self.line, "{:02X}", cpu.sound_timer()),
+                Field::Sp => write!(self.line, "{:X}", cpu.stack().len()),
+                Field::Regs => {
+                    for (index, value) in cpu.reg.iter().enumerate() {
+                        let space = if index == 0 {""} else {" "};
+                        let _ = write!(self.line, "{space}V{index:X}={value:02X}");
+                    }
+                    Ok(())
+                }
+                Field::Cycle => write!(self.line, "{cycle}"),
+                Field::Asm => {
+                    if let Some(label) = self.symbols.label(pc) {
+                        let _ = write!(self.line, "{label}: ");
+                    }
+                    write!(self.line, "{}", self.symbols.instruction(Instruction::decode(opcode)))
+                }
+            };
+            if lower {
+                self.line[start..].make_ascii_lowercase();
+            }
+        }
+    }
+
+    /// Logs the line `record` laid out, if it laid one out.
+    pub fn commit(&mut self) -> io::Result<()> {
+        if !std::mem::take(&mut self.pending) {
+            return Ok(());
+        }
+        writeln!(self.output, "{}", self.line)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::arch::Arch;
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trace() {
+        let log = Shared::default();
+        let filter = TraceFilter {
+            addresses: Some(parse_address_range("0x202-0x206").unwrap()),
+            cycles: Some(parse_cycle_range("0-2").unwrap()),
+        };
+
+        let mut arch = Arch::new();
+        // LD V0, 5; ADD V0, 1; LD I, 0x300; JP 0x206
+        arch.cpu.load_program(&[0x60, 0x05, 0x70, 0x01, 0xA3, 0x00, 0x12, 0x06]);
+        arch.tracer = Some(Tracer::new(log.clone(), filter));
+        for _ in 0..5 {
+            arch.step().unwrap();
+        }
+
+        let text = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("0202 7001 V0=05 V1=00"));
+        assert!(lines[0].ends_with("I=0000 SP=0 DT=00 ST=00 ; ADD V0, 0x01"));
+        assert!(lines[1].starts_with("0204 A300 V0=06"));
+    }
+
+    #[test]
+    fn test_trace_format() {
+        let log = Shared::default();
+        let mut tracer = Tracer::new(log.clone(), TraceFilter::default());
+        tracer.format = TraceFormat::parse("{cycle}: {pc:x} {opcode:x} v0={v0} {{i}}={i}").unwrap();
+
+        let mut arch = Arch::new();
+        // LD V0, 0xAB; LD I, 0x2FE
+        arch.cpu.load_program(&[0x60, 0xAB, 0xA2, 0xFE]);
+        arch.tracer = Some(tracer);
+        arch.step().unwrap();
+        arch.step().unwrap();
+
+        let text = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(text, "0: 0200 60ab v0=00 {i}=0000\n1: 0202 a2fe v0=AB {i}=0000\n");
+
+        assert!(TraceFormat::parse("{pc").is_err());
+        assert!(TraceFormat::parse("{vg}").is_err());
+        assert!(TraceFormat::parse("{pc:d}").is_err());
+        assert!(TraceFormat::parse("}").is_err());
+    }
+
+    #[test]
+    fn test_fault_is_not_traced() {
+        let log = Shared::default();
+        let mut arch = Arch::new();
+        // LD V0, 1; RET with nothing to return to
+        arch.cpu.load_program(&[0x60, 0x01, 0x00, 0xEE]);
+        arch.tracer = Some(Tracer::new(log.clone(), TraceFilter::default()));
+        arch.step().unwrap();
+        assert!(arch.step().is_err());
+
+        let text = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(text.lines().count(), 1);
+        assert!(text.starts_with("0200 6001"));
+    }
+
+    #[test]
+    fn test_parse_ranges() {
+        assert_eq!(parse_address_range("0x200-0x2FF").unwrap(), 0x200..=0x2FF);
+        assert_eq!(parse_address_range("0x200").unwrap(), 0x200..=0x200);
+        assert_eq!(parse_cycle_range("100-").unwrap(), 100..=u64::MAX);
+        assert!(parse_cycle_range("a-b").is_err());
+    }
+}
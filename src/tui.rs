@@ -0,0 +1,216 @@
+/* Plays the rom in the terminal, which also works over ssh. The screen is
+drawn with Unicode half blocks, two pixels a character, or braille, eight
+pixels a character for small terminals, in the colours of the palette. It
+is sized from the display's resolution, which is 64x32 until the display
+gets SCHIP's 128x64 mode.
+
+Most terminals only say when a key goes down, so a key stays pressed for a
+few frames after each press, kept down by the key repeating. Terminals that
+report releases (kitty, foot, WezTerm...) get exact key presses. */
+
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use crossterm::event::{
+    self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+    PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+use crossterm::style::{Color, Colors, Print, ResetColor, SetColors};
+use crossterm::{cursor, execute, queue, terminal};
+
+use crate::arch::Arch;
+use crate::cli::{self, Args};
+use crate::display::Display;
+use crate::palette::Palette;
+
+const FRAME : Duration = Duration::from_micros(16_667);
+/// Frames a key stays down after a press when the terminal does not say
+/// when it is released. Long enough to last until the key repeats.
+const HOLD_FRAMES : u8 = 30;
+
+/// How pixels are turned into characters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Glyphs {
+    /// A character for 1x2 pixels.
+    HalfBlocks,
+    /// A character for 2x4 pixels.
+    Braille,
+}
+
+/// Draws a `width` by `height` screen as lines of characters.
+pub fn render(pixel : impl Fn(usize, usize) -> bool, width : usize, height : usize, glyphs : Glyphs) -> Vec<String> {
+    let on = |x : usize, y : usize| x < width && y < height && pixel(x, y);
+    match glyphs {
+        Glyphs::HalfBlocks => (0..height.div_ceil(2))
+            .map(|row| {
+                (0..width)
+                    .map(|x| match (on(x, row * 2), on(x, row * 2 + 1)) {
+                        (true, true) => '█',
+                        (true, false) => '▀',
+                        (false, true) => '▄',
+                        (false, false) => ' ',
+                    })
+                    .collect()
+            })
+            .collect(),
+        Glyphs::Braille => (0..height.div_ceil(4))
+            .map(|row| {
+                (0..width.div_ceil(2))
+                    .map(|column| {
+                        // The dots of a braille character, by column then row
+                        const DOTS : [[u32; 4]; 2] = [[0x01, 0x02, 0x04, 0x40], [0x08, 0x10, 0x20, 0x80]];
+                        let mut bits = 0;
+                        for (dx, dots) in DOTS.iter().enumerate() {
+                            for (dy, dot) in dots.iter().enumerate() {
+                                if on(column * 2 + dx, row * 4 + dy) {
+                                    bits |= dot;
+                                }
+                            }
+                        }
+                        char::from_u32(0x2800 + bits).unwrap_or(' ')
+                    })
+                    .collect()
+            })
+            .collect(),
+    }
+}
+
+/// The keypad key for a key of the keyboard, laid out like the winit
+/// frontend: 1234/QWER/ASDF/ZXCV.
+pub fn keypad_key(key : char) -> Option<usize> {
+    const LAYOUT : [(char, usize); 16] = [
+        ('1', 0x1), ('2', 0x2), ('3', 0x3), ('4', 0xC),
+        ('q', 0x4), ('w', 0x5), ('e', 0x6), ('r', 0xD),
+        ('a', 0x7), ('s', 0x8), ('d', 0x9), ('f', 0xE),
+        ('z', 0xA), ('x', 0x0), ('c', 0xB), ('v', 0xF),
+    ];
+    let key = key.to_ascii_lowercase();
+    LAYOUT.iter().find(|(c, _)| *c == key).map(|(_, index)| *index)
+}
+
+/// Puts the terminal back the way it was, however we leave.
+struct Terminal {
+    enhanced: bool,
+}
+
+impl Terminal {
+    fn enter() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(io::stdout(), terminal::EnterAlternateScreen, cursor::Hide, terminal::Clear(terminal::ClearType::All))?;
+        let enhanced = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if enhanced {
+            execute!(io::stdout(), PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES))?;
+        }
+        Ok(Self { enhanced })
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        if self.enhanced {
+            let _ = execute!(io::stdout(), PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(io::stdout(), ResetColor, cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+fn draw(out : &mut impl Write, display : &Display, glyphs : Glyphs, palette : &Palette, status : &str) -> io::Result<()> {
+    let rgb = |[r, g, b] : [u8; 3]| Color::Rgb { r, g, b };
+    queue!(out, SetColors(Colors::new(rgb(palette.foreground), rgb(palette.background))))?;
+    let (width, height) = display.resolution();
+    let lines = render(|x, y| display.pixel(x, y), width, height, glyphs);
+    for (row, line) in lines.iter().enumerate() {
+        queue!(out, cursor::MoveTo(0, row as u16), Print(line))?;
+    }
+    queue!(out, ResetColor, cursor::MoveTo(0, lines.len() as u16), Print(status))?;
+    out.flush()
+}
+
+/// Runs the rom in the terminal until Esc or Ctrl-C.
+pub fn run(args : &Args) -> anyhow::Result<()> {
+    if args.debug || args.gdb.is_some() || cli::uses_dap(args) {
+        bail!("the terminal frontend can not be used with the debuggers");
+    }
+    let (mut arch, rom) = cli::load_arch(args)?;
+    let palette = cli::choose_palette(args, &rom)?;
+    let glyphs = if args.braille {Glyphs::Braille} else {Glyphs::HalfBlocks};
+    let status = format!("{}  (Esc to quit)", args.rom_path);
+
+    let result = {
+        let terminal = Terminal::enter()?;
+        play(&mut arch, glyphs, &palette, &status, terminal.enhanced)
+    };
+    cli::save_screenshot(args, &arch.display, &palette)?;
+    arch.finish()?;
+    result
+}
+
+fn play(arch : &mut Arch, glyphs : Glyphs, palette : &Palette, status : &str, releases : bool) -> anyhow::Result<()> {
+    let mut out = io::BufWriter::new(io::stdout());
+    let mut held = [0u8; 16];
+    let mut resolution = arch.display.resolution();
+    loop {
+        let start = Instant::now();
+        while event::poll(Duration::ZERO)? {
+            match event::read()? {
+                Event::Key(KeyEvent { code: KeyCode::Esc, .. }) => return Ok(()),
+                Event::Key(KeyEvent { code: KeyCode::Char('c'), modifiers, .. })
+                    if modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+                Event::Key(KeyEvent { code: KeyCode::Char(key), kind, .. }) => {
+                    if let Some(index) = keypad_key(key) {
+                        held[index] = match kind {
+                            KeyEventKind::Release => 0,
+                            _ if releases => u8::MAX,
+                            _ => HOLD_FRAMES,
+                        };
+                    }
+                }
+                Event::Resize(..) => {
+                    queue!(out, terminal::Clear(terminal::ClearType::All))?;
+                    arch.display.dirty = true;
+                }
+                _ => {}
+            }
+        }
+
+        for (pressed, frames) in arch.keypad.iter_mut().zip(&mut held) {
+            *pressed = *frames > 0;
+            if !releases {
+                *frames = frames.saturating_sub(1);
+            }
+        }
+        if let Err(fault) = arch.emulate() {
+            bail!("the rom faulted at 0x{:03X}: {fault}", arch.cpu.pc());
+        }
+
+        // What was drawn at another resolution would be left around
+        if arch.display.resolution() != resolution {
+            resolution = arch.display.resolution();
+            queue!(out, terminal::Clear(terminal::ClearType::All))?;
+        }
+        if arch.display.dirty {
+            draw(&mut out, &arch.display, glyphs, palette, status)?;
+            arch.display.dirty = false;
+        }
+        thread::sleep(FRAME.saturating_sub(start.elapsed()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        // A diagonal line
+        let pixel = |x : usize, y : usize| x == y;
+        assert_eq!(render(pixel, 4, 4, Glyphs::HalfBlocks), ["▀▄  ", "  ▀▄"]);
+        assert_eq!(render(pixel, 4, 4, Glyphs::Braille), ["\u{2811}\u{2884}"]);
+        assert_eq!(render(|_, _| true, 3, 3, Glyphs::HalfBlocks), ["███", "▀▀▀"]);
+        assert_eq!(keypad_key('X'), Some(0));
+        assert_eq!(keypad_key('p'), None);
+    }
+}
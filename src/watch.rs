@@ -0,0 +1,233 @@
+/* Watchpoints stop the machine right after an instruction touches what
+they watch. Memory is watched through the accesses the cpu records, so
+only instructions reading or writing data count (Dxyn, Fx33, Fx55, Fx65),
+never instruction fetches. Registers are watched through the instruction
+about to run: one whose destination is the register stops, even when it
+writes the value the register already holds. Flags count as writes to VF,
+and Fx0A writes Vx as soon as it runs, though the key may only come later.
+*/
+
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use anyhow::bail;
+
+use crate::arch::Arch;
+use crate::cpu::Quirks;
+use crate::debugger::Register;
+use crate::instruction::Instruction;
+use crate::trace;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// Reads and writes.
+    Access,
+}
+
+impl WatchKind {
+    fn matches(self, write : bool) -> bool {
+        match self {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::Access => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatchTarget {
+    Memory(RangeInclusive<u16>),
+    /// Only V0-VF and I, on writes.
+    Register(Register),
+}
+
+impl FromStr for WatchTarget {
+    type Err = anyhow::Error;
+
+    /// Parses a register name, an address or an address range.
+    fn from_str(text : &str) -> anyhow::Result<Self> {
+        match text.parse::<Register>() {
+            Ok(register @ (Register::V(_) | Register::I)) => Ok(WatchTarget::Register(register)),
+            Ok(register) => bail!("{register} can not be watched, only V0-VF and I can"),
+            Err(_) => Ok(WatchTarget::Memory(trace::parse_address_range(text)?)),
+        }
+    }
+}
+
+impl fmt::Display for WatchTarget {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchTarget::Memory(range) if range.start() == range.end() => write!(f, "0x{:03X}", range.start()),
+            WatchTarget::Memory(range) => write!(f, "0x{:03X}-0x{:03X}", range.start(), range.end()),
+            WatchTarget::Register(register) => write!(f, "{register}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watchpoint {
+    pub target: WatchTarget,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    pub fn new(target : WatchTarget, kind : WatchKind) -> anyhow::Result<Self> {
+        if matches!(target, WatchTarget::Register(_)) && kind != WatchKind::Write {
+            bail!("registers can only be watched for writes");
+        }
+        Ok(Self { target, kind })
+    }
+
+    /// The value a watched register holds, if the instruction about to run
+    /// writes it.
+    pub fn before(&self, arch : &Arch) -> Option<u16> {
+        match self.target {
+            WatchTarget::Register(register) => {
+                let instruction = Instruction::decode(arch.cpu.current_instruction());
+                writes(&instruction, register, &arch.cpu.quirks).then(|| register.read(arch))
+            }
+            WatchTarget::Memory(_) => None,
+        }
+    }
+
+    /// Whether the instruction just executed triggers the watchpoint,
+    /// given what `before` returned ahead of it.
+    pub fn check(&self, arch : &Arch, before : Option<u16>) -> Option<WatchHit> {
+        match &self.target {
+            WatchTarget::Register(register) => before.map(|old| WatchHit {
+                location: Location::Register(*register),
+                write: true,
+                old,
+                new: register.read(arch),
+            }),
+            WatchTarget::Memory(range) => arch.cpu.accesses().iter()
+                .find(|access| range.contains(&access.addr) && self.kind.matches(access.write))
+                .map(|access| WatchHit {
+                    location: Location::Memory(access.addr),
+                    write: access.write,
+                    old: access.old as u16,
+                    new: access.new as u16,
+                }),
+        }
+    }
+}
+
+/// Whether `instruction` writes `register`, flags included.
+fn writes(instruction : &Instruction, register : Register, quirks : &Quirks) -> bool {
+    match register {
+        Register::V(v) => match *instruction {
+            Instruction::LdByte(x, _) | Instruction::AddByte(x, _) | Instruction::LdReg(x, _) | Instruction::Rnd(x, _)
+                | Instruction::LdVxDt(x) | Instruction::LdVxK(x) => v == x,
+            Instruction::Or(x, _) | Instruction::And(x, _) | Instruction::Xor(x, _) => v == x || (v == 0xF && quirks.logic),
+            Instruction::AddReg(x, _) | Instruction::Sub(x, _) | Instruction::Shr(x, _) | Instruction::Subn(x, _)
+                | Instruction::Shl(x, _) => v == x || v == 0xF,
+            Instruction::Drw(..) => v == 0xF,
+            Instruction::LdVxI(x) => v <= x,
+            _ => false,
+        },
+        Register::I => match instruction {
+            Instruction::LdI(_) | Instruction::AddI(_) | Instruction::LdF(_) => true,
+            Instruction::LdIVx(_) | Instruction::LdVxI(_) => !quirks.load_store,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            WatchKind::Read => "reads of",
+            WatchKind::Write => "writes to",
+            WatchKind::Access => "accesses to",
+        };
+        write!(f, "{kind} {}", self.target)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Location {
+    Memory(u16),
+    Register(Register),
+}
+
+/// What triggered a watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchHit {
+    pub location: Location,
+    pub write: bool,
+    pub old: u16,
+    pub new: u16,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.location {
+            Location::Memory(addr) => write!(f, "0x{addr:03X}")?,
+            Location::Register(register) => write!(f, "{register}")?,
+        }
+        if self.write {
+            write!(f, " written: 0x{:02X} -> 0x{:02X}", self.old, self.new)
+        } else {
+            write!(f, " read: 0x{:02X}", self.old)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_watchpoints() {
+        let mut arch = Arch::new();
+        // LD V0, 7; LD I, 0x300; LD [I], V0; LD V0, [I]
+        arch.cpu.load_program(&[0x60, 0x07, 0xA3, 0x00, 0xF0, 0x55, 0xF0, 0x65]);
+        arch.cpu.record_accesses(true);
+        let write = Watchpoint::new("0x300".parse().unwrap(), WatchKind::Write).unwrap();
+        let read = Watchpoint::new("0x2F0-0x30F".parse().unwrap(), WatchKind::Read).unwrap();
+
+        for _ in 0..2 {
+            arch.step().unwrap();
+            assert_eq!(write.check(&arch, None), None);
+        }
+        arch.step().unwrap();
+        let hit = write.check(&arch, None).unwrap();
+        assert_eq!(hit, WatchHit { location: Location::Memory(0x300), write: true, old: 0, new: 7 });
+        assert_eq!(read.check(&arch, None), None);
+
+        arch.step().unwrap();
+        assert_eq!(write.check(&arch, None), None);
+        assert_eq!(read.check(&arch, None).unwrap().to_string(), "0x300 read: 0x07");
+    }
+
+    #[test]
+    fn test_register_watchpoints() {
+        assert!(Watchpoint::new("V3".parse().unwrap(), WatchKind::Read).is_err());
+        assert!("PC".parse::<WatchTarget>().is_err());
+
+        let mut arch = Arch::new();
+        // LD V3, 0; LD V3, 2; SNE V3, 2; ADD V0, V3
+        arch.cpu.load_program(&[0x63, 0x00, 0x63, 0x02, 0x43, 0x02, 0x80, 0x34]);
+        let watch = Watchpoint::new("v3".parse().unwrap(), WatchKind::Write).unwrap();
+        let flag = Watchpoint::new("VF".parse().unwrap(), WatchKind::Write).unwrap();
+
+        // Writing the value the register already holds still counts
+        let before = watch.before(&arch);
+        arch.step().unwrap();
+        assert_eq!(watch.check(&arch, before).unwrap().to_string(), "V3 written: 0x00 -> 0x00");
+        let before = watch.before(&arch);
+        arch.step().unwrap();
+        assert_eq!(watch.check(&arch, before).unwrap().to_string(), "V3 written: 0x00 -> 0x02");
+
+        // Reading it does not, but a flag set to what it was does
+        assert_eq!(watch.before(&arch), None);
+        arch.step().unwrap();
+        let before = flag.before(&arch);
+        arch.step().unwrap();
+        assert_eq!(flag.check(&arch, before).unwrap().to_string(), "VF written: 0x00 -> 0x00");
+    }
+}
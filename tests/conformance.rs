@@ -0,0 +1,95 @@
+/* Runs the community test roms headless for a fixed number of frames and
+compares the screen they end on with the golden image in tests/golden.
+
+corax' test_opcode.ch8 is kept in tests/roms and runs with every `cargo
+test`. The others are not in the repository yet, so their cases are
+ignored until the rom is put in tests/roms next to its golden image:
+
+- Timendus' chip8-test-suite: 1-chip8-logo.ch8 to 5-quirks.ch8
+- BonCoder's BC_test.ch8
+
+Then run them with `cargo test --test conformance -- --ignored`. A case
+whose rom is missing fails. Run with UPDATE_GOLDEN=1 to write the golden
+images, after checking by eye that the screens show every test passing.
+
+The SCHIP and XO-CHIP runs of the quirks test, the scrolling test and
+SCTEST need SCHIP's instructions and high resolution, and the keypad test
+needs keys pressed, so they are not run.
+*/
+
+use std::path::{Path, PathBuf};
+
+use chip8::cpu::Platform;
+use chip8::testing::{check_snapshot, Harness};
+
+struct Case {
+    name: &'static str,
+    rom: &'static str,
+    platform: Platform,
+    frames: u64,
+    /// Written to 0x1FF before starting, which Timendus' roms read to skip
+    /// their menus.
+    menu: Option<u8>,
+}
+
+fn find_rom(rom : &str) -> PathBuf {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/roms").join(rom);
+    assert!(path.exists(), "{} is missing, see the top of tests/conformance.rs", path.display());
+    path
+}
+
+fn check(case : Case) {
+    let rom = find_rom(case.rom);
+    let mut harness = Harness::load(rom.to_str().unwrap()).unwrap();
+    harness.arch.cpu.quirks = case.platform.quirks();
+    if let Some(menu) = case.menu {
+        harness.arch.cpu.memory_mut()[0x1FF] = menu;
+    }
+    harness.run(case.frames).unwrap();
+
+    let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(format!("{}.txt", case.name));
+    if let Err(error) = check_snapshot(&harness.arch.display, &golden) {
+        panic!("{}: {error:#}", case.name);
+    }
+}
+
+#[test]
+fn corax() {
+    check(Case { name: "corax", rom: "test_opcode.ch8", platform: Platform::Chip8, frames: 120, menu: None });
+}
+
+#[test]
+#[ignore = "needs tests/roms/BC_test.ch8"]
+fn bc_test() {
+    check(Case { name: "bc_test", rom: "BC_test.ch8", platform: Platform::Chip8, frames: 300, menu: None });
+}
+
+#[test]
+#[ignore = "needs tests/roms/1-chip8-logo.ch8"]
+fn chip8_logo() {
+    check(Case { name: "chip8_logo", rom: "1-chip8-logo.ch8", platform: Platform::Chip8, frames: 60, menu: None });
+}
+
+#[test]
+#[ignore = "needs tests/roms/2-ibm-logo.ch8"]
+fn ibm_logo() {
+    check(Case { name: "ibm_logo", rom: "2-ibm-logo.ch8", platform: Platform::Chip8, frames: 60, menu: None });
+}
+
+#[test]
+#[ignore = "needs tests/roms/3-corax+.ch8"]
+fn corax_plus() {
+    check(Case { name: "corax_plus", rom: "3-corax+.ch8", platform: Platform::Chip8, frames: 120, menu: None });
+}
+
+#[test]
+#[ignore = "needs tests/roms/4-flags.ch8"]
+fn flags() {
+    check(Case { name: "flags", rom: "4-flags.ch8", platform: Platform::Chip8, frames: 120, menu: None });
+}
+
+#[test]
+#[ignore = "needs tests/roms/5-quirks.ch8"]
+fn quirks_chip8() {
+    check(Case { name: "quirks_chip8", rom: "5-quirks.ch8", platform: Platform::Chip8, frames: 600, menu: Some(1) });
+}